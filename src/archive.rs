@@ -0,0 +1,243 @@
+//! Compressed source files
+//!
+//! With `--once` a rotated `.gz` or `.zst` log is decoded and its content
+//! fed into the normal pipeline. Data decoded before a corrupt or truncated
+//! part of the archive is still shipped; the error is reported afterwards.
+
+use crate::tail::{is_permission_denied, LogChunk};
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Size of the chunks decoded data is sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Compression format of a source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    fn from_magic(header: &[u8]) -> Option<Self> {
+        if header.starts_with(GZIP_MAGIC) {
+            Some(Codec::Gzip)
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Some(Codec::Zstd)
+        } else {
+            None
+        }
+    }
+
+    fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Codec::Gzip),
+            "zst" | "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Detect a compressed file from its magic bytes. A `.gz`/`.zst` name
+/// whose content doesn't match is rejected rather than shipped as text.
+pub fn detect(path: &Path) -> Result<Option<Codec>> {
+    let mut header = [0u8; 4];
+    let mut file = File::open(path).context("Failed to open file")?;
+    let mut read = 0;
+    while read < header.len() {
+        match file.read(&mut header[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    let by_magic = Codec::from_magic(&header[..read]);
+    match (Codec::from_extension(path), by_magic) {
+        (Some(expected), found) if found != Some(expected) => anyhow::bail!(
+            "{} is named like a {:?} archive but its content isn't one",
+            path.display(),
+            expected
+        ),
+        (_, found) => Ok(found),
+    }
+}
+
+/// [`detect`], retrying every `retry` while the file can't be opened for
+/// lack of permission, the way the tail waits for access to be restored.
+/// With `fail_fast` the error is returned at once.
+pub async fn detect_when_readable(
+    path: &Path,
+    fail_fast: bool,
+    retry: Duration,
+) -> Result<Option<Codec>> {
+    let mut waiting = false;
+    loop {
+        match detect(path) {
+            Err(e) if !fail_fast && is_permission_denied(&e) => {
+                if !waiting {
+                    tracing::error!(
+                        "Permission denied reading {}: the agent's user can't open it. \
+                         Retrying until access is restored (--fail-fast exits instead)",
+                        path.display()
+                    );
+                    waiting = true;
+                }
+                tokio::time::sleep(retry).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Decode `path` and send its content, returning the decoded byte count
+pub async fn stream(path: &Path, codec: Codec, tx: mpsc::Sender<LogChunk>) -> Result<u64> {
+    let file = BufReader::new(File::open(path).context("Failed to open file")?);
+    let mut decoder: Box<dyn Read + Send> = match codec {
+        Codec::Gzip => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        Codec::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+    };
+    tracing::info!("Decoding {:?} archive {}", codec, path.display());
+
+    let mut decoded = 0u64;
+    loop {
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let n = match decoder.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Archive {} is corrupt or truncated, shipped the first {} decoded bytes",
+                        path.display(),
+                        decoded
+                    )
+                })
+            }
+        };
+        buffer.truncate(n);
+        decoded += n as u64;
+        if tx.send(LogChunk::new(buffer)).await.is_err() {
+            break;
+        }
+    }
+
+    tracing::info!("Decoded {} bytes from {}", decoded, path.display());
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const CONTENT: &[u8] = b"line one\nline two\nline three\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    async fn collect(path: &Path) -> (Result<u64>, Vec<u8>) {
+        let codec = detect(path).unwrap().unwrap();
+        let (tx, mut rx) = mpsc::channel(16);
+        let result = stream(path, codec, tx).await;
+        let mut data = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            data.extend_from_slice(&chunk.data);
+        }
+        (result, data)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn waits_for_permission_to_read_the_header() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log.1.gz");
+        std::fs::write(&path, gzip(CONTENT)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o000)).unwrap();
+        if File::open(&path).is_ok() {
+            eprintln!("file permissions don't apply to this user, skipping");
+            return;
+        }
+
+        let err = detect_when_readable(&path, true, Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert!(is_permission_denied(&err), "{:#}", err);
+
+        let waiting = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                detect_when_readable(&path, false, Duration::from_millis(10)).await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let codec = tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(codec, Some(Codec::Gzip));
+    }
+
+    #[tokio::test]
+    async fn decodes_gzip_and_zstd() {
+        let dir = tempfile::tempdir().unwrap();
+        let gz = dir.path().join("app.log.1.gz");
+        std::fs::write(&gz, gzip(CONTENT)).unwrap();
+        let zst = dir.path().join("app.log.2.zst");
+        std::fs::write(&zst, zstd::encode_all(CONTENT, 3).unwrap()).unwrap();
+
+        assert_eq!(detect(&gz).unwrap(), Some(Codec::Gzip));
+        assert_eq!(detect(&zst).unwrap(), Some(Codec::Zstd));
+        for path in [&gz, &zst] {
+            let (result, data) = collect(path).await;
+            assert_eq!(result.unwrap(), CONTENT.len() as u64);
+            assert_eq!(data, CONTENT);
+        }
+    }
+
+    #[test]
+    fn detects_by_magic_and_rejects_mismatched_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("app.log");
+        std::fs::write(&plain, CONTENT).unwrap();
+        assert_eq!(detect(&plain).unwrap(), None);
+
+        // Compressed content without the usual extension
+        let renamed = dir.path().join("app.log.old");
+        std::fs::write(&renamed, gzip(CONTENT)).unwrap();
+        assert_eq!(detect(&renamed).unwrap(), Some(Codec::Gzip));
+
+        let fake = dir.path().join("app.log.gz");
+        std::fs::write(&fake, CONTENT).unwrap();
+        assert!(detect(&fake).is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_archive_ships_decoded_prefix_then_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log.gz");
+        let content = CONTENT.repeat(2000);
+        let compressed = gzip(&content);
+        std::fs::write(&path, &compressed[..compressed.len() / 2]).unwrap();
+
+        let (result, data) = collect(&path).await;
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("corrupt or truncated"), "{}", error);
+        assert!(content.starts_with(&data));
+    }
+}
@@ -0,0 +1,95 @@
+//! Reconnect delay curves
+
+use std::time::Duration;
+
+/// How the reconnect delay grows with consecutive failures
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackoffCurve {
+    /// Double the delay after every failure
+    #[default]
+    Exponential,
+    /// Add the base delay after every failure
+    Linear,
+    /// Always wait the base delay
+    Fixed,
+}
+
+/// Delay sequence for consecutive failed attempts, capped at `max`
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    curve: BackoffCurve,
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(curve: BackoffCurve, base: Duration, max: Duration) -> Self {
+        Self {
+            curve,
+            base,
+            max: max.max(base),
+            attempt: 0,
+        }
+    }
+
+    /// Delay before the next attempt
+    pub fn next(&mut self) -> Duration {
+        let delay = match self.curve {
+            BackoffCurve::Exponential => self
+                .base
+                .checked_mul(2u32.saturating_pow(self.attempt))
+                .unwrap_or(self.max),
+            BackoffCurve::Linear => self
+                .base
+                .checked_mul(self.attempt.saturating_add(1))
+                .unwrap_or(self.max),
+            BackoffCurve::Fixed => self.base,
+        };
+        self.attempt = self.attempt.saturating_add(1);
+        delay.min(self.max)
+    }
+
+    /// Start over after a successful attempt
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(curve: BackoffCurve) -> Vec<u64> {
+        let mut backoff = Backoff::new(curve, Duration::from_secs(2), Duration::from_secs(10));
+        (0..6).map(|_| backoff.next().as_secs()).collect()
+    }
+
+    #[test]
+    fn exponential_doubles_up_to_the_cap() {
+        assert_eq!(sequence(BackoffCurve::Exponential), [2, 4, 8, 10, 10, 10]);
+    }
+
+    #[test]
+    fn linear_grows_by_the_base_up_to_the_cap() {
+        assert_eq!(sequence(BackoffCurve::Linear), [2, 4, 6, 8, 10, 10]);
+    }
+
+    #[test]
+    fn fixed_stays_at_the_base() {
+        assert_eq!(sequence(BackoffCurve::Fixed), [2, 2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(
+            BackoffCurve::Exponential,
+            Duration::from_secs(1),
+            Duration::from_secs(30),
+        );
+        backoff.next();
+        backoff.next();
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,484 @@
+//! Offset checkpoints
+//!
+//! Persists the read position of every source so a restart resumes where the
+//! previous run stopped. The file is a JSON map keyed by a stable source id:
+//!
+//! `{"<source id>": {"path": "/var/log/app.log", "inode": 1234, "offset": 5678, "fingerprint": "9f3c..."}}`
+//!
+//! The recorded offset is how far the sender has shipped the file, which
+//! lags the read offset by whatever is still queued in the pipeline, so
+//! nothing read but lost in a crash is skipped on resume.
+//! The fingerprint is a hash of the bytes just before it, so a resume can
+//! tell when the file was replaced by a different one that reuses the inode
+//! (see `--resume-verify`).
+//!
+//! `--offset-audit-log` keeps a separate, append-only trail of the same
+//! position over time, one JSON line per interval:
+//!
+//! `{"ts": 1700000000000, "file": "/var/log/app.log", "offset": 5678, "bytes_sent_total": 5678}`
+
+use crate::metrics::Metrics;
+use crate::tail::TailPosition;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{watch, Notify};
+
+/// Bytes before the offset covered by a fingerprint
+const FINGERPRINT_BYTES: u64 = 256;
+
+/// Saved position of one source file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceOffset {
+    pub path: PathBuf,
+    pub inode: u64,
+    pub offset: u64,
+    /// Hash of the bytes before `offset`; missing in older checkpoints
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+impl SourceOffset {
+    /// Whether `path` still holds the content this offset was saved for.
+    /// Entries without a fingerprint can't be checked and always match.
+    pub fn verify(&self, path: &Path) -> Result<bool> {
+        let Some(expected) = &self.fingerprint else {
+            return Ok(true);
+        };
+        Ok(fingerprint(path, self.offset)?.as_ref() == Some(expected))
+    }
+}
+
+/// Hash of the up to 256 bytes before `offset` in `path`, `None` at the
+/// start of the file or when the file is shorter than `offset`
+pub fn fingerprint(path: &Path, offset: u64) -> Result<Option<String>> {
+    if offset == 0 {
+        return Ok(None);
+    }
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let start = offset.saturating_sub(FINGERPRINT_BYTES);
+    file.seek(SeekFrom::Start(start))?;
+    let mut bytes = Vec::new();
+    file.take(offset - start).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < offset - start {
+        return Ok(None);
+    }
+    // FNV-1a: stable across builds, unlike std's default hasher
+    let hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    });
+    Ok(Some(format!("{:016x}", hash)))
+}
+
+/// Checkpoint file contents
+#[derive(Debug, Default)]
+pub struct Checkpoint {
+    path: PathBuf,
+    sources: BTreeMap<String, SourceOffset>,
+}
+
+impl Checkpoint {
+    /// Load the checkpoint file, starting empty if it doesn't exist yet.
+    /// Entries for files that no longer exist are dropped.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let mut sources: BTreeMap<String, SourceOffset> = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("Failed to parse checkpoint {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read checkpoint {}", path.display()))
+            }
+        };
+
+        sources.retain(|id, source| {
+            let exists = source.path.exists();
+            if !exists {
+                tracing::debug!(
+                    "Dropping checkpoint for missing file {} ({})",
+                    source.path.display(),
+                    id
+                );
+            }
+            exists
+        });
+
+        Ok(Self { path, sources })
+    }
+
+    /// Saved position of a source
+    pub fn get(&self, id: &str) -> Option<&SourceOffset> {
+        self.sources.get(id)
+    }
+
+    /// Record the position of a source, returning whether it changed
+    pub fn update(&mut self, id: &str, source: SourceOffset) -> bool {
+        if self.sources.get(id) == Some(&source) {
+            return false;
+        }
+        self.sources.insert(id.to_string(), source);
+        true
+    }
+
+    /// Write the whole map atomically (temp file + rename)
+    pub fn save(&self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let content = serde_json::to_vec_pretty(&self.sources)?;
+        std::fs::write(&tmp, content)
+            .with_context(|| format!("Failed to write checkpoint {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Failed to replace checkpoint {}", self.path.display()))?;
+        Ok(())
+    }
+
+    fn record(&mut self, id: &str, source_path: &Path, position: &TailPosition) -> bool {
+        let (inode, offset) = position.shipped();
+        let unchanged = self.sources.get(id).is_some_and(|saved| {
+            saved.path == source_path && saved.inode == inode && saved.offset == offset
+        });
+        if unchanged {
+            return false;
+        }
+        // Only fingerprint the file the offset belongs to, not a newer one
+        // that took its name while the old one is still being drained
+        let fingerprint = if crate::tail::inode_of(source_path).ok() == Some(inode) {
+            fingerprint(source_path, offset).unwrap_or_else(|e| {
+                tracing::debug!("Failed to fingerprint {}: {:#}", source_path.display(), e);
+                None
+            })
+        } else {
+            None
+        };
+        self.update(
+            id,
+            SourceOffset {
+                path: source_path.to_path_buf(),
+                inode,
+                offset,
+                fingerprint,
+            },
+        )
+    }
+
+    /// Periodically save the position of a source until shutdown,
+    /// then write it one last time. `flush` forces a save between ticks.
+    pub async fn run(
+        mut self,
+        id: String,
+        source_path: PathBuf,
+        position: Arc<TailPosition>,
+        interval: Duration,
+        flush: Arc<Notify>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        // The saved position is current at startup, the first save is
+        // due after one interval
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if self.record(&id, &source_path, &position) {
+                        if let Err(e) = self.save() {
+                            tracing::warn!("Failed to save checkpoint: {:#}", e);
+                        }
+                    }
+                }
+                _ = flush.notified() => {
+                    self.record(&id, &source_path, &position);
+                    match self.save() {
+                        Ok(()) => tracing::info!("Checkpoint flushed to {}", self.path.display()),
+                        Err(e) => tracing::warn!("Failed to save checkpoint: {:#}", e),
+                    }
+                }
+                _ = shutdown.changed() => {
+                    self.record(&id, &source_path, &position);
+                    return self.save();
+                }
+            }
+        }
+    }
+}
+
+/// One line of the offset audit log
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Milliseconds since the Unix epoch
+    pub ts: u64,
+    pub file: PathBuf,
+    pub offset: u64,
+    pub bytes_sent_total: u64,
+}
+
+/// Append-only log of how far a file has been shipped. Entries are never
+/// rewritten, and each one is synced to disk before the next.
+#[derive(Debug)]
+pub struct OffsetAudit {
+    path: PathBuf,
+    file: File,
+}
+
+impl OffsetAudit {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open offset audit log {}", path.display()))?;
+        Ok(Self { path, file })
+    }
+
+    /// Append an entry and sync it
+    pub fn record(&mut self, source_path: &Path, offset: u64, bytes_sent_total: u64) -> Result<()> {
+        let entry = AuditEntry {
+            ts: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            file: source_path.to_path_buf(),
+            offset,
+            bytes_sent_total,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .and_then(|()| self.file.sync_data())
+            .with_context(|| format!("Failed to write offset audit log {}", self.path.display()))
+    }
+
+    /// Append an entry every `interval` and once more on shutdown
+    pub async fn run(
+        mut self,
+        source_path: PathBuf,
+        position: Arc<TailPosition>,
+        metrics: Arc<Metrics>,
+        interval: Duration,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        // Nothing has been read yet at startup, the first entry waits a
+        // full interval
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        let record = |audit: &mut Self| {
+            audit.record(
+                &source_path,
+                position.shipped().1,
+                metrics.bytes_sent.load(Ordering::Relaxed),
+            )
+        };
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if let Err(e) = record(&mut self) {
+                        tracing::warn!("{:#}", e);
+                    }
+                }
+                _ = shutdown.changed() => return record(&mut self),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{ConnectionConfig, ReconnectingConnection};
+    use crate::tail::FileTail;
+    use crate::testutil::{BlackHole, ServerStub};
+
+    #[test]
+    fn records_a_fingerprint_that_survives_a_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("app.log");
+        std::fs::write(&source, b"one\ntwo\n").unwrap();
+        let position = TailPosition::new(4, crate::tail::inode_of(&source).unwrap());
+
+        let path = dir.path().join("checkpoint.json");
+        let mut checkpoint = Checkpoint::load(&path).unwrap();
+        assert!(checkpoint.record("id", &source, &position));
+        assert!(!checkpoint.record("id", &source, &position));
+        checkpoint.save().unwrap();
+
+        let saved = Checkpoint::load(&path).unwrap().get("id").unwrap().clone();
+        assert_eq!(saved.fingerprint, fingerprint(&source, 4).unwrap());
+        assert!(saved.verify(&source).unwrap());
+        // Only the bytes before the offset count
+        std::fs::write(&source, b"one\nTWO\n").unwrap();
+        assert!(saved.verify(&source).unwrap());
+        std::fs::write(&source, b"ONE\ntwo\n").unwrap();
+        assert!(!saved.verify(&source).unwrap());
+    }
+
+    #[tokio::test]
+    async fn resumed_position_is_saved_before_anything_is_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("app.log");
+        std::fs::write(&source, b"one\ntwo\n").unwrap();
+        let inode = crate::tail::inode_of(&source).unwrap();
+        let tail = FileTail::resume(&source, 4, inode, &[]).unwrap();
+
+        let path = dir.path().join("checkpoint.json");
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let checkpoint = tokio::spawn(Checkpoint::load(&path).unwrap().run(
+            "id".to_string(),
+            source.clone(),
+            tail.position(),
+            Duration::from_millis(10),
+            Arc::new(Notify::new()),
+            shutdown_rx,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let saved = Checkpoint::load(&path).unwrap().get("id").unwrap().clone();
+        assert_eq!((saved.inode, saved.offset), (inode, 4));
+
+        shutdown_tx.send(true).unwrap();
+        checkpoint.await.unwrap().unwrap();
+        let saved = Checkpoint::load(&path).unwrap().get("id").unwrap().clone();
+        assert_eq!((saved.inode, saved.offset), (inode, 4));
+    }
+
+    #[tokio::test]
+    async fn audit_log_starts_at_the_resumed_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("app.log");
+        std::fs::write(&source, b"one\ntwo\n").unwrap();
+        let inode = crate::tail::inode_of(&source).unwrap();
+        let tail = FileTail::resume(&source, 4, inode, &[]).unwrap();
+
+        let audit_path = dir.path().join("audit.log");
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let audit = tokio::spawn(OffsetAudit::open(&audit_path).unwrap().run(
+            source.clone(),
+            tail.position(),
+            Arc::new(Metrics::new()),
+            Duration::from_millis(10),
+            shutdown_rx,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown_tx.send(true).unwrap();
+        audit.await.unwrap().unwrap();
+
+        let entries: Vec<AuditEntry> = std::fs::read_to_string(&audit_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(entries.len() >= 2, "{} entries", entries.len());
+        assert!(entries.iter().all(|e| e.offset == 4));
+    }
+
+    #[tokio::test]
+    async fn audit_log_offsets_only_grow() {
+        let server = ServerStub::start();
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("app.log");
+        let audit_path = dir.path().join("audit.log");
+        std::fs::write(&audit_path, b"").unwrap();
+        let mut file = File::create(&source).unwrap();
+
+        let metrics = Arc::new(Metrics::new());
+        let tail = FileTail::from_start(&source)
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(10), Duration::from_millis(20));
+        let position = tail.position();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let watcher = tokio::spawn(tail.watch(tx));
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let sender = tokio::spawn(ReconnectingConnection::new(config, metrics.clone()).run(rx));
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let audit = tokio::spawn(OffsetAudit::open(&audit_path).unwrap().run(
+            source.clone(),
+            position,
+            metrics,
+            Duration::from_millis(20),
+            shutdown_rx,
+        ));
+
+        for i in 0..10 {
+            writeln!(file, "line {}", i).unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+        server
+            .wait_for_data(Duration::from_secs(5), |d| d.ends_with(b"line 9\n"))
+            .await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown_tx.send(true).unwrap();
+        audit.await.unwrap().unwrap();
+        watcher.abort();
+        sender.abort();
+
+        let entries: Vec<AuditEntry> = std::fs::read_to_string(&audit_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(entries.len() >= 5, "{} entries", entries.len());
+        for pair in entries.windows(2) {
+            assert!(pair[0].ts <= pair[1].ts);
+            assert!(pair[0].offset <= pair[1].offset);
+            assert!(pair[0].bytes_sent_total <= pair[1].bytes_sent_total);
+        }
+        assert!(entries.iter().all(|e| e.file == source));
+        let last = entries.last().unwrap();
+        assert_eq!(last.offset, 70);
+        assert_eq!(last.bytes_sent_total, 70);
+        assert!(entries[0].offset < last.offset);
+    }
+
+    // The sender blocks its worker thread while it connects
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn saves_the_shipped_offset_not_the_read_offset() {
+        let server = BlackHole::start();
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("app.log");
+        std::fs::write(&source, format!("{}\n", "x".repeat(1023)).repeat(1024)).unwrap();
+
+        let tail = FileTail::from_start(&source).unwrap();
+        let position = tail.position();
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let watcher = tokio::spawn(tail.watch(tx));
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let sender =
+            tokio::spawn(ReconnectingConnection::new(config, Arc::new(Metrics::new())).run(rx));
+
+        let path = dir.path().join("checkpoint.json");
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let checkpoint = tokio::spawn(Checkpoint::load(&path).unwrap().run(
+            "id".to_string(),
+            source.clone(),
+            position.clone(),
+            Duration::from_millis(10),
+            Arc::new(Notify::new()),
+            shutdown_rx,
+        ));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        shutdown_tx.send(true).unwrap();
+        checkpoint.await.unwrap().unwrap();
+        watcher.abort();
+        sender.abort();
+
+        // What was read is still queued for a server that never took it
+        let saved = Checkpoint::load(&path).unwrap().get("id").unwrap().clone();
+        assert!(position.offset.load(Ordering::Relaxed) > 0);
+        assert_eq!(saved.offset, 0);
+    }
+}
@@ -0,0 +1,249 @@
+//! Config file support
+//!
+//! A JSON file passed with `--config`. Command line flags take precedence
+//! over values from the file. Filters, redaction patterns, the rate limit
+//! and tags can be reloaded at runtime (SIGHUP on Unix); everything else
+//! requires a restart.
+
+use crate::connection::{SharedRate, SharedTags};
+use crate::protocol::{ExtraPlacement, HandshakePayload};
+use anyhow::{Context, Result};
+use regex::bytes::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Filters shared between the line pipeline and the config reloader
+pub type SharedFilters = Arc<RwLock<LineFilters>>;
+
+/// Contents of the config file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    /// Project/service name
+    pub name: Option<String>,
+    /// Logline server address (host:port)
+    pub server: Option<String>,
+    /// Log file path to monitor
+    pub file: Option<PathBuf>,
+    /// Device identifier
+    pub device_id: Option<String>,
+    /// Session tags sent in the handshake (reloadable)
+    pub tags: BTreeMap<String, String>,
+    /// Only ship lines matching one of these regexes (reloadable)
+    pub include: Vec<String>,
+    /// Drop lines matching any of these regexes (reloadable)
+    pub exclude: Vec<String>,
+    /// Replace matches of these regexes with `[REDACTED]` (reloadable)
+    pub redact: Vec<String>,
+    /// Steady-state throughput cap in bytes per second, 0 = unlimited
+    /// (reloadable)
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+impl FileConfig {
+    /// Load the config file
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        serde_json::from_slice(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// Names of settings that differ from `other` but cannot be applied
+    /// without a restart
+    pub fn restart_required(&self, other: &FileConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.name != other.name {
+            changed.push("name");
+        }
+        if self.server != other.server {
+            changed.push("server");
+        }
+        if self.file != other.file {
+            changed.push("file");
+        }
+        if self.device_id != other.device_id {
+            changed.push("device_id");
+        }
+        changed
+    }
+
+    /// Compile the line filters described by this config
+    pub fn filters(&self) -> Result<LineFilters> {
+        Ok(LineFilters {
+            include: compile(&self.include, "include")?,
+            exclude: compile(&self.exclude, "exclude")?,
+            redact: compile(&self.redact, "redact")?,
+        })
+    }
+}
+
+/// Load the `--handshake-extra` file, which must hold a JSON object.
+/// Flattened fields may not shadow the typed handshake fields.
+pub fn load_handshake_extra(path: &Path, placement: ExtraPlacement) -> Result<Map<String, Value>> {
+    let content = std::fs::read(path)
+        .with_context(|| format!("Failed to read handshake extra file {}", path.display()))?;
+    let value: Value = serde_json::from_slice(&content)
+        .with_context(|| format!("Failed to parse handshake extra file {}", path.display()))?;
+    let Value::Object(fields) = value else {
+        anyhow::bail!(
+            "Handshake extra file {} must contain a JSON object",
+            path.display()
+        );
+    };
+    if placement == ExtraPlacement::Flatten {
+        if let Some(name) = fields
+            .keys()
+            .find(|name| HandshakePayload::FIELDS.contains(&name.as_str()))
+        {
+            anyhow::bail!(
+                "Handshake extra field '{}' clashes with a built-in handshake field",
+                name
+            );
+        }
+    }
+    Ok(fields)
+}
+
+fn compile(patterns: &[String], kind: &str) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|p| Regex::new(p).with_context(|| format!("Invalid {} pattern '{}'", kind, p)))
+        .collect()
+}
+
+/// Compiled include/exclude filters and redaction patterns
+#[derive(Debug, Clone, Default)]
+pub struct LineFilters {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    redact: Vec<Regex>,
+}
+
+impl LineFilters {
+    /// Whether the line passes the include/exclude filters
+    pub fn matches(&self, line: &[u8]) -> bool {
+        if !self.include.is_empty() && !self.include.iter().any(|re| re.is_match(line)) {
+            return false;
+        }
+        !self.exclude.iter().any(|re| re.is_match(line))
+    }
+
+    /// Replace every redaction match in the line
+    pub fn redact(&self, line: Vec<u8>) -> Vec<u8> {
+        self.redact.iter().fold(line, |line, re| {
+            re.replace_all(&line, &b"[REDACTED]"[..]).into_owned()
+        })
+    }
+}
+
+/// Re-reads the config file and applies the hot-reloadable settings
+pub struct Reloader {
+    path: PathBuf,
+    current: FileConfig,
+    /// Tags given on the command line, which override tags from the file
+    cli_tags: BTreeMap<String, String>,
+    /// Rate limit given on the command line, which overrides the file
+    cli_rate: Option<u64>,
+    filters: SharedFilters,
+    tags: SharedTags,
+    rate: SharedRate,
+}
+
+impl Reloader {
+    pub fn new(
+        path: PathBuf,
+        current: FileConfig,
+        cli_tags: BTreeMap<String, String>,
+        cli_rate: Option<u64>,
+        filters: SharedFilters,
+        tags: SharedTags,
+        rate: SharedRate,
+    ) -> Self {
+        Self {
+            path,
+            current,
+            cli_tags,
+            cli_rate,
+            filters,
+            tags,
+            rate,
+        }
+    }
+
+    /// Reload the config file. On error the previous settings stay active.
+    pub fn reload(&mut self) -> Result<()> {
+        let new = FileConfig::load(&self.path)?;
+        let filters = new.filters()?;
+
+        for setting in new.restart_required(&self.current) {
+            tracing::warn!(
+                "Config setting '{}' changed but requires restart, ignored",
+                setting
+            );
+        }
+
+        *self.filters.write().unwrap_or_else(|e| e.into_inner()) = filters;
+
+        let mut tags = new.tags.clone();
+        tags.extend(self.cli_tags.clone());
+        *self.tags.write().unwrap_or_else(|e| e.into_inner()) = tags;
+
+        *self.rate.write().unwrap_or_else(|e| e.into_inner()) =
+            self.cli_rate.or(new.max_bytes_per_sec);
+
+        tracing::info!("Reloaded config from {}", self.path.display());
+        self.current = new;
+        Ok(())
+    }
+
+    /// Reload the config every time SIGHUP is received
+    #[cfg(unix)]
+    pub async fn run(mut self) -> Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup =
+            signal(SignalKind::hangup()).context("Failed to install SIGHUP handler")?;
+        while hangup.recv().await.is_some() {
+            if let Err(e) = self.reload() {
+                tracing::warn!("Config reload failed, keeping previous settings: {:#}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_applies_the_rate_limit_unless_set_on_the_command_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.json");
+        std::fs::write(&path, r#"{"max_bytes_per_sec": 1000}"#).unwrap();
+        let current = FileConfig::load(&path).unwrap();
+        let rate = SharedRate::default();
+        let reloader = |cli_rate| {
+            Reloader::new(
+                path.clone(),
+                current.clone(),
+                BTreeMap::new(),
+                cli_rate,
+                SharedFilters::default(),
+                SharedTags::default(),
+                rate.clone(),
+            )
+        };
+
+        std::fs::write(&path, r#"{"max_bytes_per_sec": 5000}"#).unwrap();
+        reloader(None).reload().unwrap();
+        assert_eq!(*rate.read().unwrap(), Some(5000));
+
+        reloader(Some(200)).reload().unwrap();
+        assert_eq!(*rate.read().unwrap(), Some(200));
+    }
+}
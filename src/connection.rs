@@ -2,14 +2,92 @@
 //!
 //! Handles TCP connection to Logline server with automatic reconnection.
 
-use crate::protocol::{Frame, ProtocolError};
+use crate::backoff::{Backoff, BackoffCurve};
+use crate::diagnostics::{RecentLines, SharedCapture};
+use crate::latency::LatencyWatch;
+use crate::metrics::Metrics;
+use crate::protocol::{
+    ExtraPlacement, FileMetaPayload, Frame, GoodbyePayload, HandshakeAckPayload, HandshakePayload,
+    MessageType, ProtocolError, SourceClosedPayload, TimestampHeader, FEATURE_FILE_META,
+    FEATURE_GOODBYE, FEATURE_TIMESTAMPS, MAX_PAYLOAD_LEN, MAX_PROTOCOL_VERSION,
+    PROTOCOL_VERSION_TIMESTAMPS,
+};
+use crate::ratelimit::{ByteBudget, ByteCap, CapAction, RateLimiter};
+use crate::tail::{LogChunk, TailPosition};
 use anyhow::{Context, Result};
-use std::io::BufWriter;
-use std::net::{TcpStream, ToSocketAddrs};
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::time::sleep;
 
+/// Session tags, shared so a config reload can replace them
+pub type SharedTags = Arc<RwLock<BTreeMap<String, String>>>;
+
+/// Steady-state rate limit, shared so a config reload can change it
+pub type SharedRate = Arc<RwLock<Option<u64>>>;
+
+/// Default `--boot-marker-template`
+pub const DEFAULT_BOOT_MARKER: &str =
+    "--- logline-agent connected: device={device} agent={agent_id} offset={offset} ---";
+
+/// How long `--memory-retry-bytes` keeps a sent chunk. Anything older
+/// went out well before a failure could have cut it off.
+const RETRY_WINDOW: Duration = Duration::from_secs(30);
+
+/// Line sent as the first data of every session, so the server can tell
+/// where an agent (re)connected
+#[derive(Debug, Clone)]
+pub struct BootMarker {
+    template: String,
+    device: String,
+    file: String,
+    agent_id: String,
+    /// Read position reported as `{offset}`
+    position: Arc<TailPosition>,
+}
+
+impl BootMarker {
+    /// Checks the template by rendering it once
+    pub fn new(
+        template: String,
+        device: String,
+        file: String,
+        agent_id: String,
+        position: Arc<TailPosition>,
+    ) -> Result<Self, String> {
+        let marker = Self {
+            template,
+            device,
+            file,
+            agent_id,
+            position,
+        };
+        marker.render()?;
+        Ok(marker)
+    }
+
+    fn render(&self) -> Result<String, String> {
+        let offset = self.position.offset.load(Ordering::Relaxed).to_string();
+        let mut line = crate::template::expand(
+            &self.template,
+            &[
+                ("device", &self.device),
+                ("file", &self.file),
+                ("agent_id", &self.agent_id),
+                ("offset", &offset),
+            ],
+        )?;
+        line.push('\n');
+        Ok(line)
+    }
+}
+
 /// Connection configuration
 #[derive(Debug, Clone)]
 pub struct ConnectionConfig {
@@ -19,8 +97,77 @@ pub struct ConnectionConfig {
     pub project_name: String,
     /// Unique agent ID
     pub agent_id: String,
+    /// Device identifier sent in the handshake
+    pub device_id: Option<String>,
+    /// Session tags sent in the handshake
+    pub tags: SharedTags,
+    /// Operator-supplied fields added to the handshake
+    pub handshake_extra: serde_json::Map<String, serde_json::Value>,
+    /// Whether those fields are nested under `extra` or flattened
+    pub handshake_extra_placement: ExtraPlacement,
+    /// Prefix data frames with a `TimestampHeader` (protocol v2)
+    pub timestamp_frames: bool,
+    /// Ask to send `FileMeta` frames. They are only sent once the server
+    /// agreed to them in a negotiated handshake.
+    pub file_meta: bool,
+    /// Ask to send a `Goodbye` frame before closing on purpose. Without
+    /// the server's agreement the socket is just closed.
+    pub goodbye: bool,
+    /// Advertise features in the handshake and wait for the server to agree
+    /// on them instead of assuming support
+    pub negotiate: bool,
+    /// How long to wait for the `HandshakeAck` before falling back to v1
+    pub negotiate_timeout: Duration,
+    /// Treat a missing `HandshakeAck` as a connection failure, and check the
+    /// server's protocol version in it
+    pub require_handshake_ack: bool,
+    /// Local address to bind the outbound socket to
+    pub bind_addr: Option<SocketAddr>,
     /// Connection timeout
     pub connect_timeout: Duration,
+    /// Up to this much is added at random to every attempt's connection
+    /// timeout, so agents cut off together don't retry in lockstep
+    pub connect_timeout_jitter: Duration,
+    /// Give up once reconnecting has taken this long in total
+    pub connect_deadline: Option<Duration>,
+    /// Socket write timeout
+    pub write_timeout: Duration,
+    /// Reconnect when a single send takes longer than this
+    pub send_latency_threshold: Option<Duration>,
+    /// Abort a frame that hasn't been fully written within this time,
+    /// even if the socket keeps accepting a trickle of bytes
+    pub max_send_lag: Option<Duration>,
+    /// Give up after this many consecutive failed connection attempts
+    pub max_reconnect_attempts: Option<u32>,
+    /// Retry this many times without waiting when the server resets the
+    /// connection during the handshake, before the normal backoff applies
+    pub handshake_reset_retries: u32,
+    /// Give up after this many consecutive attempts where the server name
+    /// could not be resolved, as that usually means a misconfiguration
+    pub max_dns_failures: Option<u32>,
+    /// Copy of every frame's wire bytes, for debugging
+    pub capture: Option<SharedCapture>,
+    /// Sent right after every handshake, before any data
+    pub boot_marker: Option<BootMarker>,
+    /// Give up if the very first connection attempt fails
+    pub fail_fast: bool,
+    /// Steady-state throughput cap in bytes per second (0 = unlimited)
+    pub max_bytes_per_sec: SharedRate,
+    /// Throughput cap while sending the startup backlog (0 = unlimited);
+    /// falls back to `max_bytes_per_sec` when unset
+    pub backfill_bytes_per_sec: Option<u64>,
+    /// Close the connection after this long without data, and reconnect
+    /// once there is something to send
+    pub idle_disconnect: Option<Duration>,
+    /// Hard limits on the bytes sent per window
+    pub byte_caps: Vec<ByteCap>,
+    /// What to do with data once a byte cap is reached
+    pub on_byte_cap: CapAction,
+    /// Keep up to this many bytes of recently sent data in memory and
+    /// resend it after a failed send (0 = off)
+    pub memory_retry_bytes: usize,
+    /// How the reconnect delay grows between failed attempts
+    pub backoff: BackoffCurve,
     /// Initial reconnect delay
     pub initial_reconnect_delay: Duration,
     /// Maximum reconnect delay
@@ -33,11 +180,377 @@ impl ConnectionConfig {
             server_addr,
             project_name,
             agent_id,
+            device_id: None,
+            tags: SharedTags::default(),
+            handshake_extra: serde_json::Map::new(),
+            handshake_extra_placement: ExtraPlacement::default(),
+            timestamp_frames: false,
+            file_meta: false,
+            goodbye: false,
+            negotiate: false,
+            negotiate_timeout: Duration::from_secs(2),
+            require_handshake_ack: false,
+            bind_addr: None,
             connect_timeout: Duration::from_secs(10),
+            connect_timeout_jitter: Duration::from_secs(1),
+            connect_deadline: None,
+            write_timeout: Duration::from_secs(30),
+            send_latency_threshold: None,
+            max_send_lag: None,
+            max_reconnect_attempts: None,
+            handshake_reset_retries: 0,
+            max_dns_failures: None,
+            capture: None,
+            boot_marker: None,
+            fail_fast: false,
+            max_bytes_per_sec: SharedRate::default(),
+            backfill_bytes_per_sec: None,
+            idle_disconnect: None,
+            byte_caps: Vec::new(),
+            on_byte_cap: CapAction::default(),
+            memory_retry_bytes: 0,
+            backoff: BackoffCurve::default(),
             initial_reconnect_delay: Duration::from_secs(1),
             max_reconnect_delay: Duration::from_secs(30),
         }
     }
+
+    /// Connection timeout for one attempt, with its random share of the jitter
+    pub fn attempt_timeout(&self) -> Duration {
+        if self.connect_timeout_jitter.is_zero() {
+            return self.connect_timeout;
+        }
+        self.connect_timeout + self.connect_timeout_jitter.mul_f64(rand::random::<f64>())
+    }
+
+    /// Build the handshake payload for this session.
+    ///
+    /// All handshake state lives in the config, so every (re)connect sends
+    /// exactly the same bytes and the server sees one consistent session.
+    pub fn handshake_payload(&self) -> HandshakePayload {
+        let mut payload = HandshakePayload::new(&self.project_name, &self.agent_id);
+        payload.device_id = self.device_id.clone();
+        payload.tags = self.current_tags();
+        payload.set_extra(self.handshake_extra.clone(), self.handshake_extra_placement);
+        if self.negotiate {
+            payload.features = self.requested().features();
+        } else if self.timestamp_frames {
+            payload.version = PROTOCOL_VERSION_TIMESTAMPS;
+        }
+        payload
+    }
+
+    /// Capabilities the agent would like to use
+    fn requested(&self) -> Capabilities {
+        Capabilities {
+            timestamps: self.timestamp_frames,
+            file_meta: self.file_meta,
+            goodbye: self.goodbye,
+        }
+    }
+
+    /// Capabilities used without a negotiated agreement. Frame types an
+    /// older server doesn't know are never assumed.
+    fn assumed(&self) -> Capabilities {
+        Capabilities {
+            file_meta: false,
+            goodbye: false,
+            ..self.requested()
+        }
+    }
+
+    fn current_tags(&self) -> BTreeMap<String, String> {
+        self.tags.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn steady_rate(&self) -> Option<u64> {
+        *self
+            .max_bytes_per_sec
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Returned by [`ReconnectingConnection::run`] once
+/// `max_reconnect_attempts` consecutive connection attempts have failed
+#[derive(Debug, thiserror::Error)]
+#[error("Giving up after {attempts} failed connection attempts")]
+pub struct ReconnectExhausted {
+    pub attempts: u32,
+}
+
+/// Reconnecting took longer than `connect_deadline`
+#[derive(Debug, thiserror::Error)]
+#[error("Giving up after {attempts} failed connection attempts in {elapsed:?}")]
+pub struct ConnectDeadlineExceeded {
+    pub attempts: u32,
+    pub elapsed: Duration,
+}
+
+/// The first connection failed with `--fail-fast`
+#[derive(Debug, thiserror::Error)]
+#[error("Could not connect to {addr} on startup")]
+pub struct InitialConnectFailed {
+    pub addr: String,
+    pub source: anyhow::Error,
+}
+
+/// Classified reason a connection attempt failed
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    /// The server name could not be resolved
+    #[error("Failed to resolve server address: {0}")]
+    DnsFailure(std::io::Error),
+    /// Nothing is listening on the server port
+    #[error("Connection refused by server: {0}")]
+    Refused(std::io::Error),
+    #[error("Timed out connecting to server: {0}")]
+    Timeout(std::io::Error),
+    /// The server reset the connection while the handshake was exchanged
+    #[error("Connection reset during handshake: {0}")]
+    HandshakeReset(std::io::Error),
+    #[error("Failed to connect to server: {0}")]
+    Io(std::io::Error),
+}
+
+impl ConnectError {
+    /// Classify an error from connecting the socket
+    pub fn from_io(error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::ConnectionRefused => Self::Refused(error),
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => Self::Timeout(error),
+            _ => Self::Io(error),
+        }
+    }
+
+    /// Short category name for logs
+    pub fn category(&self) -> &'static str {
+        match self {
+            Self::DnsFailure(_) => "dns",
+            Self::Refused(_) => "refused",
+            Self::Timeout(_) => "timeout",
+            Self::HandshakeReset(_) => "reset",
+            Self::Io(_) => "io",
+        }
+    }
+}
+
+/// Peek at the socket without waiting, leaving it in blocking mode for
+/// the writes
+#[cfg(unix)]
+fn peek_nonblocking(stream: &TcpStream) -> std::io::Result<usize> {
+    let mut byte = [std::mem::MaybeUninit::uninit()];
+    socket2::SockRef::from(stream).recv_with_flags(&mut byte, libc::MSG_PEEK | libc::MSG_DONTWAIT)
+}
+
+/// Peek at the socket without waiting. There is no per-call flag for it
+/// here, so the socket is non-blocking just for the peek.
+#[cfg(not(unix))]
+fn peek_nonblocking(stream: &TcpStream) -> std::io::Result<usize> {
+    stream.set_nonblocking(true)?;
+    let waiting = stream.peek(&mut [0u8; 1]);
+    stream.set_nonblocking(false)?;
+    waiting
+}
+
+/// Whether an I/O error means the peer reset the connection
+fn is_reset(kind: std::io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Classify an error writing the handshake or reading its acknowledgement
+fn handshake_failure(error: ProtocolError) -> anyhow::Error {
+    match error {
+        ProtocolError::Io(e) if is_reset(e.kind()) => ConnectError::HandshakeReset(e).into(),
+        e => e.into(),
+    }
+}
+
+/// Returned by [`ReconnectingConnection::run`] once `max_dns_failures`
+/// consecutive attempts failed to resolve the server name
+#[derive(Debug, thiserror::Error)]
+#[error("Server address {addr} did not resolve in {attempts} attempts, check --server")]
+pub struct ServerUnresolvable {
+    pub addr: String,
+    pub attempts: u32,
+}
+
+/// Parse a `--bind-addr` value, either `ip` or `ip:port`
+pub fn parse_bind_addr(s: &str) -> Result<SocketAddr, String> {
+    s.parse::<SocketAddr>()
+        .or_else(|_| {
+            s.parse::<std::net::IpAddr>()
+                .map(|ip| SocketAddr::new(ip, 0))
+        })
+        .map_err(|_| format!("invalid bind address '{}', expected IP or IP:PORT", s))
+}
+
+/// Create a TCP socket bound to `local`
+fn bound_socket(local: SocketAddr) -> Result<Socket> {
+    let socket = Socket::new(
+        Domain::for_address(local),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )
+    .context("Failed to create socket")?;
+    if local.port() != 0 {
+        // Allow rebinding the same port while the previous connection is in TIME_WAIT
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&local.into()).map_err(|e| {
+        let hint = match e.kind() {
+            std::io::ErrorKind::AddrNotAvailable => " (not an address of this host)",
+            std::io::ErrorKind::AddrInUse => " (address in use)",
+            _ => "",
+        };
+        anyhow::anyhow!("Failed to bind to {}{}: {}", local, hint, e)
+    })?;
+    Ok(socket)
+}
+
+/// Check that `local` can be bound, so a bad `--bind-addr` fails at startup
+pub fn check_bind_addr(local: SocketAddr) -> Result<()> {
+    bound_socket(local).map(drop)
+}
+
+/// Connect to `remote` from a socket bound to `local`
+fn connect_from(local: SocketAddr, remote: SocketAddr, timeout: Duration) -> Result<TcpStream> {
+    if local.is_ipv4() != remote.is_ipv4() {
+        anyhow::bail!(
+            "Bind address {} and server address {} are different address families",
+            local,
+            remote
+        );
+    }
+    let socket = bound_socket(local)?;
+    socket
+        .connect_timeout(&remote.into(), timeout)
+        .map_err(ConnectError::from_io)?;
+    Ok(socket.into())
+}
+
+/// Optional protocol features in use for a session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `LogData` payloads carry a `TimestampHeader`
+    pub timestamps: bool,
+    /// The server accepts `FileMeta` frames
+    pub file_meta: bool,
+    /// The server accepts `Goodbye` frames from the agent
+    pub goodbye: bool,
+}
+
+impl Capabilities {
+    /// Feature names for the handshake
+    fn features(&self) -> Vec<String> {
+        let mut features = Vec::new();
+        if self.timestamps {
+            features.push(FEATURE_TIMESTAMPS.to_string());
+        }
+        if self.file_meta {
+            features.push(FEATURE_FILE_META.to_string());
+        }
+        if self.goodbye {
+            features.push(FEATURE_GOODBYE.to_string());
+        }
+        features
+    }
+
+    /// Requested features the server agreed to; anything the server lists
+    /// that wasn't requested is ignored
+    fn agreed(&self, ack: &HandshakeAckPayload) -> Self {
+        let has = |name: &str| ack.features.iter().any(|f| f == name);
+        Self {
+            timestamps: self.timestamps && has(FEATURE_TIMESTAMPS),
+            file_meta: self.file_meta && has(FEATURE_FILE_META),
+            goodbye: self.goodbye && has(FEATURE_GOODBYE),
+        }
+    }
+}
+
+/// What a server answered to a negotiating handshake
+/// (`--probe-server-version`)
+#[derive(Debug, Serialize)]
+pub struct ServerProbe {
+    pub server: String,
+    /// Whether a `HandshakeAck` arrived in time
+    pub acknowledged: bool,
+    /// Protocol version the server reported, if any
+    pub server_version: Option<u8>,
+    /// Newest protocol version this agent speaks
+    pub agent_version: u8,
+    /// Features the server listed in its acknowledgement
+    pub server_features: Vec<String>,
+    /// Features a session with this server would use
+    pub agreed_features: Vec<String>,
+}
+
+impl std::fmt::Display for ServerProbe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |features: &[String]| match features {
+            [] => "none".to_string(),
+            features => features.join(", "),
+        };
+        let version = self
+            .server_version
+            .map_or("not reported".to_string(), |v| format!("v{}", v));
+        writeln!(f, "Server:           {}", self.server)?;
+        writeln!(
+            f,
+            "Acknowledged:     {}",
+            if self.acknowledged {
+                "yes"
+            } else {
+                "no (protocol v1)"
+            }
+        )?;
+        writeln!(
+            f,
+            "Protocol version: {} (agent speaks up to v{})",
+            version, self.agent_version
+        )?;
+        writeln!(f, "Server features:  {}", list(&self.server_features))?;
+        write!(f, "Agreed features:  {}", list(&self.agreed_features))
+    }
+}
+
+/// Connect once with a handshake asking for every optional feature, report
+/// what the server acknowledged and close the connection again
+pub fn probe_server(mut config: ConnectionConfig) -> Result<ServerProbe> {
+    config.negotiate = true;
+    config.timestamp_frames = true;
+    config.file_meta = true;
+    config.goodbye = true;
+    config.boot_marker = None;
+    let server = config.server_addr.clone();
+    let mut connection = Connection::new(config);
+    connection.connect()?;
+
+    let ack = connection.server_ack.take();
+    let probe = ServerProbe {
+        server,
+        acknowledged: ack.is_some(),
+        server_version: ack.as_ref().and_then(|ack| ack.version),
+        agent_version: MAX_PROTOCOL_VERSION,
+        server_features: ack.map(|ack| ack.features).unwrap_or_default(),
+        agreed_features: connection.capabilities.features(),
+    };
+    if let Err(e) = connection.close_gracefully("probe") {
+        tracing::debug!("Failed to close the probe connection: {}", e);
+    }
+    Ok(probe)
+}
+
+/// The server acknowledged the handshake with a protocol version newer
+/// than the agent speaks; retrying can't help
+#[derive(Debug, thiserror::Error)]
+#[error("Server requires protocol v{server}, agent speaks up to v{agent}; upgrade the agent")]
+pub struct IncompatibleServer {
+    pub server: u8,
+    pub agent: u8,
 }
 
 /// Connection state
@@ -52,8 +565,24 @@ pub enum ConnectionState {
 /// Manages connection to Logline server
 pub struct Connection {
     config: ConnectionConfig,
-    stream: Option<BufWriter<TcpStream>>,
+    // Frames are encoded into a single buffer and written directly, so the
+    // number of bytes that reached the socket is known exactly.
+    stream: Option<TcpStream>,
     state: ConnectionState,
+    /// Set when a frame was only partially written; the stream is out of
+    /// sync and must be replaced by a fresh connection.
+    poisoned: bool,
+    /// Tags sent in the last handshake
+    sent_tags: BTreeMap<String, String>,
+    /// Features in use for the current session
+    capabilities: Capabilities,
+    /// The server's `HandshakeAck` for the current session, if it sent one
+    server_ack: Option<HandshakeAckPayload>,
+    /// Address from the server's last `Goodbye`, used for the next
+    /// connection attempt only
+    redirect: Option<String>,
+    /// Readiness is cleared on every disconnect
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl Connection {
@@ -62,77 +591,328 @@ impl Connection {
             config,
             stream: None,
             state: ConnectionState::Disconnected,
+            poisoned: false,
+            sent_tags: BTreeMap::new(),
+            capabilities: Capabilities::default(),
+            server_ack: None,
+            redirect: None,
+            metrics: None,
         }
     }
 
+    /// Clear `metrics.connected` whenever the connection is closed
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
     /// Try to connect to the server
     pub fn connect(&mut self) -> Result<()> {
+        self.connect_before(None)
+    }
+
+    /// Try to connect to the server, cutting the connection timeout short
+    /// at `deadline`
+    pub fn connect_before(&mut self, deadline: Option<std::time::Instant>) -> Result<()> {
         self.state = ConnectionState::Connecting;
 
+        // A failed redirect falls back to the configured server on the
+        // next attempt
+        let server = self
+            .redirect
+            .take()
+            .unwrap_or_else(|| self.config.server_addr.clone());
+
         // Resolve address
-        let addr = self
-            .config
-            .server_addr
+        let addr = server
             .to_socket_addrs()
-            .context("Failed to resolve server address")?
+            .map_err(ConnectError::DnsFailure)?
             .next()
-            .context("No address found")?;
+            .ok_or_else(|| {
+                ConnectError::DnsFailure(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "no address found",
+                ))
+            })?;
 
         // Connect with timeout
-        let stream = TcpStream::connect_timeout(&addr, self.config.connect_timeout)
-            .context("Failed to connect to server")?;
+        let mut timeout = self.config.attempt_timeout();
+        if let Some(deadline) = deadline {
+            timeout = timeout.min(deadline.saturating_duration_since(std::time::Instant::now()));
+        }
+        // A zero timeout is rejected rather than expiring at once
+        let timeout = timeout.max(Duration::from_millis(1));
+        let mut stream = match self.config.bind_addr {
+            Some(local) => connect_from(local, addr, timeout)?,
+            None => TcpStream::connect_timeout(&addr, timeout).map_err(ConnectError::from_io)?,
+        };
 
         stream.set_nodelay(true)?;
-        stream.set_write_timeout(Some(Duration::from_secs(30)))?;
-
-        let mut writer = BufWriter::new(stream);
+        stream.set_write_timeout(Some(self.config.write_timeout))?;
 
         // Send handshake
-        let handshake = Frame::handshake(&self.config.project_name, &self.config.agent_id)?;
-        handshake.write_to(&mut writer)?;
+        let payload = self.config.handshake_payload();
+        let handshake = Frame::handshake(&payload)?;
+        handshake.write_to(&mut stream).map_err(handshake_failure)?;
+        self.capture(&handshake);
+        self.sent_tags = payload.tags;
+        self.server_ack = None;
+
+        self.capabilities = if self.config.negotiate || self.config.require_handshake_ack {
+            self.negotiate(&mut stream)?
+        } else {
+            self.config.assumed()
+        };
 
-        self.stream = Some(writer);
+        self.stream = Some(stream);
         self.state = ConnectionState::Connected;
+        self.poisoned = false;
+
+        if let Some(marker) = &self.config.boot_marker {
+            // The template was checked when the marker was built
+            let line = marker.render().map_err(anyhow::Error::msg)?;
+            if let Err(e) = self.send_data(LogChunk::new(line.into_bytes())) {
+                self.disconnect();
+                return Err(handshake_failure(e)).context("Failed to send boot marker");
+            }
+        }
+
+        tracing::info!("Connected to {}", server);
+        Ok(())
+    }
+
+    /// Wait for the server's `HandshakeAck`, falling back to plain v1
+    /// framing if none arrives in time (unless an ack is required)
+    fn negotiate(&mut self, stream: &mut TcpStream) -> Result<Capabilities> {
+        stream.set_read_timeout(Some(self.config.negotiate_timeout))?;
+        let reply = Frame::read_from(stream);
+        stream.set_read_timeout(None)?;
+
+        let frame = match reply {
+            Ok(frame) => frame,
+            Err(ProtocolError::Io(e))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                if self.config.require_handshake_ack {
+                    anyhow::bail!("Server did not acknowledge the handshake");
+                }
+                tracing::info!("Server did not acknowledge the handshake, using protocol v1");
+                return Ok(Capabilities::default());
+            }
+            Err(e) => {
+                return Err(handshake_failure(e))
+                    .context("Failed to read handshake acknowledgement")
+            }
+        };
+        if frame.message_type != MessageType::HandshakeAck {
+            anyhow::bail!(
+                "Expected handshake acknowledgement, got {:?}",
+                frame.message_type
+            );
+        }
+
+        let ack = HandshakeAckPayload::decode(&frame.payload)?;
+        if let Some(version) = ack.version {
+            if version > MAX_PROTOCOL_VERSION {
+                return Err(IncompatibleServer {
+                    server: version,
+                    agent: MAX_PROTOCOL_VERSION,
+                }
+                .into());
+            }
+        }
 
-        tracing::info!("Connected to {}", self.config.server_addr);
+        let agreed = if self.config.negotiate {
+            let agreed = self.config.requested().agreed(&ack);
+            tracing::info!("Negotiated features: {:?}", agreed.features());
+            agreed
+        } else {
+            self.config.assumed()
+        };
+        self.server_ack = Some(ack);
+        Ok(agreed)
+    }
+
+    /// Send log data. Data too large for one frame's length prefix is
+    /// split across several frames.
+    pub fn send_data(&mut self, chunk: LogChunk) -> Result<(), ProtocolError> {
+        if !self.capabilities.timestamps {
+            if chunk.data.len() <= MAX_PAYLOAD_LEN {
+                return self.write_frame(&Frame::log_data(chunk.data));
+            }
+            for piece in chunk.data.chunks(MAX_PAYLOAD_LEN) {
+                self.write_frame(&Frame::log_data(piece.to_vec()))?;
+            }
+            return Ok(());
+        }
+
+        let header = TimestampHeader::new(chunk.read_at);
+        let max = MAX_PAYLOAD_LEN - TimestampHeader::LEN;
+        if chunk.data.len() <= max {
+            return self.write_frame(&Frame::timestamped_log_data(header, &chunk.data));
+        }
+        for piece in chunk.data.chunks(max) {
+            self.write_frame(&Frame::timestamped_log_data(header, piece))?;
+        }
         Ok(())
     }
 
-    /// Send log data
-    pub fn send_data(&mut self, data: Vec<u8>) -> Result<(), ProtocolError> {
-        let writer = self.stream.as_mut().ok_or_else(|| {
-            ProtocolError::Io(std::io::Error::new(
-                std::io::ErrorKind::NotConnected,
-                "Not connected",
-            ))
-        })?;
+    /// Send a file metadata snapshot
+    pub fn send_file_meta(&mut self, meta: &FileMetaPayload) -> Result<(), ProtocolError> {
+        self.write_frame(&Frame::file_meta(meta)?)
+    }
 
-        let frame = Frame::log_data(data);
-        frame.write_to(writer)
+    /// Send the sentinel for a source that has ended
+    pub fn send_source_closed(
+        &mut self,
+        closed: &SourceClosedPayload,
+    ) -> Result<(), ProtocolError> {
+        self.write_frame(&Frame::source_closed(closed)?)
     }
 
     /// Send keepalive
     pub fn send_keepalive(&mut self) -> Result<(), ProtocolError> {
-        let writer = self.stream.as_mut().ok_or_else(|| {
-            ProtocolError::Io(std::io::Error::new(
-                std::io::ErrorKind::NotConnected,
-                "Not connected",
-            ))
-        })?;
+        self.write_frame(&Frame::keepalive())
+    }
+
+    /// Close the connection, after a goodbye frame if the server agreed to
+    /// them. Returns whether one was sent; the connection is closed even if
+    /// it can't be.
+    pub fn close_gracefully(&mut self, reason: &str) -> Result<bool, ProtocolError> {
+        let result = if self.capabilities.goodbye {
+            let goodbye = GoodbyePayload {
+                reason: reason.to_string(),
+                redirect: None,
+            };
+            Frame::goodbye(&goodbye).and_then(|frame| self.write_frame(&frame))
+        } else {
+            Ok(())
+        };
+        if let Some(stream) = &self.stream {
+            let _ = stream.shutdown(std::net::Shutdown::Write);
+        }
+        self.disconnect();
+        result.map(|()| self.capabilities.goodbye)
+    }
+
+    /// Check for a `Goodbye` from the server without blocking, if the
+    /// server agreed to send one. When one arrived the connection is closed
+    /// and its redirect, if any, is used for the next connection attempt.
+    /// Any other frame the server sends mid-session means nothing to the
+    /// agent: it is read and discarded so a `Goodbye` queued behind it is
+    /// still seen.
+    pub fn poll_goodbye(&mut self) -> Result<Option<GoodbyePayload>, ProtocolError> {
+        if !self.capabilities.goodbye {
+            return Ok(None);
+        }
+        let Some(stream) = self.stream.as_mut() else {
+            return Ok(None);
+        };
+        match peek_nonblocking(stream) {
+            // Nothing sent, or the server closed its end, which the next
+            // write reports
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
 
-        let frame = Frame::keepalive();
-        frame.write_to(writer)
+        stream.set_read_timeout(Some(self.config.negotiate_timeout))?;
+        let frame = Frame::read_from(stream);
+        stream.set_read_timeout(None)?;
+        // A frame cut off by the timeout leaves the stream somewhere in its
+        // middle, where nothing can be read any more: that is a disconnect
+        let frame = frame.inspect_err(|_| self.disconnect())?;
+        if frame.message_type != MessageType::Goodbye {
+            tracing::debug!(
+                "Discarding {:?} frame the server sent mid-session",
+                frame.message_type
+            );
+            return Ok(None);
+        }
+
+        let goodbye: GoodbyePayload = serde_json::from_slice(&frame.payload)
+            .map_err(|e| ProtocolError::Serialization(e.to_string()))?;
+        self.redirect = goodbye.redirect.clone();
+        self.disconnect();
+        Ok(Some(goodbye))
+    }
+
+    /// Append a sent frame to the capture file, if any
+    fn capture(&self, frame: &Frame) {
+        let Some(capture) = &self.config.capture else {
+            return;
+        };
+        match frame.encode() {
+            Ok(bytes) => capture
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .record(&bytes),
+            Err(e) => tracing::warn!("Failed to capture frame: {}", e),
+        }
+    }
+
+    /// Write a frame, poisoning the connection if it was only partially sent
+    fn write_frame(&mut self, frame: &Frame) -> Result<(), ProtocolError> {
+        let writer = self
+            .stream
+            .as_mut()
+            .filter(|_| !self.poisoned)
+            .ok_or_else(|| {
+                ProtocolError::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "Not connected",
+                ))
+            })?;
+
+        let result = match self.config.max_send_lag {
+            Some(lag) => frame.write_to(&mut DeadlineWriter {
+                stream: writer,
+                deadline: std::time::Instant::now() + lag,
+                write_timeout: self.config.write_timeout,
+            }),
+            None => frame.write_to(writer),
+        };
+        if result.is_ok() {
+            self.capture(frame);
+        }
+        if let Err(ProtocolError::PartialWrite { written, total, .. }) = &result {
+            tracing::warn!(
+                "Frame partially written ({} of {} bytes), connection must be re-established",
+                written,
+                total
+            );
+            self.poisoned = true;
+        }
+        result
     }
 
     /// Close the connection
     pub fn disconnect(&mut self) {
         self.stream = None;
         self.state = ConnectionState::Disconnected;
+        self.poisoned = false;
+        if let Some(metrics) = &self.metrics {
+            metrics.connected.store(false, Ordering::Relaxed);
+        }
     }
 
     /// Check if connected
     pub fn is_connected(&self) -> bool {
-        self.stream.is_some() && self.state == ConnectionState::Connected
+        self.stream.is_some() && self.state == ConnectionState::Connected && !self.poisoned
+    }
+
+    /// Features in use for the current session
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Check whether the session tags changed since the last handshake
+    pub fn handshake_outdated(&self) -> bool {
+        *self.config.tags.read().unwrap_or_else(|e| e.into_inner()) != self.sent_tags
     }
 
     /// Get current state
@@ -142,84 +922,659 @@ impl Connection {
     }
 }
 
+/// Writer that fails once a deadline passes, bounding how long a whole
+/// frame may take rather than each individual write
+struct DeadlineWriter<'a> {
+    stream: &'a mut TcpStream,
+    deadline: std::time::Instant,
+    write_timeout: Duration,
+}
+
+impl DeadlineWriter<'_> {
+    fn remaining(&self) -> std::io::Result<Duration> {
+        let remaining = self
+            .deadline
+            .saturating_duration_since(std::time::Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "send lag exceeded",
+            ));
+        }
+        Ok(remaining)
+    }
+}
+
+impl Write for DeadlineWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let timeout = self.remaining()?.min(self.write_timeout);
+        self.stream.set_write_timeout(Some(timeout))?;
+        let result = self.stream.write(buf);
+        self.stream.set_write_timeout(Some(self.write_timeout))?;
+        result
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Recently sent chunks, kept for resending after a connection failure.
+///
+/// The protocol has no acknowledgements, so data written shortly before a
+/// write fails may have been lost with the old socket. After a failure
+/// everything still held is sent again, oldest first, ahead of new data.
+/// The server may see some of it twice. Chunks sent more than
+/// `RETRY_WINDOW` ago are assumed delivered and forgotten.
+#[derive(Debug)]
+struct RetryBuffer {
+    /// Sent since the last failure, oldest first, with when they were sent
+    sent: VecDeque<(std::time::Instant, LogChunk)>,
+    sent_bytes: usize,
+    max_bytes: usize,
+    max_age: Duration,
+    /// Waiting to be sent again
+    resend: VecDeque<LogChunk>,
+}
+
+impl RetryBuffer {
+    fn new(max_bytes: usize, max_age: Duration) -> Self {
+        Self {
+            sent: VecDeque::new(),
+            sent_bytes: 0,
+            max_bytes,
+            max_age,
+            resend: VecDeque::new(),
+        }
+    }
+
+    /// Remember a chunk that was just written, forgetting the oldest
+    /// ones beyond the byte limit or the age limit
+    fn record(&mut self, chunk: LogChunk) {
+        self.sent_bytes += chunk.data.len();
+        self.sent.push_back((std::time::Instant::now(), chunk));
+        while self.sent_bytes > self.max_bytes {
+            let Some((_, oldest)) = self.sent.pop_front() else {
+                break;
+            };
+            self.sent_bytes -= oldest.data.len();
+        }
+        self.expire();
+    }
+
+    /// Forget chunks sent longer ago than the age limit
+    fn expire(&mut self) {
+        while let Some((sent_at, oldest)) = self.sent.front() {
+            if sent_at.elapsed() < self.max_age {
+                break;
+            }
+            self.sent_bytes -= oldest.data.len();
+            self.sent.pop_front();
+        }
+    }
+
+    /// Queue everything remembered for resending
+    fn rewind(&mut self) {
+        self.expire();
+        while let Some((_, chunk)) = self.sent.pop_back() {
+            self.resend.push_front(chunk);
+        }
+        self.sent_bytes = 0;
+    }
+
+    fn next_resend(&mut self) -> Option<LogChunk> {
+        self.resend.pop_front()
+    }
+}
+
 /// Auto-reconnecting connection manager
 pub struct ReconnectingConnection {
     config: ConnectionConfig,
+    metrics: Arc<Metrics>,
+    recent: Option<Arc<RecentLines>>,
+    latency: Option<Arc<LatencyWatch>>,
+    meta_rx: Option<mpsc::Receiver<FileMetaPayload>>,
+    closed_rx: Option<mpsc::Receiver<SourceClosedPayload>>,
 }
 
 impl ReconnectingConnection {
-    pub fn new(config: ConnectionConfig) -> Self {
-        Self { config }
+    pub fn new(config: ConnectionConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config,
+            metrics,
+            recent: None,
+            latency: None,
+            meta_rx: None,
+            closed_rx: None,
+        }
+    }
+
+    /// Report how long data takes from being read to reaching the socket
+    pub fn with_latency_watch(mut self, latency: Arc<LatencyWatch>) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Record shipped lines for crash diagnostics
+    pub fn with_recent_lines(mut self, recent: Arc<RecentLines>) -> Self {
+        self.recent = Some(recent);
+        self
+    }
+
+    /// Forward file metadata snapshots published by the tail
+    pub fn with_file_meta(mut self, rx: mpsc::Receiver<FileMetaPayload>) -> Self {
+        self.meta_rx = Some(rx);
+        self
+    }
+
+    /// Send the source closed sentinel published by the tail once all
+    /// data has been sent
+    pub fn with_source_closed(mut self, rx: mpsc::Receiver<SourceClosedPayload>) -> Self {
+        self.closed_rx = Some(rx);
+        self
     }
 
     /// Run the connection loop, receiving data from the channel and sending to server
-    pub async fn run(self, mut rx: mpsc::Receiver<Vec<u8>>) -> Result<()> {
-        let mut connection = Connection::new(self.config.clone());
-        let mut reconnect_delay = self.config.initial_reconnect_delay;
+    #[tracing::instrument(
+        name = "connection",
+        skip_all,
+        fields(agent_id = %self.config.agent_id, server = %self.config.server_addr)
+    )]
+    pub async fn run(mut self, mut rx: mpsc::Receiver<LogChunk>) -> Result<()> {
+        let mut connection =
+            Connection::new(self.config.clone()).with_metrics(self.metrics.clone());
+        let mut backoff = Backoff::new(
+            self.config.backoff,
+            self.config.initial_reconnect_delay,
+            self.config.max_reconnect_delay,
+        );
+        // Goodbyes in a row without data sent in between back off on their
+        // own, so a server that keeps closing isn't hammered
+        let mut goodbye_backoff = Backoff::new(
+            self.config.backoff,
+            self.config.initial_reconnect_delay,
+            self.config.max_reconnect_delay,
+        );
         let mut consecutive_failures = 0u32;
+        let mut dns_failures = 0u32;
+        let mut reset_retries = 0u32;
+        // Without a handshake ack, a reset shows up on the first send
+        let mut awaiting_first_send = false;
+        let mut ever_connected = false;
         let mut last_activity = std::time::Instant::now();
+        let mut steady_rate = self.config.steady_rate();
+        let backfill_rate = self.config.backfill_bytes_per_sec.or(steady_rate);
+        let mut steady_limiter = steady_rate.filter(|&r| r > 0).map(RateLimiter::new);
+        let mut backfill_limiter = backfill_rate.filter(|&r| r > 0).map(RateLimiter::new);
+        let mut in_backfill = false;
+        let mut meta_rx = self.meta_rx.take();
+        let mut retry = (self.config.memory_retry_bytes > 0)
+            .then(|| RetryBuffer::new(self.config.memory_retry_bytes, RETRY_WINDOW));
+        let mut budgets: Vec<ByteBudget> = self
+            .config
+            .byte_caps
+            .iter()
+            .cloned()
+            .map(ByteBudget::new)
+            .collect();
+        // Chunk taken from the channel but not sent yet, waiting for a byte
+        // cap window to reset or for the reconnect after an idle disconnect
+        let mut held: Option<LogChunk> = None;
+        let mut last_data = std::time::Instant::now();
+        let mut idle_closed = false;
+        // When the current run of connection attempts started
+        let mut connecting_since: Option<std::time::Instant> = None;
+        // When the current connection was established
+        let mut connected_at: Option<std::time::Instant> = None;
 
         loop {
+            if !connection.is_connected() {
+                if let Some(at) = connected_at.take() {
+                    self.metrics.connection_duration.observe(at.elapsed());
+                }
+            }
+
+            // Closed for lack of data: stay disconnected until there is
+            // something to send
+            if idle_closed {
+                match rx.recv().await {
+                    Some(chunk) => {
+                        tracing::info!("New data after idle disconnect, reconnecting");
+                        held = Some(chunk);
+                    }
+                    None => {
+                        // Only reconnect to send a pending source closed
+                        if self.closed_rx.as_ref().is_none_or(|rx| rx.is_empty()) {
+                            tracing::info!("Data channel closed, shutting down");
+                            break;
+                        }
+                    }
+                }
+                idle_closed = false;
+                self.metrics
+                    .idle_disconnected
+                    .store(false, Ordering::Relaxed);
+            }
+
             // Try to connect if not connected
             if !connection.is_connected() {
-                match connection.connect() {
+                let since = *connecting_since.get_or_insert_with(std::time::Instant::now);
+                let deadline = self.config.connect_deadline.map(|limit| since + limit);
+                match connection.connect_before(deadline) {
                     Ok(()) => {
-                        reconnect_delay = self.config.initial_reconnect_delay;
+                        connecting_since = None;
+                        connected_at = Some(std::time::Instant::now());
+                        backoff.reset();
                         consecutive_failures = 0;
+                        dns_failures = 0;
+                        awaiting_first_send = true;
                         tracing::info!("Connection established");
+                        self.metrics.connected.store(true, Ordering::Relaxed);
+                        let frames = 1 + u64::from(self.config.boot_marker.is_some());
+                        self.metrics
+                            .frames_sent
+                            .fetch_add(frames, Ordering::Relaxed);
+                        if ever_connected {
+                            self.metrics.reconnects.fetch_add(1, Ordering::Relaxed);
+                            // Waiting for data after an idle disconnect
+                            // is not part of it
+                            self.metrics.reconnect_time.observe(since.elapsed());
+                        }
+                        if !ever_connected
+                            && self.config.file_meta
+                            && !connection.capabilities().file_meta
+                        {
+                            tracing::info!(
+                                "Server did not agree to file metadata frames, not sending them"
+                            );
+                        }
+                        ever_connected = true;
                         last_activity = std::time::Instant::now();
+                        last_data = last_activity;
+                    }
+                    Err(e) if e.is::<IncompatibleServer>() => {
+                        self.metrics.connected.store(false, Ordering::Relaxed);
+                        return Err(e);
+                    }
+                    Err(e)
+                        if matches!(
+                            e.downcast_ref::<ConnectError>(),
+                            Some(ConnectError::HandshakeReset(_))
+                        ) && reset_retries < self.config.handshake_reset_retries =>
+                    {
+                        // Some load balancers reset the first connection
+                        // after a cold start, a second try usually works
+                        reset_retries += 1;
+                        self.metrics.connected.store(false, Ordering::Relaxed);
+                        tracing::warn!(
+                            "{:#}, retrying immediately ({}/{})",
+                            e,
+                            reset_retries,
+                            self.config.handshake_reset_retries
+                        );
+                        continue;
                     }
                     Err(e) => {
+                        self.metrics.connected.store(false, Ordering::Relaxed);
+                        if self.config.fail_fast && !ever_connected {
+                            return Err(InitialConnectFailed {
+                                addr: self.config.server_addr.clone(),
+                                source: e,
+                            }
+                            .into());
+                        }
+
                         consecutive_failures += 1;
                         connection.state = ConnectionState::Reconnecting {
                             attempt: consecutive_failures,
                         };
 
+                        if self
+                            .config
+                            .max_reconnect_attempts
+                            .is_some_and(|max| consecutive_failures >= max)
+                        {
+                            tracing::warn!("Connection failed: {}", e);
+                            return Err(ReconnectExhausted {
+                                attempts: consecutive_failures,
+                            }
+                            .into());
+                        }
+
+                        let category = match e.downcast_ref::<ConnectError>() {
+                            Some(ConnectError::DnsFailure(_)) => {
+                                dns_failures += 1;
+                                if self
+                                    .config
+                                    .max_dns_failures
+                                    .is_some_and(|max| dns_failures >= max)
+                                {
+                                    tracing::warn!("Connection failed: {}", e);
+                                    return Err(ServerUnresolvable {
+                                        addr: self.config.server_addr.clone(),
+                                        attempts: dns_failures,
+                                    }
+                                    .into());
+                                }
+                                "dns"
+                            }
+                            Some(error) => {
+                                dns_failures = 0;
+                                error.category()
+                            }
+                            None => {
+                                dns_failures = 0;
+                                "session"
+                            }
+                        };
+
+                        let mut reconnect_delay = backoff.next();
+                        if let Some(deadline) = deadline {
+                            let remaining =
+                                deadline.saturating_duration_since(std::time::Instant::now());
+                            if remaining.is_zero() {
+                                tracing::warn!("Connection failed: {}", e);
+                                return Err(ConnectDeadlineExceeded {
+                                    attempts: consecutive_failures,
+                                    elapsed: since.elapsed(),
+                                }
+                                .into());
+                            }
+                            // One last attempt right at the deadline
+                            reconnect_delay = reconnect_delay.min(remaining);
+                        }
                         tracing::warn!(
-                            "Connection failed (attempt {}): {}. Retrying in {:?}",
+                            "Connection failed (attempt {}, {}): {}. Retrying in {:?}",
                             consecutive_failures,
+                            category,
                             e,
                             reconnect_delay
                         );
 
+                        self.metrics.backoff_delay.observe(reconnect_delay);
+                        // Take the next chunk in hand so its age is watched
+                        // while the server is away
+                        if let Some(latency) = &self.latency {
+                            if held.is_none() {
+                                if let Ok(chunk) = rx.try_recv() {
+                                    latency.hold(chunk.read_at);
+                                    held = Some(chunk);
+                                }
+                            }
+                        }
                         sleep(reconnect_delay).await;
+                        continue;
+                    }
+                }
+            }
 
-                        // Exponential backoff
-                        reconnect_delay =
-                            std::cmp::min(reconnect_delay * 2, self.config.max_reconnect_delay);
+            // Tags were reloaded, the server needs a fresh handshake
+            if connection.handshake_outdated() {
+                tracing::info!("Session tags changed, reconnecting to re-send handshake");
+                connection.disconnect();
+                continue;
+            }
 
-                        continue;
+            // A server closing on purpose is not a failure. A redirect is
+            // followed right away, otherwise the agent waits before
+            // reconnecting to the same server
+            match connection.poll_goodbye() {
+                Ok(Some(goodbye)) => {
+                    match &goodbye.redirect {
+                        Some(target) => tracing::info!(
+                            "Server said goodbye ({}), reconnecting to {}",
+                            goodbye.reason,
+                            target
+                        ),
+                        None => {
+                            let delay = goodbye_backoff.next();
+                            tracing::info!(
+                                "Server said goodbye ({}), reconnecting in {:?}",
+                                goodbye.reason,
+                                delay
+                            );
+                            self.metrics.backoff_delay.observe(delay);
+                            sleep(delay).await;
+                        }
                     }
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!("Failed to read from server: {}", e);
+                    connection.disconnect();
+                    if let Some(retry) = retry.as_mut() {
+                        retry.rewind();
+                    }
+                    continue;
                 }
             }
 
-            // Wait for data with short timeout to stay responsive
-            let result = tokio::time::timeout(Duration::from_millis(100), rx.recv()).await;
+            // Snapshots the server didn't agree to are dropped
+            if let Some(meta) = meta_rx
+                .as_mut()
+                .and_then(|rx| rx.try_recv().ok())
+                .filter(|_| connection.capabilities().file_meta)
+            {
+                if let Err(e) = connection.send_file_meta(&meta) {
+                    tracing::warn!("Failed to send file metadata: {}", e);
+                    connection.disconnect();
+                    if let Some(retry) = retry.as_mut() {
+                        retry.rewind();
+                    }
+                    continue;
+                }
+                self.metrics.frames_sent.fetch_add(1, Ordering::Relaxed);
+                last_activity = std::time::Instant::now();
+            }
+
+            // While paused, or while a chunk waits for a byte cap to reset,
+            // data stays queued in the channel and the connection is only
+            // kept alive. Otherwise resend buffered data before taking
+            // anything new, and wait for data with short timeout to stay
+            // responsive.
+            let paused = self.metrics.paused.load(Ordering::Relaxed);
+            let resend = if paused {
+                None
+            } else {
+                retry.as_mut().and_then(RetryBuffer::next_resend)
+            };
+            let resending = resend.is_some();
+            // Any room left in the window lets the front of a held chunk go
+            let capped = held.is_some() && !budgets_allow(&mut budgets, 1);
+            let result = match resend {
+                Some(chunk) => Some(Some(chunk)),
+                None if paused || capped => {
+                    sleep(Duration::from_millis(100)).await;
+                    None
+                }
+                None => match held.take() {
+                    Some(chunk) => Some(Some(chunk)),
+                    None => tokio::time::timeout(Duration::from_millis(100), rx.recv())
+                        .await
+                        .ok(),
+                },
+            };
 
             match result {
-                Ok(Some(data)) => {
+                Some(Some(mut chunk)) => {
+                    if !resending && !budgets_allow(&mut budgets, chunk.data.len()) {
+                        // Send what still fits in this window, the rest
+                        // waits for the next one or is dropped
+                        let room = budgets_room(&mut budgets).min(chunk.data.len());
+                        let rest = chunk.data.split_off(room);
+                        let mut rest = chunk.derive(rest);
+                        rest.new_file = false;
+                        // Only the rest reaches the end of the source data
+                        chunk.end = None;
+                        match self.config.on_byte_cap {
+                            CapAction::Hold => held = Some(rest),
+                            CapAction::Drop => {
+                                self.metrics
+                                    .bytes_dropped_by_cap
+                                    .fetch_add(rest.data.len() as u64, Ordering::Relaxed);
+                            }
+                        }
+                        if chunk.data.is_empty() {
+                            continue;
+                        }
+                    }
+                    let data_len = chunk.data.len();
+                    if !resending {
+                        if let Some(recent) = &self.recent {
+                            recent.record(&chunk.data);
+                        }
+                    }
+                    let read_at = chunk.read_at;
+                    let end = chunk.end.clone();
+                    if let Some(latency) = &self.latency {
+                        latency.hold(read_at);
+                    }
+
+                    // Rate limit, with a separate cap for the startup backlog
+                    let rate = self.config.steady_rate();
+                    if rate != steady_rate {
+                        tracing::info!("Rate limit changed to {:?} bytes/sec", rate);
+                        steady_rate = rate;
+                        steady_limiter = rate.filter(|&r| r > 0).map(RateLimiter::new);
+                        if self.config.backfill_bytes_per_sec.is_none() {
+                            backfill_limiter = rate.filter(|&r| r > 0).map(RateLimiter::new);
+                        }
+                    }
+                    if chunk.backfill {
+                        in_backfill = true;
+                    } else if in_backfill {
+                        in_backfill = false;
+                        tracing::info!("Backfill complete, switching to steady-state rate limit");
+                    }
+                    let limiter = if chunk.backfill {
+                        backfill_limiter.as_mut()
+                    } else {
+                        steady_limiter.as_mut()
+                    };
+                    if let Some(limiter) = limiter {
+                        limiter.throttle(data_len).await;
+                    }
+
                     // Send data
-                    let data_len = data.len();
-                    if let Err(e) = connection.send_data(data) {
-                        tracing::error!("Failed to send data: {}", e);
+                    let started = std::time::Instant::now();
+                    let copy = retry.is_some().then(|| chunk.clone());
+                    let first_copy = (awaiting_first_send
+                        && retry.is_none()
+                        && reset_retries < self.config.handshake_reset_retries)
+                        .then(|| chunk.clone());
+                    let sent = connection.send_data(chunk);
+                    if let (Some(retry), Some(copy)) = (retry.as_mut(), copy) {
+                        retry.record(copy);
+                        if sent.is_err() {
+                            retry.rewind();
+                            tracing::info!(
+                                "Resending {} buffered chunks after reconnecting",
+                                retry.resend.len()
+                            );
+                        }
+                    }
+                    if let Err(e) = sent {
                         connection.disconnect();
+                        match first_copy {
+                            Some(chunk) if matches!(&e, ProtocolError::Io(io) if is_reset(io.kind())) =>
+                            {
+                                // Treated like a reset handshake, the chunk
+                                // goes out again on the next connection
+                                reset_retries += 1;
+                                tracing::warn!(
+                                    "Connection reset on the first send: {}, retrying immediately ({}/{})",
+                                    e,
+                                    reset_retries,
+                                    self.config.handshake_reset_retries
+                                );
+                                held = Some(chunk);
+                            }
+                            _ => tracing::error!("Failed to send data: {}", e),
+                        }
                         continue;
                     }
+                    if awaiting_first_send {
+                        awaiting_first_send = false;
+                        reset_retries = 0;
+                    }
+                    goodbye_backoff.reset();
                     tracing::debug!("Sent {} bytes to server", data_len);
+                    if let Some(end) = &end {
+                        end.shipped();
+                    }
+                    if let Some(latency) = &self.latency {
+                        latency.sent(read_at);
+                        // The rest of a capped chunk is still waiting
+                        if let Some(rest) = &held {
+                            latency.hold(rest.read_at);
+                        }
+                    }
+                    for budget in &mut budgets {
+                        budget.charge(data_len);
+                    }
+                    self.metrics.frames_sent.fetch_add(1, Ordering::Relaxed);
+                    self.metrics
+                        .bytes_sent
+                        .fetch_add(data_len as u64, Ordering::Relaxed);
                     last_activity = std::time::Instant::now();
+                    last_data = last_activity;
+
+                    // A peer that is barely accepting data is treated as dead
+                    // long before the write timeout would fire
+                    if send_too_slow(self.config.send_latency_threshold, started, data_len) {
+                        connection.disconnect();
+                    }
                 }
-                Ok(None) => {
+                Some(None) => {
                     // Channel closed, exit
                     tracing::info!("Data channel closed, shutting down");
+                    if let Some(closed) = self.closed_rx.as_mut().and_then(|rx| rx.try_recv().ok())
+                    {
+                        match connection.send_source_closed(&closed) {
+                            Ok(()) => {
+                                tracing::info!("Sent source closed for {}", closed.path);
+                                self.metrics.frames_sent.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e) => tracing::warn!("Failed to send source closed: {}", e),
+                        }
+                    }
                     break;
                 }
-                Err(_) => {
-                    // Timeout - check if we need to send keepalive
+                None => {
+                    // Timeout - close an idle connection, unless a held
+                    // chunk is still waiting to go out on it
+                    if let Some(idle) = self.config.idle_disconnect {
+                        if held.is_none() && last_data.elapsed() >= idle {
+                            tracing::info!(
+                                "No data for {:?}, closing the connection until there is",
+                                idle
+                            );
+                            match connection.close_gracefully("idle") {
+                                Ok(true) => {
+                                    self.metrics.frames_sent.fetch_add(1, Ordering::Relaxed);
+                                }
+                                Ok(false) => {}
+                                Err(e) => tracing::warn!("Failed to send goodbye: {}", e),
+                            }
+                            // Closed on purpose, still ready to send
+                            self.metrics
+                                .idle_disconnected
+                                .store(true, Ordering::Relaxed);
+                            idle_closed = true;
+                            continue;
+                        }
+                    }
+                    // Check if we need to send keepalive
                     if last_activity.elapsed() > Duration::from_secs(30) {
                         if let Err(e) = connection.send_keepalive() {
                             tracing::warn!("Keepalive failed: {}", e);
                             connection.disconnect();
+                            if let Some(retry) = retry.as_mut() {
+                                retry.rewind();
+                            }
                         } else {
+                            self.metrics.frames_sent.fetch_add(1, Ordering::Relaxed);
                             last_activity = std::time::Instant::now();
                         }
                     }
@@ -230,3 +1585,1509 @@ impl ReconnectingConnection {
         Ok(())
     }
 }
+
+/// Whether a send of `bytes` started at `started` took longer than
+/// `threshold`, logging it if so
+fn send_too_slow(threshold: Option<Duration>, started: std::time::Instant, bytes: usize) -> bool {
+    let elapsed = started.elapsed();
+    match threshold {
+        Some(threshold) if elapsed > threshold => {
+            tracing::warn!(
+                "Sending {} bytes took {:?} (threshold {:?}), reconnecting",
+                bytes,
+                elapsed,
+                threshold
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Bytes that fit in every byte cap
+fn budgets_room(budgets: &mut [ByteBudget]) -> usize {
+    budgets
+        .iter_mut()
+        .map(|budget| budget.room() as usize)
+        .min()
+        .unwrap_or(usize::MAX)
+}
+
+/// Whether `bytes` fit in every byte cap
+fn budgets_allow(budgets: &mut [ByteBudget], bytes: usize) -> bool {
+    // Check them all so each logs its own cap event
+    let mut allowed = true;
+    for budget in budgets {
+        allowed &= budget.allows(bytes);
+    }
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tail::FileTail;
+    use crate::testutil::{BlackHole, ServerStub};
+    use std::io::Write;
+
+    #[test]
+    fn classifies_connect_errors() {
+        use std::io::{Error, ErrorKind};
+        let category = |kind| ConnectError::from_io(Error::from(kind)).category();
+        assert_eq!(category(ErrorKind::ConnectionRefused), "refused");
+        assert_eq!(category(ErrorKind::TimedOut), "timeout");
+        assert_eq!(category(ErrorKind::WouldBlock), "timeout");
+        assert_eq!(category(ErrorKind::PermissionDenied), "io");
+        assert_eq!(category(ErrorKind::ConnectionReset), "io");
+    }
+
+    #[test]
+    fn connect_reports_refused_and_unresolvable_servers() {
+        // Grab a free port, then close it so nothing listens there
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = ConnectionConfig::new(
+            format!("127.0.0.1:{}", port),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let error = Connection::new(config).connect().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ConnectError>(),
+            Some(ConnectError::Refused(_))
+        ));
+
+        let config = ConnectionConfig::new(
+            "no-such-host.invalid:12500".to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let error = Connection::new(config).connect().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ConnectError>(),
+            Some(ConnectError::DnsFailure(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_unresolvable_server() {
+        let mut config = ConnectionConfig::new(
+            "no-such-host.invalid:12500".to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        config.max_dns_failures = Some(2);
+        config.initial_reconnect_delay = Duration::from_millis(10);
+        let (_tx, rx) = mpsc::channel(1);
+
+        let error = ReconnectingConnection::new(config, Arc::new(Metrics::new()))
+            .run(rx)
+            .await
+            .unwrap_err();
+        let error = error.downcast_ref::<ServerUnresolvable>().unwrap();
+        assert_eq!(error.attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn capture_file_matches_encoded_frames() {
+        let server = ServerStub::start();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("frames.bin");
+        let mut config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        config.capture = Some(Arc::new(std::sync::Mutex::new(
+            crate::diagnostics::FrameCapture::open(path.clone(), u64::MAX).unwrap(),
+        )));
+
+        let mut connection = Connection::new(config.clone());
+        connection.connect().unwrap();
+        connection
+            .send_data(LogChunk::new(b"hello\n".to_vec()))
+            .unwrap();
+        connection.send_keepalive().unwrap();
+
+        let mut expected = Frame::handshake(&config.handshake_payload())
+            .unwrap()
+            .encode()
+            .unwrap();
+        expected.extend(Frame::log_data(b"hello\n".to_vec()).encode().unwrap());
+        expected.extend(Frame::keepalive().encode().unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn binds_every_connection_to_the_bind_address() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            (0..2)
+                .map(|_| listener.accept().unwrap().1)
+                .collect::<Vec<_>>()
+        });
+
+        let mut config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        config.bind_addr = Some(parse_bind_addr("127.0.0.1:0").unwrap());
+        let mut connection = Connection::new(config);
+        let mut local_ports = Vec::new();
+        for _ in 0..2 {
+            connection.connect().unwrap();
+            let stream = connection.stream.as_ref().unwrap();
+            assert_eq!(stream.local_addr().unwrap().ip().to_string(), "127.0.0.1");
+            local_ports.push(stream.local_addr().unwrap().port());
+            connection.disconnect();
+        }
+        let peers = server.join().unwrap();
+        assert_eq!(
+            peers.iter().map(|p| p.port()).collect::<Vec<_>>(),
+            local_ports
+        );
+
+        assert_eq!(
+            parse_bind_addr("127.0.0.1").unwrap(),
+            "127.0.0.1:0".parse::<SocketAddr>().unwrap()
+        );
+        assert!(parse_bind_addr("eth0").is_err());
+        // TEST-NET-1, never an address of this host
+        let error = check_bind_addr("192.0.2.1:0".parse().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("Failed to bind"), "{:#}", error);
+    }
+
+    #[test]
+    fn reconnects_send_an_identical_handshake() {
+        let server = ServerStub::start();
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        config
+            .tags
+            .write()
+            .unwrap()
+            .insert("env".to_string(), "prod".to_string());
+
+        let mut connection = Connection::new(config);
+        connection.connect().unwrap();
+        connection.disconnect();
+        connection.connect().unwrap();
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while server.handshakes().len() < 2 {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "second handshake missing"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let frames = server.frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].encode().unwrap(), frames[1].encode().unwrap());
+        assert_eq!(server.handshakes()[1].tags["env"], "prod");
+    }
+
+    #[tokio::test]
+    async fn every_disconnect_clears_readiness() {
+        let server = ServerStub::start();
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let metrics = Arc::new(Metrics::new());
+        let (tx, rx) = mpsc::channel(4);
+        let sender =
+            tokio::spawn(ReconnectingConnection::new(config.clone(), metrics.clone()).run(rx));
+        tx.send(LogChunk::new(b"line\n".to_vec())).await.unwrap();
+        server
+            .wait_for_data(Duration::from_secs(5), |d| !d.is_empty())
+            .await;
+        assert!(metrics.connected.load(Ordering::Relaxed));
+        sender.abort();
+
+        // A disconnect outside the send loop, e.g. to re-send the handshake
+        let mut connection = Connection::new(config).with_metrics(metrics.clone());
+        connection.connect().unwrap();
+        connection.disconnect();
+        assert!(!metrics.connected.load(Ordering::Relaxed));
+    }
+
+    fn refused_config() -> ConnectionConfig {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = ConnectionConfig::new(
+            format!("127.0.0.1:{}", port),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        config.initial_reconnect_delay = Duration::from_millis(10);
+        config.max_reconnect_delay = Duration::from_millis(10);
+        config
+    }
+
+    #[tokio::test]
+    async fn fail_fast_gives_up_on_first_connect() {
+        let mut config = refused_config();
+        config.fail_fast = true;
+        let (_tx, rx) = mpsc::channel(1);
+
+        let error = ReconnectingConnection::new(config, Arc::new(Metrics::new()))
+            .run(rx)
+            .await
+            .unwrap_err();
+        assert!(error.is::<InitialConnectFailed>(), "{:#}", error);
+        // The cause is reported once, as the error's source
+        let message = format!("{:#}", error);
+        assert_eq!(message.matches("os error").count(), 1, "{}", message);
+    }
+
+    #[tokio::test]
+    async fn keeps_retrying_first_connect_without_fail_fast() {
+        let (_tx, rx) = mpsc::channel(1);
+        let run = ReconnectingConnection::new(refused_config(), Arc::new(Metrics::new())).run(rx);
+        assert!(tokio::time::timeout(Duration::from_millis(200), run)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn fail_fast_does_not_apply_after_first_connect() {
+        // A server that takes one session, then closes it and stops listening
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            Frame::read_from(&mut stream).unwrap();
+        });
+
+        let mut config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        config.fail_fast = true;
+        config.initial_reconnect_delay = Duration::from_millis(10);
+        config.max_reconnect_delay = Duration::from_millis(10);
+        let metrics = Arc::new(Metrics::new());
+        let (tx, rx) = mpsc::channel(1);
+        let handle = tokio::spawn(ReconnectingConnection::new(config, metrics.clone()).run(rx));
+
+        server.join().unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while metrics.connected.load(Ordering::Relaxed)
+            || metrics.frames_sent.load(Ordering::Relaxed) == 0
+        {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "disconnect not noticed"
+            );
+            let _ = tx.send(LogChunk::new(b"line\n".to_vec())).await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Reconnects are refused now, but the connection keeps retrying
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!handle.is_finished());
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn streams_file_to_server_in_order() {
+        let server = ServerStub::start();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"first line\nsecond line\n").unwrap();
+
+        let (tx, rx) = mpsc::channel(16);
+        let tail = FileTail::from_start(file.path()).unwrap();
+        let watcher = tokio::spawn(tail.watch(tx));
+
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let sender =
+            tokio::spawn(ReconnectingConnection::new(config, Arc::new(Metrics::new())).run(rx));
+
+        let expected = b"first line\nsecond line\n".to_vec();
+        let data = server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= expected.len())
+            .await;
+        assert_eq!(data, expected);
+
+        file.write_all(b"third line\n").unwrap();
+        file.flush().unwrap();
+        let expected = b"first line\nsecond line\nthird line\n".to_vec();
+        let data = server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= expected.len())
+            .await;
+        assert_eq!(data, expected);
+
+        let handshakes = server.handshakes();
+        assert_eq!(handshakes.len(), 1);
+        assert_eq!(handshakes[0].project_name, "e2e");
+        assert_eq!(handshakes[0].agent_id, "agent-1");
+        assert_eq!(
+            server.frames()[0].message_type,
+            crate::protocol::MessageType::Handshake
+        );
+
+        watcher.abort();
+        sender.abort();
+    }
+
+    #[tokio::test]
+    async fn sends_source_closed_after_grace() {
+        let server = ServerStub::start();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"last words\n").unwrap();
+
+        let (tx, rx) = mpsc::channel(16);
+        let (closed_tx, closed_rx) = mpsc::channel(1);
+        let tail = FileTail::from_start(&path)
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(10), Duration::from_millis(20))
+            .with_source_close(Duration::from_millis(300), closed_tx);
+        let tail_metrics = Arc::new(Metrics::new());
+        let tail = tail.with_metrics(tail_metrics.clone());
+        let watcher = tokio::spawn(tail.watch(tx));
+
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let sender = tokio::spawn(
+            ReconnectingConnection::new(config, Arc::new(Metrics::new()))
+                .with_source_closed(closed_rx)
+                .run(rx),
+        );
+
+        server
+            .wait_for_data(Duration::from_secs(5), |d| d.ends_with(b"words\n"))
+            .await;
+        std::fs::remove_file(&path).unwrap();
+        let removed = std::time::Instant::now();
+
+        // Both sides finish on their own once the grace period has passed
+        tokio::time::timeout(Duration::from_secs(5), watcher)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(removed.elapsed() >= Duration::from_millis(300));
+        // Tells the agent the stream ended on purpose, so it exits cleanly
+        assert!(tail_metrics.source_closed.load(Ordering::Relaxed));
+        tokio::time::timeout(Duration::from_secs(5), sender)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+        let frames = server.frames();
+        let last = frames.last().unwrap();
+        assert_eq!(last.message_type, MessageType::SourceClosed);
+        let closed: SourceClosedPayload = serde_json::from_slice(&last.payload).unwrap();
+        assert_eq!(closed.reason, "removed");
+        assert_eq!(closed.offset, 11);
+        assert_eq!(closed.path, path.to_string_lossy());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn resends_buffered_chunks_after_brief_disconnect() {
+        // The first session is dropped after one data frame, the second
+        // collects everything it gets
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            Frame::read_from(&mut stream).unwrap();
+            Frame::read_from(&mut stream).unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut data = Vec::new();
+            while let Ok(frame) = Frame::read_from(&mut stream) {
+                if frame.message_type == MessageType::LogData {
+                    data.extend(frame.payload);
+                }
+            }
+            data
+        });
+
+        let mut config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        config.memory_retry_bytes = 1024;
+        config.initial_reconnect_delay = Duration::from_millis(10);
+        let (tx, rx) = mpsc::channel(16);
+        let sender =
+            tokio::spawn(ReconnectingConnection::new(config, Arc::new(Metrics::new())).run(rx));
+
+        for i in 1..=8 {
+            tx.send(LogChunk::new(format!("{}\n", i).into_bytes()))
+                .await
+                .unwrap();
+            sleep(Duration::from_millis(50)).await;
+        }
+        drop(tx);
+        sender.await.unwrap().unwrap();
+
+        // Chunks written into the dead socket are not lost
+        let data = tokio::task::spawn_blocking(move || server.join().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(data, b"1\n2\n3\n4\n5\n6\n7\n8\n");
+    }
+
+    #[test]
+    fn retry_buffer_keeps_the_newest_chunks() {
+        let mut retry = RetryBuffer::new(4, Duration::from_secs(60));
+        for data in [b"ab", b"cd", b"ef"] {
+            retry.record(LogChunk::new(data.to_vec()));
+        }
+        retry.rewind();
+
+        // Failing again partway through the resend keeps the order
+        let first = retry.next_resend().unwrap();
+        assert_eq!(first.data, b"cd");
+        retry.record(first);
+        retry.rewind();
+
+        let resent: Vec<_> = std::iter::from_fn(|| retry.next_resend())
+            .map(|c| c.data)
+            .collect();
+        assert_eq!(resent, vec![b"cd".to_vec(), b"ef".to_vec()]);
+    }
+
+    #[test]
+    fn retry_buffer_forgets_chunks_sent_long_ago() {
+        let mut retry = RetryBuffer::new(1024, Duration::from_millis(50));
+        retry.record(LogChunk::new(b"old".to_vec()));
+        std::thread::sleep(Duration::from_millis(80));
+        retry.record(LogChunk::new(b"new".to_vec()));
+        assert_eq!(retry.sent_bytes, 3);
+        retry.rewind();
+
+        let resent: Vec<_> = std::iter::from_fn(|| retry.next_resend())
+            .map(|c| c.data)
+            .collect();
+        assert_eq!(resent, vec![b"new".to_vec()]);
+    }
+
+    /// Accepts everything, but only after a delay per write
+    struct SlowWriter(Duration);
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            std::thread::sleep(self.0);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flags_sends_slower_than_the_latency_threshold() {
+        let frame = Frame::log_data(b"line\n".to_vec());
+        let threshold = Some(Duration::from_millis(20));
+
+        let started = std::time::Instant::now();
+        frame
+            .write_to(&mut SlowWriter(Duration::from_millis(50)))
+            .unwrap();
+        assert!(send_too_slow(threshold, started, 5));
+        assert!(!send_too_slow(None, started, 5));
+
+        let started = std::time::Instant::now();
+        frame.write_to(&mut SlowWriter(Duration::ZERO)).unwrap();
+        assert!(!send_too_slow(threshold, started, 5));
+    }
+
+    #[test]
+    fn jitter_only_lengthens_the_connect_timeout() {
+        let mut config = ConnectionConfig::new(String::new(), "e2e".to_string(), "a".to_string());
+        config.connect_timeout_jitter = Duration::from_millis(500);
+        for _ in 0..100 {
+            let timeout = config.attempt_timeout();
+            assert!(timeout >= config.connect_timeout);
+            assert!(timeout <= config.connect_timeout + Duration::from_millis(500));
+        }
+        config.connect_timeout_jitter = Duration::ZERO;
+        assert_eq!(config.attempt_timeout(), config.connect_timeout);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn gives_up_at_the_connect_deadline() {
+        let black_hole = BlackHole::start();
+        let probe = TcpStream::connect_timeout(&black_hole.addr(), Duration::from_millis(100));
+        assert_eq!(probe.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        let mut config = ConnectionConfig::new(
+            black_hole.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        // A single attempt would hang far beyond the deadline
+        config.connect_deadline = Some(Duration::from_millis(600));
+        config.initial_reconnect_delay = Duration::from_millis(100);
+        let (_tx, rx) = mpsc::channel(4);
+
+        let started = std::time::Instant::now();
+        let error = tokio::time::timeout(
+            Duration::from_secs(5),
+            ReconnectingConnection::new(config, Arc::new(Metrics::new())).run(rx),
+        )
+        .await
+        .unwrap()
+        .unwrap_err();
+        let elapsed = started.elapsed();
+
+        let exceeded = error.downcast_ref::<ConnectDeadlineExceeded>().unwrap();
+        assert!(exceeded.attempts >= 1);
+        assert!(elapsed >= Duration::from_millis(600), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connection_and_reconnects_for_new_data() {
+        let server = ServerStub::start();
+        let metrics = Arc::new(Metrics::new());
+        let mut config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        config.idle_disconnect = Some(Duration::from_millis(300));
+        let (tx, rx) = mpsc::channel(4);
+        let sender = tokio::spawn(ReconnectingConnection::new(config, metrics.clone()).run(rx));
+
+        tx.send(LogChunk::new(b"first\n".to_vec())).await.unwrap();
+        server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= 6)
+            .await;
+        sleep(Duration::from_millis(600)).await;
+
+        // Without a negotiated handshake the server may not know Goodbye
+        let frames = server.frames();
+        let last = frames.last().unwrap();
+        assert_eq!(last.message_type, MessageType::LogData);
+        assert!(!metrics.connected.load(Ordering::Relaxed));
+        assert!(metrics.idle_disconnected.load(Ordering::Relaxed));
+        // Staying disconnected while nothing arrives
+        assert_eq!(server.accepts(), 1);
+
+        tx.send(LogChunk::new(b"second\n".to_vec())).await.unwrap();
+        let data = server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= 13)
+            .await;
+        assert_eq!(data, b"first\nsecond\n");
+        assert_eq!(server.accepts(), 2);
+        assert_eq!(server.handshakes().len(), 2);
+        assert!(!metrics.idle_disconnected.load(Ordering::Relaxed));
+
+        drop(tx);
+        tokio::time::timeout(Duration::from_secs(5), sender)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn events_carry_connection_span_fields() {
+        let server = ServerStub::start();
+        let (tx, rx) = mpsc::channel(4);
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let sender =
+            tokio::spawn(ReconnectingConnection::new(config, Arc::new(Metrics::new())).run(rx));
+        tx.send(LogChunk::new(b"hello\n".to_vec())).await.unwrap();
+        server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= 6)
+            .await;
+        sender.abort();
+
+        let fields = format!("connection{{agent_id=agent-1 server={}}}", server.addr());
+        logs_assert(|lines: &[&str]| {
+            if lines
+                .iter()
+                .any(|line| line.contains(&fields) && line.contains("Connection established"))
+            {
+                Ok(())
+            } else {
+                Err(format!("no event inside {}", fields))
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn boot_marker_is_the_first_data_of_every_session() {
+        let server = ServerStub::start();
+        let position = Arc::new(crate::tail::TailPosition::default());
+        position.offset.store(42, Ordering::Relaxed);
+        let mut config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        config.boot_marker = Some(
+            BootMarker::new(
+                DEFAULT_BOOT_MARKER.to_string(),
+                "host".to_string(),
+                "app.log".to_string(),
+                "agent-1".to_string(),
+                position.clone(),
+            )
+            .unwrap(),
+        );
+
+        let mut connection = Connection::new(config);
+        connection.connect().unwrap();
+        connection
+            .send_data(LogChunk::new(b"data\n".to_vec()))
+            .unwrap();
+        connection.disconnect();
+        position.offset.store(47, Ordering::Relaxed);
+        connection.connect().unwrap();
+
+        let marker = |offset| {
+            format!(
+                "--- logline-agent connected: device=host agent=agent-1 offset={} ---\n",
+                offset
+            )
+        };
+        let expected = [marker(42), "data\n".to_string(), marker(47)].concat();
+        let data = server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= expected.len())
+            .await;
+        assert_eq!(String::from_utf8(data).unwrap(), expected);
+
+        let types: Vec<MessageType> = server.frames().iter().map(|f| f.message_type).collect();
+        assert_eq!(
+            types,
+            [
+                MessageType::Handshake,
+                MessageType::LogData,
+                MessageType::LogData,
+                MessageType::Handshake,
+                MessageType::LogData,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn boot_marker_reports_the_resumed_offset() {
+        let server = ServerStub::start();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"one\ntwo\n").unwrap();
+        let inode = crate::tail::inode_of(&path).unwrap();
+        let tail = FileTail::resume(&path, 4, inode, &[]).unwrap();
+
+        let mut config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        config.boot_marker = Some(
+            BootMarker::new(
+                "offset={offset}".to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+                tail.position(),
+            )
+            .unwrap(),
+        );
+        // Nothing has been read since the resume
+        Connection::new(config).connect().unwrap();
+
+        let data = server
+            .wait_for_data(Duration::from_secs(5), |d| d.ends_with(b"\n"))
+            .await;
+        assert_eq!(data, b"offset=4\n");
+    }
+
+    #[test]
+    fn boot_marker_rejects_unknown_placeholders() {
+        let err = BootMarker::new(
+            "{nope}".to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+            Arc::default(),
+        )
+        .unwrap_err();
+        assert!(err.contains("unknown placeholder"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn counts_bytes_and_frames() {
+        let server = ServerStub::start();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let content = b"one\ntwo\nthree\n";
+        file.write_all(content).unwrap();
+
+        let metrics = Arc::new(Metrics::new());
+        let (tx, rx) = mpsc::channel(16);
+        let tail = FileTail::from_start(file.path())
+            .unwrap()
+            .with_metrics(metrics.clone());
+        let watcher = tokio::spawn(tail.watch(tx));
+
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let sender = tokio::spawn(ReconnectingConnection::new(config, metrics.clone()).run(rx));
+
+        server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= content.len())
+            .await;
+        // The sender bumps its counters right after the write returns
+        sleep(Duration::from_millis(100)).await;
+        watcher.abort();
+        sender.abort();
+
+        assert_eq!(
+            metrics.bytes_read.load(Ordering::Relaxed),
+            content.len() as u64
+        );
+        assert_eq!(
+            metrics.bytes_sent.load(Ordering::Relaxed),
+            content.len() as u64
+        );
+        assert_eq!(
+            metrics.frames_sent.load(Ordering::Relaxed),
+            server.frames().len() as u64
+        );
+        assert_eq!(metrics.reconnects.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn pausing_halts_sends_until_resumed() {
+        let server = ServerStub::start();
+        let metrics = Arc::new(Metrics::new());
+        let (tx, rx) = mpsc::channel(16);
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let sender = tokio::spawn(ReconnectingConnection::new(config, metrics.clone()).run(rx));
+
+        tx.send(LogChunk::new(b"before\n".to_vec())).await.unwrap();
+        server
+            .wait_for_data(Duration::from_secs(5), |d| d == b"before\n")
+            .await;
+
+        metrics.paused.store(true, Ordering::Relaxed);
+        // Let the loop notice the flag before more data arrives
+        sleep(Duration::from_millis(200)).await;
+        tx.send(LogChunk::new(b"during\n".to_vec())).await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(server.log_data(), b"before\n");
+        assert_eq!(tx.capacity(), 15, "chunk stays queued while paused");
+
+        metrics.paused.store(false, Ordering::Relaxed);
+        let data = server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= 14)
+            .await;
+        assert_eq!(data, b"before\nduring\n");
+
+        sender.abort();
+    }
+
+    #[tokio::test]
+    async fn byte_cap_holds_data_until_window_resets() {
+        let server = ServerStub::start();
+        let (tx, rx) = mpsc::channel(16);
+        let mut config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        config.byte_caps = vec![ByteCap {
+            name: "test",
+            bytes: 10,
+            window: Duration::from_millis(500),
+        }];
+        let sender =
+            tokio::spawn(ReconnectingConnection::new(config, Arc::new(Metrics::new())).run(rx));
+
+        for line in [b"aaaaa\n", b"bbbbb\n", b"ccccc\n"] {
+            tx.send(LogChunk::new(line.to_vec())).await.unwrap();
+        }
+        // The chunk that crosses the cap is split at it
+        sleep(Duration::from_millis(250)).await;
+        assert_eq!(server.log_data(), b"aaaaa\nbbbb");
+
+        // Ten bytes per window from here on
+        let data = server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= 18)
+            .await;
+        assert_eq!(data, b"aaaaa\nbbbbb\nccccc\n");
+
+        sender.abort();
+    }
+
+    // The sender blocks its worker thread while a write stalls
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn reconnects_when_server_stops_reading() {
+        let server = ServerStub::start_stalled();
+        let (tx, rx) = mpsc::channel(4);
+
+        let mut config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        config.max_send_lag = Some(Duration::from_secs(1));
+        config.initial_reconnect_delay = Duration::from_millis(100);
+        let sender =
+            tokio::spawn(ReconnectingConnection::new(config, Arc::new(Metrics::new())).run(rx));
+
+        // Keep feeding data until the socket buffers fill up and a write stalls
+        let feeder = tokio::spawn(async move {
+            while tx
+                .send(LogChunk::new(vec![b'x'; 1024 * 1024]))
+                .await
+                .is_ok()
+            {}
+        });
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while server.accepts() < 2 && std::time::Instant::now() < deadline {
+            sleep(Duration::from_millis(50)).await;
+        }
+        feeder.abort();
+        sender.abort();
+
+        assert!(
+            server.accepts() >= 2,
+            "expected a reconnect after the send lag"
+        );
+    }
+
+    // Resets the first connection once its handshake arrives, then
+    // acknowledges the handshake on the second one and records its data
+    fn reset_first_connection_server() -> (SocketAddr, std::sync::mpsc::Receiver<Vec<u8>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (data_tx, data_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut first, _) = listener.accept().unwrap();
+            Frame::read_from(&mut first).unwrap();
+            socket2::SockRef::from(&first)
+                .set_linger(Some(Duration::ZERO))
+                .unwrap();
+            drop(first);
+
+            let (mut second, _) = listener.accept().unwrap();
+            Frame::read_from(&mut second).unwrap();
+            let ack = HandshakeAckPayload {
+                version: Some(MAX_PROTOCOL_VERSION),
+                features: Vec::new(),
+            };
+            Frame::new(MessageType::HandshakeAck, serde_json::to_vec(&ack).unwrap())
+                .write_to(&mut second)
+                .unwrap();
+            while let Ok(frame) = Frame::read_from(&mut second) {
+                if frame.message_type == MessageType::LogData {
+                    let _ = data_tx.send(frame.payload);
+                }
+            }
+        });
+        (addr, data_rx)
+    }
+
+    #[test]
+    fn classifies_reset_during_handshake() {
+        let (addr, _data) = reset_first_connection_server();
+        let mut config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        config.require_handshake_ack = true;
+        let err = Connection::new(config).connect().unwrap_err();
+        let connect = err.downcast_ref::<ConnectError>().unwrap();
+        assert_eq!(connect.category(), "reset");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn retries_immediately_after_handshake_reset() {
+        let (addr, data) = reset_first_connection_server();
+        let mut config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        config.require_handshake_ack = true;
+        config.handshake_reset_retries = 1;
+        // Backoff alone would outlast the test
+        config.initial_reconnect_delay = Duration::from_secs(30);
+        let (tx, rx) = mpsc::channel(4);
+        let sender =
+            tokio::spawn(ReconnectingConnection::new(config, Arc::new(Metrics::new())).run(rx));
+
+        tx.send(LogChunk::new(b"hello\n".to_vec())).await.unwrap();
+        let payload =
+            tokio::task::spawn_blocking(move || data.recv_timeout(Duration::from_secs(5)))
+                .await
+                .unwrap()
+                .expect("data after the immediate retry");
+        assert_eq!(payload, b"hello\n");
+
+        sender.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn resends_a_first_chunk_lost_to_a_reset_without_negotiation() {
+        use std::io::Read;
+        // Too big to fit in the socket buffers, so the reset interrupts
+        // the write instead of discarding data it already took
+        const LEN: usize = 15 * 1024 * 1024;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (len_tx, len_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut first, _) = listener.accept().unwrap();
+            Frame::read_from(&mut first).unwrap();
+            socket2::SockRef::from(&first)
+                .set_linger(Some(Duration::ZERO))
+                .unwrap();
+            drop(first);
+
+            // Data frames are larger than the agent accepts inbound, read
+            // this one by hand
+            let (mut second, _) = listener.accept().unwrap();
+            Frame::read_from(&mut second).unwrap();
+            let mut header = [0u8; 5];
+            second.read_exact(&mut header).unwrap();
+            assert_eq!(header[4], MessageType::LogData as u8);
+            let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize - 1;
+            let mut payload = vec![0u8; len];
+            second.read_exact(&mut payload).unwrap();
+            let _ = len_tx.send(payload.len());
+        });
+
+        let mut config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        config.handshake_reset_retries = 1;
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(LogChunk::new(vec![b'x'; LEN])).await.unwrap();
+        let sender =
+            tokio::spawn(ReconnectingConnection::new(config, Arc::new(Metrics::new())).run(rx));
+
+        let received =
+            tokio::task::spawn_blocking(move || len_rx.recv_timeout(Duration::from_secs(5)))
+                .await
+                .unwrap()
+                .expect("data after the immediate retry");
+        assert_eq!(received, LEN);
+
+        sender.abort();
+    }
+
+    #[tokio::test]
+    async fn stops_on_newer_server_protocol() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            Frame::read_from(&mut stream).unwrap();
+            let ack = HandshakeAckPayload {
+                version: Some(MAX_PROTOCOL_VERSION + 1),
+                features: Vec::new(),
+            };
+            Frame::new(MessageType::HandshakeAck, serde_json::to_vec(&ack).unwrap())
+                .write_to(&mut stream)
+                .unwrap();
+            std::thread::sleep(Duration::from_secs(1));
+        });
+
+        let mut config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        config.require_handshake_ack = true;
+        let (_tx, rx) = mpsc::channel(1);
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            ReconnectingConnection::new(config, Arc::new(Metrics::new())).run(rx),
+        )
+        .await
+        .expect("should stop instead of retrying");
+
+        let err = result.unwrap_err();
+        let incompatible = err.downcast_ref::<IncompatibleServer>().unwrap();
+        assert_eq!(incompatible.server, MAX_PROTOCOL_VERSION + 1);
+    }
+
+    #[tokio::test]
+    async fn follows_a_goodbye_redirect_from_the_server() {
+        let target = ServerStub::start();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let redirect = target.addr().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            ack_goodbye(&mut stream);
+            let goodbye = GoodbyePayload {
+                reason: "draining".to_string(),
+                redirect: Some(redirect),
+            };
+            Frame::goodbye(&goodbye)
+                .unwrap()
+                .write_to(&mut stream)
+                .unwrap();
+            std::thread::sleep(Duration::from_secs(5));
+        });
+
+        let metrics = Arc::new(Metrics::new());
+        let config = goodbye_config(addr);
+        let (tx, rx) = mpsc::channel(4);
+        let sender = tokio::spawn(ReconnectingConnection::new(config, metrics.clone()).run(rx));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while target.accepts() == 0 {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("should reconnect to the redirect target");
+        tx.send(LogChunk::new(b"hello\n".to_vec())).await.unwrap();
+        let data = target
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= 6)
+            .await;
+        assert_eq!(data, b"hello\n");
+        assert_eq!(target.handshakes().len(), 1);
+        assert_eq!(metrics.reconnects.load(Ordering::Relaxed), 1);
+
+        sender.abort();
+    }
+
+    #[tokio::test]
+    async fn backs_off_between_goodbyes_without_a_redirect() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = accepts.clone();
+        std::thread::spawn(move || {
+            let mut streams = Vec::new();
+            while let Ok((mut stream, _)) = listener.accept() {
+                counted.fetch_add(1, Ordering::SeqCst);
+                ack_goodbye(&mut stream);
+                let goodbye = GoodbyePayload {
+                    reason: "overloaded".to_string(),
+                    redirect: None,
+                };
+                Frame::goodbye(&goodbye)
+                    .unwrap()
+                    .write_to(&mut stream)
+                    .unwrap();
+                streams.push(stream);
+            }
+        });
+
+        let metrics = Arc::new(Metrics::new());
+        let mut config = goodbye_config(addr);
+        config.initial_reconnect_delay = Duration::from_millis(200);
+        let (_tx, rx) = mpsc::channel(1);
+        let sender = tokio::spawn(ReconnectingConnection::new(config, metrics.clone()).run(rx));
+        sleep(Duration::from_secs(1)).await;
+
+        // 200ms, then 400ms, then 800ms between connections
+        let accepts = accepts.load(Ordering::SeqCst);
+        assert!((2..=4).contains(&accepts), "{} connections", accepts);
+        assert!(metrics.backoff_delay.snapshot().count >= 2);
+
+        sender.abort();
+    }
+
+    #[test]
+    fn poll_goodbye_discards_other_server_frames() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            ack_goodbye(&mut stream);
+            Frame::new(MessageType::Keepalive, Vec::new())
+                .write_to(&mut stream)
+                .unwrap();
+            let goodbye = GoodbyePayload {
+                reason: "draining".to_string(),
+                redirect: None,
+            };
+            Frame::goodbye(&goodbye)
+                .unwrap()
+                .write_to(&mut stream)
+                .unwrap();
+            stream
+        });
+
+        let mut connection = Connection::new(goodbye_config(addr));
+        connection.connect().unwrap();
+        let _stream = server.join().unwrap();
+
+        let mut discarded = 0;
+        let goodbye = loop {
+            match connection.poll_goodbye().unwrap() {
+                Some(goodbye) => break goodbye,
+                None => discarded += 1,
+            }
+            assert!(discarded < 100, "goodbye never seen");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(goodbye.reason, "draining");
+        assert!(discarded >= 1);
+        assert!(!connection.is_connected());
+    }
+
+    #[test]
+    fn poll_goodbye_ignores_a_server_that_did_not_agree() {
+        let ack = HandshakeAckPayload {
+            version: Some(MAX_PROTOCOL_VERSION),
+            features: Vec::new(),
+        };
+        let (addr, server) = negotiating_server(Some(ack));
+        let mut config = goodbye_config(addr);
+        config.negotiate_timeout = Duration::from_secs(1);
+        let mut connection = Connection::new(config);
+        connection.connect().unwrap();
+        assert!(!connection.capabilities().goodbye);
+
+        assert!(connection.poll_goodbye().unwrap().is_none());
+        assert!(connection.is_connected());
+        connection.disconnect();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn poll_goodbye_disconnects_on_a_partial_frame() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            ack_goodbye(&mut stream);
+            let goodbye = GoodbyePayload {
+                reason: "draining".to_string(),
+                redirect: None,
+            };
+            let frame = Frame::goodbye(&goodbye).unwrap().encode().unwrap();
+            stream.write_all(&frame[..frame.len() / 2]).unwrap();
+            stream
+        });
+
+        let mut connection = Connection::new(goodbye_config(addr));
+        connection.connect().unwrap();
+        let _stream = server.join().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        assert!(connection.poll_goodbye().is_err());
+        assert!(!connection.is_connected());
+    }
+
+    #[tokio::test]
+    async fn records_connection_and_reconnect_histograms() {
+        // Nothing listens at first, so the agent has to back off
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let mut config = goodbye_config(addr);
+        config.initial_reconnect_delay = Duration::from_millis(50);
+        let (_tx, rx) = mpsc::channel(1);
+        let sender = tokio::spawn(ReconnectingConnection::new(config, metrics.clone()).run(rx));
+        sleep(Duration::from_millis(200)).await;
+
+        // Three short connections ended by the server, then one that stays
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        std::thread::spawn(move || {
+            let mut streams = Vec::new();
+            for _ in 0..4 {
+                let (mut stream, _) = listener.accept().unwrap();
+                ack_goodbye(&mut stream);
+                streams.push(stream);
+                if streams.len() < 4 {
+                    std::thread::sleep(Duration::from_millis(200));
+                    let goodbye = GoodbyePayload {
+                        reason: "restart".to_string(),
+                        redirect: None,
+                    };
+                    Frame::goodbye(&goodbye)
+                        .unwrap()
+                        .write_to(streams.last_mut().unwrap())
+                        .unwrap();
+                }
+            }
+            std::thread::sleep(Duration::from_secs(5));
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.reconnects.load(Ordering::Relaxed) < 3 {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("should reconnect after every goodbye");
+
+        let durations = metrics.connection_duration.snapshot();
+        assert_eq!(durations.count, 3);
+        assert!(durations.sum_secs >= 0.5, "{:?}", durations);
+        // Each connection lasted 200-300ms
+        assert_eq!(durations.buckets[0].count, 0);
+        assert_eq!(durations.buckets[1].count, 3);
+        assert_eq!(metrics.reconnect_time.snapshot().count, 3);
+        let backoff = metrics.backoff_delay.snapshot();
+        assert!(backoff.count >= 2, "{:?}", backoff);
+        assert!(backoff.sum_secs >= 0.1, "{:?}", backoff);
+
+        sender.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn alerts_when_data_waits_longer_than_max_latency() {
+        // Nothing listens at first, so the chunk is stuck in the agent
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let mut config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        config.initial_reconnect_delay = Duration::from_millis(50);
+        let watch = LatencyWatch::new(Duration::from_secs(1), metrics.clone());
+        let watcher = tokio::spawn(watch.clone().run());
+        let (tx, rx) = mpsc::channel(4);
+        let sender = tokio::spawn(
+            ReconnectingConnection::new(config, metrics.clone())
+                .with_latency_watch(watch)
+                .run(rx),
+        );
+        tx.send(LogChunk::new(b"late line\n".to_vec()))
+            .await
+            .unwrap();
+
+        sleep(Duration::from_millis(500)).await;
+        assert_eq!(metrics.latency_breaches.load(Ordering::Relaxed), 0);
+        tokio::time::timeout(Duration::from_secs(3), async {
+            while metrics.latency_breaches.load(Ordering::Relaxed) == 0 {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("should alert while the server is away");
+        assert_eq!(metrics.bytes_sent.load(Ordering::Relaxed), 0);
+
+        // Once the server is back the late chunk ships, without a second alert
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while Frame::read_from(&mut stream).is_ok() {}
+        });
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.ship_latency_ms.load(Ordering::Relaxed) == 0 {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("should ship once connected");
+        assert!(metrics.ship_latency_ms.load(Ordering::Relaxed) >= 1000);
+        assert_eq!(metrics.latency_breaches.load(Ordering::Relaxed), 1);
+
+        sender.abort();
+        watcher.abort();
+    }
+
+    /// Server that answers the handshake with `ack` (or stays silent),
+    /// then returns the first frame sent after it
+    fn negotiating_server(
+        ack: Option<HandshakeAckPayload>,
+    ) -> (SocketAddr, std::thread::JoinHandle<Option<Frame>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            Frame::read_from(&mut stream).unwrap();
+            if let Some(ack) = ack {
+                Frame::new(MessageType::HandshakeAck, serde_json::to_vec(&ack).unwrap())
+                    .write_to(&mut stream)
+                    .unwrap();
+            }
+            Frame::read_from(&mut stream).ok()
+        });
+        (addr, server)
+    }
+
+    /// Answer the handshake on `stream`, agreeing to goodbyes
+    fn ack_goodbye(stream: &mut std::net::TcpStream) {
+        Frame::read_from(stream).unwrap();
+        let ack = HandshakeAckPayload {
+            version: Some(MAX_PROTOCOL_VERSION),
+            features: vec![FEATURE_GOODBYE.to_string()],
+        };
+        Frame::new(MessageType::HandshakeAck, serde_json::to_vec(&ack).unwrap())
+            .write_to(stream)
+            .unwrap();
+    }
+
+    /// Negotiates goodbyes, and falls back quickly without an answer
+    fn goodbye_config(addr: SocketAddr) -> ConnectionConfig {
+        let mut config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        config.negotiate = true;
+        config.goodbye = true;
+        config.negotiate_timeout = Duration::from_millis(100);
+        config
+    }
+
+    fn negotiating_config(addr: SocketAddr) -> ConnectionConfig {
+        let mut config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        config.negotiate = true;
+        config.timestamp_frames = true;
+        config.file_meta = true;
+        config.negotiate_timeout = Duration::from_millis(100);
+        config
+    }
+
+    /// Connect, send one line and return the capabilities in use
+    fn negotiate_and_send(config: ConnectionConfig) -> Result<Capabilities> {
+        let mut connection = Connection::new(config);
+        connection.connect()?;
+        connection.send_data(LogChunk::new(b"line\n".to_vec()))?;
+        Ok(connection.capabilities)
+    }
+
+    #[test]
+    fn sends_goodbye_only_when_the_server_agreed() {
+        let mut config = negotiating_config("127.0.0.1:0".parse().unwrap());
+        config.goodbye = true;
+        let close = |config: &ConnectionConfig, ack| {
+            let (addr, server) = negotiating_server(ack);
+            let mut config = config.clone();
+            config.server_addr = addr.to_string();
+            let mut connection = Connection::new(config);
+            connection.connect().unwrap();
+            let sent = connection.close_gracefully("idle").unwrap();
+            (sent, server.join().unwrap())
+        };
+
+        let ack = HandshakeAckPayload {
+            version: Some(MAX_PROTOCOL_VERSION),
+            features: vec![FEATURE_GOODBYE.to_string()],
+        };
+        let (sent, frame) = close(&config, Some(ack));
+        assert!(sent);
+        assert_eq!(frame.unwrap().message_type, MessageType::Goodbye);
+
+        // A v1 server only sees the socket close
+        let (sent, frame) = close(&config, None);
+        assert!(!sent);
+        assert!(frame.is_none());
+    }
+
+    #[test]
+    fn negotiation_uses_only_features_the_server_agreed_to() {
+        let ack = |features: &[&str]| HandshakeAckPayload {
+            version: Some(MAX_PROTOCOL_VERSION),
+            features: features.iter().map(|f| f.to_string()).collect(),
+        };
+
+        // Agreed: data carries the timestamp header
+        let (addr, server) = negotiating_server(Some(ack(&[FEATURE_TIMESTAMPS])));
+        let capabilities = negotiate_and_send(negotiating_config(addr)).unwrap();
+        assert!(capabilities.timestamps);
+        assert!(!capabilities.file_meta);
+        let frame = server.join().unwrap().unwrap();
+        let (_, data) = TimestampHeader::decode(&frame.payload).unwrap();
+        assert_eq!(data, b"line\n");
+
+        // Acknowledged without the feature: plain v1 data
+        let (addr, server) = negotiating_server(Some(ack(&[FEATURE_FILE_META])));
+        let capabilities = negotiate_and_send(negotiating_config(addr)).unwrap();
+        assert!(!capabilities.timestamps);
+        assert!(capabilities.file_meta);
+        assert_eq!(server.join().unwrap().unwrap().payload, b"line\n");
+
+        // Without negotiating, frame types the server may not know are
+        // never assumed
+        let (addr, _server) = negotiating_server(None);
+        let mut config = negotiating_config(addr);
+        config.negotiate = false;
+        let capabilities = negotiate_and_send(config).unwrap();
+        assert!(capabilities.timestamps);
+        assert!(!capabilities.file_meta);
+    }
+
+    #[test]
+    fn negotiation_falls_back_to_v1_without_an_ack() {
+        let (addr, server) = negotiating_server(None);
+        let capabilities = negotiate_and_send(negotiating_config(addr)).unwrap();
+        assert!(!capabilities.timestamps);
+        assert_eq!(server.join().unwrap().unwrap().payload, b"line\n");
+
+        // Unless an ack is required
+        let (addr, _server) = negotiating_server(None);
+        let mut config = negotiating_config(addr);
+        config.require_handshake_ack = true;
+        let error = negotiate_and_send(config).unwrap_err();
+        assert!(
+            error.to_string().contains("did not acknowledge"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn probe_reports_the_servers_capabilities() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let handshake = Frame::read_from(&mut stream).unwrap();
+            let ack = HandshakeAckPayload {
+                version: Some(2),
+                features: vec![
+                    FEATURE_TIMESTAMPS.to_string(),
+                    FEATURE_GOODBYE.to_string(),
+                    "checksums".to_string(),
+                ],
+            };
+            Frame::new(MessageType::HandshakeAck, serde_json::to_vec(&ack).unwrap())
+                .write_to(&mut stream)
+                .unwrap();
+            let goodbye = Frame::read_from(&mut stream).unwrap();
+            (handshake, goodbye)
+        });
+
+        let config =
+            ConnectionConfig::new(addr.to_string(), "e2e".to_string(), "agent-1".to_string());
+        let probe = probe_server(config).unwrap();
+        assert!(probe.acknowledged);
+        assert_eq!(probe.server_version, Some(2));
+        assert_eq!(
+            probe.server_features,
+            ["timestamps", "goodbye", "checksums"]
+        );
+        // Only what the agent asked for is agreed
+        assert_eq!(probe.agreed_features, ["timestamps", "goodbye"]);
+        assert!(probe
+            .to_string()
+            .contains("Agreed features:  timestamps, goodbye"));
+
+        let (handshake, goodbye) = server.join().unwrap();
+        let handshake: HandshakePayload = serde_json::from_slice(&handshake.payload).unwrap();
+        assert_eq!(handshake.features, ["timestamps", "file_meta", "goodbye"]);
+        assert_eq!(goodbye.message_type, MessageType::Goodbye);
+    }
+}
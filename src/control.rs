@@ -0,0 +1,174 @@
+//! Local control socket
+//!
+//! With `--control-socket` the agent listens on a Unix socket for one
+//! command per line:
+//!
+//! - `pause`  - stop sending; the file is still tailed into the channel
+//! - `resume` - start sending again
+//! - `status` - reply with a JSON line describing the sender
+//!
+//! Pausing only sets [`Metrics::paused`], the connection loop checks it
+//! before taking the next chunk.
+
+use crate::metrics::Metrics;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Reply to `status`
+#[derive(Debug, Serialize)]
+struct Status {
+    paused: bool,
+    connected: bool,
+    bytes_sent: u64,
+}
+
+/// Serve control commands on `path` until the task is aborted
+pub async fn serve(path: PathBuf, metrics: Arc<Metrics>) -> Result<()> {
+    // A socket left behind by a previous run would make bind fail. Anything
+    // else at the path is not ours to delete.
+    match std::fs::symlink_metadata(&path) {
+        Ok(meta) if meta.file_type().is_socket() => remove_socket(&path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?,
+        Ok(_) => anyhow::bail!(
+            "Control socket path {} exists and is not a socket",
+            path.display()
+        ),
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    // Anyone who can connect can pause the agent
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict control socket {}", path.display()))?;
+    tracing::info!("Control socket listening on {}", path.display());
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::debug!("Control socket accept failed: {}", e);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &metrics).await {
+                tracing::debug!("Control socket connection error: {}", e);
+            }
+        });
+    }
+}
+
+/// Delete the socket at `path`, leaving anything that isn't a socket alone
+pub fn remove_socket(path: &Path) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(path)?.file_type().is_socket() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+async fn handle(stream: UnixStream, metrics: &Metrics) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let mut reply = execute(line.trim(), metrics);
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn execute(command: &str, metrics: &Metrics) -> String {
+    match command {
+        "pause" => {
+            if !metrics.paused.swap(true, Ordering::Relaxed) {
+                tracing::warn!("Sending paused from the control socket");
+            }
+            "ok".to_string()
+        }
+        "resume" => {
+            if metrics.paused.swap(false, Ordering::Relaxed) {
+                tracing::info!("Sending resumed from the control socket");
+            }
+            "ok".to_string()
+        }
+        "status" => {
+            let status = Status {
+                paused: metrics.paused.load(Ordering::Relaxed),
+                connected: metrics.connected.load(Ordering::Relaxed),
+                bytes_sent: metrics.bytes_sent.load(Ordering::Relaxed),
+            };
+            serde_json::to_string(&status).unwrap_or_else(|e| format!("error: {}", e))
+        }
+        other => format!("error: unknown command {:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn toggles_paused_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let metrics = Arc::new(Metrics::new());
+        let server = tokio::spawn(serve(path.clone(), metrics.clone()));
+
+        let stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let (reader, mut writer) = stream.into_split();
+        let mut replies = BufReader::new(reader).lines();
+
+        let mut replies_and_state = Vec::new();
+        for command in ["pause", "status", "resume", "bogus"] {
+            writer
+                .write_all(format!("{}\n", command).as_bytes())
+                .await
+                .unwrap();
+            let reply = replies.next_line().await.unwrap().unwrap();
+            replies_and_state.push((reply, metrics.paused.load(Ordering::Relaxed)));
+        }
+
+        assert_eq!(replies_and_state[0], ("ok".to_string(), true));
+        assert!(replies_and_state[1].0.contains(r#""paused":true"#));
+        assert_eq!(replies_and_state[2], ("ok".to_string(), false));
+        assert!(replies_and_state[3].0.starts_with("error: unknown command"));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn only_replaces_a_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        std::fs::write(&path, b"not a socket").unwrap();
+        let err = serve(path.clone(), Arc::new(Metrics::new()))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("is not a socket"), "{:#}", err);
+        remove_socket(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+
+        std::fs::remove_file(&path).unwrap();
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let server = tokio::spawn(serve(path.clone(), Arc::new(Metrics::new())));
+        while UnixStream::connect(&path).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        server.abort();
+    }
+}
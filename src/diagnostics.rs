@@ -0,0 +1,393 @@
+//! Crash diagnostics
+//!
+//! Keeps the last few complete lines handed to the sender in a bounded ring
+//! buffer. When the file watcher or the connection fails fatally the buffer
+//! is dumped to `--diagnostics-file` (or stderr) so there is context about
+//! what the agent was shipping. Lines are redacted with the configured
+//! patterns before being written.
+//!
+//! On Unix, SIGUSR1 writes a snapshot of the agent's current state to
+//! stderr, whatever the log level, and flushes the checkpoint file. This only reads shared counters, streaming carries on.
+//!
+//! `--capture-file` keeps a byte-exact copy of every frame sent to the server.
+
+use crate::config::SharedFilters;
+use crate::metrics::{HistogramSnapshot, Metrics};
+use crate::tail::{LogChunk, TailPosition};
+use crate::transform::DEFAULT_LINE_DELIMITER;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+
+/// Longest line kept in the buffer, longer lines are cut off
+const MAX_LINE_LEN: usize = 4096;
+
+/// Default number of lines kept
+pub const DEFAULT_RECENT_LINES: usize = 100;
+
+#[derive(Debug, Default)]
+struct Buffer {
+    lines: VecDeque<Vec<u8>>,
+    /// Start of a line whose delimiter hasn't been seen yet
+    partial: Vec<u8>,
+}
+
+/// Ring buffer of the most recently shipped lines
+pub struct RecentLines {
+    capacity: usize,
+    buffer: Mutex<Buffer>,
+    filters: SharedFilters,
+    output: Option<PathBuf>,
+    delimiter: u8,
+}
+
+impl RecentLines {
+    pub fn new(capacity: usize, filters: SharedFilters, output: Option<PathBuf>) -> Self {
+        Self {
+            capacity,
+            buffer: Mutex::new(Buffer::default()),
+            filters,
+            output,
+            delimiter: DEFAULT_LINE_DELIMITER,
+        }
+    }
+
+    /// Keep lines ending in `delimiter` instead of a newline. The dump
+    /// still writes one line per row.
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Record shipped data, keeping only complete lines
+    pub fn record(&self, data: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut buffer = self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+        let mut rest = data;
+        while let Some(pos) = rest.iter().position(|&b| b == self.delimiter) {
+            let room = MAX_LINE_LEN.saturating_sub(buffer.partial.len());
+            let take = pos.min(room);
+            buffer.partial.extend_from_slice(&rest[..take]);
+            let line = std::mem::take(&mut buffer.partial);
+            if buffer.lines.len() == self.capacity {
+                buffer.lines.pop_front();
+            }
+            buffer.lines.push_back(line);
+            rest = &rest[pos + 1..];
+        }
+        let room = MAX_LINE_LEN.saturating_sub(buffer.partial.len());
+        buffer
+            .partial
+            .extend_from_slice(&rest[..rest.len().min(room)]);
+    }
+
+    /// Write the buffered lines, redacted, after a fatal error
+    pub fn dump(&self, error: &anyhow::Error) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Err(e) = self.write_dump(error) {
+            tracing::warn!("Failed to write diagnostics: {:#}", e);
+        }
+    }
+
+    fn write_dump(&self, error: &anyhow::Error) -> Result<()> {
+        let lines: Vec<Vec<u8>> = {
+            let buffer = self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+            buffer.lines.iter().cloned().collect()
+        };
+        let filters = self
+            .filters
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+
+        let mut out = format!(
+            "# logline-agent diagnostics\n# error: {:#}\n# last {} lines shipped:\n",
+            error,
+            lines.len()
+        )
+        .into_bytes();
+        for line in lines {
+            out.extend_from_slice(&filters.redact(line));
+            out.push(b'\n');
+        }
+
+        match &self.output {
+            Some(path) => {
+                std::fs::write(path, &out)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                tracing::error!("Diagnostics written to {}", path.display());
+            }
+            None => std::io::stderr().write_all(&out)?,
+        }
+        Ok(())
+    }
+}
+
+/// Default size at which the capture file is rotated (100MB)
+pub const DEFAULT_CAPTURE_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Appends the wire bytes of sent frames to a file. Once the file would
+/// grow past `max_bytes` it is moved to `<path>.1`, replacing the previous
+/// one, and a new file is started.
+#[derive(Debug)]
+pub struct FrameCapture {
+    path: PathBuf,
+    max_bytes: u64,
+    file: std::fs::File,
+    written: u64,
+}
+
+impl FrameCapture {
+    pub fn open(path: PathBuf, max_bytes: u64) -> Result<Self> {
+        let file = Self::open_file(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            file,
+            written,
+        })
+    }
+
+    fn open_file(path: &std::path::Path) -> Result<std::fs::File> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open capture file {}", path.display()))
+    }
+
+    /// Append one encoded frame. Failures are logged, never fatal.
+    pub fn record(&mut self, frame: &[u8]) {
+        if let Err(e) = self.append(frame) {
+            tracing::warn!("Failed to write capture file: {:#}", e);
+        }
+    }
+
+    fn append(&mut self, frame: &[u8]) -> Result<()> {
+        if self.written > 0 && self.written + frame.len() as u64 > self.max_bytes {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            std::fs::rename(&self.path, &rotated)?;
+            self.file = Self::open_file(&self.path)?;
+            self.written = 0;
+            tracing::info!(
+                "Capture file reached {} bytes, previous frames moved to {}",
+                self.max_bytes,
+                PathBuf::from(rotated).display()
+            );
+        }
+        self.file.write_all(frame)?;
+        self.written += frame.len() as u64;
+        Ok(())
+    }
+}
+
+/// Capture shared by every connection made with one config
+pub type SharedCapture = Arc<Mutex<FrameCapture>>;
+
+/// Point-in-time view of the agent, logged on SIGUSR1
+#[derive(Debug, Serialize)]
+pub struct StateSnapshot {
+    pub path: PathBuf,
+    pub inode: u64,
+    pub offset: u64,
+    /// Current size of the file on disk, if it can be read
+    pub file_size: Option<u64>,
+    pub connected: bool,
+    /// Sending paused from the control socket
+    pub paused: bool,
+    pub watcher_alive: bool,
+    pub bytes_read: u64,
+    pub bytes_sent: u64,
+    pub frames_sent: u64,
+    pub reconnects: u64,
+    /// Chunks queued between the file watcher and the sender
+    pub channel_depth: usize,
+    /// Adaptive batch target, 0 unless `--adaptive-batch` is on
+    pub batch_target_bytes: u64,
+    /// Fragments cut off at `--max-line-bytes`
+    pub lines_truncated: u64,
+    /// Lines sent in parts by `--split-large-lines`
+    pub lines_split: u64,
+    pub connection_duration: HistogramSnapshot,
+    pub reconnect_time: HistogramSnapshot,
+    pub backoff_delay: HistogramSnapshot,
+    /// Read-to-socket latency of the last sent chunk
+    pub ship_latency_ms: u64,
+    pub latency_breaches: u64,
+}
+
+/// Reports the agent's state on request
+pub struct StateReporter {
+    path: PathBuf,
+    position: Arc<TailPosition>,
+    metrics: Arc<Metrics>,
+    /// Weak so the reporter doesn't keep the data channel open
+    channel: mpsc::WeakSender<LogChunk>,
+    checkpoint: Option<Arc<Notify>>,
+}
+
+impl StateReporter {
+    pub fn new(
+        path: PathBuf,
+        position: Arc<TailPosition>,
+        metrics: Arc<Metrics>,
+        channel: mpsc::WeakSender<LogChunk>,
+    ) -> Self {
+        Self {
+            path,
+            position,
+            metrics,
+            channel,
+            checkpoint: None,
+        }
+    }
+
+    /// Flush the checkpoint through `flush` whenever state is reported
+    pub fn with_checkpoint(mut self, flush: Arc<Notify>) -> Self {
+        self.checkpoint = Some(flush);
+        self
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        let channel_depth = self
+            .channel
+            .upgrade()
+            .map_or(0, |tx| tx.max_capacity() - tx.capacity());
+        StateSnapshot {
+            path: self.path.clone(),
+            inode: self.position.inode.load(Ordering::Relaxed),
+            offset: self.position.offset.load(Ordering::Relaxed),
+            file_size: std::fs::metadata(&self.path).ok().map(|m| m.len()),
+            connected: self.metrics.connected.load(Ordering::Relaxed),
+            paused: self.metrics.paused.load(Ordering::Relaxed),
+            watcher_alive: self.metrics.watcher_alive.load(Ordering::Relaxed),
+            bytes_read: self.metrics.bytes_read.load(Ordering::Relaxed),
+            bytes_sent: self.metrics.bytes_sent.load(Ordering::Relaxed),
+            frames_sent: self.metrics.frames_sent.load(Ordering::Relaxed),
+            reconnects: self.metrics.reconnects.load(Ordering::Relaxed),
+            channel_depth,
+            batch_target_bytes: self.metrics.batch_target_bytes.load(Ordering::Relaxed),
+            lines_truncated: self.metrics.lines_truncated.load(Ordering::Relaxed),
+            lines_split: self.metrics.lines_split.load(Ordering::Relaxed),
+            connection_duration: self.metrics.connection_duration.snapshot(),
+            reconnect_time: self.metrics.reconnect_time.snapshot(),
+            backoff_delay: self.metrics.backoff_delay.snapshot(),
+            ship_latency_ms: self.metrics.ship_latency_ms.load(Ordering::Relaxed),
+            latency_breaches: self.metrics.latency_breaches.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Write the current state to stderr and flush the checkpoint. The
+    /// state is asked for explicitly, so `--quiet` doesn't hide it.
+    pub fn report(&self) -> StateSnapshot {
+        let snapshot = self.snapshot();
+        match serde_json::to_string(&snapshot) {
+            Ok(json) => {
+                let _ = writeln!(std::io::stderr().lock(), "Agent state: {}", json);
+            }
+            Err(e) => tracing::warn!("Failed to serialize agent state: {}", e),
+        }
+        if let Some(flush) = &self.checkpoint {
+            flush.notify_one();
+        }
+        snapshot
+    }
+
+    /// Report the state every time SIGUSR1 is received
+    #[cfg(unix)]
+    pub async fn run(self) -> Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut usr1 =
+            signal(SignalKind::user_defined1()).context("Failed to install SIGUSR1 handler")?;
+        while usr1.recv().await.is_some() {
+            self.report();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_rotates_at_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("frames.bin");
+        let mut capture = FrameCapture::open(path.clone(), 8).unwrap();
+
+        capture.record(b"12345");
+        capture.record(b"678");
+        capture.record(b"9");
+        assert_eq!(std::fs::read(&path).unwrap(), b"9");
+        assert_eq!(
+            std::fs::read(dir.path().join("frames.bin.1")).unwrap(),
+            b"12345678"
+        );
+    }
+
+    #[test]
+    fn recent_lines_split_on_the_configured_delimiter() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("diagnostics.txt");
+        let recent = RecentLines::new(2, Default::default(), Some(path.clone())).with_delimiter(0);
+
+        recent.record(b"one\ncontinued\0two\0thr");
+        recent.record(b"ee\0four");
+        recent.dump(&anyhow::anyhow!("boom"));
+        let dump = std::fs::read_to_string(&path).unwrap();
+        assert!(dump.ends_with("two\nthree\n"), "{}", dump);
+    }
+
+    #[tokio::test]
+    async fn report_snapshots_state_and_flushes_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let position = Arc::new(TailPosition::default());
+        position.offset.store(4, Ordering::Relaxed);
+        let metrics = Arc::new(Metrics::new());
+        metrics.bytes_sent.store(4, Ordering::Relaxed);
+        metrics.connected.store(true, Ordering::Relaxed);
+        metrics.lines_truncated.store(2, Ordering::Relaxed);
+        metrics.lines_split.store(1, Ordering::Relaxed);
+        let (tx, _rx) = mpsc::channel(8);
+        tx.send(LogChunk::new(b"0123".to_vec())).await.unwrap();
+        let flush = Arc::new(Notify::new());
+
+        let reporter = StateReporter::new(path.clone(), position, metrics, tx.downgrade())
+            .with_checkpoint(flush.clone());
+        let snapshot = reporter.report();
+        assert_eq!(snapshot.path, path);
+        assert_eq!(snapshot.offset, 4);
+        assert_eq!(snapshot.file_size, Some(10));
+        assert_eq!(snapshot.bytes_sent, 4);
+        assert!(snapshot.connected);
+        assert_eq!(snapshot.channel_depth, 1);
+        assert_eq!(snapshot.lines_truncated, 2);
+        assert_eq!(snapshot.lines_split, 1);
+
+        // The stored permit completes immediately
+        tokio::time::timeout(std::time::Duration::from_secs(1), flush.notified())
+            .await
+            .unwrap();
+
+        // Reporting never keeps the channel open
+        drop(tx);
+        assert_eq!(reporter.snapshot().channel_depth, 0);
+    }
+}
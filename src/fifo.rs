@@ -0,0 +1,162 @@
+//! Named pipe sources
+//!
+//! When `--file` is a FIFO there is no offset to track and nothing to
+//! rotate, so the tail is bypassed. A dedicated thread opens the pipe,
+//! which blocks until a writer connects, and forwards whatever is read
+//! until the writer closes its end. The pipe is then opened again for the
+//! next writer, unless `--once` is set.
+
+use crate::metrics::Metrics;
+use crate::tail::LogChunk;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+/// Size of the chunks read from the pipe
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Whether `path` is a named pipe
+#[cfg(unix)]
+pub fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+pub fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+/// Stream the pipe at `path` into `tx`, one writer after another unless
+/// `once` is set
+pub async fn stream(
+    path: PathBuf,
+    once: bool,
+    tx: mpsc::Sender<LogChunk>,
+    metrics: Arc<Metrics>,
+) -> Result<()> {
+    // A plain thread rather than spawn_blocking: opening the pipe blocks
+    // until a writer shows up, which must not hold up runtime shutdown
+    let (done_tx, done_rx) = oneshot::channel();
+    std::thread::Builder::new()
+        .name("fifo-reader".to_string())
+        .spawn(move || {
+            let _ = done_tx.send(read_writers(&path, once, &tx, &metrics));
+        })
+        .context("Failed to start FIFO reader")?;
+    done_rx.await.context("FIFO reader stopped unexpectedly")?
+}
+
+fn read_writers(
+    path: &Path,
+    once: bool,
+    tx: &mpsc::Sender<LogChunk>,
+    metrics: &Metrics,
+) -> Result<()> {
+    loop {
+        tracing::info!("Waiting for a writer on {}", path.display());
+        let mut pipe =
+            File::open(path).with_context(|| format!("Failed to open FIFO {}", path.display()))?;
+        tracing::info!("Writer connected to {}", path.display());
+
+        loop {
+            let mut buffer = vec![0u8; CHUNK_SIZE];
+            let n = match pipe.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {}", path.display()))
+                }
+            };
+            buffer.truncate(n);
+            metrics.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+            if tx.blocking_send(LogChunk::new(buffer)).is_err() {
+                return Ok(());
+            }
+        }
+
+        tracing::info!("Writer closed {}", path.display());
+        if once {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::time::Duration;
+
+    fn mkfifo(path: &Path) {
+        let status = std::process::Command::new("mkfifo")
+            .arg(path)
+            .status()
+            .expect("mkfifo");
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn streams_across_writer_reconnects() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.pipe");
+        mkfifo(&path);
+        assert!(is_fifo(&path));
+        assert!(!is_fifo(dir.path()));
+
+        let metrics = Arc::new(Metrics::new());
+        let (tx, mut rx) = mpsc::channel(16);
+        let reader = tokio::spawn(stream(path.clone(), false, tx, metrics.clone()));
+
+        for line in [&b"first writer\n"[..], b"second writer\n"] {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || {
+                // Blocks until the reader has the pipe open
+                let mut writer = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+                writer.write_all(line).unwrap();
+            })
+            .await
+            .unwrap();
+
+            let chunk = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(chunk.data, line);
+        }
+
+        assert_eq!(metrics.bytes_read.load(Ordering::Relaxed), 27);
+        assert!(!reader.is_finished());
+        reader.abort();
+    }
+
+    #[tokio::test]
+    async fn once_stops_when_the_writer_closes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.pipe");
+        mkfifo(&path);
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let reader = tokio::spawn(stream(path.clone(), true, tx, Arc::new(Metrics::new())));
+        tokio::task::spawn_blocking(move || {
+            let mut writer = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+            writer.write_all(b"only\n").unwrap();
+        })
+        .await
+        .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), reader)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap().data, b"only\n");
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,152 @@
+//! Health check endpoint
+//!
+//! A tiny HTTP server for liveness/readiness probes, enabled with
+//! `--health-addr`:
+//!
+//! - `GET /livez`  - 200 as long as the process is serving requests
+//! - `GET /readyz` - 200 when connected to the server (or idle-disconnected
+//!   until there is data) and tailing the file, 503 otherwise with the
+//!   reasons in the JSON body
+//! - `GET /metrics` - the connection duration, reconnect time and backoff
+//!   delay histograms as JSON, the same ones `SIGUSR1` reports
+//!
+//! State is read from the same [`Metrics`] atomics the other tasks update.
+
+use crate::metrics::{HistogramSnapshot, Metrics};
+use anyhow::{Context, Result};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// JSON body of a health response
+#[derive(Debug, Serialize)]
+struct Status {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    reasons: Vec<&'static str>,
+}
+
+/// JSON body of `/metrics`
+#[derive(Debug, Serialize)]
+struct Histograms {
+    connection_duration: HistogramSnapshot,
+    reconnect_time: HistogramSnapshot,
+    backoff_delay: HistogramSnapshot,
+}
+
+/// Serve health checks on `addr` until the task is aborted
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind health endpoint on {}", addr))?;
+    tracing::info!("Health endpoint listening on http://{}", addr);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::debug!("Health endpoint accept failed: {}", e);
+                continue;
+            }
+        };
+
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(respond(&req, &metrics)) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Health endpoint connection error: {}", e);
+            }
+        });
+    }
+}
+
+fn respond(req: &Request<Incoming>, metrics: &Metrics) -> Response<Full<Bytes>> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return json(
+            StatusCode::METHOD_NOT_ALLOWED,
+            Status {
+                status: "method not allowed",
+                reasons: Vec::new(),
+            },
+        );
+    }
+
+    match req.uri().path() {
+        "/livez" => json(
+            StatusCode::OK,
+            Status {
+                status: "ok",
+                reasons: Vec::new(),
+            },
+        ),
+        "/readyz" => {
+            let mut reasons = Vec::new();
+            if !metrics.connected.load(Ordering::Relaxed)
+                && !metrics.idle_disconnected.load(Ordering::Relaxed)
+            {
+                reasons.push("not connected to server");
+            }
+            if !metrics.watcher_alive.load(Ordering::Relaxed) {
+                reasons.push("file watcher not running");
+            }
+            if reasons.is_empty() {
+                json(
+                    StatusCode::OK,
+                    Status {
+                        status: "ready",
+                        reasons,
+                    },
+                )
+            } else {
+                json(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Status {
+                        status: "unready",
+                        reasons,
+                    },
+                )
+            }
+        }
+        "/metrics" => json(
+            StatusCode::OK,
+            Histograms {
+                connection_duration: metrics.connection_duration.snapshot(),
+                reconnect_time: metrics.reconnect_time.snapshot(),
+                backoff_delay: metrics.backoff_delay.snapshot(),
+            },
+        ),
+        _ => json(
+            StatusCode::NOT_FOUND,
+            Status {
+                status: "not found",
+                reasons: Vec::new(),
+            },
+        ),
+    }
+}
+
+fn json(code: StatusCode, body: impl Serialize) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = code;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
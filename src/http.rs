@@ -0,0 +1,709 @@
+//! HTTP sink
+//!
+//! Batches complete lines and POSTs them as NDJSON or a JSON array for
+//! collectors that only speak HTTP. Failed requests are retried with the
+//! same backoff as TCP reconnects, honouring `Retry-After`. Fields pulled
+//! out of each line with `--extract` are added to its JSON object.
+
+use crate::backoff::Backoff;
+use crate::connection::{ConnectionConfig, ReconnectExhausted};
+use crate::metrics::Metrics;
+use crate::sink::Sink;
+use crate::tail::LogChunk;
+use crate::transform::DEFAULT_LINE_DELIMITER;
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// Default number of lines per request
+pub const DEFAULT_BATCH_LINES: usize = 500;
+
+/// Request body layout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HttpFormat {
+    /// One JSON object per line
+    #[default]
+    Ndjson,
+    /// A single JSON array of objects
+    Json,
+}
+
+/// Default minimum size reduction for a gzipped body to be sent compressed
+pub const DEFAULT_GZIP_MIN_SAVINGS: u8 = 5;
+
+/// Weight of the newest batch in the rolling compression ratio
+const RATIO_WEIGHT: f64 = 0.2;
+
+/// Gzips bodies only while it pays off. A body that doesn't shrink by at
+/// least `min_savings` is sent as is, and once the rolling ratio is that
+/// poor compression is skipped altogether for `cooldown`.
+#[derive(Debug)]
+struct AdaptiveGzip {
+    /// Largest compressed/original ratio still worth sending
+    max_ratio: f64,
+    cooldown: Duration,
+    /// Rolling compressed/original ratio since the last cooldown
+    ratio: Option<f64>,
+    skip_until: Option<Instant>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl AdaptiveGzip {
+    fn new(min_savings_percent: u8, cooldown: Duration) -> Self {
+        Self {
+            max_ratio: 1.0 - f64::from(min_savings_percent.min(100)) / 100.0,
+            cooldown,
+            ratio: None,
+            skip_until: None,
+            metrics: None,
+        }
+    }
+
+    /// The body to send, and whether it is gzipped
+    fn encode(&mut self, body: Vec<u8>) -> Result<(Vec<u8>, bool)> {
+        if body.is_empty() || self.skip_until.is_some_and(|until| Instant::now() < until) {
+            return Ok((body, false));
+        }
+        self.skip_until = None;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body)?;
+        let compressed = encoder.finish()?;
+
+        let ratio = compressed.len() as f64 / body.len() as f64;
+        let rolling = match self.ratio {
+            Some(previous) => previous + RATIO_WEIGHT * (ratio - previous),
+            None => ratio,
+        };
+        self.ratio = Some(rolling);
+        if let Some(metrics) = &self.metrics {
+            metrics
+                .compression_ratio_permille
+                .store((rolling * 1000.0).round() as u64, Ordering::Relaxed);
+        }
+
+        if rolling > self.max_ratio {
+            tracing::info!(
+                "Gzip only reaches {:.0}% of the original size, sending uncompressed for {:?}",
+                rolling * 100.0,
+                self.cooldown
+            );
+            self.ratio = None;
+            self.skip_until = Some(Instant::now() + self.cooldown);
+        }
+        if ratio > self.max_ratio {
+            if let Some(metrics) = &self.metrics {
+                metrics.compression_skipped.fetch_add(1, Ordering::Relaxed);
+            }
+            return Ok((body, false));
+        }
+        Ok((compressed, true))
+    }
+}
+
+/// One shipped line
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    /// When the line was read, milliseconds since Unix epoch
+    timestamp_ms: u64,
+    /// Source line number, with `--line-numbers`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line_number: Option<u64>,
+    line: &'a str,
+    /// Values found by `--extract`
+    #[serde(flatten)]
+    fields: BTreeMap<&'a str, Option<&'a str>>,
+}
+
+/// What an `--extract` field becomes when its regex doesn't match
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MissingField {
+    /// Leave the key out
+    #[default]
+    Omit,
+    /// Include the key with a `null` value
+    Null,
+}
+
+/// Parse an `--extract` argument (`FIELD=REGEX`)
+pub fn parse_extract(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((field, regex)) if !field.is_empty() && !regex.is_empty() => {
+            Ok((field.to_string(), regex.to_string()))
+        }
+        _ => Err(format!("invalid extraction '{}', expected FIELD=REGEX", s)),
+    }
+}
+
+/// Keys every [`Record`] may carry, which extracted fields can't take
+const RESERVED_FIELDS: &[&str] = &["line", "timestamp_ms", "line_number"];
+
+/// Promotes parts of each line to top-level JSON keys. A field's value is
+/// the capture group named after it, or the whole match when the regex
+/// has no such group.
+#[derive(Debug, Default)]
+pub struct Extractor {
+    fields: Vec<(String, Regex)>,
+    missing: MissingField,
+}
+
+impl Extractor {
+    pub fn new(extractions: &[(String, String)], missing: MissingField) -> Result<Self> {
+        let mut fields: Vec<(String, Regex)> = Vec::new();
+        for (field, pattern) in extractions {
+            if RESERVED_FIELDS.contains(&field.as_str()) {
+                anyhow::bail!("field '{}' is reserved", field);
+            }
+            if fields.iter().any(|(name, _)| name == field) {
+                anyhow::bail!("field '{}' is extracted more than once", field);
+            }
+            let regex = Regex::new(pattern)
+                .with_context(|| format!("invalid regex for field '{}'", field))?;
+            fields.push((field.clone(), regex));
+        }
+        Ok(Self { fields, missing })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    fn extract<'a>(&'a self, line: &'a str) -> BTreeMap<&'a str, Option<&'a str>> {
+        let mut values = BTreeMap::new();
+        for (field, regex) in &self.fields {
+            let value = regex.captures(line).map(|captures| {
+                captures
+                    .name(field)
+                    .or_else(|| captures.get(0))
+                    .map_or("", |m| m.as_str())
+            });
+            if value.is_some() || self.missing == MissingField::Null {
+                values.insert(field.as_str(), value);
+            }
+        }
+        values
+    }
+}
+
+/// POSTs batches of lines to an HTTP endpoint
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+    config: ConnectionConfig,
+    format: HttpFormat,
+    gzip: Option<AdaptiveGzip>,
+    extractor: Extractor,
+    batch_lines: usize,
+    /// Complete lines waiting to be sent, with the time they were read and
+    /// their source line number
+    batch: Vec<(u64, Option<u64>, String)>,
+    delimiter: u8,
+    /// Start of a line whose delimiter hasn't been seen yet
+    partial: Vec<u8>,
+}
+
+impl HttpSink {
+    /// Identity headers, timeouts and retry policy come from `config`
+    pub fn new(url: impl Into<String>, config: ConnectionConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.write_timeout)
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(Self {
+            client,
+            url: url.into(),
+            config,
+            format: HttpFormat::default(),
+            gzip: None,
+            extractor: Extractor::default(),
+            batch_lines: DEFAULT_BATCH_LINES,
+            batch: Vec::new(),
+            delimiter: DEFAULT_LINE_DELIMITER,
+            partial: Vec::new(),
+        })
+    }
+
+    pub fn with_format(mut self, format: HttpFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Compress request bodies with gzip, unless that saves less than
+    /// `min_savings` percent; after a run of such batches compression is
+    /// not attempted for `cooldown`
+    pub fn with_gzip(mut self, min_savings: u8, cooldown: Duration) -> Self {
+        self.gzip = Some(AdaptiveGzip::new(min_savings, cooldown));
+        self
+    }
+
+    /// Publish the observed compression ratio
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        if let Some(gzip) = &mut self.gzip {
+            gzip.metrics = Some(metrics);
+        }
+        self
+    }
+
+    /// Add fields extracted from each line to its record
+    pub fn with_extractor(mut self, extractor: Extractor) -> Self {
+        self.extractor = extractor;
+        self
+    }
+
+    /// Send a request once this many lines are buffered
+    pub fn with_batch_lines(mut self, lines: usize) -> Self {
+        self.batch_lines = lines.max(1);
+        self
+    }
+
+    /// Batch lines ending in `delimiter` instead of a newline
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    fn headers(&self, gzipped: bool) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let content_type = match self.format {
+            HttpFormat::Ndjson => "application/x-ndjson",
+            HttpFormat::Json => "application/json",
+        };
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        if gzipped {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        }
+
+        let tags = self
+            .config
+            .tags
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(",");
+        let identity = [
+            ("x-logline-project", Some(self.config.project_name.as_str())),
+            ("x-logline-agent", Some(self.config.agent_id.as_str())),
+            ("x-logline-device", self.config.device_id.as_deref()),
+            (
+                "x-logline-tags",
+                Some(tags.as_str()).filter(|t| !t.is_empty()),
+            ),
+        ];
+        for (name, value) in identity {
+            // Values that aren't valid header text are left out
+            if let Some(value) = value.and_then(|v| HeaderValue::from_str(v).ok()) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
+
+    /// The request body and whether it is gzipped
+    fn body(&mut self) -> Result<(Vec<u8>, bool)> {
+        let extractor = &self.extractor;
+        let records = self
+            .batch
+            .iter()
+            .map(|(timestamp_ms, line_number, line)| Record {
+                timestamp_ms: *timestamp_ms,
+                line_number: *line_number,
+                line,
+                fields: extractor.extract(line),
+            });
+        let body = match self.format {
+            HttpFormat::Ndjson => {
+                let mut body = Vec::new();
+                for record in records {
+                    serde_json::to_writer(&mut body, &record)?;
+                    body.push(b'\n');
+                }
+                body
+            }
+            HttpFormat::Json => serde_json::to_vec(&records.collect::<Vec<_>>())?,
+        };
+
+        match self.gzip.as_mut() {
+            Some(gzip) => gzip.encode(body),
+            None => Ok((body, false)),
+        }
+    }
+
+    /// POST the buffered lines, retrying until the endpoint accepts them
+    async fn post_batch(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let (body, gzipped) = self.body()?;
+        let headers = self.headers(gzipped);
+        let mut backoff = Backoff::new(
+            self.config.backoff,
+            self.config.initial_reconnect_delay,
+            self.config.max_reconnect_delay,
+        );
+        let mut failures = 0u32;
+        loop {
+            let result = self
+                .client
+                .post(&self.url)
+                .headers(headers.clone())
+                .body(body.clone())
+                .send()
+                .await;
+
+            let (error, retry_after) = match result {
+                Ok(response) if response.status().is_success() => {
+                    tracing::debug!("Posted {} lines to {}", self.batch.len(), self.url);
+                    self.batch.clear();
+                    return Ok(());
+                }
+                Ok(response) => {
+                    let retry_after = response
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.trim().parse::<u64>().ok())
+                        .map(Duration::from_secs);
+                    (
+                        format!("server answered {}", response.status()),
+                        retry_after,
+                    )
+                }
+                Err(e) => (e.to_string(), None),
+            };
+
+            failures += 1;
+            if self
+                .config
+                .max_reconnect_attempts
+                .is_some_and(|max| failures >= max)
+            {
+                tracing::warn!("POST to {} failed: {}", self.url, error);
+                return Err(ReconnectExhausted { attempts: failures }.into());
+            }
+            let delay = backoff.next();
+            let delay = retry_after.unwrap_or(delay);
+            tracing::warn!(
+                "POST to {} failed (attempt {}): {}. Retrying in {:?}",
+                self.url,
+                failures,
+                error,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+impl Sink for HttpSink {
+    async fn send(&mut self, chunk: LogChunk) -> Result<()> {
+        let timestamp_ms = chunk
+            .read_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        let mut line_numbers = chunk.line_numbers.iter().copied();
+        let mut rest = chunk.data.as_slice();
+        while let Some(pos) = rest.iter().position(|&b| b == self.delimiter) {
+            self.partial.extend_from_slice(&rest[..pos]);
+            let line = std::mem::take(&mut self.partial);
+            self.batch.push((
+                timestamp_ms,
+                line_numbers.next(),
+                String::from_utf8_lossy(&line).into_owned(),
+            ));
+            rest = &rest[pos + 1..];
+
+            if self.batch.len() >= self.batch_lines {
+                self.post_batch().await?;
+            }
+        }
+        self.partial.extend_from_slice(rest);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.post_batch().await
+    }
+
+    fn holds_partial(&self) -> bool {
+        !self.partial.is_empty()
+    }
+}
+
+impl Drop for HttpSink {
+    fn drop(&mut self) {
+        let pending = self.batch.len() + usize::from(!self.partial.is_empty());
+        if pending > 0 {
+            tracing::warn!("{} lines not delivered to {}", pending, self.url);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+
+    /// A received request: lowercase header lines and the raw body
+    type Request = (Vec<String>, Vec<u8>);
+
+    /// HTTP server answering with `responses` in turn, then 200
+    fn mock_server(responses: Vec<&'static str>) -> (SocketAddr, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            let mut responses = responses.into_iter();
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { break };
+                let mut reader = BufReader::new(stream);
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    let line = line.trim_end().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    headers.push(line);
+                }
+                let len = headers
+                    .iter()
+                    .find_map(|h| h.strip_prefix("content-length: "))
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0);
+                let mut body = vec![0u8; len];
+                reader.read_exact(&mut body).unwrap();
+                recorded.lock().unwrap().push((headers, body));
+
+                let status = responses.next().unwrap_or("200 OK");
+                let response = format!(
+                    "HTTP/1.1 {}\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (addr, requests)
+    }
+
+    fn config() -> ConnectionConfig {
+        let mut config =
+            ConnectionConfig::new(String::new(), "e2e".to_string(), "agent-1".to_string());
+        config.device_id = Some("host-1".to_string());
+        config.initial_reconnect_delay = Duration::from_millis(10);
+        config
+    }
+
+    #[tokio::test]
+    async fn posts_ndjson_batches_with_identity_headers() {
+        let (addr, requests) = mock_server(Vec::new());
+        let mut sink = HttpSink::new(format!("http://{}/ingest", addr), config())
+            .unwrap()
+            .with_batch_lines(2);
+
+        sink.send(LogChunk::new(b"one\ntwo\nthr".to_vec()))
+            .await
+            .unwrap();
+        sink.send(LogChunk::new(b"ee\n".to_vec())).await.unwrap();
+        sink.flush().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let (headers, body) = &requests[0];
+        assert!(headers.contains(&"content-type: application/x-ndjson".to_string()));
+        assert!(headers.contains(&"x-logline-agent: agent-1".to_string()));
+        assert!(headers.contains(&"x-logline-device: host-1".to_string()));
+        let lines: Vec<serde_json::Value> = body
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["line"], "one");
+        assert_eq!(lines[1]["line"], "two");
+
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[1].1.strip_suffix(b"\n").unwrap()).unwrap();
+        assert_eq!(body["line"], "three");
+    }
+
+    #[tokio::test]
+    async fn batches_lines_ending_in_the_configured_delimiter() {
+        let (addr, requests) = mock_server(Vec::new());
+        let mut sink = HttpSink::new(format!("http://{}/ingest", addr), config())
+            .unwrap()
+            .with_delimiter(0);
+
+        sink.send(LogChunk::new(b"one\ncontinued\0two\0".to_vec()))
+            .await
+            .unwrap();
+        sink.flush().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let lines: Vec<serde_json::Value> = requests[0]
+            .1
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["line"], "one\ncontinued");
+        assert_eq!(lines[1]["line"], "two");
+    }
+
+    #[tokio::test]
+    async fn retries_failed_posts_and_gzips() {
+        let (addr, requests) =
+            mock_server(vec!["503 Service Unavailable", "429 Too Many Requests"]);
+        let mut sink = HttpSink::new(format!("http://{}/ingest", addr), config())
+            .unwrap()
+            .with_format(HttpFormat::Json)
+            .with_gzip(DEFAULT_GZIP_MIN_SAVINGS, Duration::from_secs(60));
+
+        sink.send(LogChunk::new(b"hello\n".repeat(20)))
+            .await
+            .unwrap();
+        sink.flush().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let (headers, body) = &requests[2];
+        assert!(headers.contains(&"content-encoding: gzip".to_string()));
+        let mut json = String::new();
+        GzDecoder::new(body.as_slice())
+            .read_to_string(&mut json)
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(body[0]["line"], "hello");
+        assert_eq!(body.as_array().unwrap().len(), 20);
+    }
+
+    fn extractions(args: &[&str]) -> Vec<(String, String)> {
+        args.iter().map(|a| parse_extract(a).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn promotes_extracted_fields() {
+        let (addr, requests) = mock_server(Vec::new());
+        let extractor = Extractor::new(
+            &extractions(&[
+                r"level=\b(INFO|WARN|ERROR)\b",
+                r"request_id=req=(?P<request_id>[0-9a-f]+)",
+            ]),
+            MissingField::Omit,
+        )
+        .unwrap();
+        let mut sink = HttpSink::new(format!("http://{}/ingest", addr), config())
+            .unwrap()
+            .with_extractor(extractor);
+
+        sink.send(LogChunk::new(
+            b"ERROR timeout req=3fa9 upstream\nstarting up\n".to_vec(),
+        ))
+        .await
+        .unwrap();
+        sink.flush().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let records: Vec<serde_json::Value> = requests[0]
+            .1
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        assert_eq!(records[0]["line"], "ERROR timeout req=3fa9 upstream");
+        assert_eq!(records[0]["level"], "ERROR");
+        assert_eq!(records[0]["request_id"], "3fa9");
+        // Nothing matched, so nothing is added
+        let keys: Vec<&String> = records[1].as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["line", "timestamp_ms"]);
+    }
+
+    #[test]
+    fn unmatched_fields_can_be_null() {
+        let extractor = Extractor::new(
+            &extractions(&["user=user=(?P<user>\\w+)"]),
+            MissingField::Null,
+        )
+        .unwrap();
+        let fields = extractor.extract("no user here");
+        assert_eq!(fields.get("user"), Some(&None));
+        let fields = extractor.extract("login user=alice");
+        assert_eq!(fields.get("user"), Some(&Some("alice")));
+    }
+
+    #[test]
+    fn rejects_invalid_extractions() {
+        let error =
+            Extractor::new(&extractions(&["level=(unclosed"]), MissingField::Omit).unwrap_err();
+        assert!(format!("{:#}", error).contains("invalid regex for field 'level'"));
+        for reserved in ["line=.*", "timestamp_ms=.*", "line_number=.*"] {
+            assert!(Extractor::new(&extractions(&[reserved]), MissingField::Omit).is_err());
+        }
+        assert!(Extractor::new(&extractions(&["a=x", "a=y"]), MissingField::Omit).is_err());
+        assert!(parse_extract("no-regex").is_err());
+        assert!(parse_extract("=x").is_err());
+    }
+
+    #[test]
+    fn skips_gzip_for_incompressible_data() {
+        use rand::RngCore;
+
+        let metrics = Arc::new(Metrics::new());
+        let mut gzip = AdaptiveGzip::new(DEFAULT_GZIP_MIN_SAVINGS, Duration::from_secs(60));
+        gzip.metrics = Some(metrics.clone());
+
+        let mut random = vec![0u8; 16 * 1024];
+        rand::thread_rng().fill_bytes(&mut random);
+        let (body, gzipped) = gzip.encode(random.clone()).unwrap();
+        assert!(!gzipped);
+        assert_eq!(body, random);
+        assert_eq!(metrics.compression_skipped.load(Ordering::Relaxed), 1);
+        assert!(metrics.compression_ratio_permille.load(Ordering::Relaxed) > 950);
+
+        // Cooling down: not even compressible data is tried
+        let text = b"the same line again\n".repeat(500);
+        let (body, gzipped) = gzip.encode(text.clone()).unwrap();
+        assert!(!gzipped);
+        assert_eq!(body, text);
+
+        // Once it has passed, compression is attempted again
+        gzip.skip_until = Some(Instant::now());
+        let (body, gzipped) = gzip.encode(text.clone()).unwrap();
+        assert!(gzipped);
+        assert!(body.len() < text.len() / 10);
+        assert!(metrics.compression_ratio_permille.load(Ordering::Relaxed) < 100);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (addr, _requests) = mock_server(vec!["500 Internal Server Error"; 5]);
+        let mut config = config();
+        config.max_reconnect_attempts = Some(2);
+        let mut sink = HttpSink::new(format!("http://{}/ingest", addr), config).unwrap();
+
+        sink.send(LogChunk::new(b"hello\n".to_vec())).await.unwrap();
+        let error = sink.flush().await.unwrap_err();
+        assert!(error.is::<ReconnectExhausted>());
+    }
+}
@@ -0,0 +1,267 @@
+//! journald sink
+//!
+//! Writes every complete line to the local journal over its native
+//! protocol: one datagram per entry on `/run/systemd/journal/socket`,
+//! holding `KEY=value` fields. Values containing a newline use the binary
+//! form, `KEY\n` followed by a little-endian u64 length and the raw value.
+//!
+//! Entries carry `MESSAGE`, `PRIORITY` (from the level found in the line,
+//! `info` otherwise), `SYSLOG_IDENTIFIER` (the project name) and
+//! `LOGLINE_DEVICE`. An entry too large for a single datagram is dropped
+//! with a warning.
+
+use crate::sink::Sink;
+use crate::tail::LogChunk;
+use crate::transform::{Level, DEFAULT_LINE_DELIMITER};
+use anyhow::Result;
+use std::path::Path;
+
+/// Where journald listens for native protocol entries
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Linux errno for a datagram larger than the socket accepts
+#[cfg(target_os = "linux")]
+const EMSGSIZE: i32 = 90;
+
+/// syslog priority of a level; lines without one are logged as info
+fn priority(level: Option<Level>) -> u8 {
+    match level {
+        Some(Level::Error) => 3,
+        Some(Level::Warn) => 4,
+        Some(Level::Info) | None => 6,
+        Some(Level::Debug) | Some(Level::Trace) => 7,
+    }
+}
+
+/// Serialize one journal entry
+fn encode_entry(fields: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut entry = Vec::new();
+    for (name, value) in fields {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains(&b'\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value);
+        entry.push(b'\n');
+    }
+    entry
+}
+
+/// Forwards lines to journald
+pub struct JournaldSink {
+    #[cfg(target_os = "linux")]
+    socket: std::os::unix::net::UnixDatagram,
+    identifier: String,
+    device: String,
+    level_regex: regex::bytes::Regex,
+    delimiter: u8,
+    /// Start of a line whose delimiter hasn't been seen yet
+    partial: Vec<u8>,
+}
+
+impl JournaldSink {
+    /// Connect to the journal socket at `path`. `level_regex` finds the
+    /// level, from its first capture group or the whole match.
+    #[cfg(target_os = "linux")]
+    pub fn connect(
+        path: &Path,
+        identifier: String,
+        device: String,
+        level_regex: &str,
+    ) -> Result<Self> {
+        use anyhow::Context;
+
+        let socket = std::os::unix::net::UnixDatagram::unbound()
+            .context("Failed to create journald socket")?;
+        socket
+            .connect(path)
+            .with_context(|| format!("Failed to connect to journald at {}", path.display()))?;
+        Ok(Self {
+            socket,
+            identifier,
+            device,
+            level_regex: regex::bytes::Regex::new(level_regex)?,
+            delimiter: DEFAULT_LINE_DELIMITER,
+            partial: Vec::new(),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn connect(
+        _path: &Path,
+        _identifier: String,
+        _device: String,
+        _level_regex: &str,
+    ) -> Result<Self> {
+        anyhow::bail!("--sink journald is only supported on Linux")
+    }
+
+    /// Write entries for lines ending in `delimiter` instead of a newline
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    fn level(&self, line: &[u8]) -> Option<Level> {
+        let captures = self.level_regex.captures(line)?;
+        let token = captures.get(1).or_else(|| captures.get(0))?;
+        Level::from_token(token.as_bytes())
+    }
+
+    fn write_line(&self, line: &[u8]) -> Result<()> {
+        let priority = priority(self.level(line)).to_string();
+        let entry = encode_entry(&[
+            ("MESSAGE", line),
+            ("PRIORITY", priority.as_bytes()),
+            ("SYSLOG_IDENTIFIER", self.identifier.as_bytes()),
+            ("LOGLINE_DEVICE", self.device.as_bytes()),
+        ]);
+        self.send_entry(&entry)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_entry(&self, entry: &[u8]) -> Result<()> {
+        use anyhow::Context;
+
+        match self.socket.send(entry) {
+            Ok(_) => Ok(()),
+            // Larger entries would need a memfd, which isn't worth it here
+            Err(e) if e.raw_os_error() == Some(EMSGSIZE) => {
+                tracing::warn!(
+                    "Dropping a {} byte entry, too large for a journald datagram",
+                    entry.len()
+                );
+                Ok(())
+            }
+            Err(e) => Err(e).context("Failed to write to journald"),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send_entry(&self, _entry: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for JournaldSink {
+    async fn send(&mut self, chunk: LogChunk) -> Result<()> {
+        let mut rest = chunk.data.as_slice();
+        while let Some(pos) = rest.iter().position(|&b| b == self.delimiter) {
+            self.partial.extend_from_slice(&rest[..pos]);
+            let line = std::mem::take(&mut self.partial);
+            self.write_line(&line)?;
+            rest = &rest[pos + 1..];
+        }
+        self.partial.extend_from_slice(rest);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        // Entries are written as soon as their line is complete
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        if self.partial.is_empty() {
+            return Ok(());
+        }
+        let line = std::mem::take(&mut self.partial);
+        self.write_line(&line)
+    }
+
+    fn holds_partial(&self) -> bool {
+        !self.partial.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_multiline_values_in_binary_form() {
+        let entry = encode_entry(&[("MESSAGE", b"a\nb"), ("PRIORITY", b"6")]);
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\nPRIORITY=6\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn writes_lines_as_journal_entries() {
+        use crate::transform::DEFAULT_LEVEL_REGEX;
+        use std::os::unix::net::UnixDatagram;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.socket");
+        let journal = UnixDatagram::bind(&path).unwrap();
+        let mut sink = JournaldSink::connect(
+            &path,
+            "payment-service".to_string(),
+            "host-1".to_string(),
+            DEFAULT_LEVEL_REGEX,
+        )
+        .unwrap();
+
+        sink.send(LogChunk::new(b"ERROR disk full\nstar".to_vec()))
+            .await
+            .unwrap();
+        sink.send(LogChunk::new(b"ted\n".to_vec())).await.unwrap();
+
+        let mut buffer = vec![0u8; 4096];
+        let n = journal.recv(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..n],
+            b"MESSAGE=ERROR disk full\nPRIORITY=3\nSYSLOG_IDENTIFIER=payment-service\nLOGLINE_DEVICE=host-1\n"
+        );
+        let n = journal.recv(&mut buffer).unwrap();
+        assert!(buffer[..n].starts_with(b"MESSAGE=started\nPRIORITY=6\n"));
+
+        // A last line without a newline is written once the input ends
+        sink.send(LogChunk::new(b"WARN shutting down".to_vec()))
+            .await
+            .unwrap();
+        sink.flush().await.unwrap();
+        journal.set_nonblocking(true).unwrap();
+        assert!(journal.recv(&mut buffer).is_err());
+        sink.finish().await.unwrap();
+        let n = journal.recv(&mut buffer).unwrap();
+        assert!(buffer[..n].starts_with(b"MESSAGE=WARN shutting down\nPRIORITY=4\n"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn writes_entries_for_the_configured_delimiter() {
+        use crate::transform::DEFAULT_LEVEL_REGEX;
+        use std::os::unix::net::UnixDatagram;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.socket");
+        let journal = UnixDatagram::bind(&path).unwrap();
+        let mut sink = JournaldSink::connect(
+            &path,
+            "payment-service".to_string(),
+            "host-1".to_string(),
+            DEFAULT_LEVEL_REGEX,
+        )
+        .unwrap()
+        .with_delimiter(0);
+
+        sink.send(LogChunk::new(b"WARN a\nb\0".to_vec()))
+            .await
+            .unwrap();
+
+        let mut buffer = vec![0u8; 4096];
+        let n = journal.recv(&mut buffer).unwrap();
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&8u64.to_le_bytes());
+        expected.extend_from_slice(
+            b"WARN a\nb\nPRIORITY=4\nSYSLOG_IDENTIFIER=payment-service\nLOGLINE_DEVICE=host-1\n",
+        );
+        assert_eq!(&buffer[..n], expected);
+    }
+}
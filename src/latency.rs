@@ -0,0 +1,148 @@
+//! End-to-end latency watchdog for `--max-latency-secs`
+//!
+//! Latency runs from the time a chunk was read from the file to the time
+//! it was written to the server socket, so delays added by batching,
+//! coalescing, rate limits and reconnects all count. The sender reports the
+//! chunk it is working on and each chunk it sent; a timer catches sends that
+//! are stuck, so a stall is reported while it lasts rather than after it.
+//!
+//! A breach is logged once. The watchdog re-arms when a chunk goes out
+//! within the limit again.
+
+use crate::metrics::Metrics;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often data in hand is checked while nothing is sent
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Tracks how long shipped data took to leave the agent
+#[derive(Debug)]
+pub struct LatencyWatch {
+    max: Duration,
+    /// Read time of the data the sender holds, in ms since the epoch (0 if none)
+    oldest: AtomicU64,
+    /// Whether the current breach was reported already
+    alerted: AtomicBool,
+    metrics: Arc<Metrics>,
+}
+
+impl LatencyWatch {
+    pub fn new(max: Duration, metrics: Arc<Metrics>) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            oldest: AtomicU64::new(0),
+            alerted: AtomicBool::new(false),
+            metrics,
+        })
+    }
+
+    /// The sender took data read at `read_at` and is about to send it,
+    /// unless it already holds older data
+    pub fn hold(&self, read_at: SystemTime) {
+        let read_at = millis(read_at).max(1);
+        let _ = self
+            .oldest
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |oldest| {
+                (oldest == 0 || read_at < oldest).then_some(read_at)
+            });
+    }
+
+    /// Data read at `read_at` reached the socket. Older data the sender
+    /// still holds stays watched.
+    pub fn sent(&self, read_at: SystemTime) {
+        let _ = self.oldest.compare_exchange(
+            millis(read_at).max(1),
+            0,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        let latency = SystemTime::now()
+            .duration_since(read_at)
+            .unwrap_or_default();
+        self.metrics
+            .ship_latency_ms
+            .store(latency.as_millis() as u64, Ordering::Relaxed);
+        if !self.check(latency, "Shipped data") {
+            self.alerted.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Report `age` if it breaches the limit, once per breach
+    fn check(&self, age: Duration, what: &str) -> bool {
+        if age <= self.max {
+            return false;
+        }
+        if !self.alerted.swap(true, Ordering::Relaxed) {
+            self.metrics
+                .latency_breaches
+                .fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(
+                "{} {:.1}s after it was read, over --max-latency-secs {}",
+                what,
+                age.as_secs_f64(),
+                self.max.as_secs_f64()
+            );
+        }
+        true
+    }
+
+    /// Watch the data in hand until the task is aborted
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            let oldest = self.oldest.load(Ordering::Relaxed);
+            if oldest == 0 {
+                continue;
+            }
+            let age = Duration::from_millis(millis(SystemTime::now()).saturating_sub(oldest));
+            self.check(age, "Unsent data still waiting");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alerts_once_per_breach() {
+        let metrics = Arc::new(Metrics::new());
+        let watch = LatencyWatch::new(Duration::from_secs(2), metrics.clone());
+        let breaches = || metrics.latency_breaches.load(Ordering::Relaxed);
+
+        watch.sent(SystemTime::now());
+        assert_eq!(breaches(), 0);
+
+        let late = SystemTime::now() - Duration::from_secs(5);
+        watch.sent(late);
+        watch.sent(late);
+        assert_eq!(breaches(), 1);
+        assert!(metrics.ship_latency_ms.load(Ordering::Relaxed) >= 5000);
+
+        // Back within the limit re-arms the alert
+        watch.sent(SystemTime::now());
+        watch.sent(late);
+        assert_eq!(breaches(), 2);
+    }
+
+    #[test]
+    fn keeps_watching_older_data_after_a_newer_send() {
+        let metrics = Arc::new(Metrics::new());
+        let watch = LatencyWatch::new(Duration::from_secs(2), metrics);
+        let old = SystemTime::now() - Duration::from_secs(5);
+
+        watch.hold(old);
+        watch.sent(SystemTime::now());
+        assert_eq!(watch.oldest.load(Ordering::Relaxed), millis(old));
+
+        watch.sent(old);
+        assert_eq!(watch.oldest.load(Ordering::Relaxed), 0);
+    }
+}
@@ -13,17 +13,62 @@
 //!   # Specify custom device identifier
 //!   logline-agent --name "payment-service" --server "192.168.1.10:12500" --file "/var/log/payment.log" --device-id "prod-server-01"
 
+mod archive;
+mod backoff;
+mod checkpoint;
+mod config;
 mod connection;
+#[cfg(unix)]
+mod control;
+mod diagnostics;
+mod fifo;
+mod health;
+mod http;
+mod journald;
+mod latency;
+mod memory;
+mod metrics;
 mod protocol;
+mod ratelimit;
+mod replay;
+#[cfg(feature = "sftp")]
+mod sftp;
+mod sink;
 mod tail;
+mod template;
+#[cfg(test)]
+mod testutil;
+mod transform;
 
+use anyhow::Context;
+use checkpoint::{Checkpoint, OffsetAudit, SourceOffset};
 use clap::Parser;
-use connection::{ConnectionConfig, ReconnectingConnection};
+use config::FileConfig;
+use connection::{
+    BootMarker, ConnectDeadlineExceeded, ConnectionConfig, InitialConnectFailed,
+    ReconnectExhausted, ReconnectingConnection, ServerUnresolvable,
+};
+use diagnostics::RecentLines;
+use latency::LatencyWatch;
+use metrics::{Metrics, Summary};
+use protocol::{ExtraPlacement, MAX_PROJECT_NAME_LEN};
+use ratelimit::ByteCap;
+use replay::Replay;
+use sink::{FileSink, NullSink, SinkKind, Tee};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
-use tail::FileTail;
-use tokio::sync::mpsc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tail::{FileTail, LogChunk, RangeSnap};
+use tokio::sync::{mpsc, watch};
+use transform::{
+    AnsiStripper, Coalescer, Deduper, FrameBatcher, HeaderMatch, HeaderSkip, IncludeExclude, Join,
+    LevelFilter, LineNumberRotation, LinePipeline, MalformedPolicy, NdjsonValidator, Prefix,
+    Redact, SampleMode, Sampler, ShipLimit, Transform, TransformKind, DEFAULT_LINE_DELIMITER,
+};
 
 /// Logline Agent - Stream logs to Logline server
 #[derive(Parser, Debug)]
@@ -31,119 +76,2362 @@ use tokio::sync::mpsc;
 #[command(author = "Logline Team")]
 #[command(version = "0.1.0")]
 #[command(about = "Lightweight log streaming agent for Logline", long_about = None)]
+#[command(group(clap::ArgGroup::new("framing").args(["lines_per_frame", "adaptive_batch"]).multiple(true)))]
 struct Args {
-    /// Project/service name identifier
-    #[arg(short, long)]
-    name: String,
+    /// Project/service name; supports {device}, {hostname}, {file} (the
+    /// full path), {file_name} and {file_stem}
+    #[arg(short, long, env = "LOGLINE_NAME")]
+    name: Option<String>,
 
-    /// Logline server address (host:port)
-    #[arg(short, long, default_value = "127.0.0.1:12500")]
-    server: String,
+    /// Logline server address (host:port) [default: 127.0.0.1:12500]
+    #[arg(short, long, env = "LOGLINE_SERVER")]
+    server: Option<String>,
 
-    /// Log file path to monitor
-    #[arg(short, long)]
-    file: PathBuf,
+    /// Log file path to monitor, or sftp://user@host[:port]/path for a
+    /// file on another host (needs the `sftp` feature)
+    #[arg(short, long, env = "LOGLINE_FILE")]
+    file: Option<PathBuf>,
+
+    #[cfg(feature = "sftp")]
+    /// Private key for an sftp:// file (default: keys from ssh-agent)
+    #[arg(long, env = "LOGLINE_SSH_KEY")]
+    ssh_key: Option<PathBuf>,
+
+    #[cfg(feature = "sftp")]
+    /// Passphrase of --ssh-key
+    #[arg(
+        long,
+        requires = "ssh_key",
+        hide_env_values = true,
+        env = "LOGLINE_SSH_KEY_PASSPHRASE"
+    )]
+    ssh_key_passphrase: Option<String>,
+
+    #[cfg(feature = "sftp")]
+    /// Password for an sftp:// file, instead of a key
+    #[arg(
+        long,
+        conflicts_with = "ssh_key",
+        hide_env_values = true,
+        env = "LOGLINE_SSH_PASSWORD"
+    )]
+    ssh_password: Option<String>,
+
+    #[cfg(feature = "sftp")]
+    /// known_hosts file that must list the remote host key
+    /// [default: ~/.ssh/known_hosts]
+    #[arg(long, env = "LOGLINE_SSH_KNOWN_HOSTS")]
+    ssh_known_hosts: Option<PathBuf>,
+
+    /// JSON config file; filters, redaction and tags reload on SIGHUP
+    #[arg(short, long, env = "LOGLINE_CONFIG")]
+    config: Option<PathBuf>,
 
     /// Stream existing file content from beginning
-    #[arg(long, default_value = "false")]
+    #[arg(long, default_value = "false", env = "LOGLINE_FROM_START")]
     from_start: bool,
 
     /// Send last N bytes of existing content (default: 64KB)
-    #[arg(short = 't', long, default_value = "65536")]
+    #[arg(short = 't', long, default_value = "65536", env = "LOGLINE_TAIL_BYTES")]
     tail_bytes: u64,
 
+    /// Start streaming at this byte offset (moved forward to a line start)
+    #[arg(long, conflicts_with_all = ["from_start", "tail_bytes", "start_line"], env = "LOGLINE_START_OFFSET")]
+    start_offset: Option<u64>,
+
+    /// Start streaming at this line number (1-based)
+    #[arg(long, conflicts_with_all = ["from_start", "tail_bytes"], value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_START_LINE")]
+    start_line: Option<u64>,
+
+    /// Only ship bytes START-END of the file (END exclusive, `START-` for
+    /// everything to the end), with --once
+    #[arg(
+        long,
+        value_parser = tail::parse_range,
+        requires = "once",
+        conflicts_with_all = ["from_start", "tail_bytes", "start_offset", "start_line", "checkpoint"],
+        env = "LOGLINE_RANGE"
+    )]
+    range: Option<(u64, Option<u64>)>,
+
+    /// Move the --range ends forward to line starts (`lines`) or use them
+    /// as they are (`bytes`)
+    #[arg(
+        long,
+        value_enum,
+        default_value = "lines",
+        requires = "range",
+        env = "LOGLINE_RANGE_SNAP"
+    )]
+    range_snap: RangeSnap,
+
+    /// Ship the file up to its current end and exit; `.gz`/`.zst` archives
+    /// are decoded
+    #[arg(long, default_value = "false", env = "LOGLINE_ONCE")]
+    once: bool,
+
+    /// Release lines at the pace of their timestamps (with --from-start --once)
+    #[arg(long, default_value = "false", requires_all = ["from_start", "once"], env = "LOGLINE_REPLAY")]
+    replay: bool,
+
+    /// Replay this many times faster than real time
+    #[arg(long, default_value = "1", value_parser = replay::parse_speed, requires = "replay", env = "LOGLINE_REPLAY_SPEED")]
+    replay_speed: f64,
+
+    /// Regex locating a line's timestamp, from its first capture group
+    #[arg(long, default_value = replay::DEFAULT_TIMESTAMP_REGEX, requires = "replay", env = "LOGLINE_TIMESTAMP_REGEX")]
+    timestamp_regex: String,
+
+    /// Send no existing content at all, only data appended after the watch
+    /// starts; a checkpoint is not resumed either
+    #[arg(long, default_value = "false", conflicts_with_all = ["from_start", "tail_bytes", "start_offset", "start_line"], env = "LOGLINE_NO_INITIAL_SEND")]
+    no_initial_send: bool,
+
+    /// Drop the lines at the start of the file until one matches this
+    /// regex, e.g. a banner or column definitions before the first entry
+    /// (line mode, with --from-start)
+    #[arg(long, env = "LOGLINE_SKIP_HEADER_UNTIL")]
+    skip_header_until: Option<String>,
+
+    /// Whether the line matching --skip-header-until is sent (`keep`) or
+    /// dropped with the header (`drop`)
+    #[arg(
+        long,
+        value_enum,
+        default_value = "keep",
+        requires = "skip_header_until",
+        env = "LOGLINE_SKIP_HEADER_MATCH"
+    )]
+    skip_header_match: HeaderMatch,
+
+    /// Skip the header of each file that replaces the current one after a
+    /// rotation or truncation, too
+    #[arg(
+        long,
+        default_value = "false",
+        requires = "skip_header_until",
+        env = "LOGLINE_SKIP_HEADER_ON_ROTATION"
+    )]
+    skip_header_on_rotation: bool,
+
+    /// Where to ship data: the Logline server, an HTTP endpoint, journald,
+    /// a local file or nowhere
+    #[arg(long, value_enum, default_value = "logline", env = "LOGLINE_SINK")]
+    sink: SinkKind,
+
+    /// Output file for `--sink file` (default: stdout, with logs moved to
+    /// stderr)
+    #[arg(long, env = "LOGLINE_SINK_PATH")]
+    sink_path: Option<PathBuf>,
+
+    /// Also print every complete line to stdout as it is shipped, after
+    /// transforms and redaction
+    #[arg(long, default_value = "false", env = "LOGLINE_STDOUT_TEE")]
+    stdout_tee: bool,
+
+    /// Endpoint for `--sink http`
+    #[arg(long, required_if_eq("sink", "http"), env = "LOGLINE_URL")]
+    url: Option<String>,
+
+    /// Body layout for `--sink http`
+    #[arg(
+        long,
+        value_enum,
+        default_value = "ndjson",
+        env = "LOGLINE_HTTP_FORMAT"
+    )]
+    http_format: http::HttpFormat,
+
+    /// Add a top-level JSON field to every record of `--sink http`
+    /// (FIELD=REGEX, repeatable); the value is the capture group named
+    /// FIELD, or the whole match
+    #[arg(long, value_parser = http::parse_extract, env = "LOGLINE_EXTRACT")]
+    extract: Vec<(String, String)>,
+
+    /// What an --extract field becomes when its regex doesn't match a line
+    #[arg(
+        long,
+        value_enum,
+        default_value = "omit",
+        env = "LOGLINE_EXTRACT_MISSING"
+    )]
+    extract_missing: http::MissingField,
+
+    /// Gzip request bodies for `--sink http`
+    #[arg(long, default_value = "false", env = "LOGLINE_HTTP_GZIP")]
+    http_gzip: bool,
+
+    /// Send a body uncompressed unless gzip shrinks it by this many percent
+    #[arg(long, default_value_t = http::DEFAULT_GZIP_MIN_SAVINGS, value_parser = clap::value_parser!(u8).range(0..=100), requires = "http_gzip", env = "LOGLINE_HTTP_GZIP_MIN_SAVINGS")]
+    http_gzip_min_savings: u8,
+
+    /// How long to stop trying gzip once it keeps falling short
+    #[arg(
+        long,
+        default_value = "300",
+        requires = "http_gzip",
+        env = "LOGLINE_HTTP_GZIP_COOLDOWN_SECS"
+    )]
+    http_gzip_cooldown_secs: u64,
+
+    /// Lines per request for `--sink http`
+    #[arg(long, default_value_t = http::DEFAULT_BATCH_LINES, env = "LOGLINE_HTTP_BATCH_LINES")]
+    http_batch_lines: usize,
+
+    /// Keep a hard link to the log file in this directory (same filesystem)
+    /// so a file unlinked during rotation can still be drained
+    #[arg(long, env = "LOGLINE_HARDLINK_GUARD")]
+    hardlink_guard: Option<PathBuf>,
+
+    /// Prefix each data frame with the time it was read (protocol v2)
+    #[arg(long, default_value = "false", env = "LOGLINE_TIMESTAMP_FRAMES")]
+    timestamp_frames: bool,
+
+    /// Require the server to acknowledge the handshake and stop with an
+    /// error if it speaks a newer protocol version than the agent
+    #[arg(long, default_value = "false", env = "LOGLINE_REQUIRE_HANDSHAKE_ACK")]
+    require_handshake_ack: bool,
+
+    /// Local address (IP or IP:PORT) to send from, e.g. a management interface
+    #[arg(long, value_parser = connection::parse_bind_addr, env = "LOGLINE_BIND_ADDR")]
+    bind_addr: Option<std::net::SocketAddr>,
+
+    /// Advertise optional features in the handshake and only use those the
+    /// server acknowledges (falls back to protocol v1 if it doesn't reply)
+    #[arg(long, default_value = "false", env = "LOGLINE_NEGOTIATE")]
+    negotiate: bool,
+
+    /// Validate that every line is JSON (NDJSON logs)
+    #[arg(long, default_value = "false", env = "LOGLINE_NDJSON")]
+    ndjson: bool,
+
+    /// What to do with lines that are not valid JSON
+    #[arg(
+        long,
+        value_enum,
+        default_value = "drop",
+        requires = "ndjson",
+        env = "LOGLINE_NDJSON_MALFORMED"
+    )]
+    ndjson_malformed: MalformedPolicy,
+
+    /// Drop lines below this severity (line mode)
+    #[arg(long, value_enum, env = "LOGLINE_MIN_LEVEL")]
+    min_level: Option<transform::Level>,
+
+    /// Regex extracting the level from a line, from its first capture group
+    #[arg(long, default_value = transform::DEFAULT_LEVEL_REGEX, requires = "min_level", env = "LOGLINE_LEVEL_REGEX")]
+    level_regex: String,
+
+    /// What to do with lines in which no level is found
+    #[arg(
+        long,
+        value_enum,
+        default_value = "keep",
+        requires = "min_level",
+        env = "LOGLINE_UNLEVELED"
+    )]
+    unleveled: transform::UnleveledPolicy,
+
+    /// Order of the line stages; stages that aren't enabled are skipped
+    /// and enabled ones missing from the list don't run
+    #[arg(long, value_enum, value_delimiter = ',', default_value = transform::DEFAULT_TRANSFORMS, env = "LOGLINE_TRANSFORMS")]
+    transforms: Vec<TransformKind>,
+
+    /// Remove ANSI color and control sequences from lines (line mode)
+    #[arg(long, default_value = "false", env = "LOGLINE_STRIP_ANSI")]
+    strip_ansi: bool,
+
+    /// Collapse consecutive identical lines into a repeat count (line mode)
+    #[arg(long, default_value = "false", env = "LOGLINE_DEDUPE")]
+    dedupe: bool,
+
+    /// Longest time a repeat count is held back before being reported
+    #[arg(long, default_value = "5000", value_parser = clap::value_parser!(u64).range(1..), requires = "dedupe", env = "LOGLINE_DEDUPE_WINDOW_MS")]
+    dedupe_window_ms: u64,
+
+    /// Prefix for every line; supports {device}, {file} (the full path) and
+    /// {agent_id}
+    #[arg(long, env = "LOGLINE_LINE_PREFIX")]
+    line_prefix: Option<String>,
+
+    /// Flatten records that span several lines into one, joining them with
+    /// this separator (line mode). Escapes \n, \r, \t, \0, \\ and \xNN
+    /// are expanded
+    #[arg(
+        long,
+        visible_alias = "flatten-multiline-with",
+        value_parser = transform::parse_separator,
+        env = "LOGLINE_MULTILINE_JOIN"
+    )]
+    // Spelled out: clap takes a bare `Vec<u8>` for a list of `u8` values
+    // rather than one parsed value
+    multiline_join: Option<std::vec::Vec<u8>>,
+
+    /// Send a marker line as the first data of every connection
+    #[arg(long, default_value = "false", env = "LOGLINE_BOOT_MARKER")]
+    boot_marker: bool,
+
+    /// Text of the --boot-marker line; supports {device}, {file},
+    /// {agent_id} and {offset} (the read offset when connecting)
+    #[arg(
+        long,
+        default_value = connection::DEFAULT_BOOT_MARKER,
+        requires = "boot_marker",
+        env = "LOGLINE_BOOT_MARKER_TEMPLATE"
+    )]
+    boot_marker_template: String,
+
+    /// In line mode, longer lines are sent as truncated fragments
+    #[arg(long, default_value_t = transform::DEFAULT_MAX_LINE_BYTES, env = "LOGLINE_MAX_LINE_BYTES")]
+    max_line_bytes: usize,
+
+    /// Byte that ends a line: newline, nul or byte:0xNN (e.g. byte:0x1e).
+    /// Splits lines in line mode and places line starts when seeking
+    #[arg(
+        long,
+        default_value = "newline",
+        value_parser = transform::parse_line_delimiter,
+        env = "LOGLINE_LINE_DELIMITER"
+    )]
+    line_delimiter: u8,
+
+    /// Send lines longer than this many bytes as several `[part i/n] `
+    /// prefixed lines, each no longer than this with its marker, that the
+    /// server can join back together (line mode). Lines are still cut at
+    /// --max-line-bytes first
+    #[arg(long, value_parser = clap::value_parser!(u64).range(32..), env = "LOGLINE_SPLIT_LARGE_LINES")]
+    split_large_lines: Option<u64>,
+
+    /// Add each line's source line number to HTTP sink records (line mode),
+    /// counting complete lines from where the tail started
+    #[arg(long, default_value = "false", env = "LOGLINE_LINE_NUMBERS")]
+    line_numbers: bool,
+
+    /// Whether --line-numbers starts over from 1 when the file is rotated
+    /// or truncated, or keeps counting
+    #[arg(
+        long,
+        value_enum,
+        default_value = "reset",
+        requires = "line_numbers",
+        env = "LOGLINE_LINE_NUMBERS_ON_ROTATION"
+    )]
+    line_numbers_on_rotation: LineNumberRotation,
+
+    /// Send at most this many complete lines per frame (line mode)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_LINES_PER_FRAME")]
+    lines_per_frame: Option<u64>,
+
+    /// Byte cap for a frame built with --lines-per-frame; a longer line is sent alone
+    #[arg(long, default_value_t = transform::DEFAULT_FRAME_BYTES, requires = "lines_per_frame", conflicts_with = "adaptive_batch", env = "LOGLINE_FRAME_BYTES")]
+    frame_bytes: usize,
+
+    /// Grow the frame byte target while the sender is backlogged and shrink it when traffic is sparse (line mode)
+    #[arg(long, default_value = "false", env = "LOGLINE_ADAPTIVE_BATCH")]
+    adaptive_batch: bool,
+
+    /// Lower bound of the adaptive frame byte target
+    #[arg(long, default_value_t = transform::DEFAULT_BATCH_MIN_BYTES, requires = "adaptive_batch", env = "LOGLINE_BATCH_MIN_BYTES")]
+    batch_min_bytes: usize,
+
+    /// Upper bound of the adaptive frame byte target
+    #[arg(long, default_value_t = transform::DEFAULT_BATCH_MAX_BYTES, requires = "adaptive_batch", env = "LOGLINE_BATCH_MAX_BYTES")]
+    batch_max_bytes: usize,
+
+    /// Longest time a partial frame waits for more lines
+    #[arg(
+        long,
+        default_value = "100",
+        requires = "framing",
+        env = "LOGLINE_FRAME_LINGER_MS"
+    )]
+    frame_linger_ms: u64,
+
+    /// Send at most once per this many milliseconds, combining everything
+    /// that arrives in between
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_MIN_SEND_INTERVAL_MS")]
+    min_send_interval_ms: Option<u64>,
+
+    /// Reopen the file after a stale handle (ESTALE) or repeated read errors
+    #[arg(long, default_value = "true", action = clap::ArgAction::Set, env = "LOGLINE_REOPEN_ON_ERROR")]
+    reopen_on_error: bool,
+
+    /// What to do when the file is truncated in place
+    #[arg(long, value_enum, default_value = "reset", env = "LOGLINE_ON_TRUNCATE")]
+    on_truncate: tail::TruncatePolicy,
+
+    /// What to do with a byte order mark at the start of the file
+    #[arg(long, value_enum, default_value = "keep", env = "LOGLINE_BOM")]
+    bom: tail::BomPolicy,
+
+    /// Replace invalid UTF-8 with U+FFFD instead of sending bytes verbatim
+    #[arg(long, default_value = "false", env = "LOGLINE_LOSSY_UTF8")]
+    lossy_utf8: bool,
+
+    /// Socket write timeout in seconds
+    #[arg(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_WRITE_TIMEOUT_SECS")]
+    write_timeout_secs: u64,
+
+    /// Reconnect when a single send takes longer than this many milliseconds
+    #[arg(long, env = "LOGLINE_SEND_LATENCY_THRESHOLD_MS")]
+    send_latency_threshold_ms: Option<u64>,
+
+    /// Reconnect when a frame can't be fully written within this many seconds
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_MAX_SEND_LAG_SECS")]
+    max_send_lag_secs: Option<u64>,
+
+    /// Warn when data is not written to the server within this many
+    /// seconds of being read, counting every stage in between
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_MAX_LATENCY_SECS")]
+    max_latency_secs: Option<u64>,
+
+    /// Steady-state throughput cap in bytes per second (0 = unlimited)
+    #[arg(long, env = "LOGLINE_MAX_BYTES_PER_SEC")]
+    max_bytes_per_sec: Option<u64>,
+
+    /// Throughput cap while sending the startup backlog (0 = unlimited;
+    /// defaults to --max-bytes-per-sec)
+    #[arg(long, env = "LOGLINE_BACKFILL_BYTES_PER_SEC")]
+    backfill_bytes_per_sec: Option<u64>,
+
+    /// Send at most this many bytes per hour, then stop until the hour is up
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_HOURLY_BYTE_CAP")]
+    hourly_byte_cap: Option<u64>,
+
+    /// Send at most this many bytes per day, then stop until the day is up
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_DAILY_BYTE_CAP")]
+    daily_byte_cap: Option<u64>,
+
+    /// What to do with data once a byte cap is reached
+    #[arg(long, value_enum, default_value = "hold", env = "LOGLINE_ON_BYTE_CAP")]
+    on_byte_cap: ratelimit::CapAction,
+
+    /// Serve /livez and /readyz health checks on this address (e.g. 0.0.0.0:8081)
+    #[arg(long, env = "LOGLINE_HEALTH_ADDR")]
+    health_addr: Option<std::net::SocketAddr>,
+
+    /// Accept pause/resume/status commands on this Unix socket
+    #[arg(long, env = "LOGLINE_CONTROL_SOCKET")]
+    control_socket: Option<PathBuf>,
+
+    /// Stop after shipping this many lines (line mode)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_MAX_LINES")]
+    max_lines: Option<u64>,
+
+    /// Stop after shipping this many bytes
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_MAX_BYTES")]
+    max_bytes: Option<u64>,
+
+    /// Print the shutdown summary as a JSON line on stdout
+    #[arg(long, default_value = "false", env = "LOGLINE_SUMMARY_JSON")]
+    summary_json: bool,
+
+    /// Connect once, print the server's protocol version and the features
+    /// it agrees to, then exit
+    #[arg(long, default_value = "false", env = "LOGLINE_PROBE_SERVER_VERSION")]
+    probe_server_version: bool,
+
+    /// Print the --probe-server-version result as a JSON line
+    #[arg(
+        long,
+        default_value = "false",
+        requires = "probe_server_version",
+        env = "LOGLINE_PROBE_JSON"
+    )]
+    probe_json: bool,
+
+    /// Number of recently shipped lines kept for crash diagnostics (0 = off)
+    #[arg(long, default_value_t = diagnostics::DEFAULT_RECENT_LINES, env = "LOGLINE_RECENT_LINES")]
+    recent_lines: usize,
+
+    /// Write crash diagnostics here instead of stderr
+    #[arg(long, env = "LOGLINE_DIAGNOSTICS_FILE")]
+    diagnostics_file: Option<PathBuf>,
+
+    /// Checkpoint file for resuming after a restart; a valid checkpoint
+    /// takes precedence over --from-start and --tail-bytes
+    #[arg(long, env = "LOGLINE_CHECKPOINT")]
+    checkpoint: Option<PathBuf>,
+
+    /// When resuming from --checkpoint, check that the bytes before the
+    /// saved offset are still the same and start fresh if they aren't
+    #[arg(long, default_value = "true", action = clap::ArgAction::Set, env = "LOGLINE_RESUME_VERIFY")]
+    resume_verify: bool,
+
+    /// Fastest file poll interval, used while data is arriving
+    #[arg(long, default_value_t = tail::DEFAULT_MIN_POLL_MS, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_MIN_POLL_MS")]
+    min_poll_ms: u64,
+
+    /// Slowest file poll interval, reached by backing off while the file is idle
+    #[arg(long, default_value_t = tail::DEFAULT_MAX_POLL_MS, env = "LOGLINE_MAX_POLL_MS")]
+    max_poll_ms: u64,
+
+    /// Identify the source by the symlink path rather than its target, so
+    /// the agent ID stays stable when the link is repointed (the tail
+    /// switches to the new target after draining the old one)
+    #[arg(long, default_value = "false", env = "LOGLINE_FOLLOW_SYMLINK")]
+    follow_symlink: bool,
+
+    /// Suffixes of rotated file names (e.g. `app.log.1`, `app.1`) searched
+    /// for the checkpointed file when it was rotated while stopped
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = ".1",
+        env = "LOGLINE_ROTATED_SUFFIXES"
+    )]
+    rotated_suffixes: Vec<String>,
+
+    /// Rotated files (named with --rotated-suffixes) that appeared unread
+    /// between two checks are drained before the live file; at most this
+    /// many are queued at once (0 = off)
+    #[arg(long, default_value = "8", env = "LOGLINE_MAX_ROTATION_BACKLOG")]
+    max_rotation_backlog: usize,
+
+    /// Once the file has been removed for this long, send a SourceClosed frame and exit
+    #[arg(long, env = "LOGLINE_SOURCE_CLOSE_GRACE_SECS")]
+    source_close_grace_secs: Option<u64>,
+
+    /// On Ctrl+C or SIGTERM, keep sending what was already read for up to this long
+    /// before exiting (0 = exit immediately)
+    #[arg(long, default_value = "5", env = "LOGLINE_DRAIN_TIMEOUT_SECS")]
+    drain_timeout_secs: u64,
+
+    /// Send file size, mtime and read offset to the server this often
+    /// and after rotation, once it agreed to them with --negotiate
+    /// (0 = never)
+    #[arg(long, default_value = "30", env = "LOGLINE_META_INTERVAL_SECS")]
+    meta_interval_secs: u64,
+
+    /// How often the checkpoint file is written
+    #[arg(long, default_value = "5", env = "LOGLINE_CHECKPOINT_INTERVAL_SECS")]
+    checkpoint_interval_secs: u64,
+
+    /// Append the read offset and bytes sent to this file every
+    /// --offset-audit-interval-secs and on shutdown
+    #[arg(long, env = "LOGLINE_OFFSET_AUDIT_LOG")]
+    offset_audit_log: Option<PathBuf>,
+
+    /// How often an entry is appended to the offset audit log
+    #[arg(long, default_value = "60", value_parser = clap::value_parser!(u64).range(1..), requires = "offset_audit_log", env = "LOGLINE_OFFSET_AUDIT_INTERVAL_SECS")]
+    offset_audit_interval_secs: u64,
+
+    /// Cap the total backlog sent on startup, whatever the start mode
+    #[arg(long, env = "LOGLINE_MAX_INITIAL_BYTES")]
+    max_initial_bytes: Option<u64>,
+
     /// Verbose logging
-    #[arg(short, long, default_value = "false")]
+    #[arg(short, long, default_value = "false", env = "LOGLINE_VERBOSE")]
     verbose: bool,
 
+    /// Only log warnings and errors
+    #[arg(short, long, conflicts_with = "verbose", env = "LOGLINE_QUIET")]
+    quiet: bool,
+
+    /// Restart a failed file watcher this many times before exiting
+    #[arg(long, default_value = "5", env = "LOGLINE_MAX_WATCHER_RESTARTS")]
+    max_watcher_restarts: u32,
+
+    /// Exit with code 3 after this many consecutive failed connection attempts
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), env = "LOGLINE_MAX_RECONNECT_ATTEMPTS")]
+    max_reconnect_attempts: Option<u32>,
+
+    /// Exit with code 3 once reconnecting has taken this many seconds in
+    /// total, whichever comes first with --max-reconnect-attempts
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_CONNECT_DEADLINE_SECS")]
+    connect_deadline_secs: Option<u64>,
+
+    /// Add up to this many milliseconds at random to each attempt's 10s
+    /// connection timeout, so agents don't time out in lockstep
+    #[arg(
+        long,
+        default_value = "1000",
+        env = "LOGLINE_CONNECT_TIMEOUT_JITTER_MS"
+    )]
+    connect_timeout_jitter_ms: u64,
+
+    /// Retry this many times without waiting when the server resets the
+    /// connection during the handshake or on the first send after it,
+    /// then back off as usual
+    #[arg(long, default_value = "0", env = "LOGLINE_HANDSHAKE_RETRY_ON_RESET")]
+    handshake_retry_on_reset: u32,
+
+    /// Close the connection (after a Goodbye frame, if the server agreed to
+    /// them) once no data has been sent for this many seconds, and
+    /// reconnect when new data arrives
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_IDLE_DISCONNECT_SECS")]
+    idle_disconnect_secs: Option<u64>,
+
+    /// Exit with code 2 after this many consecutive attempts where the
+    /// server name did not resolve
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), env = "LOGLINE_MAX_DNS_FAILURES")]
+    max_dns_failures: Option<u32>,
+
+    /// Append the exact bytes of every frame sent to this file
+    #[arg(long, env = "LOGLINE_CAPTURE_FILE")]
+    capture_file: Option<PathBuf>,
+
+    /// Size at which the capture file is moved to `<file>.1` and restarted
+    #[arg(long, default_value_t = diagnostics::DEFAULT_CAPTURE_MAX_BYTES, requires = "capture_file", env = "LOGLINE_CAPTURE_MAX_BYTES")]
+    capture_max_bytes: u64,
+
+    /// Keep this many bytes of recently sent data in memory and resend it
+    /// after a failed send, so a brief disconnect loses nothing (0 = off)
+    #[arg(long, default_value = "0", env = "LOGLINE_MEMORY_RETRY_BYTES")]
+    memory_retry_bytes: usize,
+
+    /// Stop reading the file while this many bytes are buffered in the
+    /// agent's queues and retry buffer
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_MAX_MEMORY_BYTES")]
+    max_memory_bytes: Option<u64>,
+
+    /// Exit with code 3 if the first connection attempt fails, instead of
+    /// retrying; later disconnects are still retried. Also exit when the
+    /// log file can't be read for lack of permission
+    #[arg(long, default_value = "false", env = "LOGLINE_FAIL_FAST")]
+    fail_fast: bool,
+
+    /// How the reconnect delay grows between failed attempts
+    #[arg(
+        long,
+        value_enum,
+        default_value = "exponential",
+        env = "LOGLINE_BACKOFF"
+    )]
+    backoff: backoff::BackoffCurve,
+
+    /// First reconnect delay in seconds (the step for linear, the delay for fixed)
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_BACKOFF_BASE_SECS")]
+    backoff_base_secs: u64,
+
+    /// Longest reconnect delay in seconds
+    #[arg(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..), env = "LOGLINE_BACKOFF_MAX_SECS")]
+    backoff_max_secs: u64,
+
     /// Device identifier (defaults to hostname)
-    #[arg(short = 'd', long)]
+    #[arg(short = 'd', long, env = "LOGLINE_DEVICE_ID")]
     device_id: Option<String>,
+
+    /// Session tag sent in the handshake (KEY=VALUE, repeatable)
+    #[arg(long = "tag", value_parser = parse_tag, env = "LOGLINE_TAG")]
+    tags: Vec<(String, String)>,
+
+    /// JSON file whose object is added to the handshake (cluster id,
+    /// tenant, custom attributes)
+    #[arg(long, env = "LOGLINE_HANDSHAKE_EXTRA")]
+    handshake_extra: Option<PathBuf>,
+
+    /// Send the --handshake-extra fields under an `extra` object or next
+    /// to the built-in fields
+    #[arg(
+        long,
+        value_enum,
+        default_value = "nested",
+        requires = "handshake_extra",
+        env = "LOGLINE_HANDSHAKE_EXTRA_PLACEMENT"
+    )]
+    handshake_extra_placement: ExtraPlacement,
+
+    /// Keep only every Nth line (1/N, lossy)
+    #[arg(long, value_parser = transform::parse_sample, conflicts_with = "sample_rate", env = "LOGLINE_SAMPLE")]
+    sample: Option<u64>,
+
+    /// Keep each line with the given probability (0, 1] (lossy)
+    #[arg(long, value_parser = transform::parse_sample_rate, env = "LOGLINE_SAMPLE_RATE")]
+    sample_rate: Option<f64>,
+}
+
+impl Args {
+    /// Whether the file sink writes shipped data to stdout
+    fn sink_on_stdout(&self) -> bool {
+        self.sink == SinkKind::File && self.sink_path.is_none()
+    }
+
+    /// Whether shipped data is written to stdout, by the sink or
+    /// --stdout-tee, leaving it to the data
+    fn data_on_stdout(&self) -> bool {
+        self.sink_on_stdout() || self.stdout_tee
+    }
+
+    /// Whether any option requires splitting the stream into lines
+    fn line_mode(&self) -> bool {
+        self.config.is_some()
+            || self.sample.is_some()
+            || self.sample_rate.is_some()
+            || self.line_prefix.is_some()
+            || self.ndjson
+            || self.min_level.is_some()
+            || self.strip_ansi
+            || self.dedupe
+            || self.lines_per_frame.is_some()
+            || self.adaptive_batch
+            || self.replay
+            || self.max_lines.is_some()
+            || self.split_large_lines.is_some()
+            || self.line_numbers
+            || self.multiline_join.is_some()
+            || self.skip_header_until.is_some()
+    }
+}
+
+const DEFAULT_SERVER: &str = "127.0.0.1:12500";
+
+fn local_hostname() -> String {
+    hostname::get()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Expand `{device}`, `{hostname}`, `{file}`, `{file_name}` and
+/// `{file_stem}` in the project name and check the result can be sent in a
+/// handshake. `{file}` is the full path, as in the other templates.
+fn expand_project_name(template: &str, device_id: &str, file: &Path) -> anyhow::Result<String> {
+    let hostname = local_hostname();
+    let path = file.to_string_lossy();
+    let file_stem = file
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default();
+    let file_name = file
+        .file_name()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default();
+
+    let name = template::expand(
+        template,
+        &[
+            ("device", device_id),
+            ("hostname", &hostname),
+            ("file", &path),
+            ("file_name", &file_name),
+            ("file_stem", &file_stem),
+        ],
+    )
+    .map_err(anyhow::Error::msg)
+    .context("Invalid --name")?;
+
+    if name.trim().is_empty() {
+        anyhow::bail!("Project name '{}' expands to an empty name", template);
+    }
+    if name.len() > MAX_PROJECT_NAME_LEN {
+        anyhow::bail!(
+            "Project name is {} bytes after expansion, the maximum is {}",
+            name.len(),
+            MAX_PROJECT_NAME_LEN
+        );
+    }
+
+    Ok(name)
+}
+
+/// Absolute path of a symlink itself, with only its directory resolved,
+/// so the source identity survives the link being repointed
+fn symlink_source_path(file: &Path) -> PathBuf {
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match (dir.canonicalize(), file.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => file.to_path_buf(),
+    }
+}
+
+/// Where to resume from a saved offset: the offset, and the rotated file
+/// to drain first if the checkpointed file was renamed while stopped.
+/// `None` when the checkpoint doesn't belong to the file on disk.
+fn resume_point(
+    saved: &SourceOffset,
+    file: &Path,
+    rotated_suffixes: &[String],
+    verify: bool,
+) -> anyhow::Result<Option<(Option<PathBuf>, u64)>> {
+    let size = std::fs::metadata(file)?.len();
+    let rotated = if tail::inode_of(file)? == saved.inode && saved.offset <= size {
+        None
+    } else if let Some(rotated) = tail::find_rotated(file, saved.inode, rotated_suffixes) {
+        tracing::info!(
+            "Checkpoint refers to rotated file {}, draining it first",
+            rotated.display()
+        );
+        Some(rotated)
+    } else {
+        tracing::info!("Checkpoint refers to a different file, starting fresh");
+        return Ok(None);
+    };
+
+    // The inode can be reused by a file replaced in a deploy
+    let path = rotated.as_deref().unwrap_or(file);
+    if verify && !saved.verify(path)? {
+        tracing::warn!(
+            "Checkpoint invalidated: {} no longer has the content before offset {}, starting fresh",
+            path.display(),
+            saved.offset
+        );
+        return Ok(None);
+    }
+    Ok(Some((rotated, saved.offset)))
+}
+
+/// What `--file` points at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SourceKind {
+    /// A regular file, possibly behind a symlink
+    File,
+    /// A named pipe
+    Fifo,
+    /// A file on another host, read over SFTP
+    #[cfg(feature = "sftp")]
+    Sftp,
+}
+
+/// Source kind of a `--file` that names a remote file
+#[cfg(feature = "sftp")]
+fn remote_source(file: &Path) -> Result<Option<SourceKind>, AgentError> {
+    Ok(sftp::is_url(file).then_some(SourceKind::Sftp))
+}
+
+#[cfg(not(feature = "sftp"))]
+fn remote_source(file: &Path) -> Result<Option<SourceKind>, AgentError> {
+    if file.to_string_lossy().starts_with("sftp://") {
+        return Err(AgentError::Config(anyhow::anyhow!(
+            "sftp:// files need an agent built with the `sftp` feature"
+        )));
+    }
+    Ok(None)
+}
+
+/// Build the reader for an sftp:// `file` from the SSH flags
+#[cfg(feature = "sftp")]
+fn sftp_source(args: &Args, file: &Path) -> anyhow::Result<sftp::SftpSource> {
+    // These need the file on the local disk
+    let local_only = [
+        ("--checkpoint", args.checkpoint.is_some()),
+        ("--start-offset", args.start_offset.is_some()),
+        ("--start-line", args.start_line.is_some()),
+        ("--range", args.range.is_some()),
+        ("--max-initial-bytes", args.max_initial_bytes.is_some()),
+    ];
+    if let Some((flag, _)) = local_only.iter().find(|(_, set)| *set) {
+        anyhow::bail!("{} is not supported for an sftp:// file", flag);
+    }
+
+    let url = sftp::SftpUrl::parse(&file.to_string_lossy()).map_err(anyhow::Error::msg)?;
+    let auth = match (&args.ssh_key, &args.ssh_password) {
+        (Some(path), _) => sftp::SshAuth::Key {
+            path: path.clone(),
+            passphrase: args.ssh_key_passphrase.clone(),
+        },
+        (None, Some(password)) => sftp::SshAuth::Password(password.clone()),
+        (None, None) => sftp::SshAuth::Agent,
+    };
+    let known_hosts = match &args.ssh_known_hosts {
+        Some(path) => path.clone(),
+        None => std::env::var_os("HOME")
+            .map(|home| Path::new(&home).join(".ssh").join("known_hosts"))
+            .context("HOME is not set, pass --ssh-known-hosts")?,
+    };
+    tracing::info!(
+        "  Source is remote, polling it over SFTP (host keys from {})",
+        known_hosts.display()
+    );
+
+    let backoff = backoff::Backoff::new(
+        args.backoff,
+        Duration::from_secs(args.backoff_base_secs),
+        Duration::from_secs(args.backoff_max_secs),
+    );
+    let mut source = sftp::SftpSource::new(url, auth, known_hosts, backoff).with_poll_interval(
+        Duration::from_millis(args.min_poll_ms),
+        Duration::from_millis(args.max_poll_ms),
+    );
+    if args.no_initial_send {
+        source = source.with_backfill(0, args.line_delimiter);
+    } else if !args.from_start {
+        source = source.with_backfill(args.tail_bytes, args.line_delimiter);
+    }
+    if args.once {
+        source = source.once();
+    }
+    Ok(source)
+}
+
+/// Check that `--multiline-join` can do anything with `delimiter`
+fn validate_multiline_join(separator: &[u8], delimiter: u8) -> anyhow::Result<()> {
+    // Newline-delimited lines hold no line breaks to replace
+    if delimiter == DEFAULT_LINE_DELIMITER {
+        anyhow::bail!("--multiline-join needs a --line-delimiter other than newline");
+    }
+    // A joined record would be split again at the separator
+    if separator.contains(&delimiter) {
+        anyhow::bail!(
+            "--multiline-join separator contains the line delimiter {:#04x}",
+            delimiter
+        );
+    }
+    Ok(())
+}
+
+/// Check that `file` is something that can be tailed, rather than letting
+/// a directory or a device fail in confusing ways later on
+fn validate_source(file: &Path) -> Result<SourceKind, AgentError> {
+    let metadata = match std::fs::metadata(file) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(AgentError::FileNotFound(file.to_path_buf()))
+        }
+        Err(e) => {
+            return Err(AgentError::Config(
+                anyhow::Error::new(e).context(format!("Cannot inspect {}", file.display())),
+            ))
+        }
+    };
+    if metadata.is_file() {
+        return Ok(SourceKind::File);
+    }
+    if fifo::is_fifo(file) {
+        return Ok(SourceKind::Fifo);
+    }
+
+    let kind = if metadata.is_dir() {
+        "a directory"
+    } else {
+        special_file_kind(&metadata.file_type())
+    };
+    Err(AgentError::Config(anyhow::anyhow!(
+        "{} is {}; only regular files and named pipes can be shipped",
+        file.display(),
+        kind
+    )))
+}
+
+#[cfg(unix)]
+fn special_file_kind(file_type: &std::fs::FileType) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_block_device() {
+        "a block device"
+    } else if file_type.is_char_device() {
+        "a character device"
+    } else if file_type.is_socket() {
+        "a socket"
+    } else {
+        "not a regular file"
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file_kind(_file_type: &std::fs::FileType) -> &'static str {
+    "not a regular file"
+}
+
+/// Parse a `KEY=VALUE` tag argument
+fn parse_tag(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid tag '{}', expected KEY=VALUE", s)),
+    }
+}
+
+/// Failures reported with a dedicated exit code
+#[derive(Debug, thiserror::Error)]
+enum AgentError {
+    /// Invalid configuration or arguments
+    #[error(transparent)]
+    Config(anyhow::Error),
+    /// The log file to monitor does not exist
+    #[error("Log file does not exist: {}", .0.display())]
+    FileNotFound(PathBuf),
+}
+
+/// Exit codes, see the "Exit Codes" section of the README
+mod exit_code {
+    pub const FAILURE: i32 = 1;
+    pub const CONFIG: i32 = 2;
+    pub const CONNECTION_EXHAUSTED: i32 = 3;
+    pub const FILE_NOT_FOUND: i32 = 4;
+}
+
+/// Exit code for an error returned by `run`
+fn exit_code(error: &anyhow::Error) -> i32 {
+    #[cfg(feature = "sftp")]
+    if error.is::<sftp::SshRejected>() {
+        return exit_code::CONFIG;
+    }
+    if let Some(error) = error.downcast_ref::<AgentError>() {
+        match error {
+            AgentError::Config(_) => exit_code::CONFIG,
+            AgentError::FileNotFound(_) => exit_code::FILE_NOT_FOUND,
+        }
+    } else if error.is::<ServerUnresolvable>() {
+        exit_code::CONFIG
+    } else if error.is::<ReconnectExhausted>()
+        || error.is::<ConnectDeadlineExceeded>()
+        || error.is::<InitialConnectFailed>()
+    {
+        exit_code::CONNECTION_EXHAUSTED
+    } else {
+        exit_code::FAILURE
+    }
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() {
     let args = Args::parse();
 
     // Initialize logging
-    let log_level = if args.verbose { "debug" } else { "info" };
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(log_level)),
-        )
-        .init();
+    let log_level = if args.verbose {
+        "debug"
+    } else if args.quiet {
+        "warn"
+    } else {
+        "info"
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(
+        tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(log_level)),
+    );
+    // Logs go to stderr when stdout carries data
+    if args.data_on_stdout() {
+        subscriber.with_writer(std::io::stderr).init();
+    } else {
+        subscriber.init();
+    }
+
+    if let Err(e) = run(args).await {
+        tracing::error!("{:#}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    let started = std::time::Instant::now();
+
+    // Command line flags take precedence over the config file
+    let file_config = match &args.config {
+        Some(path) => FileConfig::load(path).map_err(AgentError::Config)?,
+        None => FileConfig::default(),
+    };
+    let filters = Arc::new(RwLock::new(
+        file_config.filters().map_err(AgentError::Config)?,
+    ));
+    let name = args
+        .name
+        .clone()
+        .or_else(|| file_config.name.clone())
+        .context("Missing project name: pass --name or set `name` in the config file")
+        .map_err(AgentError::Config)?;
+    let server = args
+        .server
+        .clone()
+        .or_else(|| file_config.server.clone())
+        .unwrap_or_else(|| DEFAULT_SERVER.to_string());
+    let file = args
+        .file
+        .clone()
+        .or_else(|| file_config.file.clone())
+        .context("Missing log file: pass --file or set `file` in the config file")
+        .map_err(AgentError::Config)?;
 
     tracing::info!("Logline Agent starting...");
-    tracing::info!("  Project: {}", args.name);
-    tracing::info!("  Server: {}", args.server);
-    tracing::info!("  File: {}", args.file.display());
+    tracing::info!("  Server: {}", server);
+    tracing::info!("  File: {}", file.display());
 
-    // Verify file exists
-    if !args.file.exists() {
-        anyhow::bail!("Log file does not exist: {}", args.file.display());
+    // Verify file exists and is something that can be tailed
+    let source = match remote_source(&file)? {
+        Some(source) => source,
+        None => validate_source(&file)?,
+    };
+    let fifo = source == SourceKind::Fifo;
+
+    // Opening a named pipe blocks until there is a writer, so don't sniff it
+    let codec = match source {
+        SourceKind::Fifo => {
+            tracing::info!("  Source is a FIFO, reading from each writer in turn");
+            None
+        }
+        #[cfg(feature = "sftp")]
+        SourceKind::Sftp => None,
+        SourceKind::File => {
+            archive::detect_when_readable(&file, args.fail_fast, Duration::from_secs(1))
+                .await
+                .map_err(|e| {
+                    // Exits like the tail does with --fail-fast
+                    if tail::is_permission_denied(&e) {
+                        e.context(tail::PermissionDenied(file.clone()))
+                    } else {
+                        AgentError::Config(e).into()
+                    }
+                })?
+        }
+    };
+    #[cfg(feature = "sftp")]
+    let sftp = match source {
+        SourceKind::Sftp => Some(sftp_source(&args, &file).map_err(AgentError::Config)?),
+        _ => None,
+    };
+    if let Some(codec) = codec {
+        if !args.once {
+            return Err(AgentError::Config(anyhow::anyhow!(
+                "{} is a {:?} archive, which can only be shipped with --once",
+                file.display(),
+                codec
+            ))
+            .into());
+        }
     }
 
-    // Get device identifier (from args or hostname)
-    let device_id = if let Some(id) = args.device_id {
+    // Get device identifier (from args, config or hostname)
+    let device_id = if let Some(id) = args
+        .device_id
+        .clone()
+        .or_else(|| file_config.device_id.clone())
+    {
         id
     } else {
-        hostname::get()
-            .ok()
-            .and_then(|h| h.into_string().ok())
-            .unwrap_or_else(|| "unknown".to_string())
+        local_hostname()
     };
     tracing::info!("  Device: {}", device_id);
 
+    let name = expand_project_name(&name, &device_id, &file).map_err(AgentError::Config)?;
+    tracing::info!("  Project: {}", name);
+
     // Generate unique agent ID from device + file path
-    let canonical_path = args
-        .file
-        .canonicalize()
-        .unwrap_or_else(|_| args.file.clone());
+    let canonical_path = if args.follow_symlink {
+        if !file.is_symlink() {
+            tracing::warn!(
+                "--follow-symlink given but {} is not a symlink",
+                file.display()
+            );
+        }
+        symlink_source_path(&file)
+    } else {
+        file.canonicalize().unwrap_or_else(|_| file.clone())
+    };
     let mut hasher = DefaultHasher::new();
     device_id.hash(&mut hasher);
     canonical_path.to_string_lossy().hash(&mut hasher);
     let agent_id = format!("{:x}", hasher.finish());
     tracing::info!("  Agent ID: {} (device: {})", agent_id, device_id);
 
+    let cli_tags = args.tags.iter().cloned().collect::<BTreeMap<_, _>>();
+    let mut tags = file_config.tags.clone();
+    tags.extend(cli_tags.clone());
+
+    let mut conn_config = ConnectionConfig::new(server, name, agent_id.clone());
+    conn_config.device_id = Some(device_id.clone());
+    conn_config.timestamp_frames = args.timestamp_frames;
+    conn_config.file_meta = args.meta_interval_secs > 0;
+    if let Some(path) = &args.handshake_extra {
+        conn_config.handshake_extra =
+            config::load_handshake_extra(path, args.handshake_extra_placement)
+                .map_err(AgentError::Config)?;
+        conn_config.handshake_extra_placement = args.handshake_extra_placement;
+        tracing::info!(
+            "  Handshake extra: {} field(s) from {}",
+            conn_config.handshake_extra.len(),
+            path.display()
+        );
+    }
+    conn_config.negotiate = args.negotiate;
+    conn_config.require_handshake_ack = args.require_handshake_ack;
+    if let Some(bind_addr) = args.bind_addr {
+        connection::check_bind_addr(bind_addr).map_err(AgentError::Config)?;
+        tracing::info!("  Bind address: {}", bind_addr);
+        conn_config.bind_addr = Some(bind_addr);
+    }
+    conn_config.write_timeout = Duration::from_secs(args.write_timeout_secs);
+    conn_config.max_reconnect_attempts = args.max_reconnect_attempts;
+    conn_config.connect_timeout_jitter = Duration::from_millis(args.connect_timeout_jitter_ms);
+    conn_config.connect_deadline = args.connect_deadline_secs.map(Duration::from_secs);
+    conn_config.handshake_reset_retries = args.handshake_retry_on_reset;
+    if let Some(secs) = args.idle_disconnect_secs {
+        tracing::info!("  Idle disconnect: after {}s without data", secs);
+        conn_config.idle_disconnect = Some(Duration::from_secs(secs));
+    }
+    // Either side may end a negotiated session with a goodbye
+    conn_config.goodbye = true;
+    conn_config.max_dns_failures = args.max_dns_failures;
+    conn_config.fail_fast = args.fail_fast;
+    conn_config.memory_retry_bytes = args.memory_retry_bytes;
+    if let Some(path) = &args.capture_file {
+        tracing::info!("  Capturing frames to {}", path.display());
+        let capture = diagnostics::FrameCapture::open(path.clone(), args.capture_max_bytes)
+            .map_err(AgentError::Config)?;
+        conn_config.capture = Some(Arc::new(std::sync::Mutex::new(capture)));
+    }
+    conn_config.backoff = args.backoff;
+    conn_config.initial_reconnect_delay = Duration::from_secs(args.backoff_base_secs);
+    conn_config.max_reconnect_delay = Duration::from_secs(args.backoff_max_secs);
+    *conn_config
+        .max_bytes_per_sec
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) =
+        args.max_bytes_per_sec.or(file_config.max_bytes_per_sec);
+    conn_config.backfill_bytes_per_sec = args.backfill_bytes_per_sec;
+    let caps = [
+        ("hourly", args.hourly_byte_cap, Duration::from_secs(3600)),
+        ("daily", args.daily_byte_cap, Duration::from_secs(86400)),
+    ];
+    conn_config.byte_caps = caps
+        .into_iter()
+        .filter_map(|(name, bytes, window)| {
+            Some(ByteCap {
+                name,
+                bytes: bytes?,
+                window,
+            })
+        })
+        .collect();
+    conn_config.on_byte_cap = args.on_byte_cap;
+    let extractor = http::Extractor::new(&args.extract, args.extract_missing)
+        .context("Invalid --extract")
+        .map_err(AgentError::Config)?;
+    if !extractor.is_empty() && args.sink != SinkKind::Http {
+        tracing::warn!("--extract only applies to the HTTP sink, ignoring");
+    }
+    if !conn_config.byte_caps.is_empty() && args.sink != SinkKind::Logline {
+        tracing::warn!("Byte caps only apply to the Logline sink, ignoring");
+    }
+    conn_config.max_send_lag = args.max_send_lag_secs.map(Duration::from_secs);
+    conn_config.send_latency_threshold = args.send_latency_threshold_ms.map(Duration::from_millis);
+    *conn_config.tags.write().unwrap_or_else(|e| e.into_inner()) = tags;
+    if args.probe_server_version {
+        let config = conn_config.clone();
+        let probe = tokio::task::spawn_blocking(move || connection::probe_server(config))
+            .await
+            .context("Probe task failed")??;
+        if args.probe_json {
+            println!("{}", serde_json::to_string(&probe)?);
+        } else {
+            println!("{}", probe);
+        }
+        return Ok(());
+    }
+
+    let metrics = Arc::new(Metrics::new());
+
+    // The retry buffer alone must not be able to hold the tail back forever
+    let memory = match args.max_memory_bytes {
+        Some(limit) if args.memory_retry_bytes as u64 >= limit => {
+            return Err(AgentError::Config(anyhow::anyhow!(
+                "--memory-retry-bytes ({}) must be below --max-memory-bytes ({})",
+                args.memory_retry_bytes,
+                limit
+            ))
+            .into());
+        }
+        Some(limit) => {
+            tracing::info!("  Max memory: {} bytes", limit);
+            Some(memory::MemoryGauge::new(limit))
+        }
+        None => None,
+    };
+
     // Create channel for file data
-    let (tx, rx) = mpsc::channel::<Vec<u8>>(1000);
+    let (tx, rx) = mpsc::channel::<LogChunk>(1000);
+    let channel = tx.downgrade();
+
+    // Resume from the checkpoint if it still matches the file on disk
+    let checkpoint = match &args.checkpoint {
+        Some(path) => Some(Checkpoint::load(path).map_err(AgentError::Config)?),
+        None => None,
+    };
+    let resume = match checkpoint.as_ref().and_then(|cp| cp.get(&agent_id)) {
+        // Nothing that is in the file now is sent, not even what a
+        // previous run left unsent
+        Some(saved) if args.no_initial_send => {
+            tracing::info!(
+                "  Initial send disabled, skipping {} from checkpoint offset {}",
+                file.display(),
+                saved.offset
+            );
+            None
+        }
+        Some(saved) => resume_point(saved, &file, &args.rotated_suffixes, args.resume_verify)?,
+        None => None,
+    };
+
+    // Insert the line-mode stage between the watcher and the connection
+    let sample_mode = match (args.sample, args.sample_rate) {
+        (Some(n), _) => Some(SampleMode::EveryNth(n)),
+        (None, Some(rate)) => Some(SampleMode::Rate(rate)),
+        (None, None) => None,
+    };
+    let (rx, line_handle) = if args.line_mode() {
+        if args.line_delimiter != DEFAULT_LINE_DELIMITER {
+            if args.ndjson {
+                return Err(AgentError::Config(anyhow::anyhow!(
+                    "--ndjson needs newline-delimited lines, drop --line-delimiter"
+                ))
+                .into());
+            }
+            tracing::info!("  Line delimiter: {:#04x}", args.line_delimiter);
+        }
+        // Build the configured stages, then chain them in --transforms order
+        let mut stages: HashMap<TransformKind, Box<dyn Transform>> = HashMap::new();
+        stages.insert(
+            TransformKind::Filter,
+            Box::new(IncludeExclude(filters.clone())),
+        );
+        stages.insert(TransformKind::Redact, Box::new(Redact(filters.clone())));
+        if args.strip_ansi {
+            tracing::info!("  Stripping ANSI escape sequences");
+            stages.insert(
+                TransformKind::StripAnsi,
+                Box::new(AnsiStripper::new(metrics.clone())),
+            );
+        }
+        if let Some(min_level) = args.min_level {
+            tracing::info!("  Minimum level: {:?}", min_level);
+            let filter = LevelFilter::new(
+                min_level,
+                &args.level_regex,
+                args.unleveled,
+                metrics.clone(),
+            )
+            .context("Invalid --level-regex")
+            .map_err(AgentError::Config)?;
+            stages.insert(TransformKind::Level, Box::new(filter));
+        }
+        if args.ndjson {
+            tracing::info!(
+                "  NDJSON validation: malformed lines {:?}",
+                args.ndjson_malformed
+            );
+            stages.insert(
+                TransformKind::Ndjson,
+                Box::new(NdjsonValidator::new(args.ndjson_malformed, metrics.clone())),
+            );
+        }
+        if args.dedupe {
+            tracing::info!(
+                "  Dedupe: repeats reported within {}ms",
+                args.dedupe_window_ms
+            );
+            stages.insert(
+                TransformKind::Dedupe,
+                Box::new(
+                    Deduper::new(
+                        Duration::from_millis(args.dedupe_window_ms),
+                        args.ndjson,
+                        metrics.clone(),
+                    )
+                    .with_delimiter(args.line_delimiter),
+                ),
+            );
+        }
+        if let Some(mode) = sample_mode {
+            tracing::info!("  Sampling: {:?} (lossy)", mode);
+            stages.insert(
+                TransformKind::Sample,
+                Box::new(Sampler::new(mode, metrics.clone())),
+            );
+        }
+        if let Some(template) = &args.line_prefix {
+            let file_name = file.to_string_lossy();
+            let prefix = template::expand(
+                template,
+                &[
+                    ("device", &device_id),
+                    ("file", &file_name),
+                    ("agent_id", &agent_id),
+                ],
+            )
+            .map_err(anyhow::Error::msg)
+            .context("Invalid --line-prefix")
+            .map_err(AgentError::Config)?;
+            tracing::info!("  Line prefix: {:?}", prefix);
+            stages.insert(TransformKind::Prefix, Box::new(Prefix(prefix.into_bytes())));
+        }
+        if let Some(separator) = &args.multiline_join {
+            validate_multiline_join(separator, args.line_delimiter).map_err(AgentError::Config)?;
+            tracing::info!(
+                "  Joining multiline records with {:?}",
+                String::from_utf8_lossy(separator)
+            );
+            stages.insert(
+                TransformKind::Join,
+                Box::new(Join::new(separator.clone(), args.line_delimiter)),
+            );
+        }
+
+        let mut pipeline = LinePipeline::new(args.max_line_bytes, metrics.clone())
+            .with_line_delimiter(args.line_delimiter);
+        if let Some(limit) = args.split_large_lines {
+            if limit as usize >= args.max_line_bytes {
+                tracing::warn!(
+                    "--split-large-lines {} has no effect, lines are cut at --max-line-bytes {} first",
+                    limit,
+                    args.max_line_bytes
+                );
+            }
+            tracing::info!("  Splitting lines longer than {} bytes into parts", limit);
+            pipeline = pipeline.with_split_large_lines(limit as usize);
+        }
+        if args.line_numbers {
+            if args.sink != SinkKind::Http {
+                tracing::warn!("--line-numbers only applies to the HTTP sink, ignoring");
+            }
+            tracing::info!(
+                "  Line numbers: {:?} on rotation",
+                args.line_numbers_on_rotation
+            );
+            pipeline = pipeline.with_line_numbers(args.line_numbers_on_rotation);
+        }
+        if let Some(pattern) = &args.skip_header_until {
+            let until = regex::bytes::Regex::new(pattern)
+                .context("Invalid --skip-header-until")
+                .map_err(AgentError::Config)?;
+            // Only a tail that starts at the top of the file sees a header
+            let at_start = args.from_start && resume.is_none();
+            if !at_start && !args.skip_header_on_rotation {
+                tracing::warn!(
+                    "--skip-header-until has no effect without --from-start or --skip-header-on-rotation"
+                );
+            }
+            tracing::info!(
+                "  Skipping header lines until {:?} ({:?} the match)",
+                pattern,
+                args.skip_header_match
+            );
+            pipeline = pipeline.with_header_skip(HeaderSkip::new(
+                until,
+                args.skip_header_match,
+                args.skip_header_on_rotation,
+                at_start,
+            ));
+        }
+        let mut order = Vec::new();
+        for kind in &args.transforms {
+            if order.contains(kind) {
+                return Err(AgentError::Config(anyhow::anyhow!(
+                    "--transforms lists {} more than once",
+                    kind
+                ))
+                .into());
+            }
+            order.push(*kind);
+            if let Some(stage) = stages.remove(kind) {
+                pipeline = pipeline.with_stage(stage);
+            }
+        }
+        let names: Vec<String> = order.iter().map(ToString::to_string).collect();
+        tracing::info!("  Transforms: {}", names.join(" -> "));
+        // Filters only do something with a config file, and its redaction
+        // patterns must never be skipped
+        if args.config.is_some() && stages.contains_key(&TransformKind::Redact) {
+            return Err(AgentError::Config(anyhow::anyhow!(
+                "--transforms must list redact when --config is set"
+            ))
+            .into());
+        }
+        if args.config.is_none() {
+            stages.remove(&TransformKind::Filter);
+            stages.remove(&TransformKind::Redact);
+        }
+        for kind in stages.keys() {
+            tracing::warn!(
+                "{} is enabled but not listed in --transforms, skipping it",
+                kind
+            );
+        }
+
+        let (line_tx, line_rx) = mpsc::channel::<LogChunk>(1000);
+        let handle = tokio::spawn(async move {
+            if let Err(e) = pipeline.run(rx, line_tx).await {
+                tracing::error!("Line pipeline error: {}", e);
+            }
+        });
+        (line_rx, Some(handle))
+    } else {
+        (rx, None)
+    };
+
+    // Pace lines by their timestamps
+    let (rx, replay_handle) = if args.replay {
+        tracing::info!("  Replay at {}x", args.replay_speed);
+        let replay = Replay::new(&args.timestamp_regex, args.replay_speed)
+            .map_err(AgentError::Config)?
+            .with_delimiter(args.line_delimiter);
+        let (replay_tx, replay_rx) = mpsc::channel::<LogChunk>(1000);
+        (replay_rx, Some(tokio::spawn(replay.run(rx, replay_tx))))
+    } else {
+        (rx, None)
+    };
+
+    // Frame by line count and/or an adaptive byte target
+    let (rx, batch_handle) = if args.lines_per_frame.is_some() || args.adaptive_batch {
+        let lines = args
+            .lines_per_frame
+            .map_or(usize::MAX, |lines| lines as usize);
+        if let Some(lines) = args.lines_per_frame {
+            tracing::info!("  Lines per frame: {}", lines);
+        }
+        let mut batcher = FrameBatcher::new(
+            lines,
+            args.frame_bytes,
+            Duration::from_millis(args.frame_linger_ms),
+        )
+        .with_delimiter(args.line_delimiter);
+        if args.adaptive_batch {
+            tracing::info!(
+                "  Adaptive batch: {}-{} bytes",
+                args.batch_min_bytes,
+                args.batch_max_bytes
+            );
+            batcher = batcher.with_adaptive_bytes(
+                args.batch_min_bytes,
+                args.batch_max_bytes,
+                metrics.clone(),
+            );
+        }
+        let (batch_tx, batch_rx) = mpsc::channel::<LogChunk>(1000);
+        (batch_rx, Some(tokio::spawn(batcher.run(rx, batch_tx))))
+    } else {
+        (rx, None)
+    };
+
+    // Space sends at least --min-send-interval-ms apart
+    let (rx, coalesce_handle) = match args.min_send_interval_ms {
+        Some(interval) => {
+            tracing::info!("  Min send interval: {}ms", interval);
+            let coalescer = Coalescer::new(Duration::from_millis(interval));
+            let (coalesce_tx, coalesce_rx) = mpsc::channel::<LogChunk>(1000);
+            (
+                coalesce_rx,
+                Some(tokio::spawn(coalescer.run(rx, coalesce_tx))),
+            )
+        }
+        None => (rx, None),
+    };
+
+    // Stop after --max-lines / --max-bytes
+    let (rx, limit_handle) = if args.max_lines.is_some() || args.max_bytes.is_some() {
+        let limit =
+            ShipLimit::new(args.max_lines, args.max_bytes).with_delimiter(args.line_delimiter);
+        let (limit_tx, limit_rx) = mpsc::channel::<LogChunk>(1000);
+        (limit_rx, Some(tokio::spawn(limit.run(rx, limit_tx))))
+    } else {
+        (rx, None)
+    };
+
+    // Mirror what is shipped with --stdout-tee
+    let (rx, tee_handle) = if args.stdout_tee {
+        if args.sink_on_stdout() {
+            return Err(AgentError::Config(anyhow::anyhow!(
+                "--stdout-tee would print every line twice with --sink file writing to stdout"
+            ))
+            .into());
+        }
+        tracing::info!("  Mirroring shipped lines to stdout");
+        let tee = Tee::stdout(args.line_delimiter);
+        let (tee_tx, tee_rx) = mpsc::channel::<LogChunk>(1000);
+        (tee_rx, Some(tokio::spawn(tee.run(rx, tee_tx))))
+    } else {
+        (rx, None)
+    };
+
+    if args.range.is_some() && (source != SourceKind::File || codec.is_some()) {
+        return Err(AgentError::Config(anyhow::anyhow!(
+            "--range needs a regular, uncompressed file"
+        ))
+        .into());
+    }
 
     // Create file tail watcher
-    let tail = if args.from_start {
-        FileTail::from_start(&args.file)?
+    let mut tail = if fifo {
+        // Only stands in for checkpoints and state reports, the pipe is
+        // read by fifo::stream
+        FileTail::new(&file)?
+    } else if source != SourceKind::File {
+        // Same for a remote file, which is read by sftp::SftpSource
+        FileTail::detached(&file)
+    } else if let Some((start, end)) = args.range {
+        tracing::info!(
+            "  Range: {}-{} ({:?} snap)",
+            start,
+            end.map_or(String::new(), |end| end.to_string()),
+            args.range_snap
+        );
+        FileTail::byte_range(&file, start, end, args.range_snap, args.line_delimiter)
+            .map_err(AgentError::Config)?
+    } else if let Some(offset) = args.start_offset {
+        tracing::info!("  Start offset: {}", offset);
+        FileTail::from_offset(&file, offset, args.line_delimiter).map_err(AgentError::Config)?
+    } else if let Some(line) = args.start_line {
+        tracing::info!("  Start line: {}", line);
+        FileTail::from_line(&file, line, args.line_delimiter).map_err(AgentError::Config)?
+    } else if let Some((rotated, offset)) = resume {
+        tracing::info!("  Resuming from checkpoint at offset {}", offset);
+        match rotated {
+            Some(rotated) => FileTail::from_rotated(&file, &rotated, offset)?,
+            None => FileTail::from_checkpoint(&file, offset)?,
+        }
+    } else if args.from_start {
+        FileTail::from_start(&file)?
+    } else if args.no_initial_send {
+        tracing::info!("  Initial send: disabled");
+        FileTail::new(&file)?.without_initial_send()
     } else if args.tail_bytes > 0 {
         tracing::info!("  Tail bytes: {}", args.tail_bytes);
-        FileTail::with_tail_bytes(&args.file, args.tail_bytes)?
+        FileTail::with_tail_bytes(&file, args.tail_bytes, args.line_delimiter)?
+    } else {
+        FileTail::new(&file)?
+    };
+
+    // Periodic file metadata, sent alongside the data stream
+    let (meta_tx, meta_rx) = if args.meta_interval_secs > 0 {
+        let (meta_tx, meta_rx) = mpsc::channel(16);
+        (Some(meta_tx), Some(meta_rx))
     } else {
-        FileTail::new(&args.file)?
+        (None, None)
+    };
+
+    // End-of-source sentinel, sent after the data stream has drained
+    let (closed_tx, closed_rx) = match args.source_close_grace_secs {
+        Some(secs) => {
+            tracing::info!("  Source close grace: {}s", secs);
+            let (closed_tx, closed_rx) = mpsc::channel(1);
+            (
+                Some((Duration::from_secs(secs), closed_tx)),
+                Some(closed_rx),
+            )
+        }
+        None => (None, None),
+    };
+
+    // Settings shared by the first tail and any restarted one
+    let configure = {
+        let metrics = metrics.clone();
+        let poll_min = Duration::from_millis(args.min_poll_ms);
+        let poll_max = Duration::from_millis(args.max_poll_ms);
+        let hardlink_guard = args.hardlink_guard.clone();
+        let meta_interval = Duration::from_secs(args.meta_interval_secs);
+        let lossy_utf8 = args.lossy_utf8;
+        let bom = args.bom;
+        let on_truncate = args.on_truncate;
+        let rotated_suffixes = args.rotated_suffixes.clone();
+        let max_rotation_backlog = args.max_rotation_backlog;
+        let reopen_on_error = args.reopen_on_error;
+        let memory = memory.clone();
+        let fail_fast = args.fail_fast;
+        move |mut tail: FileTail| {
+            tail = tail
+                .with_poll_interval(poll_min, poll_max)
+                .with_metrics(metrics.clone())
+                .with_truncate_policy(on_truncate)
+                .with_rotation_backfill(&rotated_suffixes, max_rotation_backlog)
+                .with_reopen_on_error(reopen_on_error)
+                .with_bom_policy(bom);
+            if let Some(dir) = &hardlink_guard {
+                tail = tail.with_hardlink_guard(dir);
+            }
+            if lossy_utf8 {
+                tail = tail.with_lossy_utf8();
+            }
+            if let Some(meta_tx) = &meta_tx {
+                tail = tail.with_file_meta(meta_tx.clone(), meta_interval);
+            }
+            if let Some((grace, closed_tx)) = &closed_tx {
+                tail = tail.with_source_close(*grace, closed_tx.clone());
+            }
+            if let Some(gauge) = &memory {
+                tail = tail.with_memory_limit(gauge.clone());
+            }
+            if fail_fast {
+                tail = tail.with_fail_fast();
+            }
+            tail
+        }
+    };
+    tail = configure(tail);
+
+    if let Some(max_bytes) = args.max_initial_bytes {
+        let skipped = tail.limit_backfill(max_bytes, args.line_delimiter)?;
+        if skipped > 0 {
+            tracing::warn!(
+                "Backfill truncated to {} bytes, skipped {} bytes of backlog",
+                max_bytes,
+                skipped
+            );
+        }
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    // Spawn checkpoint writer task
+    let checkpoint_flush = Arc::new(tokio::sync::Notify::new());
+    let checkpoint_handle = checkpoint.map(|checkpoint| {
+        let run = checkpoint.run(
+            agent_id.clone(),
+            canonical_path.clone(),
+            tail.position(),
+            Duration::from_secs(args.checkpoint_interval_secs.max(1)),
+            checkpoint_flush.clone(),
+            shutdown_rx.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = run.await {
+                tracing::error!("Checkpoint error: {:#}", e);
+            }
+        })
+    });
+
+    // Spawn offset audit log writer
+    let audit_handle = match &args.offset_audit_log {
+        Some(path) => {
+            tracing::info!("  Offset audit log: {}", path.display());
+            let run = OffsetAudit::open(path).map_err(AgentError::Config)?.run(
+                canonical_path.clone(),
+                tail.position(),
+                metrics.clone(),
+                Duration::from_secs(args.offset_audit_interval_secs),
+                shutdown_rx.clone(),
+            );
+            Some(tokio::spawn(async move {
+                if let Err(e) = run.await {
+                    tracing::error!("Offset audit log error: {:#}", e);
+                }
+            }))
+        }
+        None => None,
     };
 
     // Create connection manager
-    let conn_config = ConnectionConfig::new(args.server, args.name, agent_id);
-    let connection = ReconnectingConnection::new(conn_config);
+    if args.boot_marker {
+        let marker = BootMarker::new(
+            args.boot_marker_template.clone(),
+            conn_config.device_id.clone().unwrap_or_default(),
+            file.to_string_lossy().into_owned(),
+            conn_config.agent_id.clone(),
+            tail.position(),
+        )
+        .map_err(anyhow::Error::msg)
+        .context("Invalid --boot-marker-template")
+        .map_err(AgentError::Config)?;
+        conn_config.boot_marker = Some(marker);
+    }
+
+    // Recently shipped lines, dumped on fatal errors
+    let recent = Arc::new(
+        RecentLines::new(
+            args.recent_lines,
+            filters.clone(),
+            args.diagnostics_file.clone(),
+        )
+        .with_delimiter(args.line_delimiter),
+    );
 
-    // Spawn file watcher task
-    let file_handle = tokio::spawn(async move {
-        if let Err(e) = tail.watch(tx).await {
-            tracing::error!("File watcher error: {}", e);
+    // Reload hot-reloadable settings on SIGHUP
+    #[cfg(unix)]
+    if let Some(path) = args.config.clone() {
+        let reloader = config::Reloader::new(
+            path,
+            file_config,
+            cli_tags,
+            args.max_bytes_per_sec,
+            filters,
+            conn_config.tags.clone(),
+            conn_config.max_bytes_per_sec.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = reloader.run().await {
+                tracing::error!("Config reloader error: {}", e);
+            }
+        });
+    }
+    // Log the agent's state on SIGUSR1
+    #[cfg(unix)]
+    {
+        let mut reporter = diagnostics::StateReporter::new(
+            file.clone(),
+            tail.position(),
+            metrics.clone(),
+            channel,
+        );
+        if checkpoint_handle.is_some() {
+            reporter = reporter.with_checkpoint(checkpoint_flush);
         }
+        tokio::spawn(async move {
+            if let Err(e) = reporter.run().await {
+                tracing::error!("State reporter error: {}", e);
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    drop(channel);
+
+    let http_config = conn_config.clone();
+    let mut connection =
+        ReconnectingConnection::new(conn_config, metrics.clone()).with_recent_lines(recent.clone());
+    if let Some(meta_rx) = meta_rx {
+        connection = connection.with_file_meta(meta_rx);
+    }
+    if let Some(closed_rx) = closed_rx {
+        connection = connection.with_source_closed(closed_rx);
+    }
+    let latency_handle = match args.max_latency_secs {
+        Some(secs) => {
+            if args.sink != SinkKind::Logline {
+                tracing::warn!("--max-latency-secs only applies to the Logline sink, ignoring");
+            }
+            tracing::info!("  Max latency: {}s", secs);
+            let watch = LatencyWatch::new(Duration::from_secs(secs), metrics.clone());
+            connection = connection.with_latency_watch(watch.clone());
+            Some(tokio::spawn(watch.run()))
+        }
+        None => None,
+    };
+
+    // Spawn health check endpoint
+    let health_handle = args.health_addr.map(|addr| {
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = health::serve(addr, metrics).await {
+                tracing::error!("Health endpoint error: {:#}", e);
+            }
+        })
     });
 
-    // Spawn connection task
-    let conn_handle = tokio::spawn(async move {
-        if let Err(e) = connection.run(rx).await {
-            tracing::error!("Connection error: {}", e);
+    // Pause and resume sending from a local socket
+    #[cfg(unix)]
+    let control_handle = args.control_socket.clone().map(|path| {
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control::serve(path, metrics).await {
+                tracing::error!("Control socket error: {:#}", e);
+            }
+        })
+    });
+    #[cfg(not(unix))]
+    if args.control_socket.is_some() {
+        tracing::warn!("--control-socket is only supported on Unix, ignoring");
+    }
+
+    // Spawn file watcher task, restarted from the last read position if it fails
+    let rebuild = {
+        let file = file.clone();
+        let suffixes = args.rotated_suffixes.clone();
+        move |position: Arc<tail::TailPosition>| {
+            let offset = position.offset.load(Ordering::Relaxed);
+            let inode = position.inode.load(Ordering::Relaxed);
+            tracing::info!("Restarting file watcher at offset {}", offset);
+            let tail = FileTail::resume(&file, offset, inode, &suffixes)?;
+            Ok(configure(tail).with_position(position))
+        }
+    };
+    let watcher_metrics = metrics.clone();
+    let watcher_recent = recent.clone();
+    let max_watcher_restarts = args.max_watcher_restarts;
+    let once = args.once;
+    let source_path = file.clone();
+    #[cfg(feature = "sftp")]
+    let watcher_shutdown = shutdown_rx.clone();
+    let mut file_handle = tokio::spawn(async move {
+        watcher_metrics.watcher_alive.store(true, Ordering::Relaxed);
+        #[cfg(feature = "sftp")]
+        let remote = sftp.map(|source| {
+            source.stream(
+                tx.clone(),
+                watcher_metrics.clone(),
+                tail.position(),
+                watcher_shutdown,
+            )
+        });
+        #[cfg(not(feature = "sftp"))]
+        let remote: Option<std::future::Ready<anyhow::Result<()>>> = None;
+        let result = match (once, codec, remote) {
+            (_, _, Some(remote)) => remote.await,
+            _ if fifo => fifo::stream(source_path, once, tx, watcher_metrics.clone()).await,
+            (true, Some(codec), _) => archive::stream(&source_path, codec, tx).await.map(drop),
+            (true, None, _) => tail.read_once(tx).await,
+            (false, _, _) => {
+                tail::supervise(
+                    tail,
+                    rebuild,
+                    tx,
+                    max_watcher_restarts,
+                    Duration::from_secs(1),
+                )
+                .await
+            }
+        };
+        if let Err(e) = &result {
+            watcher_recent.dump(e);
         }
+        watcher_metrics
+            .watcher_alive
+            .store(false, Ordering::Relaxed);
+        result
     });
 
-    // Wait for Ctrl+C
-    tokio::signal::ctrl_c().await?;
-    tracing::info!("Shutting down...");
+    // Spawn connection task
+    let mut conn_handle = match args.sink {
+        SinkKind::Logline => tokio::spawn(connection.run(rx)),
+        SinkKind::File => {
+            let sink = match &args.sink_path {
+                Some(path) => FileSink::open(path).map_err(AgentError::Config)?,
+                None => FileSink::stdout(),
+            };
+            tokio::spawn(sink::drain(rx, sink, metrics.clone()))
+        }
+        SinkKind::Http => {
+            let url = args.url.clone().unwrap_or_default();
+            tracing::info!("  HTTP sink: {}", url);
+            let mut sink = http::HttpSink::new(url, http_config)
+                .map_err(AgentError::Config)?
+                .with_format(args.http_format)
+                .with_extractor(extractor)
+                .with_batch_lines(args.http_batch_lines)
+                .with_delimiter(args.line_delimiter);
+            if args.http_gzip {
+                sink = sink
+                    .with_gzip(
+                        args.http_gzip_min_savings,
+                        Duration::from_secs(args.http_gzip_cooldown_secs),
+                    )
+                    .with_metrics(metrics.clone());
+            }
+            tokio::spawn(sink::drain(rx, sink, metrics.clone()))
+        }
+        SinkKind::Journald => {
+            tracing::info!("  journald sink: {}", journald::JOURNAL_SOCKET);
+            let sink = journald::JournaldSink::connect(
+                Path::new(journald::JOURNAL_SOCKET),
+                http_config.project_name.clone(),
+                http_config.device_id.clone().unwrap_or_default(),
+                &args.level_regex,
+            )
+            .map_err(AgentError::Config)?
+            .with_delimiter(args.line_delimiter);
+            tokio::spawn(sink::drain(rx, sink, metrics.clone()))
+        }
+        SinkKind::Null => tokio::spawn(sink::drain(rx, NullSink, metrics.clone())),
+    };
 
-    // Abort tasks
+    // Wait for Ctrl+C or SIGTERM, the connection giving up, or the file
+    // watcher running out of restarts
+    let mut watcher_error = None;
+    let mut interrupted = false;
+    let conn_result = tokio::select! {
+        signal = shutdown_signal() => {
+            signal?;
+            tracing::info!("Shutting down...");
+            interrupted = true;
+            None
+        }
+        result = &mut conn_handle => Some(result),
+        result = &mut file_handle => match result.context("File watcher task failed")? {
+            // The pipeline closed underneath the watcher, the sender has the reason
+            Ok(()) => Some((&mut conn_handle).await),
+            Err(e) => {
+                // Ship what was read before the failure
+                if args.once {
+                    let _ = (&mut conn_handle).await;
+                }
+                watcher_error = Some(e);
+                None
+            }
+        },
+    };
+
+    // Stop reading, and give the pipeline a bounded time to empty
     file_handle.abort();
+    if interrupted && args.drain_timeout_secs > 0 {
+        drain(
+            &mut conn_handle,
+            Duration::from_secs(args.drain_timeout_secs),
+            &metrics,
+        )
+        .await;
+    }
+
+    // Abort tasks
+    let _ = shutdown_tx.send(true);
+    // The last checkpoint holds the shipped offset, so whatever a timed
+    // out drain left unsent is read again on the next run
+    if let Some(handle) = checkpoint_handle {
+        let _ = handle.await;
+    }
+    if let Some(handle) = audit_handle {
+        let _ = handle.await;
+    }
+    if let Some(handle) = line_handle {
+        handle.abort();
+    }
+    if let Some(handle) = replay_handle {
+        handle.abort();
+    }
+    if let Some(handle) = batch_handle {
+        handle.abort();
+    }
+    if let Some(handle) = coalesce_handle {
+        handle.abort();
+    }
+    if let Some(handle) = tee_handle {
+        handle.abort();
+    }
     conn_handle.abort();
+    if let Some(handle) = health_handle {
+        handle.abort();
+    }
+    if let Some(handle) = latency_handle {
+        handle.abort();
+    }
+    #[cfg(unix)]
+    if let Some(handle) = control_handle {
+        handle.abort();
+        if let Some(path) = &args.control_socket {
+            let _ = control::remove_socket(path);
+        }
+    }
 
-    Ok(())
+    if args.on_byte_cap == ratelimit::CapAction::Drop
+        && (args.hourly_byte_cap.is_some() || args.daily_byte_cap.is_some())
+    {
+        tracing::info!(
+            "Byte cap: {} bytes dropped",
+            metrics.bytes_dropped_by_cap.load(Ordering::Relaxed)
+        );
+    }
+    if args.ndjson {
+        tracing::info!(
+            "NDJSON: {} malformed lines",
+            metrics.ndjson_malformed.load(Ordering::Relaxed)
+        );
+    }
+    if args.skip_header_until.is_some() {
+        tracing::info!(
+            "Header: {} lines skipped",
+            metrics.header_lines_skipped.load(Ordering::Relaxed)
+        );
+    }
+    if args.min_level.is_some() {
+        tracing::info!(
+            "Level filter: {} lines dropped",
+            metrics.lines_dropped_by_level.load(Ordering::Relaxed)
+        );
+    }
+    if args.http_gzip {
+        tracing::info!(
+            "HTTP gzip: {:.1}% rolling size ratio, {} bodies sent uncompressed",
+            metrics.compression_ratio_permille.load(Ordering::Relaxed) as f64 / 10.0,
+            metrics.compression_skipped.load(Ordering::Relaxed)
+        );
+    }
+    if args.strip_ansi {
+        tracing::info!(
+            "Strip ANSI: {} escape sequences removed",
+            metrics.ansi_stripped.load(Ordering::Relaxed)
+        );
+    }
+    if args.dedupe {
+        tracing::info!(
+            "Dedupe: {} repeated lines collapsed",
+            metrics.lines_deduped.load(Ordering::Relaxed)
+        );
+    }
+    if sample_mode.is_some() {
+        tracing::info!(
+            "Sampling kept {} lines, dropped {} ({:.1}% kept)",
+            metrics.lines_sampled_kept.load(Ordering::Relaxed),
+            metrics.lines_sampled_dropped.load(Ordering::Relaxed),
+            metrics.sample_ratio() * 100.0
+        );
+    }
+
+    let summary = Summary::new(&metrics, started.elapsed());
+    if args.summary_json {
+        println!("{}", serde_json::to_string(&summary)?);
+    } else {
+        tracing::info!("Summary: {}", summary);
+    }
+
+    if let Some(e) = watcher_error {
+        return Err(e);
+    }
+    match conn_result {
+        Some(result) => {
+            let result = result.context("Connection task failed")?;
+            if let Err(e) = &result {
+                recent.dump(e);
+            }
+            result?;
+            // The sender stops once the stream ends; that's expected after a
+            // limit, with --once, or once the source has been closed
+            let limit_hit = match limit_handle {
+                Some(handle) => matches!(handle.await, Ok(Ok(true))),
+                None => false,
+            };
+            if limit_hit || args.once || metrics.source_closed.load(Ordering::Relaxed) {
+                Ok(())
+            } else {
+                anyhow::bail!("File watcher stopped")
+            }
+        }
+        None => Ok(()),
+    }
+}
+
+/// Wait for Ctrl+C, or for SIGTERM from a service manager or container
+/// runtime
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut term =
+            signal(SignalKind::terminate()).context("Failed to install SIGTERM handler")?;
+        tokio::select! {
+            signal = tokio::signal::ctrl_c() => signal.context("Failed to wait for Ctrl+C"),
+            _ = term.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .context("Failed to wait for Ctrl+C")
+    }
+}
+
+/// Wait up to `timeout` for the sender to ship what is left in the
+/// pipeline once reading has stopped. Returns false if time ran out.
+async fn drain<T>(
+    handle: &mut tokio::task::JoinHandle<T>,
+    timeout: Duration,
+    metrics: &Metrics,
+) -> bool {
+    if tokio::time::timeout(timeout, handle).await.is_ok() {
+        return true;
+    }
+    // Payload bytes, so filtering and transforms make this an estimate
+    let unsent = metrics
+        .bytes_read
+        .load(Ordering::Relaxed)
+        .saturating_sub(metrics.bytes_sent.load(Ordering::Relaxed));
+    tracing::warn!(
+        "Drain timed out after {}s, about {} bytes read were not sent",
+        timeout.as_secs_f64(),
+        unsent
+    );
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_fall_back_to_env_and_cli_wins() {
+        // Tests share the process environment, so the variables are only
+        // set for a run of this test in a child process
+        const CHILD: &str = "LOGLINE_AGENT_ENV_TEST_CHILD";
+        if std::env::var_os(CHILD).is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tests::flags_fall_back_to_env_and_cli_wins"])
+                .env(CHILD, "1")
+                .env("LOGLINE_NAME", "from-env")
+                .env("LOGLINE_SERVER", "10.0.0.1:12500")
+                .env("LOGLINE_FROM_START", "true")
+                .env("LOGLINE_TAIL_BYTES", "1024")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success(), "{}", stdout);
+            assert!(stdout.contains("1 passed"), "{}", stdout);
+            return;
+        }
+
+        let args = Args::try_parse_from(["logline-agent", "--server", "10.0.0.2:12500"]).unwrap();
+        assert_eq!(args.name.as_deref(), Some("from-env"));
+        assert_eq!(args.server.as_deref(), Some("10.0.0.2:12500"));
+        assert!(args.from_start);
+        assert_eq!(args.tail_bytes, 1024);
+    }
+
+    #[test]
+    fn expands_each_project_name_placeholder() {
+        let file = Path::new("/var/log/payment.log");
+        let expand = |template| expand_project_name(template, "web-1", file).unwrap();
+        assert_eq!(expand("app@{device}"), "app@web-1");
+        assert_eq!(expand("{hostname}"), local_hostname());
+        assert_eq!(expand("{file}"), "/var/log/payment.log");
+        assert_eq!(expand("{file_name}"), "payment.log");
+        assert_eq!(expand("{file_stem}-svc"), "payment-svc");
+    }
+
+    #[test]
+    fn rejects_unknown_or_empty_project_names() {
+        let file = Path::new("/var/log/payment.log");
+        let error = expand_project_name("{host}", "web-1", file).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("unknown placeholder '{host}'"),
+            "{:#}",
+            error
+        );
+        assert!(expand_project_name("  ", "web-1", file).is_err());
+        assert!(expand_project_name(&"x".repeat(MAX_PROJECT_NAME_LEN + 1), "web-1", file).is_err());
+    }
+
+    #[test]
+    fn resume_point_is_dropped_when_the_content_was_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"first deploy\n").unwrap();
+        let saved = SourceOffset {
+            path: path.clone(),
+            inode: tail::inode_of(&path).unwrap(),
+            offset: 13,
+            fingerprint: checkpoint::fingerprint(&path, 13).unwrap(),
+        };
+        assert!(saved.fingerprint.is_some());
+        let suffixes = vec![".1".to_string()];
+        assert_eq!(
+            resume_point(&saved, &path, &suffixes, true).unwrap(),
+            Some((None, 13))
+        );
+
+        // Rewritten in place: same inode, same size, different content
+        std::fs::write(&path, b"other deploy\n").unwrap();
+        assert_eq!(tail::inode_of(&path).unwrap(), saved.inode);
+        assert_eq!(resume_point(&saved, &path, &suffixes, true).unwrap(), None);
+        assert_eq!(
+            resume_point(&saved, &path, &suffixes, false).unwrap(),
+            Some((None, 13))
+        );
+    }
+
+    fn config_error(result: Result<SourceKind, AgentError>) -> String {
+        match result {
+            Err(AgentError::Config(e)) => e.to_string(),
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate_source_accepts_files_and_rejects_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(validate_source(&path).unwrap(), SourceKind::File);
+
+        let error = config_error(validate_source(dir.path()));
+        assert!(error.contains("is a directory"), "{}", error);
+
+        assert!(matches!(
+            validate_source(&dir.path().join("missing.log")),
+            Err(AgentError::FileNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn multiline_join_needs_a_delimiter_other_than_newline() {
+        assert!(validate_multiline_join(b" | ", b'\0').is_ok());
+        let error = validate_multiline_join(b" | ", b'\n').unwrap_err();
+        assert!(
+            error.to_string().contains("other than newline"),
+            "{}",
+            error
+        );
+        let error = validate_multiline_join(b"\0", b'\0').unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("contains the line delimiter 0x00"),
+            "{}",
+            error
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn validate_source_rejects_devices() {
+        let error = config_error(validate_source(Path::new("/dev/null")));
+        assert!(
+            error.contains("/dev/null is a character device"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn drain_gives_up_on_a_server_that_stops_reading() {
+        let metrics = Arc::new(Metrics::new());
+        let ship = |server: &testutil::ServerStub| {
+            let mut config =
+                ConnectionConfig::new(server.addr().to_string(), "svc".into(), "agent".into());
+            // Lets the stalled sender reach an await point to be aborted
+            config.write_timeout = Duration::from_secs(1);
+            config.initial_reconnect_delay = Duration::from_millis(100);
+            let (tx, rx) = mpsc::channel(64);
+            let handle = tokio::spawn(ReconnectingConnection::new(config, metrics.clone()).run(rx));
+            (tx, handle)
+        };
+
+        let server = testutil::ServerStub::start();
+        let (tx, mut handle) = ship(&server);
+        tx.send(LogChunk::new(b"line\n".to_vec())).await.unwrap();
+        drop(tx);
+        assert!(drain(&mut handle, Duration::from_secs(5), &metrics).await);
+
+        // Far more than the socket buffers hold
+        let stalled = testutil::ServerStub::start_stalled();
+        let (tx, mut handle) = ship(&stalled);
+        tx.send(LogChunk::new(vec![b'x'; 32 << 20])).await.unwrap();
+        metrics.bytes_read.fetch_add(32 << 20, Ordering::Relaxed);
+        drop(tx);
+        assert!(!drain(&mut handle, Duration::from_millis(500), &metrics).await);
+        assert!(!handle.is_finished());
+        handle.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn checkpoint_after_a_timed_out_drain_keeps_the_unsent_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("app.log");
+        // Far more than the socket buffers hold
+        let len = 32 << 20;
+        std::fs::write(&file, b"x\n".repeat(len / 2)).unwrap();
+        let tail = FileTail::from_start(&file).unwrap();
+        let position = tail.position();
+
+        let path = dir.path().join("checkpoint.json");
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let checkpoint = tokio::spawn(Checkpoint::load(&path).unwrap().run(
+            "id".to_string(),
+            file.clone(),
+            position.clone(),
+            Duration::from_secs(60),
+            Arc::new(tokio::sync::Notify::new()),
+            shutdown_rx,
+        ));
+
+        let stalled = testutil::ServerStub::start_stalled();
+        let mut config =
+            ConnectionConfig::new(stalled.addr().to_string(), "svc".into(), "agent".into());
+        config.write_timeout = Duration::from_secs(1);
+        let metrics = Arc::new(Metrics::new());
+        let (tx, rx) = mpsc::channel(4);
+        let mut handle = tokio::spawn(ReconnectingConnection::new(config, metrics.clone()).run(rx));
+        let reader = tokio::spawn(tail.read_once(tx));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        reader.abort();
+        assert!(!drain(&mut handle, Duration::from_millis(500), &metrics).await);
+
+        shutdown_tx.send(true).unwrap();
+        checkpoint.await.unwrap().unwrap();
+        handle.abort();
+        let saved = Checkpoint::load(&path).unwrap().get("id").unwrap().clone();
+        let read = position.offset.load(Ordering::Relaxed);
+        assert!(
+            saved.offset < read && saved.offset < len as u64,
+            "saved {} of {} read",
+            saved.offset,
+            read
+        );
+    }
 }
@@ -0,0 +1,137 @@
+//! Accounting of buffered data for `--max-memory-bytes`
+//!
+//! This is not an RSS measurement. Chunks read by the tail carry a
+//! [`MemoryCharge`] for their size, which stays with the chunk (and any
+//! copy of it, such as the `--memory-retry-bytes` buffer) until it is
+//! dropped. Stages that rewrite chunks charge their output the same way.
+//! Partial lines held by the line splitter are bounded separately by
+//! `--max-line-bytes`.
+//!
+//! There is no disk to spill to, so once the limit is reached the tail
+//! stops reading and the data waits in the file.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Bytes currently held by charged chunks
+#[derive(Debug)]
+pub struct MemoryGauge {
+    limit: u64,
+    used: AtomicU64,
+    freed: Notify,
+}
+
+impl MemoryGauge {
+    pub fn new(limit: u64) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicU64::new(0),
+            freed: Notify::new(),
+        })
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Account for `bytes` until the returned charge is dropped
+    pub fn charge(self: &Arc<Self>, bytes: usize) -> MemoryCharge {
+        self.used.fetch_add(bytes as u64, Ordering::Relaxed);
+        MemoryCharge {
+            gauge: self.clone(),
+            bytes: bytes as u64,
+        }
+    }
+
+    /// Wait while the limit is reached, until usage is back under three
+    /// quarters of it
+    pub async fn wait_for_room(&self) {
+        if self.used() < self.limit {
+            return;
+        }
+        tracing::warn!(
+            "Buffered data reached {} bytes (limit {}), pausing reads",
+            self.used(),
+            self.limit
+        );
+        let resume = self.limit / 4 * 3;
+        loop {
+            let freed = self.freed.notified();
+            if self.used() <= resume {
+                break;
+            }
+            freed.await;
+        }
+        tracing::info!("Buffered data down to {} bytes, reading again", self.used());
+    }
+}
+
+/// Share of a [`MemoryGauge`] held by one chunk
+#[derive(Debug)]
+pub struct MemoryCharge {
+    gauge: Arc<MemoryGauge>,
+    bytes: u64,
+}
+
+impl MemoryCharge {
+    pub fn gauge(&self) -> &Arc<MemoryGauge> {
+        &self.gauge
+    }
+}
+
+/// A copy of a chunk holds its own share
+impl Clone for MemoryCharge {
+    fn clone(&self) -> Self {
+        self.gauge.charge(self.bytes as usize)
+    }
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.gauge.used.fetch_sub(self.bytes, Ordering::Relaxed);
+        self.gauge.freed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn charges_follow_copies_and_drops() {
+        let gauge = MemoryGauge::new(100);
+        let charge = gauge.charge(10);
+        let copy = charge.clone();
+        assert_eq!(gauge.used(), 20);
+        drop(charge);
+        assert_eq!(gauge.used(), 10);
+        drop(copy);
+        assert_eq!(gauge.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn waits_until_usage_drops() {
+        let gauge = MemoryGauge::new(100);
+        let mut held: Vec<MemoryCharge> = (0..10).map(|_| gauge.charge(10)).collect();
+
+        let waiting = tokio::spawn({
+            let gauge = gauge.clone();
+            async move { gauge.wait_for_room().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        // Below the limit is not enough, it resumes at three quarters
+        held.truncate(8);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        held.truncate(7);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
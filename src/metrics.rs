@@ -0,0 +1,217 @@
+//! Runtime counters shared between the agent's tasks
+//!
+//! Each stage holds an `Arc<Metrics>` and bumps the relevant counters;
+//! `main` reads them back for reporting.
+
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Shared agent counters
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Lines kept by the sampler
+    pub lines_sampled_kept: AtomicU64,
+    /// Lines dropped by the sampler
+    pub lines_sampled_dropped: AtomicU64,
+    /// Fragments cut off at the line length limit
+    pub lines_truncated: AtomicU64,
+    /// Lines sent in parts by `--split-large-lines`
+    pub lines_split: AtomicU64,
+    /// Lines below `--min-level`
+    pub lines_dropped_by_level: AtomicU64,
+    /// Header lines dropped by `--skip-header-until`
+    pub header_lines_skipped: AtomicU64,
+    /// ANSI escape sequences removed by `--strip-ansi`
+    pub ansi_stripped: AtomicU64,
+    /// Repeated lines collapsed by `--dedupe`
+    pub lines_deduped: AtomicU64,
+    /// Lines that failed NDJSON validation
+    pub ndjson_malformed: AtomicU64,
+    /// Rolling gzip size ratio of HTTP bodies, compressed/original in ‰
+    pub compression_ratio_permille: AtomicU64,
+    /// HTTP bodies sent uncompressed because gzip didn't pay off
+    pub compression_skipped: AtomicU64,
+    /// Current adaptive batch size target in bytes
+    pub batch_target_bytes: AtomicU64,
+    /// Bytes discarded after a byte cap was reached
+    pub bytes_dropped_by_cap: AtomicU64,
+    /// Bytes read from the log file
+    pub bytes_read: AtomicU64,
+    /// Log payload bytes sent to the server
+    pub bytes_sent: AtomicU64,
+    /// Frames of any type sent to the server
+    pub frames_sent: AtomicU64,
+    /// Connections established after the first one
+    pub reconnects: AtomicU64,
+    /// How long each server connection lasted before it was lost
+    pub connection_duration: Histogram,
+    /// Time from losing a connection to establishing the next one
+    pub reconnect_time: Histogram,
+    /// Backoff delays slept between failed connection attempts
+    pub backoff_delay: Histogram,
+    /// Time from reading the last sent chunk to writing it to the server
+    pub ship_latency_ms: AtomicU64,
+    /// Times data took longer than `--max-latency-secs` to be sent
+    pub latency_breaches: AtomicU64,
+    /// Whether a server connection is currently established
+    pub connected: AtomicBool,
+    /// Whether the connection was closed by `--idle-disconnect-secs` and
+    /// is reopened once there is data
+    pub idle_disconnected: AtomicBool,
+    /// Whether the file watcher task is running
+    pub watcher_alive: AtomicBool,
+    /// Whether the watched file was closed by `--source-close-grace-secs`
+    pub source_closed: AtomicBool,
+    /// Whether sending is paused from the control socket
+    pub paused: AtomicBool,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fraction of sampled lines that were kept (1.0 if nothing was sampled)
+    pub fn sample_ratio(&self) -> f64 {
+        let kept = self.lines_sampled_kept.load(Ordering::Relaxed);
+        let dropped = self.lines_sampled_dropped.load(Ordering::Relaxed);
+        let total = kept + dropped;
+        if total == 0 {
+            1.0
+        } else {
+            kept as f64 / total as f64
+        }
+    }
+}
+
+/// Upper bounds of the [`Histogram`] buckets in seconds, a last bucket
+/// takes everything longer
+pub const HISTOGRAM_BOUNDS_SECS: [f64; 10] =
+    [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 1800.0, 3600.0];
+
+/// Distribution of durations over fixed buckets, so memory stays the same
+/// however many are observed
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [AtomicU64; HISTOGRAM_BOUNDS_SECS.len() + 1],
+    count: AtomicU64,
+    sum_ms: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = HISTOGRAM_BOUNDS_SECS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(HISTOGRAM_BOUNDS_SECS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Current counts, with cumulative buckets as in Prometheus
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut total = 0;
+        let buckets = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                total += count.load(Ordering::Relaxed);
+                HistogramBucket {
+                    le_secs: HISTOGRAM_BOUNDS_SECS.get(i).copied(),
+                    count: total,
+                }
+            })
+            .collect();
+        HistogramSnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            sum_secs: self.sum_ms.load(Ordering::Relaxed) as f64 / 1000.0,
+            buckets,
+        }
+    }
+}
+
+/// Point-in-time view of a [`Histogram`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum_secs: f64,
+    pub buckets: Vec<HistogramBucket>,
+}
+
+/// Observations at or below `le_secs` (all of them for the last bucket)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistogramBucket {
+    pub le_secs: Option<f64>,
+    pub count: u64,
+}
+
+/// End-of-run totals printed on shutdown
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub bytes_read: u64,
+    pub bytes_sent: u64,
+    pub frames_sent: u64,
+    pub reconnects: u64,
+    /// Fragments cut off at `--max-line-bytes`
+    pub lines_truncated: u64,
+    /// Lines sent in parts by `--split-large-lines`
+    pub lines_split: u64,
+    pub uptime_secs: f64,
+}
+
+impl Summary {
+    pub fn new(metrics: &Metrics, uptime: Duration) -> Self {
+        Self {
+            bytes_read: metrics.bytes_read.load(Ordering::Relaxed),
+            bytes_sent: metrics.bytes_sent.load(Ordering::Relaxed),
+            frames_sent: metrics.frames_sent.load(Ordering::Relaxed),
+            reconnects: metrics.reconnects.load(Ordering::Relaxed),
+            lines_truncated: metrics.lines_truncated.load(Ordering::Relaxed),
+            lines_split: metrics.lines_split.load(Ordering::Relaxed),
+            uptime_secs: uptime.as_secs_f64(),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "read={}B sent={}B frames={} reconnects={} truncated={} split={} uptime={:.1}s",
+            self.bytes_read,
+            self.bytes_sent,
+            self.frames_sent,
+            self.reconnects,
+            self.lines_truncated,
+            self.lines_split,
+            self.uptime_secs
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_secs(3));
+        histogram.observe(Duration::from_secs(7200));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 3);
+        assert_eq!(snapshot.sum_secs, 7203.05);
+        let counts: Vec<u64> = snapshot.buckets.iter().map(|b| b.count).collect();
+        assert_eq!(counts, [1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 3]);
+        assert_eq!(snapshot.buckets.last().unwrap().le_secs, None);
+    }
+}
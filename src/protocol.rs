@@ -4,18 +4,49 @@
 //! [Length: u32][Type: u8][Payload: bytes]
 
 use serde::{Deserialize, Serialize};
-use std::io::{self, Write};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
 /// Protocol version
 pub const PROTOCOL_VERSION: u8 = 1;
 
+/// Longest project name accepted in a handshake
+pub const MAX_PROJECT_NAME_LEN: usize = 256;
+
+/// Protocol version negotiated when `LogData` payloads carry a `TimestampHeader`
+pub const PROTOCOL_VERSION_TIMESTAMPS: u8 = 2;
+
+/// Newest protocol version the agent speaks
+pub const MAX_PROTOCOL_VERSION: u8 = PROTOCOL_VERSION_TIMESTAMPS;
+
+/// Feature name for `LogData` payloads carrying a `TimestampHeader`
+pub const FEATURE_TIMESTAMPS: &str = "timestamps";
+
+/// Feature name for `FileMeta` frames
+pub const FEATURE_FILE_META: &str = "file_meta";
+
+/// Feature name for `Goodbye` frames sent by the agent
+pub const FEATURE_GOODBYE: &str = "goodbye";
+
+/// Largest frame accepted from the server
+pub const MAX_INBOUND_FRAME_LEN: usize = 64 * 1024;
+
+/// Largest payload whose length (plus the type byte) fits the u32 prefix
+pub const MAX_PAYLOAD_LEN: usize = u32::MAX as usize - 1;
+
 /// Message type identifiers
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MessageType {
     Handshake = 0x01,
     LogData = 0x02,
+    HandshakeAck = 0x03,
+    FileMeta = 0x04,
+    SourceClosed = 0x05,
+    Goodbye = 0x06,
     Keepalive = 0xFF,
 }
 
@@ -26,6 +57,10 @@ impl TryFrom<u8> for MessageType {
         match value {
             0x01 => Ok(MessageType::Handshake),
             0x02 => Ok(MessageType::LogData),
+            0x03 => Ok(MessageType::HandshakeAck),
+            0x04 => Ok(MessageType::FileMeta),
+            0x05 => Ok(MessageType::SourceClosed),
+            0x06 => Ok(MessageType::Goodbye),
             0xFF => Ok(MessageType::Keepalive),
             _ => Err(ProtocolError::UnknownMessageType(value)),
         }
@@ -42,6 +77,16 @@ pub enum ProtocolError {
 
     #[error("Serialization error: {0}")]
     Serialization(String),
+
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+
+    #[error("Partial write: {written} of {total} bytes sent ({source})")]
+    PartialWrite {
+        written: usize,
+        total: usize,
+        source: io::Error,
+    },
 }
 
 /// Handshake message payload
@@ -52,6 +97,32 @@ pub struct HandshakePayload {
     pub version: u8,
     /// Unique agent ID (hash of log file path)
     pub agent_id: String,
+    /// Device identifier the agent runs on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// Free-form session tags (ordered so the encoding is stable)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// Optional features the agent supports; a server that understands
+    /// them answers with a `HandshakeAck`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    /// Operator-supplied fields (`--handshake-extra`), sent as an object
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub extra: Map<String, Value>,
+    /// Operator-supplied fields merged into the top level instead
+    #[serde(flatten)]
+    pub flattened_extra: Map<String, Value>,
+}
+
+/// Where `--handshake-extra` fields are placed in the handshake
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExtraPlacement {
+    /// Under an `extra` object
+    #[default]
+    Nested,
+    /// Next to the typed fields
+    Flatten,
 }
 
 fn default_version() -> u8 {
@@ -64,10 +135,133 @@ impl HandshakePayload {
             project_name: project_name.into(),
             version: PROTOCOL_VERSION,
             agent_id: agent_id.into(),
+            device_id: None,
+            tags: BTreeMap::new(),
+            features: Vec::new(),
+            extra: Map::new(),
+            flattened_extra: Map::new(),
+        }
+    }
+
+    /// Top-level names used by the typed fields, which flattened extra
+    /// fields must not reuse
+    pub const FIELDS: &'static [&'static str] = &[
+        "project_name",
+        "version",
+        "agent_id",
+        "device_id",
+        "tags",
+        "features",
+        "extra",
+    ];
+
+    /// Attach operator-supplied fields
+    pub fn set_extra(&mut self, fields: Map<String, Value>, placement: ExtraPlacement) {
+        match placement {
+            ExtraPlacement::Nested => self.extra = fields,
+            ExtraPlacement::Flatten => self.flattened_extra = fields,
         }
     }
 }
 
+/// Handshake acknowledgement sent by servers that support negotiation
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HandshakeAckPayload {
+    /// Protocol version the server speaks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u8>,
+    /// Features agreed for this session
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl HandshakeAckPayload {
+    /// Decode the JSON payload of a `HandshakeAck` frame
+    pub fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
+        serde_json::from_slice(payload).map_err(|e| ProtocolError::Serialization(e.to_string()))
+    }
+}
+
+/// Periodic snapshot of the tailed file, so the server can show its size
+/// and compute lag independently of the data stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetaPayload {
+    pub path: String,
+    /// File size in bytes
+    pub size: u64,
+    /// Last modification time, milliseconds since Unix epoch
+    pub mtime: u64,
+    /// Offset the agent has read up to
+    pub offset: u64,
+}
+
+/// Last frame for a source that is gone for good, sent after all of its data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceClosedPayload {
+    pub path: String,
+    /// Why the source ended, e.g. `removed`
+    pub reason: String,
+    /// Offset the agent had read up to
+    pub offset: u64,
+}
+
+/// Sent before either side closes a healthy connection on purpose
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GoodbyePayload {
+    /// Why the connection is closed, e.g. `idle`
+    pub reason: String,
+    /// From the server only: where the agent should reconnect
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<String>,
+}
+
+/// Fixed binary header prefixed to `LogData` payloads in protocol v2
+///
+/// [Timestamp: u64 (milliseconds since Unix epoch, big-endian)][Data: bytes]
+///
+/// The timestamp is taken when the data was read from the file, so the
+/// server can restore ordering of frames retransmitted after a reconnect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampHeader {
+    pub timestamp_ms: u64,
+}
+
+impl TimestampHeader {
+    /// Encoded header length
+    pub const LEN: usize = 8;
+
+    pub fn new(time: SystemTime) -> Self {
+        let timestamp_ms = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        Self { timestamp_ms }
+    }
+
+    /// Encode the header
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        self.timestamp_ms.to_be_bytes()
+    }
+
+    /// Decode the header from the front of a payload, returning the remaining data
+    #[allow(dead_code)]
+    pub fn decode(payload: &[u8]) -> Result<(Self, &[u8]), ProtocolError> {
+        if payload.len() < Self::LEN {
+            return Err(ProtocolError::InvalidFrame(format!(
+                "timestamp header needs {} bytes, got {}",
+                Self::LEN,
+                payload.len()
+            )));
+        }
+
+        let (header, data) = payload.split_at(Self::LEN);
+        let mut bytes = [0u8; Self::LEN];
+        bytes.copy_from_slice(header);
+        let timestamp_ms = u64::from_be_bytes(bytes);
+        Ok((Self { timestamp_ms }, data))
+    }
+}
+
 /// A protocol frame
 #[derive(Debug, Clone)]
 pub struct Frame {
@@ -84,13 +278,9 @@ impl Frame {
     }
 
     /// Create a handshake frame
-    pub fn handshake(
-        project_name: impl Into<String>,
-        agent_id: impl Into<String>,
-    ) -> Result<Self, ProtocolError> {
-        let payload = HandshakePayload::new(project_name, agent_id);
-        let bytes = serde_json::to_vec(&payload)
-            .map_err(|e| ProtocolError::Serialization(e.to_string()))?;
+    pub fn handshake(payload: &HandshakePayload) -> Result<Self, ProtocolError> {
+        let bytes =
+            serde_json::to_vec(payload).map_err(|e| ProtocolError::Serialization(e.to_string()))?;
         Ok(Self::new(MessageType::Handshake, bytes))
     }
 
@@ -99,28 +289,250 @@ impl Frame {
         Self::new(MessageType::LogData, data)
     }
 
+    /// Create a log data frame prefixed with a `TimestampHeader`
+    pub fn timestamped_log_data(header: TimestampHeader, data: &[u8]) -> Self {
+        let mut payload = Vec::with_capacity(TimestampHeader::LEN + data.len());
+        payload.extend_from_slice(&header.encode());
+        payload.extend_from_slice(data);
+        Self::new(MessageType::LogData, payload)
+    }
+
+    /// Create a file metadata frame
+    pub fn file_meta(payload: &FileMetaPayload) -> Result<Self, ProtocolError> {
+        let bytes =
+            serde_json::to_vec(payload).map_err(|e| ProtocolError::Serialization(e.to_string()))?;
+        Ok(Self::new(MessageType::FileMeta, bytes))
+    }
+
+    /// Create a source closed frame
+    pub fn source_closed(payload: &SourceClosedPayload) -> Result<Self, ProtocolError> {
+        let bytes =
+            serde_json::to_vec(payload).map_err(|e| ProtocolError::Serialization(e.to_string()))?;
+        Ok(Self::new(MessageType::SourceClosed, bytes))
+    }
+
+    /// Create a goodbye frame
+    pub fn goodbye(payload: &GoodbyePayload) -> Result<Self, ProtocolError> {
+        let bytes =
+            serde_json::to_vec(payload).map_err(|e| ProtocolError::Serialization(e.to_string()))?;
+        Ok(Self::new(MessageType::Goodbye, bytes))
+    }
+
     /// Create a keepalive frame
     pub fn keepalive() -> Self {
         Self::new(MessageType::Keepalive, Vec::new())
     }
 
+    /// Length prefix for a payload of `payload_len` bytes, which counts the
+    /// type byte too. Fails instead of wrapping when it doesn't fit in u32.
+    pub fn length_prefix(payload_len: usize) -> Result<u32, ProtocolError> {
+        payload_len
+            .checked_add(1)
+            .and_then(|len| u32::try_from(len).ok())
+            .ok_or_else(|| {
+                ProtocolError::InvalidFrame(format!(
+                    "payload of {} bytes exceeds the maximum of {}",
+                    payload_len, MAX_PAYLOAD_LEN
+                ))
+            })
+    }
+
     /// Encode frame to bytes
-    pub fn encode(&self) -> Vec<u8> {
-        let payload_len = self.payload.len() + 1;
-        let mut buf = Vec::with_capacity(4 + payload_len);
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        let len = Self::length_prefix(self.payload.len())?;
+        let mut buf = Vec::with_capacity(4 + len as usize);
 
-        buf.extend_from_slice(&(payload_len as u32).to_be_bytes());
+        buf.extend_from_slice(&len.to_be_bytes());
         buf.push(self.message_type as u8);
         buf.extend_from_slice(&self.payload);
 
-        buf
+        Ok(buf)
+    }
+
+    /// Read one frame from reader
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, ProtocolError> {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 || len > MAX_INBOUND_FRAME_LEN {
+            return Err(ProtocolError::InvalidFrame(format!(
+                "frame length {} out of range",
+                len
+            )));
+        }
+
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body)?;
+        let message_type = MessageType::try_from(body[0])?;
+        body.remove(0);
+        Ok(Self::new(message_type, body))
     }
 
-    /// Write frame to writer
+    /// Write frame to writer.
+    ///
+    /// Tracks how much of the frame reached the writer. If an error (including
+    /// a write timeout surfacing as `WouldBlock`/`TimedOut`) happens after part
+    /// of the frame was written, `ProtocolError::PartialWrite` is returned: the
+    /// stream is then out of sync and must not be written to again.
     pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
-        let encoded = self.encode();
-        writer.write_all(&encoded)?;
-        writer.flush()?;
-        Ok(())
+        let encoded = self.encode()?;
+        let total = encoded.len();
+        let mut written = 0;
+
+        while written < total {
+            match writer.write(&encoded[written..]) {
+                Ok(0) => {
+                    return Err(Self::write_error(
+                        io::Error::new(io::ErrorKind::WriteZero, "failed to write frame"),
+                        written,
+                        total,
+                    ))
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Self::write_error(e, written, total)),
+            }
+        }
+
+        writer
+            .flush()
+            .map_err(|e| Self::write_error(e, written, total))
+    }
+
+    fn write_error(source: io::Error, written: usize, total: usize) -> ProtocolError {
+        if written == 0 {
+            ProtocolError::Io(source)
+        } else {
+            ProtocolError::PartialWrite {
+                written,
+                total,
+                source,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_prefix_rejects_payloads_past_u32() {
+        assert_eq!(Frame::length_prefix(0).unwrap(), 1);
+        assert_eq!(Frame::length_prefix(MAX_PAYLOAD_LEN).unwrap(), u32::MAX);
+        assert!(matches!(
+            Frame::length_prefix(MAX_PAYLOAD_LEN + 1),
+            Err(ProtocolError::InvalidFrame(_))
+        ));
+        assert!(Frame::length_prefix(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn serializes_extra_handshake_fields() {
+        let fields = serde_json::json!({"cluster": "eu-1", "attrs": {"tier": 2}});
+        let fields = fields.as_object().unwrap().clone();
+
+        let mut payload = HandshakePayload::new("svc", "agent");
+        payload.set_extra(fields.clone(), ExtraPlacement::Nested);
+        let json: Value = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["extra"]["cluster"], "eu-1");
+        assert_eq!(json["extra"]["attrs"]["tier"], 2);
+        assert!(json.get("cluster").is_none());
+
+        let mut payload = HandshakePayload::new("svc", "agent");
+        payload.set_extra(fields, ExtraPlacement::Flatten);
+        let json: Value = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["cluster"], "eu-1");
+        assert_eq!(json["project_name"], "svc");
+        assert!(json.get("extra").is_none());
+    }
+
+    #[test]
+    fn encodes_length_type_and_payload() {
+        let encoded = Frame::log_data(b"hi".to_vec()).encode().unwrap();
+        assert_eq!(
+            encoded,
+            [0, 0, 0, 3, MessageType::LogData as u8, b'h', b'i']
+        );
+    }
+
+    #[test]
+    fn timestamp_header_round_trips() {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_123);
+        let header = TimestampHeader::new(time);
+        assert_eq!(header.timestamp_ms, 1_700_000_000_123);
+
+        let frame = Frame::timestamped_log_data(header, b"line\n");
+        let encoded = frame.encode().unwrap();
+        let decoded = Frame::read_from(&mut &encoded[..]).unwrap();
+        assert_eq!(decoded.message_type, MessageType::LogData);
+        let (decoded_header, data) = TimestampHeader::decode(&decoded.payload).unwrap();
+        assert_eq!(decoded_header, header);
+        assert_eq!(data, b"line\n");
+
+        // A header alone carries no data
+        let encoded = header.encode();
+        let (_, data) = TimestampHeader::decode(&encoded).unwrap();
+        assert!(data.is_empty());
+        assert!(matches!(
+            TimestampHeader::decode(&[0u8; 7]),
+            Err(ProtocolError::InvalidFrame(_))
+        ));
+    }
+
+    /// Accepts `room` bytes, then times out like a stalled socket
+    struct ShortWriter {
+        written: Vec<u8>,
+        room: usize,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out"));
+            }
+            let n = buf.len().min(self.room).min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            self.room -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reports_how_much_of_a_frame_was_written() {
+        let frame = Frame::log_data(b"hello world".to_vec());
+        let mut writer = ShortWriter {
+            written: Vec::new(),
+            room: 7,
+        };
+        match frame.write_to(&mut writer) {
+            Err(ProtocolError::PartialWrite { written, total, .. }) => {
+                assert_eq!(written, 7);
+                assert_eq!(total, 16);
+            }
+            other => panic!("expected a partial write, got {:?}", other),
+        }
+        assert_eq!(writer.written, frame.encode().unwrap()[..7]);
+
+        // Nothing written yet is a plain I/O error, the stream is still in sync
+        let mut writer = ShortWriter {
+            written: Vec::new(),
+            room: 0,
+        };
+        assert!(matches!(
+            frame.write_to(&mut writer),
+            Err(ProtocolError::Io(_))
+        ));
+
+        let mut writer = ShortWriter {
+            written: Vec::new(),
+            room: usize::MAX,
+        };
+        frame.write_to(&mut writer).unwrap();
+        assert_eq!(writer.written, frame.encode().unwrap());
     }
 }
@@ -0,0 +1,171 @@
+//! Byte rate limiting
+//!
+//! A token bucket refilled at a fixed byte rate, holding at most one second
+//! worth of tokens. A send larger than the bucket is let through and the
+//! resulting debt is paid off by sleeping.
+//!
+//! Byte caps are a hard budget instead: once a window's bytes are used up
+//! nothing more is sent until the next window starts.
+
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Token bucket limiting throughput to a number of bytes per second
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            tokens: bytes_per_sec,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.last_refill = now;
+    }
+
+    /// Account for `bytes` about to be sent, waiting if over the rate
+    pub async fn throttle(&mut self, bytes: usize) {
+        self.refill();
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            sleep(Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)).await;
+        }
+    }
+}
+
+/// What happens to data once a byte cap is reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CapAction {
+    /// Stop reading from the queue; the file is read again once the
+    /// window resets
+    #[default]
+    Hold,
+    /// Discard data until the window resets
+    Drop,
+}
+
+/// Most bytes sent within each fixed window
+#[derive(Debug, Clone)]
+pub struct ByteCap {
+    /// Name used in log messages, e.g. "hourly"
+    pub name: &'static str,
+    pub bytes: u64,
+    pub window: Duration,
+}
+
+/// Bytes used against a [`ByteCap`] in the current window. Windows follow
+/// each other from the moment the budget is created.
+#[derive(Debug)]
+pub struct ByteBudget {
+    cap: ByteCap,
+    window_start: Instant,
+    used: u64,
+    exhausted: bool,
+}
+
+impl ByteBudget {
+    pub fn new(cap: ByteCap) -> Self {
+        Self {
+            cap,
+            window_start: Instant::now(),
+            used: 0,
+            exhausted: false,
+        }
+    }
+
+    fn roll(&mut self) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < self.cap.window {
+            return;
+        }
+        let windows = (elapsed.as_nanos() / self.cap.window.as_nanos().max(1)) as u32;
+        self.window_start += self.cap.window * windows;
+        self.used = 0;
+        if self.exhausted {
+            self.exhausted = false;
+            tracing::info!("{} byte cap window reset, sending again", self.cap.name);
+        }
+    }
+
+    /// Whether `bytes` more fit in the current window
+    pub fn allows(&mut self, bytes: usize) -> bool {
+        self.roll();
+        if self.used + bytes as u64 <= self.cap.bytes {
+            return true;
+        }
+        if !self.exhausted {
+            self.exhausted = true;
+            tracing::warn!(
+                "{} byte cap of {} bytes reached, sending stops for {:?}",
+                self.cap.name,
+                self.cap.bytes,
+                self.cap.window.saturating_sub(self.window_start.elapsed())
+            );
+        }
+        false
+    }
+
+    /// Bytes that still fit in the current window
+    pub fn room(&mut self) -> u64 {
+        self.roll();
+        self.cap.bytes.saturating_sub(self.used)
+    }
+
+    /// Account for `bytes` that were sent
+    pub fn charge(&mut self, bytes: usize) {
+        self.roll();
+        self.used += bytes as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_budget_resets_each_window() {
+        let mut budget = ByteBudget::new(ByteCap {
+            name: "test",
+            bytes: 10,
+            window: Duration::from_millis(100),
+        });
+        assert!(budget.allows(6));
+        budget.charge(6);
+        assert!(budget.allows(4));
+        budget.charge(4);
+        assert!(!budget.allows(1));
+
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(budget.allows(10));
+        budget.charge(10);
+        assert!(!budget.allows(1));
+    }
+
+    #[test]
+    fn oversized_chunk_only_gets_the_room_left() {
+        let mut budget = ByteBudget::new(ByteCap {
+            name: "test",
+            bytes: 10,
+            window: Duration::from_secs(60),
+        });
+        assert!(!budget.allows(50));
+        assert_eq!(budget.room(), 10);
+        budget.charge(4);
+        assert_eq!(budget.room(), 6);
+        budget.charge(6);
+        assert_eq!(budget.room(), 0);
+        assert!(!budget.allows(1));
+    }
+}
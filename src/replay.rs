@@ -0,0 +1,259 @@
+//! Timed replay of historical logs
+//!
+//! With `--replay` (together with `--from-start --once`) lines are released
+//! at the pace their embedded timestamps were written, sped up by
+//! `--replay-speed`. Useful for load-testing a server with real traffic
+//! shapes instead of one burst.
+
+use crate::tail::LogChunk;
+use crate::transform::DEFAULT_LINE_DELIMITER;
+use anyhow::{Context, Result};
+use regex::bytes::Regex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Default `--timestamp-regex`: an ISO 8601 / RFC 3339 style date and time
+pub const DEFAULT_TIMESTAMP_REGEX: &str = r"(\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?)";
+
+/// Parse a `--replay-speed` factor
+pub fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(format!(
+            "invalid replay speed '{}', expected a factor > 0",
+            s
+        )),
+    }
+}
+
+/// Parse a timestamp into seconds. Accepts `YYYY-MM-DD[T ]HH:MM:SS[.frac]`
+/// (any zone suffix is ignored, only differences matter) and Unix epoch
+/// seconds or milliseconds.
+pub fn parse_timestamp(text: &str) -> Option<f64> {
+    let text = text.trim();
+    if let Ok(value) = text.parse::<f64>() {
+        // 13 digits and up is milliseconds
+        return Some(if value >= 1e12 { value / 1000.0 } else { value });
+    }
+
+    let (date, time) = text.split_once(['T', ' '])?;
+    let mut date = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    let time = time.trim_end_matches('Z');
+    let mut time = time.splitn(3, ':');
+    let hour: i64 = time.next()?.parse().ok()?;
+    let minute: i64 = time.next()?.parse().ok()?;
+    let seconds = time.next()?;
+    let seconds_end = seconds
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == ','))
+        .unwrap_or(seconds.len());
+    let second: f64 = seconds[..seconds_end].replace(',', ".").parse().ok()?;
+
+    let days = days_from_civil(year, month, day);
+    Some((days * 86_400 + hour * 3600 + minute * 60) as f64 + second)
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Works out how long to wait before each line
+pub struct Replay {
+    regex: Regex,
+    speed: f64,
+    last_timestamp: Option<f64>,
+    last_delay: Duration,
+    delimiter: u8,
+}
+
+impl Replay {
+    pub fn new(pattern: &str, speed: f64) -> Result<Self> {
+        let regex = Regex::new(pattern).context("Invalid --timestamp-regex")?;
+        Ok(Self {
+            regex,
+            speed,
+            last_timestamp: None,
+            last_delay: Duration::ZERO,
+            delimiter: DEFAULT_LINE_DELIMITER,
+        })
+    }
+
+    /// Pace lines ending in `delimiter` instead of a newline
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    fn timestamp(&self, line: &[u8]) -> Option<f64> {
+        let captures = self.regex.captures(line)?;
+        let text = captures.get(1).or_else(|| captures.get(0))?;
+        parse_timestamp(std::str::from_utf8(text.as_bytes()).ok()?)
+    }
+
+    /// Delay before `line`: the gap to the previous timestamp divided by the
+    /// speed. Lines without a timestamp repeat the previous delay; time
+    /// going backwards means no delay.
+    pub fn delay(&mut self, line: &[u8]) -> Duration {
+        let Some(timestamp) = self.timestamp(line) else {
+            return self.last_delay;
+        };
+        let delay = match self.last_timestamp {
+            Some(last) if timestamp > last => {
+                Duration::from_secs_f64((timestamp - last) / self.speed)
+            }
+            _ => Duration::ZERO,
+        };
+        self.last_timestamp = Some(timestamp);
+        self.last_delay = delay;
+        delay
+    }
+
+    /// Forward lines from `rx` to `tx`, sleeping between them. Lines due
+    /// at the same time are sent together.
+    pub async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<LogChunk>,
+        tx: mpsc::Sender<LogChunk>,
+    ) -> Result<()> {
+        let delimiter = self.delimiter;
+        // Source end of the last chunk forwarded in full
+        let mut end = None;
+        while let Some(chunk) = rx.recv().await {
+            let mut pending = Vec::new();
+            let mut pending_numbers = Vec::new();
+            let mut line_numbers = chunk.line_numbers.iter();
+            for line in chunk.data.split_inclusive(|&b| b == delimiter) {
+                let delay = self.delay(line);
+                if !delay.is_zero() {
+                    if !pending.is_empty() {
+                        let mut ready = LogChunk::new(std::mem::take(&mut pending));
+                        ready.line_numbers = std::mem::take(&mut pending_numbers);
+                        ready.end.clone_from(&end);
+                        if tx.send(ready).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    tokio::time::sleep(delay).await;
+                }
+                pending.extend_from_slice(line);
+                if line.last() == Some(&delimiter) {
+                    pending_numbers.extend(line_numbers.next());
+                }
+            }
+            if chunk.end.is_some() {
+                end = chunk.end;
+            }
+            if !pending.is_empty() {
+                let mut ready = LogChunk::new(pending);
+                ready.line_numbers = pending_numbers;
+                ready.end.clone_from(&end);
+                if tx.send(ready).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn parses_common_timestamp_formats() {
+        let base = parse_timestamp("2024-03-01T12:00:00Z").unwrap();
+        assert_eq!(
+            parse_timestamp("2024-03-01 12:00:01.5").unwrap() - base,
+            1.5
+        );
+        assert_eq!(
+            parse_timestamp("2024-03-02T12:00:00+08:00").unwrap() - base,
+            86_400.0
+        );
+        assert_eq!(parse_timestamp("1709294400").unwrap(), base);
+        assert_eq!(parse_timestamp("1709294400250").unwrap() - base, 0.25);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn delays_follow_timestamp_gaps() {
+        let mut replay = Replay::new(DEFAULT_TIMESTAMP_REGEX, 2.0).unwrap();
+        assert_eq!(replay.delay(b"2024-03-01 12:00:00 start\n"), Duration::ZERO);
+        assert_eq!(
+            replay.delay(b"2024-03-01 12:00:04 next\n"),
+            Duration::from_secs(2)
+        );
+        // No timestamp: same delay as the line before
+        assert_eq!(replay.delay(b"  at frame 1\n"), Duration::from_secs(2));
+        // Out of order
+        assert_eq!(replay.delay(b"2024-03-01 11:59:00 late\n"), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn paces_lines_relative_to_each_other() {
+        let replay = Replay::new(DEFAULT_TIMESTAMP_REGEX, 10.0).unwrap();
+        let (in_tx, in_rx) = mpsc::channel(4);
+        let (out_tx, mut out_rx) = mpsc::channel(4);
+        tokio::spawn(replay.run(in_rx, out_tx));
+
+        in_tx
+            .send(LogChunk::new(
+                b"2024-03-01 12:00:00 a\n2024-03-01 12:00:00 b\n\
+                  2024-03-01 12:00:01 c\n2024-03-01 12:00:04 d\n"
+                    .to_vec(),
+            ))
+            .await
+            .unwrap();
+        drop(in_tx);
+
+        let start = Instant::now();
+        let mut arrivals = Vec::new();
+        while let Some(chunk) = out_rx.recv().await {
+            arrivals.push((chunk.data, start.elapsed()));
+        }
+
+        // a and b together, c 100ms later, d 300ms after c
+        assert_eq!(arrivals.len(), 3);
+        assert!(arrivals[0].0.ends_with(b" b\n"));
+        let gap1 = arrivals[1].1 - arrivals[0].1;
+        let gap2 = arrivals[2].1 - arrivals[1].1;
+        assert!(gap1 >= Duration::from_millis(100), "{:?}", gap1);
+        assert!(gap2 >= Duration::from_millis(300), "{:?}", gap2);
+        assert!(gap2 > gap1 * 2, "{:?} vs {:?}", gap1, gap2);
+    }
+
+    #[tokio::test]
+    async fn paces_lines_ending_in_the_configured_delimiter() {
+        let replay = Replay::new(DEFAULT_TIMESTAMP_REGEX, 10.0)
+            .unwrap()
+            .with_delimiter(0);
+        let (in_tx, in_rx) = mpsc::channel(4);
+        let (out_tx, mut out_rx) = mpsc::channel(4);
+        tokio::spawn(replay.run(in_rx, out_tx));
+
+        let mut chunk =
+            LogChunk::new(b"2024-03-01 12:00:00 a\n more\x002024-03-01 12:00:01 b\x00".to_vec());
+        chunk.line_numbers = vec![1, 2];
+        in_tx.send(chunk).await.unwrap();
+        drop(in_tx);
+
+        let mut arrivals = Vec::new();
+        while let Some(chunk) = out_rx.recv().await {
+            arrivals.push(chunk);
+        }
+        assert_eq!(arrivals.len(), 2);
+        assert_eq!(arrivals[0].data, b"2024-03-01 12:00:00 a\n more\x00");
+        assert_eq!(arrivals[0].line_numbers, vec![1]);
+        assert_eq!(arrivals[1].line_numbers, vec![2]);
+    }
+}
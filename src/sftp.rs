@@ -0,0 +1,748 @@
+//! SFTP sources
+//!
+//! `--file sftp://user@host[:port]/path` tails a file on a host the agent
+//! can't be installed on. ssh2 is blocking, so a dedicated thread holds the
+//! session, polls the remote file at the `--min-poll-ms`/`--max-poll-ms`
+//! pace and forwards whatever was appended. A dropped session is opened
+//! again with the reconnect backoff and reading resumes at the same offset.
+//!
+//! SFTP has no inodes, so rotation is noticed from sizes alone: once the
+//! open handle is drained, a path shorter than the read offset means the
+//! file was truncated or replaced, and the path is read again from the
+//! start. A replacement that already grew past the old offset by the next
+//! poll is not noticed.
+//!
+//! An unknown or changed host key and a refused login stop the reader
+//! instead of being retried. Built with the `sftp` cargo feature.
+
+use crate::backoff::Backoff;
+use crate::metrics::Metrics;
+use crate::tail::{LogChunk, PollInterval, TailPosition};
+use anyhow::{Context, Result};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session, Sftp};
+use std::io::{Read, Seek, SeekFrom};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// Prefix of a `--file` value read over SFTP
+pub const SCHEME: &str = "sftp://";
+
+/// Size of the chunks read from the remote file
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Limit for connecting and for any single SSH operation, so a dead
+/// session fails instead of hanging the reader
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// SFTP status for a path that doesn't exist
+const FX_NO_SUCH_FILE: i32 = 2;
+
+/// libssh2 errors for a key file that can't be used and a login the
+/// server refused
+const LOGIN_REFUSED: [i32; 3] = [-16, -18, -19];
+
+/// How often a sleeping reader checks for shutdown
+const SHUTDOWN_CHECK: Duration = Duration::from_millis(100);
+
+/// The remote host can't be trusted or won't let the agent in; retrying
+/// can't help
+#[derive(Debug, thiserror::Error)]
+pub enum SshRejected {
+    #[error("Host key of {host} is not listed in {}", .known_hosts.display())]
+    UnknownHostKey { host: String, known_hosts: PathBuf },
+    #[error("Host key of {host} does not match the one in {}", .known_hosts.display())]
+    HostKeyMismatch { host: String, known_hosts: PathBuf },
+    #[error("Failed to log in as {user}: {source}")]
+    Login { user: String, source: ssh2::Error },
+}
+
+/// Whether `--file` names a remote file
+pub fn is_url(file: &Path) -> bool {
+    file.to_str().is_some_and(|file| file.starts_with(SCHEME))
+}
+
+/// Parsed `sftp://user@host[:port]/path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SftpUrl {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub path: PathBuf,
+}
+
+impl SftpUrl {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| format!("'{}' is not an {} URL", url, SCHEME))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => return Err(format!("'{}' has no remote file path", url)),
+        };
+        let (user, host_port) = authority
+            .split_once('@')
+            .filter(|(user, _)| !user.is_empty())
+            .ok_or_else(|| format!("'{}' has no user, expected sftp://user@host/path", url))?;
+
+        // Bracketed IPv6 addresses contain colons of their own
+        let (host, port) = match host_port.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("'{}' has an unterminated IPv6 address", url))?;
+                (host, after.strip_prefix(':'))
+            }
+            None => match host_port.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (host_port, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(format!("'{}' has no host", url));
+        }
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("'{}' has an invalid port '{}'", url, port))?,
+            None => 22,
+        };
+        if path.len() < 2 {
+            return Err(format!("'{}' has no remote file path", url));
+        }
+
+        Ok(Self {
+            user: user.to_string(),
+            host: host.to_string(),
+            port,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl std::fmt::Display for SftpUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "{}{}@[{}]", SCHEME, self.user, self.host)?;
+        } else {
+            write!(f, "{}{}@{}", SCHEME, self.user, self.host)?;
+        }
+        if self.port != 22 {
+            write!(f, ":{}", self.port)?;
+        }
+        write!(f, "{}", self.path.display())
+    }
+}
+
+/// How to log in to the remote host
+#[derive(Debug, Clone)]
+pub enum SshAuth {
+    /// Keys offered by the running ssh-agent
+    Agent,
+    /// A private key file, with its passphrase if it has one
+    Key {
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+    Password(String),
+}
+
+/// A remote file tailed over SFTP
+pub struct SftpSource {
+    url: SftpUrl,
+    auth: SshAuth,
+    known_hosts: PathBuf,
+    /// Existing content to send at most, `None` for all of it
+    backfill: Option<u64>,
+    delimiter: u8,
+    once: bool,
+    poll: PollInterval,
+    backoff: Backoff,
+    /// End of the content that existed when the file was first opened
+    backfill_end: u64,
+    /// The next chunk read starts a new file
+    new_file: bool,
+}
+
+impl SftpSource {
+    /// The host key must be listed in `known_hosts`
+    pub fn new(url: SftpUrl, auth: SshAuth, known_hosts: PathBuf, backoff: Backoff) -> Self {
+        Self {
+            url,
+            auth,
+            known_hosts,
+            backfill: None,
+            delimiter: b'\n',
+            once: false,
+            poll: PollInterval::default(),
+            backoff,
+            backfill_end: 0,
+            new_file: false,
+        }
+    }
+
+    /// Send at most `bytes` of the content already in the file, moved
+    /// forward to a line start, instead of all of it
+    pub fn with_backfill(mut self, bytes: u64, delimiter: u8) -> Self {
+        self.backfill = Some(bytes);
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn with_poll_interval(mut self, min: Duration, max: Duration) -> Self {
+        self.poll = PollInterval::new(min, max);
+        self
+    }
+
+    /// Stop at the end of the file instead of following it
+    pub fn once(mut self) -> Self {
+        self.once = true;
+        self
+    }
+
+    /// Stream the remote file into `tx`, publishing the read offset to
+    /// `position`, until `shutdown` is set
+    pub async fn stream(
+        self,
+        tx: mpsc::Sender<LogChunk>,
+        metrics: Arc<Metrics>,
+        position: Arc<TailPosition>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        // A plain thread rather than spawn_blocking: a stuck SSH operation
+        // must not hold up runtime shutdown
+        let (done_tx, done_rx) = oneshot::channel();
+        std::thread::Builder::new()
+            .name("sftp-reader".to_string())
+            .spawn(move || {
+                let _ = done_tx.send(self.run(&tx, &metrics, &position, &shutdown));
+            })
+            .context("Failed to start SFTP reader")?;
+        done_rx.await.context("SFTP reader stopped unexpectedly")?
+    }
+
+    fn run(
+        mut self,
+        tx: &mpsc::Sender<LogChunk>,
+        metrics: &Metrics,
+        position: &TailPosition,
+        shutdown: &watch::Receiver<bool>,
+    ) -> Result<()> {
+        // Unknown until the file was first opened
+        let mut offset = None;
+        loop {
+            let result = self.connect().and_then(|sftp| {
+                tracing::info!("Connected to {}", self.url);
+                self.backoff.reset();
+                self.follow(&sftp, &mut offset, tx, metrics, position, shutdown)
+            });
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is::<SshRejected>() => return Err(e),
+                Err(e) => {
+                    let delay = self.backoff.next();
+                    tracing::warn!(
+                        "SFTP source {} failed: {:#}. Retrying in {:?}",
+                        self.url,
+                        e,
+                        delay
+                    );
+                    if sleep_unless_shutdown(shutdown, delay) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<Sftp> {
+        let addr = (self.url.host.as_str(), self.url.port)
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve {}", self.url.host))?
+            .next()
+            .with_context(|| format!("{} resolved to no address", self.url.host))?;
+        let tcp = TcpStream::connect_timeout(&addr, SESSION_TIMEOUT)
+            .with_context(|| format!("Failed to connect to {}", addr))?;
+
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(SESSION_TIMEOUT.as_millis() as u32);
+        session.handshake().context("SSH handshake failed")?;
+        self.verify_host_key(&session)?;
+
+        let user = &self.url.user;
+        match &self.auth {
+            SshAuth::Agent => session.userauth_agent(user),
+            SshAuth::Key { path, passphrase } => {
+                session.userauth_pubkey_file(user, None, path, passphrase.as_deref())
+            }
+            SshAuth::Password(password) => session.userauth_password(user, password),
+        }
+        .map_err(|source| match source.code() {
+            ErrorCode::Session(code) if LOGIN_REFUSED.contains(&code) => SshRejected::Login {
+                user: user.clone(),
+                source,
+            }
+            .into(),
+            _ => anyhow::Error::new(source).context(format!("Failed to log in as {}", user)),
+        })?;
+
+        session.sftp().context("Failed to start the SFTP subsystem")
+    }
+
+    fn verify_host_key(&self, session: &Session) -> Result<()> {
+        let (key, _) = session.host_key().context("Server sent no host key")?;
+        let mut known_hosts = session.known_hosts()?;
+        known_hosts
+            .read_file(&self.known_hosts, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("Failed to read {}", self.known_hosts.display()))?;
+        match known_hosts.check_port(&self.url.host, self.url.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => Err(SshRejected::UnknownHostKey {
+                host: self.url.host.clone(),
+                known_hosts: self.known_hosts.clone(),
+            }
+            .into()),
+            CheckResult::Mismatch => Err(SshRejected::HostKeyMismatch {
+                host: self.url.host.clone(),
+                known_hosts: self.known_hosts.clone(),
+            }
+            .into()),
+            CheckResult::Failure => anyhow::bail!("Failed to check the host key"),
+        }
+    }
+
+    /// Read the file until the session fails; `Ok` means there is nothing
+    /// left to do
+    fn follow(
+        &mut self,
+        sftp: &Sftp,
+        offset: &mut Option<u64>,
+        tx: &mpsc::Sender<LogChunk>,
+        metrics: &Metrics,
+        position: &TailPosition,
+        shutdown: &watch::Receiver<bool>,
+    ) -> Result<()> {
+        // Owned, drain() borrows self mutably
+        let path = self.url.path.clone();
+        let path = path.as_path();
+        let mut file = sftp
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let size = file.stat()?.size.unwrap_or(0);
+        let start = match *offset {
+            None => {
+                self.backfill_end = size;
+                backfill_start(&mut file, size, self.backfill, self.delimiter)?
+            }
+            Some(offset) if offset <= size => offset,
+            Some(offset) => {
+                tracing::info!(
+                    "{} shrank below offset {} while disconnected, reading from the start",
+                    self.url,
+                    offset
+                );
+                self.new_file = true;
+                0
+            }
+        };
+        file.seek(SeekFrom::Start(start))?;
+        *offset = Some(start);
+        position.offset.store(start, Ordering::Relaxed);
+
+        loop {
+            let Some(read) = self.drain(&mut file, offset, tx, metrics, position)? else {
+                // The receiver is gone, the agent is shutting down
+                return Ok(());
+            };
+            if self.once {
+                return Ok(());
+            }
+
+            let size = match sftp.stat(path) {
+                Ok(stat) => stat.size.unwrap_or(0),
+                // Between a rename and the new file being created
+                Err(e) if e.code() == ErrorCode::SFTP(FX_NO_SUCH_FILE) => u64::MAX,
+                Err(e) => return Err(e.into()),
+            };
+            if size < offset.unwrap_or(0) {
+                tracing::info!(
+                    "{} was truncated or replaced, reading from the start",
+                    self.url
+                );
+                // Whatever reached a renamed file since the last read
+                if self
+                    .drain(&mut file, offset, tx, metrics, position)?
+                    .is_none()
+                {
+                    return Ok(());
+                }
+                file = sftp
+                    .open(path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                *offset = Some(0);
+                position.offset.store(0, Ordering::Relaxed);
+                self.new_file = true;
+                self.poll.reset();
+                continue;
+            }
+
+            if read > 0 {
+                self.poll.reset();
+            } else {
+                self.poll.idle();
+            }
+            if sleep_unless_shutdown(shutdown, self.poll.current()) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Send everything up to the end of `file`. Returns the number of bytes
+    /// read, or `None` once the receiver is gone.
+    fn drain<R: Read>(
+        &mut self,
+        file: &mut R,
+        offset: &mut Option<u64>,
+        tx: &mpsc::Sender<LogChunk>,
+        metrics: &Metrics,
+        position: &TailPosition,
+    ) -> Result<Option<u64>> {
+        let mut total = 0;
+        loop {
+            let mut buffer = vec![0u8; CHUNK_SIZE];
+            let n = match file.read(&mut buffer) {
+                Ok(0) => return Ok(Some(total)),
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("Failed to read the remote file"),
+            };
+            buffer.truncate(n);
+            total += n as u64;
+            let start = offset.unwrap_or(0);
+            *offset = Some(start + n as u64);
+            position.offset.store(start + n as u64, Ordering::Relaxed);
+            metrics.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+
+            let mut chunk = LogChunk::new(buffer);
+            chunk.new_file = std::mem::take(&mut self.new_file);
+            // Existing content counts as backfill until the file is replaced
+            if chunk.new_file {
+                self.backfill_end = 0;
+            }
+            chunk.backfill = start < self.backfill_end;
+            if tx.blocking_send(chunk).is_err() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Sleep for `delay`, waking early once `shutdown` is set. Returns whether
+/// the agent is shutting down.
+fn sleep_unless_shutdown(shutdown: &watch::Receiver<bool>, delay: Duration) -> bool {
+    let deadline = Instant::now() + delay;
+    loop {
+        if *shutdown.borrow() {
+            return true;
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return false;
+        }
+        std::thread::sleep(left.min(SHUTDOWN_CHECK));
+    }
+}
+
+/// Where to start in a file of `size` bytes so at most `limit` bytes are
+/// sent, moved forward to the start of the next line
+fn backfill_start<R: Read + Seek>(
+    file: &mut R,
+    size: u64,
+    limit: Option<u64>,
+    delimiter: u8,
+) -> std::io::Result<u64> {
+    let start = match limit {
+        Some(limit) if limit < size => size - limit,
+        _ => return Ok(0),
+    };
+    // Include the byte before `start`, a line may begin right there
+    file.seek(SeekFrom::Start(start - 1))?;
+    let mut window = Vec::new();
+    file.by_ref()
+        .take(size - start + 1)
+        .read_to_end(&mut window)?;
+    Ok(match window.iter().position(|&b| b == delimiter) {
+        Some(pos) => start + pos as u64,
+        None => size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn parses_sftp_urls() {
+        assert_eq!(
+            SftpUrl::parse("sftp://ops@legacy-1/var/log/app.log").unwrap(),
+            SftpUrl {
+                user: "ops".to_string(),
+                host: "legacy-1".to_string(),
+                port: 22,
+                path: PathBuf::from("/var/log/app.log"),
+            }
+        );
+        let url = SftpUrl::parse("sftp://ops@[::1]:2222/app.log").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 2222));
+        assert_eq!(url.to_string(), "sftp://ops@[::1]:2222/app.log");
+
+        assert!(SftpUrl::parse("sftp://legacy-1/var/log/app.log").is_err());
+        assert!(SftpUrl::parse("sftp://ops@legacy-1").is_err());
+        assert!(SftpUrl::parse("sftp://ops@legacy-1/").is_err());
+        assert!(SftpUrl::parse("sftp://ops@legacy-1:ssh/app.log").is_err());
+        assert!(is_url(Path::new("sftp://ops@legacy-1/app.log")));
+        assert!(!is_url(Path::new("/var/log/app.log")));
+    }
+
+    #[test]
+    fn backfill_starts_at_a_line_start() {
+        let data = b"first\nsecond\nthird\n";
+        let size = data.len() as u64;
+        let start = |limit| backfill_start(&mut Cursor::new(data), size, limit, b'\n').unwrap();
+
+        assert_eq!(start(None), 0);
+        assert_eq!(start(Some(100)), 0);
+        // "third\n" exactly
+        assert_eq!(start(Some(6)), 13);
+        // Mid-way through "second", skip to "third"
+        assert_eq!(start(Some(9)), 13);
+        assert_eq!(start(Some(0)), size);
+    }
+
+    #[tokio::test]
+    async fn stops_retrying_on_shutdown() {
+        // Nothing listens here, every attempt fails
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = SftpUrl::parse(&format!("sftp://ops@127.0.0.1:{}/app.log", port)).unwrap();
+        let backoff = Backoff::new(
+            Default::default(),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        );
+        let source = SftpSource::new(url, SshAuth::Agent, PathBuf::from("known_hosts"), backoff);
+
+        let (tx, _rx) = mpsc::channel(1);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let reader = tokio::spawn(source.stream(
+            tx,
+            Arc::new(Metrics::new()),
+            Arc::new(TailPosition::default()),
+            shutdown_rx,
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        shutdown_tx.send(true).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(2), reader)
+            .await
+            .expect("should stop waiting for the next attempt")
+            .unwrap()
+            .unwrap();
+    }
+
+    /// A local sshd accepting `client_key`, stopped on drop
+    #[cfg(feature = "sshd-tests")]
+    struct Sshd {
+        process: std::process::Child,
+        port: u16,
+        /// `type key` of the server's host key
+        host_key: String,
+        client_key: PathBuf,
+    }
+
+    #[cfg(feature = "sshd-tests")]
+    impl Sshd {
+        /// Needs `sshd` and `ssh-keygen`
+        fn start(dir: &Path) -> Self {
+            use std::process::{Command, Stdio};
+
+            let sshd = ["/usr/sbin/sshd", "/usr/local/sbin/sshd", "/sbin/sshd"]
+                .into_iter()
+                .find(|path| Path::new(path).exists())
+                .expect("the sshd-tests feature needs sshd installed");
+            let keygen = |name: &str| {
+                let status = Command::new("ssh-keygen")
+                    .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+                    .arg(dir.join(name))
+                    .status()
+                    .expect("ssh-keygen");
+                assert!(status.success());
+                std::fs::read_to_string(dir.join(format!("{}.pub", name))).unwrap()
+            };
+            let host_key = keygen("host_key");
+            let client_key = keygen("client_key");
+            std::fs::write(dir.join("authorized_keys"), client_key).unwrap();
+
+            let port = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let config = dir.join("sshd_config");
+            std::fs::write(
+                &config,
+                format!(
+                    "Port {port}\nListenAddress 127.0.0.1\nHostKey {dir}/host_key\n\
+                     AuthorizedKeysFile {dir}/authorized_keys\nPidFile {dir}/sshd.pid\n\
+                     StrictModes no\nUsePAM no\nSubsystem sftp internal-sftp\n",
+                    dir = dir.display()
+                ),
+            )
+            .unwrap();
+            let process = Command::new(sshd)
+                .arg("-D")
+                .arg("-f")
+                .arg(&config)
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+
+            Self {
+                process,
+                port,
+                host_key: host_key
+                    .split_whitespace()
+                    .take(2)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                client_key: dir.join("client_key"),
+            }
+        }
+
+        fn source(&self, file: &Path, known_hosts: PathBuf) -> SftpSource {
+            let user = std::process::Command::new("id")
+                .arg("-un")
+                .output()
+                .unwrap()
+                .stdout;
+            let url = SftpUrl::parse(&format!(
+                "sftp://{}@127.0.0.1:{}{}",
+                String::from_utf8(user).unwrap().trim(),
+                self.port,
+                file.display()
+            ))
+            .unwrap();
+            let auth = SshAuth::Key {
+                path: self.client_key.clone(),
+                passphrase: None,
+            };
+            let backoff = Backoff::new(
+                Default::default(),
+                Duration::from_millis(100),
+                Duration::from_millis(500),
+            );
+            SftpSource::new(url, auth, known_hosts, backoff)
+                .with_poll_interval(Duration::from_millis(50), Duration::from_millis(100))
+        }
+    }
+
+    #[cfg(feature = "sshd-tests")]
+    impl Drop for Sshd {
+        fn drop(&mut self) {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+    }
+
+    #[cfg(feature = "sshd-tests")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tails_a_file_through_a_local_sshd() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let sshd = Sshd::start(dir.path());
+        let known_hosts = dir.path().join("known_hosts");
+        std::fs::write(
+            &known_hosts,
+            format!("[127.0.0.1]:{} {}\n", sshd.port, sshd.host_key),
+        )
+        .unwrap();
+
+        let log = dir.path().join("app.log");
+        std::fs::write(&log, b"before\n").unwrap();
+        let (tx, mut rx) = mpsc::channel(16);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let reader = tokio::spawn(sshd.source(&log, known_hosts).stream(
+            tx,
+            Arc::new(Metrics::new()),
+            Arc::new(TailPosition::default()),
+            shutdown_rx,
+        ));
+        async fn next(rx: &mut mpsc::Receiver<LogChunk>) -> LogChunk {
+            tokio::time::timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        }
+        let chunk = next(&mut rx).await;
+        assert_eq!(chunk.data, b"before\n");
+        assert!(chunk.backfill);
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&log)
+            .unwrap()
+            .write_all(b"appended\n")
+            .unwrap();
+        let chunk = next(&mut rx).await;
+        assert_eq!(chunk.data, b"appended\n");
+        assert!(!chunk.backfill);
+
+        // Truncated in place, read again from the start
+        std::fs::write(&log, b"new\n").unwrap();
+        let chunk = next(&mut rx).await;
+        assert_eq!(chunk.data, b"new\n");
+        assert!(chunk.new_file);
+        assert!(!chunk.backfill);
+
+        reader.abort();
+    }
+
+    #[cfg(feature = "sshd-tests")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fails_on_an_unknown_host_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let sshd = Sshd::start(dir.path());
+        let known_hosts = dir.path().join("known_hosts");
+        std::fs::write(&known_hosts, b"").unwrap();
+
+        let log = dir.path().join("app.log");
+        std::fs::write(&log, b"before\n").unwrap();
+        let (tx, _rx) = mpsc::channel(16);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let result = tokio::time::timeout(
+            Duration::from_secs(10),
+            sshd.source(&log, known_hosts).stream(
+                tx,
+                Arc::new(Metrics::new()),
+                Arc::new(TailPosition::default()),
+                shutdown_rx,
+            ),
+        )
+        .await
+        .expect("should not retry");
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<SshRejected>(),
+            Some(SshRejected::UnknownHostKey { .. })
+        ));
+    }
+}
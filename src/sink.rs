@@ -0,0 +1,341 @@
+//! Output sinks
+//!
+//! Where shipped data ends up. The Logline server connection is the default;
+//! an HTTP endpoint (see [`crate::http`]), journald (see
+//! [`crate::journald`]), a local file (or stdout) and a discarding sink are
+//! the alternatives.
+//!
+//! [`Tee`] sits in front of any of them and mirrors the complete lines on
+//! their way out to stdout, for `--stdout-tee`.
+
+use crate::connection::Connection;
+use crate::metrics::Metrics;
+use crate::tail::LogChunk;
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Output selected with `--sink`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SinkKind {
+    /// Stream to the Logline server
+    #[default]
+    Logline,
+    /// Append to `--sink-path`, or stdout
+    File,
+    /// POST batches to `--url`
+    Http,
+    /// Write entries to the local journald
+    Journald,
+    /// Discard everything
+    Null,
+}
+
+/// Destination for shipped data
+pub trait Sink {
+    /// Ship one chunk
+    async fn send(&mut self, chunk: LogChunk) -> Result<()>;
+
+    /// Push out anything buffered, called whenever the input runs dry
+    async fn flush(&mut self) -> Result<()>;
+
+    /// Push out everything left once the input has ended, including a
+    /// last line that was never terminated
+    async fn finish(&mut self) -> Result<()> {
+        self.flush().await
+    }
+
+    /// Whether part of a line is held back until the rest of it arrives
+    fn holds_partial(&self) -> bool {
+        false
+    }
+}
+
+/// Feed everything from `rx` into `sink` until the channel closes
+pub async fn drain<S: Sink>(
+    mut rx: mpsc::Receiver<LogChunk>,
+    mut sink: S,
+    metrics: Arc<Metrics>,
+) -> Result<()> {
+    metrics.connected.store(true, Ordering::Relaxed);
+    // Source end of the data sent since the last flush, shipped once
+    // that flush succeeds
+    let mut unflushed = None;
+    loop {
+        // Paused from the control socket, leave data queued in the channel
+        if metrics.paused.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            continue;
+        }
+        let Some(chunk) = rx.recv().await else { break };
+        let len = chunk.data.len() as u64;
+        if chunk.end.is_some() {
+            unflushed = chunk.end.clone();
+        }
+        sink.send(chunk).await?;
+        metrics.frames_sent.fetch_add(1, Ordering::Relaxed);
+        metrics.bytes_sent.fetch_add(len, Ordering::Relaxed);
+        if rx.is_empty() {
+            sink.flush().await?;
+            if !sink.holds_partial() {
+                if let Some(end) = unflushed.take() {
+                    end.shipped();
+                }
+            }
+        }
+    }
+    sink.finish().await?;
+    if let Some(end) = unflushed {
+        end.shipped();
+    }
+    tracing::info!("Data channel closed, shutting down");
+    Ok(())
+}
+
+/// Writes raw data to a local file or stdout
+pub struct FileSink {
+    writer: Box<dyn Write + Send>,
+}
+
+impl FileSink {
+    /// Append to `path`, creating it if needed
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open sink file {}", path.display()))?;
+        Ok(Self {
+            writer: Box::new(std::io::BufWriter::new(file)),
+        })
+    }
+
+    pub fn stdout() -> Self {
+        Self {
+            writer: Box::new(std::io::stdout()),
+        }
+    }
+}
+
+impl Sink for FileSink {
+    async fn send(&mut self, chunk: LogChunk) -> Result<()> {
+        self.writer
+            .write_all(&chunk.data)
+            .context("Failed to write to sink")
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.writer.flush().context("Failed to flush sink")
+    }
+}
+
+/// Echoes every complete line that goes by to a local writer, then passes
+/// the chunk on unchanged. Output is buffered and flushed whenever the
+/// input runs dry.
+pub struct Tee {
+    writer: Box<dyn Write + Send>,
+    delimiter: u8,
+    /// Start of a line whose delimiter hasn't been seen yet
+    partial: Vec<u8>,
+}
+
+impl Tee {
+    pub fn new(writer: Box<dyn Write + Send>, delimiter: u8) -> Self {
+        Self {
+            writer,
+            delimiter,
+            partial: Vec::new(),
+        }
+    }
+
+    pub fn stdout(delimiter: u8) -> Self {
+        Self::new(
+            Box::new(std::io::BufWriter::new(std::io::stdout())),
+            delimiter,
+        )
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let Some(last) = data.iter().rposition(|&b| b == self.delimiter) else {
+            self.partial.extend_from_slice(data);
+            return Ok(());
+        };
+        if !self.partial.is_empty() {
+            self.writer.write_all(&std::mem::take(&mut self.partial))?;
+        }
+        self.writer.write_all(&data[..=last])?;
+        self.partial.extend_from_slice(&data[last + 1..]);
+        Ok(())
+    }
+
+    /// Mirror chunks from `rx` while forwarding them to `tx`. A failing
+    /// writer only turns the mirror off, shipping carries on.
+    pub async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<LogChunk>,
+        tx: mpsc::Sender<LogChunk>,
+    ) -> Result<()> {
+        let mut mirroring = true;
+        while let Some(chunk) = rx.recv().await {
+            if mirroring {
+                let mut written = self.write(&chunk.data);
+                if written.is_ok() && rx.is_empty() {
+                    written = self.writer.flush();
+                }
+                if let Err(e) = written {
+                    tracing::warn!("Failed to mirror to stdout, stopping --stdout-tee: {}", e);
+                    mirroring = false;
+                }
+            }
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+        if mirroring {
+            // A last unterminated line is still shipped, so show it too
+            let partial = std::mem::take(&mut self.partial);
+            let _ = self.writer.write_all(&partial);
+            let _ = self.writer.flush();
+        }
+        Ok(())
+    }
+}
+
+/// Discards data, for measuring the tail pipeline alone
+#[derive(Debug, Default)]
+pub struct NullSink;
+
+impl Sink for NullSink {
+    async fn send(&mut self, _chunk: LogChunk) -> Result<()> {
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A single server connection, established on first use. Errors drop the
+/// connection and are returned; the next send reconnects.
+impl Sink for Connection {
+    async fn send(&mut self, chunk: LogChunk) -> Result<()> {
+        if !self.is_connected() {
+            self.connect()?;
+        }
+        if let Err(e) = self.send_data(chunk) {
+            self.disconnect();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        // Frames are written straight to the socket
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionConfig;
+    use crate::testutil::ServerStub;
+
+    #[tokio::test]
+    async fn file_sink_writes_chunks_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.log");
+        let metrics = Arc::new(Metrics::new());
+
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(LogChunk::new(b"one\n".to_vec())).await.unwrap();
+        tx.send(LogChunk::new(b"two\n".to_vec())).await.unwrap();
+        drop(tx);
+        drain(rx, FileSink::open(&path).unwrap(), metrics.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"one\ntwo\n");
+        assert_eq!(metrics.bytes_sent.load(Ordering::Relaxed), 8);
+    }
+
+    /// Writer whose output the test can read back
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn tee_mirrors_complete_lines_that_are_shipped() {
+        let server = ServerStub::start();
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+        let captured = Captured::default();
+        let tee = Tee::new(Box::new(captured.clone()), b'\n');
+
+        let (tx, rx) = mpsc::channel(4);
+        let (tee_tx, tee_rx) = mpsc::channel(4);
+        let tee = tokio::spawn(tee.run(rx, tee_tx));
+        let sender = tokio::spawn(drain(
+            tee_rx,
+            Connection::new(config),
+            Arc::new(Metrics::new()),
+        ));
+
+        tx.send(LogChunk::new(b"one\ntw".to_vec())).await.unwrap();
+        let data = server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= 6)
+            .await;
+        // Only complete lines are echoed
+        assert_eq!(data, b"one\ntw");
+        assert_eq!(captured.0.lock().unwrap().as_slice(), b"one\n");
+
+        tx.send(LogChunk::new(b"o\nthree".to_vec())).await.unwrap();
+        drop(tx);
+        tee.await.unwrap().unwrap();
+        sender.await.unwrap().unwrap();
+        let data = server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= 13)
+            .await;
+        assert_eq!(captured.0.lock().unwrap().as_slice(), data.as_slice());
+    }
+
+    #[tokio::test]
+    async fn connection_sink_ships_to_server() {
+        let server = ServerStub::start();
+        let config = ConnectionConfig::new(
+            server.addr().to_string(),
+            "e2e".to_string(),
+            "agent-1".to_string(),
+        );
+
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(LogChunk::new(b"hello\n".to_vec())).await.unwrap();
+        drop(tx);
+        drain(rx, Connection::new(config), Arc::new(Metrics::new()))
+            .await
+            .unwrap();
+
+        let data = server
+            .wait_for_data(Duration::from_secs(5), |d| d.len() >= 6)
+            .await;
+        assert_eq!(data, b"hello\n");
+        assert_eq!(server.handshakes().len(), 1);
+    }
+}
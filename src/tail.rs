@@ -2,23 +2,842 @@
 //!
 //! Watches a file and streams new content as it's appended.
 
+use crate::backoff::{Backoff, BackoffCurve};
+use crate::memory::{MemoryCharge, MemoryGauge};
+use crate::metrics::Metrics;
+use crate::protocol::{FileMetaPayload, SourceClosedPayload};
 use anyhow::{Context, Result};
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashSet, VecDeque};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{BufRead, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc as tokio_mpsc;
 
+/// Default fastest poll interval
+pub const DEFAULT_MIN_POLL_MS: u64 = 200;
+/// Default slowest poll interval for an idle file
+pub const DEFAULT_MAX_POLL_MS: u64 = 2000;
+
+/// Poll interval that doubles while the file is idle and snaps back to
+/// the minimum on activity
+#[derive(Debug, Clone)]
+pub struct PollInterval {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl PollInterval {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        let max = max.max(min);
+        Self {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    /// Time to wait before the next poll
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// A poll found nothing, back off
+    pub fn idle(&mut self) {
+        self.current = (self.current * 2).min(self.max);
+    }
+
+    /// Data or a file event was seen, poll fast again
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+impl Default for PollInterval {
+    fn default() -> Self {
+        Self::new(
+            Duration::from_millis(DEFAULT_MIN_POLL_MS),
+            Duration::from_millis(DEFAULT_MAX_POLL_MS),
+        )
+    }
+}
+
+/// A chunk of file content together with the time it was read
+#[derive(Debug, Clone)]
+pub struct LogChunk {
+    pub data: Vec<u8>,
+    pub read_at: SystemTime,
+    /// Part of the content that already existed when the agent started
+    pub backfill: bool,
+    /// Counts the data against `--max-memory-bytes` while it is buffered
+    pub charge: Option<MemoryCharge>,
+    /// First data read from a file that replaced the previous one, or from
+    /// the start of a truncated file
+    pub new_file: bool,
+    /// Source line number of each complete line in `data`, filled in by
+    /// the line pipeline with `--line-numbers`
+    pub line_numbers: Vec<u64>,
+    /// Where in the source file everything up to and including `data`
+    /// ends; `None` if that isn't known
+    pub end: Option<SourceEnd>,
+}
+
+impl LogChunk {
+    /// Wrap data read just now
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            read_at: SystemTime::now(),
+            backfill: false,
+            charge: None,
+            new_file: false,
+            line_numbers: Vec::new(),
+            end: None,
+        }
+    }
+
+    /// New data produced from this chunk, read at the same time and
+    /// charged to the same memory limit
+    pub fn derive(&self, data: Vec<u8>) -> Self {
+        let charge = self
+            .charge
+            .as_ref()
+            .map(|charge| charge.gauge().charge(data.len()));
+        Self {
+            data,
+            read_at: self.read_at,
+            backfill: self.backfill,
+            charge,
+            new_file: self.new_file,
+            line_numbers: Vec::new(),
+            end: self.end.clone(),
+        }
+    }
+}
+
+/// Source offset a chunk ends at, reported back to the tail's
+/// [`TailPosition`] once the chunk has been shipped
+#[derive(Debug, Clone)]
+pub struct SourceEnd {
+    position: Arc<TailPosition>,
+    inode: u64,
+    offset: u64,
+}
+
+impl SourceEnd {
+    /// Everything up to here reached the sender's destination
+    pub fn shipped(&self) {
+        self.position.ship(self.inode, self.offset);
+    }
+}
+
+/// Read position of a `FileTail`, and how much of it has been shipped,
+/// published for checkpointing
+#[derive(Debug, Default)]
+pub struct TailPosition {
+    /// Offset read so far in the current file
+    pub offset: AtomicU64,
+    /// Inode of the current file (0 where unavailable)
+    pub inode: AtomicU64,
+    /// Inode and offset the sender has shipped up to, which lags the read
+    /// offset by whatever is still in the pipeline
+    shipped: Mutex<(u64, u64)>,
+}
+
+impl TailPosition {
+    pub fn new(offset: u64, inode: u64) -> Self {
+        Self {
+            offset: AtomicU64::new(offset),
+            inode: AtomicU64::new(inode),
+            shipped: Mutex::new((inode, offset)),
+        }
+    }
+
+    /// Inode and offset shipped so far
+    pub fn shipped(&self) -> (u64, u64) {
+        *self.shipped.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn ship(&self, inode: u64, offset: u64) {
+        *self.shipped.lock().unwrap_or_else(|e| e.into_inner()) = (inode, offset);
+    }
+
+    /// Move past content that is deliberately not sent, before anything
+    /// has been read
+    fn skip_to(&self, offset: u64) {
+        self.offset.store(offset, Ordering::Relaxed);
+        self.ship(self.inode.load(Ordering::Relaxed), offset);
+    }
+}
+
+/// What to do when the file shrinks below the read offset without being
+/// replaced (`--on-truncate`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TruncatePolicy {
+    /// Start over from the beginning of the file
+    #[default]
+    Reset,
+    /// Keep the offset and wait for the file to grow past it again
+    Continue,
+    /// Jump to the new end of the file, sending nothing that is there now
+    ResyncFromEnd,
+}
+
+/// How the ends of a `--range` are moved onto line boundaries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RangeSnap {
+    /// Move both ends forward to the start of the next line, so only
+    /// whole lines are sent
+    #[default]
+    Lines,
+    /// Use the exact byte offsets
+    Bytes,
+}
+
+/// Parse a `--range` value: `START-END` (end exclusive) or `START-` for
+/// everything up to the end of the file
+pub fn parse_range(value: &str) -> Result<(u64, Option<u64>), String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("expected START-END or START-, got '{}'", value))?;
+    let start = start
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid range start '{}': {}", start, e))?;
+    let end = match end.trim() {
+        "" => None,
+        end => Some(
+            end.parse()
+                .map_err(|e| format!("invalid range end '{}': {}", end, e))?,
+        ),
+    };
+    if end.is_some_and(|end| end <= start) {
+        return Err(format!("range '{}' is empty", value));
+    }
+    Ok((start, end))
+}
+
+/// What to do with a byte order mark at the start of the file (`--bom`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BomPolicy {
+    /// Send the file as it is
+    #[default]
+    Keep,
+    /// Drop a leading UTF-8 or UTF-16 byte order mark
+    Strip,
+    /// Drop the mark and convert UTF-16 files to UTF-8
+    Transcode,
+}
+
+/// Byte order marks recognized at the start of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bom {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Bom {
+    const ALL: [Bom; 3] = [Bom::Utf8, Bom::Utf16Le, Bom::Utf16Be];
+
+    fn bytes(self) -> &'static [u8] {
+        match self {
+            Bom::Utf8 => b"\xEF\xBB\xBF",
+            Bom::Utf16Le => b"\xFF\xFE",
+            Bom::Utf16Be => b"\xFE\xFF",
+        }
+    }
+
+    fn detect(data: &[u8]) -> Option<Bom> {
+        Self::ALL
+            .into_iter()
+            .find(|bom| data.starts_with(bom.bytes()))
+    }
+
+    /// Whether `data` could still turn out to start with a mark once
+    /// more of the file has been written
+    fn incomplete(data: &[u8]) -> bool {
+        Self::ALL
+            .into_iter()
+            .any(|bom| data.len() < bom.bytes().len() && bom.bytes().starts_with(data))
+    }
+}
+
+/// Converts UTF-16 to UTF-8, replacing unpaired surrogates with U+FFFD.
+/// An odd byte or a high surrogate at the end of a read is held back
+/// until the rest of it arrives.
+#[derive(Debug)]
+pub struct Utf16Decoder {
+    big_endian: bool,
+    pending: Vec<u8>,
+}
+
+impl Utf16Decoder {
+    pub fn new(big_endian: bool) -> Self {
+        Self {
+            big_endian,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(data);
+
+        let mut units: Vec<u16> = input
+            .chunks_exact(2)
+            .map(|pair| {
+                let pair = [pair[0], pair[1]];
+                if self.big_endian {
+                    u16::from_be_bytes(pair)
+                } else {
+                    u16::from_le_bytes(pair)
+                }
+            })
+            .collect();
+        let mut used = units.len() * 2;
+        if units
+            .last()
+            .is_some_and(|unit| (0xD800..0xDC00).contains(unit))
+        {
+            units.pop();
+            used -= 2;
+        }
+        self.pending = input[used..].to_vec();
+
+        char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    /// Bytes held back for an incomplete character
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Replace a held back incomplete character, once no more data follows
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        self.pending.clear();
+        Some(char::REPLACEMENT_CHARACTER.to_string().into_bytes())
+    }
+}
+
+/// Replaces invalid UTF-8 with U+FFFD. A multi-byte character split
+/// across two reads is held back until the rest of it arrives, instead
+/// of being replaced at the chunk edge.
+#[derive(Debug, Default)]
+pub struct LossyUtf8 {
+    /// Start of an incomplete character from the previous read
+    pending: Vec<u8>,
+}
+
+impl LossyUtf8 {
+    pub fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(data);
+
+        let mut out = Vec::with_capacity(input.len());
+        let mut chunks = input.utf8_chunks().peekable();
+        while let Some(chunk) = chunks.next() {
+            out.extend_from_slice(chunk.valid().as_bytes());
+            let invalid = chunk.invalid();
+            if invalid.is_empty() {
+                continue;
+            }
+            // Only the last chunk can end in a truncated sequence
+            let truncated = std::str::from_utf8(invalid).is_err_and(|e| e.error_len().is_none());
+            if chunks.peek().is_none() && truncated {
+                self.pending = invalid.to_vec();
+            } else {
+                out.extend_from_slice(char::REPLACEMENT_CHARACTER.to_string().as_bytes());
+            }
+        }
+        out
+    }
+
+    /// Bytes held back for an incomplete character
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Replace a held back incomplete character, once no more data follows
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        self.pending.clear();
+        Some(char::REPLACEMENT_CHARACTER.to_string().into_bytes())
+    }
+}
+
 /// File tail watcher
 pub struct FileTail {
     path: PathBuf,
     offset: u64,
     buffer_size: usize,
+    /// Handle to the file currently being read. Kept open so the old inode
+    /// can still be drained after the path is replaced.
+    file: Option<File>,
+    /// Identity of the file behind `file`
+    file_id: Option<FileId>,
+    position: Arc<TailPosition>,
+    /// File size at startup, while content up to it is still being read
+    backfill_end: Option<u64>,
+    poll: PollInterval,
+    metrics: Option<Arc<Metrics>>,
+    /// Buffered data limit, see `crate::memory`
+    memory: Option<Arc<MemoryGauge>>,
+    /// Give up on permission errors instead of retrying
+    fail_fast: bool,
+    /// Where to publish file metadata snapshots, and how often
+    meta: Option<(tokio_mpsc::Sender<FileMetaPayload>, Duration)>,
+    /// The file was replaced or truncated since the last snapshot
+    rotated: bool,
+    /// The next chunk starts a different file, or the file over again
+    new_file: bool,
+    /// Read whatever lies past `offset` as soon as the watch starts
+    initial_send: bool,
+    /// Directory for a hard link to the current file
+    guard_dir: Option<PathBuf>,
+    /// Hard link keeping the current inode reachable after `path` is
+    /// unlinked, removed once that inode has been drained
+    guard_link: Option<PathBuf>,
+    /// Replace invalid UTF-8 before sending
+    utf8: Option<LossyUtf8>,
+    bom: BomPolicy,
+    /// Converting the current file from UTF-16
+    utf16: Option<Utf16Decoder>,
+    /// The current file's encoding is known, either because its start
+    /// was read or because it was looked up when resuming mid-file
+    bom_checked: bool,
+    on_truncate: TruncatePolicy,
+    /// Waiting for the file to grow back past the offset (`continue`)
+    truncated: bool,
+    /// Nothing at or past this offset is read (`--range`)
+    end: Option<u64>,
+    /// Rotated names searched for files that were rotated away before
+    /// the tail got to them
+    rotation_suffixes: Vec<String>,
+    max_backlog: usize,
+    /// Rotated files already accounted for (drained, or there at startup)
+    known_rotated: HashSet<FileId>,
+    /// Missed rotated files still to drain, oldest first
+    backlog: VecDeque<(File, FileId, PathBuf)>,
+    /// Reopen the file after a stale handle or repeated read errors
+    reopen_on_error: bool,
+    /// How long the path may stay missing before the source counts as
+    /// closed, and where to report it
+    source_close: Option<(Duration, tokio_mpsc::Sender<SourceClosedPayload>)>,
+    /// When the path was first seen missing after the file was drained
+    missing_since: Option<Instant>,
+}
+
+/// Identity of a file on disk (device and inode on Unix)
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> Option<FileId> {
+    None
+}
+
+/// Consecutive read errors after which the file is reopened
+const REOPEN_AFTER_ERRORS: u32 = 3;
+
+/// Returned by [`FileTail::watch`] with `--fail-fast` when the file can't
+/// be opened for lack of permission, instead of waiting for it to be fixed
+#[derive(Debug, thiserror::Error)]
+#[error("Permission denied reading {}", .0.display())]
+pub struct PermissionDenied(pub PathBuf);
+
+/// Whether opening or reading a file failed for lack of permission
+pub fn is_permission_denied(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+    })
+}
+
+/// Whether a read failed on a stale NFS handle (ESTALE)
+fn is_stale_handle(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::StaleNetworkFileHandle)
+    })
+}
+
+/// Inode of the file at `path` (0 where unavailable)
+pub fn inode_of(path: &Path) -> Result<u64> {
+    let metadata = std::fs::metadata(path).context("Failed to get file metadata")?;
+    Ok(file_id(&metadata).map(|(_, ino)| ino).unwrap_or(0))
+}
+
+/// Names a rotated copy of `path` may have been renamed to: each suffix
+/// appended to the file name (`app.log.1`) and to its stem (`app.1`)
+pub fn rotated_candidates(path: &Path, suffixes: &[String]) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    for suffix in suffixes {
+        let mut full = path.as_os_str().to_owned();
+        full.push(suffix);
+        candidates.push(PathBuf::from(full));
+
+        if path.extension().is_some() {
+            let mut stem = path.with_extension("").into_os_string();
+            stem.push(suffix);
+            candidates.push(PathBuf::from(stem));
+        }
+    }
+    candidates
+}
+
+/// Find the rotated copy of `path` that has the given inode
+pub fn find_rotated(path: &Path, inode: u64, suffixes: &[String]) -> Option<PathBuf> {
+    rotated_candidates(path, suffixes)
+        .into_iter()
+        .find(|candidate| inode_of(candidate).ok() == Some(inode))
 }
 
 impl FileTail {
+    fn at_offset(path: PathBuf, offset: u64) -> Self {
+        // Published before the first read, so a checkpoint taken early
+        // saves where the tail starts rather than offset 0
+        let inode = inode_of(&path).unwrap_or(0);
+        Self {
+            path,
+            offset,
+            buffer_size: 64 * 1024, // 64KB buffer
+            file: None,
+            file_id: None,
+            position: Arc::new(TailPosition::new(offset, inode)),
+            backfill_end: None,
+            poll: PollInterval::default(),
+            metrics: None,
+            memory: None,
+            fail_fast: false,
+            meta: None,
+            rotated: false,
+            new_file: false,
+            initial_send: true,
+            guard_dir: None,
+            guard_link: None,
+            utf8: None,
+            bom: BomPolicy::default(),
+            utf16: None,
+            bom_checked: false,
+            on_truncate: TruncatePolicy::default(),
+            truncated: false,
+            end: None,
+            rotation_suffixes: Vec::new(),
+            max_backlog: 0,
+            known_rotated: HashSet::new(),
+            backlog: VecDeque::new(),
+            reopen_on_error: true,
+            source_close: None,
+            missing_since: None,
+        }
+    }
+
+    /// Count bytes read in the shared metrics
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Stop with [`PermissionDenied`] when the file can't be opened for
+    /// lack of permission, rather than waiting for access
+    pub fn with_fail_fast(mut self) -> Self {
+        self.fail_fast = true;
+        self
+    }
+
+    /// Charge chunks to `gauge`, and stop reading while it is full
+    pub fn with_memory_limit(mut self, gauge: Arc<MemoryGauge>) -> Self {
+        self.memory = Some(gauge);
+        self
+    }
+
+    async fn wait_for_memory(&self) {
+        if let Some(gauge) = &self.memory {
+            gauge.wait_for_room().await;
+        }
+    }
+
+    /// Poll between `min` and `max`, backing off while the file is idle
+    pub fn with_poll_interval(mut self, min: Duration, max: Duration) -> Self {
+        self.poll = PollInterval::new(min, max);
+        self
+    }
+
+    /// Publish a metadata snapshot every `interval` and after rotation
+    pub fn with_file_meta(
+        mut self,
+        tx: tokio_mpsc::Sender<FileMetaPayload>,
+        interval: Duration,
+    ) -> Self {
+        self.meta = Some((tx, interval));
+        self
+    }
+
+    /// Stop watching once the path has been gone for `grace` with every
+    /// byte read, publishing a `SourceClosedPayload` on `tx`
+    pub fn with_source_close(
+        mut self,
+        grace: Duration,
+        tx: tokio_mpsc::Sender<SourceClosedPayload>,
+    ) -> Self {
+        self.source_close = Some((grace, tx));
+        self
+    }
+
+    /// Skip the initial read and start at the end of the file as it is
+    /// when the watch starts, so nothing written before then is sent
+    pub fn without_initial_send(mut self) -> Self {
+        self.initial_send = false;
+        self
+    }
+
+    /// How to handle the file being truncated in place
+    pub fn with_truncate_policy(mut self, policy: TruncatePolicy) -> Self {
+        self.on_truncate = policy;
+        self
+    }
+
+    /// When the file is replaced, also drain rotated copies (named with
+    /// `suffixes`) that appeared since the last check, so files rotated
+    /// away within one poll aren't skipped. At most `max_backlog` are
+    /// queued at a time. Files present now are assumed to be sent already.
+    pub fn with_rotation_backfill(mut self, suffixes: &[String], max_backlog: usize) -> Self {
+        self.rotation_suffixes = suffixes.to_vec();
+        self.max_backlog = max_backlog;
+        self.known_rotated = self.rotated_files().map(|(_, id, _)| id).collect();
+        self
+    }
+
+    /// Rotated copies of the file currently on disk, with their mtime
+    fn rotated_files(&self) -> impl Iterator<Item = (SystemTime, FileId, PathBuf)> {
+        rotated_candidates(&self.path, &self.rotation_suffixes)
+            .into_iter()
+            .filter_map(|path| {
+                let metadata = std::fs::metadata(&path).ok()?;
+                let id = file_id(&metadata)?;
+                Some((metadata.modified().ok()?, id, path))
+            })
+    }
+
+    /// Queue rotated files nobody has read yet, oldest first
+    fn queue_missed_rotations(&mut self) {
+        if self.max_backlog == 0 || !self.backlog.is_empty() {
+            return;
+        }
+
+        let present: Vec<_> = self.rotated_files().collect();
+        self.known_rotated
+            .retain(|known| present.iter().any(|(_, id, _)| id == known));
+        // The file just drained
+        if let Some(id) = self.file_id {
+            self.known_rotated.insert(id);
+        }
+        let live = std::fs::metadata(&self.path).ok().and_then(|m| file_id(&m));
+
+        let mut missed: Vec<_> = present
+            .into_iter()
+            .filter(|(_, id, _)| !self.known_rotated.contains(id) && Some(*id) != live)
+            .collect();
+        missed.sort_by_key(|(mtime, id, _)| (*mtime, id.1));
+        missed.dedup_by_key(|(_, id, _)| *id);
+        if missed.len() > self.max_backlog {
+            tracing::warn!(
+                "{} rotated files were never read, draining the oldest {} first; rotation is outpacing the tail",
+                missed.len(),
+                self.max_backlog
+            );
+            missed.truncate(self.max_backlog);
+        }
+
+        for (_, id, path) in missed {
+            match File::open(&path) {
+                Ok(file) => {
+                    self.known_rotated.insert(id);
+                    self.backlog.push_back((file, id, path));
+                }
+                Err(e) => tracing::warn!("Failed to open rotated file {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Whether to reopen the file after a stale handle or repeated read
+    /// errors (on by default)
+    pub fn with_reopen_on_error(mut self, reopen: bool) -> Self {
+        self.reopen_on_error = reopen;
+        self
+    }
+
+    /// How to treat a byte order mark at the start of the file
+    pub fn with_bom_policy(mut self, policy: BomPolicy) -> Self {
+        self.bom = policy;
+        self
+    }
+
+    /// Replace invalid UTF-8 sequences with U+FFFD
+    pub fn with_lossy_utf8(mut self) -> Self {
+        self.utf8 = Some(LossyUtf8::default());
+        self
+    }
+
+    /// Keep a hard link to the current file in `dir`, so its inode can
+    /// still be drained if the path is unlinked before the tail opens it.
+    /// Does nothing if the link can't be created (e.g. another filesystem).
+    pub fn with_hardlink_guard(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.guard_dir = Some(dir.into());
+        self.link_guard();
+        self
+    }
+
+    /// Hard link the file currently at `path` into the guard directory
+    fn link_guard(&mut self) {
+        let Some(dir) = &self.guard_dir else {
+            return;
+        };
+        let name = self
+            .path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        // Link the target itself, not a symlink pointing at it
+        let result = self.path.canonicalize().and_then(|target| {
+            let ino = file_id(&std::fs::metadata(&target)?).map_or(0, |(_, ino)| ino);
+            let link = dir.join(format!(
+                ".{}.{}.{}.logline-guard",
+                name,
+                std::process::id(),
+                ino
+            ));
+            let _ = std::fs::remove_file(&link);
+            std::fs::hard_link(target, &link).map(|()| link)
+        });
+        match result {
+            Ok(link) => {
+                tracing::debug!("Hard link guard at {}", link.display());
+                self.guard_link = Some(link);
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Can't create hard link guard in {} ({}), continuing without it",
+                    dir.display(),
+                    e
+                );
+                self.guard_dir = None;
+            }
+        }
+    }
+
+    /// Remove the hard link to a drained file
+    fn unlink_guard(&mut self) {
+        if let Some(link) = self.guard_link.take() {
+            if let Err(e) = std::fs::remove_file(&link) {
+                tracing::debug!("Failed to remove {}: {}", link.display(), e);
+            }
+        }
+    }
+
+    /// Resume at an exact offset saved by a previous run
+    pub fn from_checkpoint(path: impl AsRef<Path>, offset: u64) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let metadata = std::fs::metadata(&path).context("Failed to get file metadata")?;
+        if offset > metadata.len() {
+            anyhow::bail!(
+                "Checkpoint offset {} exceeds file size {}",
+                offset,
+                metadata.len()
+            );
+        }
+
+        Ok(Self::at_offset(path, offset))
+    }
+
+    /// Resume at `offset` in a file that was rotated away from `path`.
+    /// The rotated file is drained to EOF before switching to `path`.
+    pub fn from_rotated(path: impl AsRef<Path>, rotated: &Path, offset: u64) -> Result<Self> {
+        let file = File::open(rotated)
+            .with_context(|| format!("Failed to open rotated file {}", rotated.display()))?;
+        let metadata = file.metadata()?;
+        if offset > metadata.len() {
+            anyhow::bail!(
+                "Checkpoint offset {} exceeds rotated file size {}",
+                offset,
+                metadata.len()
+            );
+        }
+
+        let mut tail = Self::at_offset(path.as_ref().to_path_buf(), offset);
+        tail.file_id = file_id(&metadata);
+        if let Some((_, ino)) = tail.file_id {
+            tail.position = Arc::new(TailPosition::new(offset, ino));
+        }
+        tail.file = Some(file);
+        Ok(tail)
+    }
+
+    /// Shared handle to the current read position
+    pub fn position(&self) -> Arc<TailPosition> {
+        self.position.clone()
+    }
+
+    /// Publish the read position to an existing handle, e.g. the one a
+    /// previous tail of the same file reported to. The handle is updated
+    /// to where this tail starts.
+    pub fn with_position(mut self, position: Arc<TailPosition>) -> Self {
+        let start = &self.position;
+        position
+            .offset
+            .store(start.offset.load(Ordering::Relaxed), Ordering::Relaxed);
+        position
+            .inode
+            .store(start.inode.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.position = position;
+        self
+    }
+
+    /// Continue where a previous tail of `path` stopped: at `offset` in the
+    /// same file, draining a rotated copy first, or from the start of a
+    /// file that replaced it
+    pub fn resume(
+        path: impl AsRef<Path>,
+        offset: u64,
+        inode: u64,
+        suffixes: &[String],
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let size = std::fs::metadata(path)
+            .context("Failed to get file metadata")?
+            .len();
+        // Inode 0: the file was never opened, so it can't have changed
+        if (inode == 0 || inode_of(path)? == inode) && offset <= size {
+            return Self::from_checkpoint(path, offset);
+        }
+        match find_rotated(path, inode, suffixes) {
+            Some(rotated) => Self::from_rotated(path, &rotated, offset),
+            None => Self::from_start(path),
+        }
+    }
+
     /// Create a new file tail watcher
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
         let path = path.as_ref().to_path_buf();
@@ -26,11 +845,14 @@ impl FileTail {
         // Get initial file size
         let metadata = std::fs::metadata(&path).context("Failed to get file metadata")?;
 
-        Ok(Self {
-            path,
-            offset: metadata.len(), // Start from end of file
-            buffer_size: 64 * 1024, // 64KB buffer
-        })
+        // Start from end of file
+        Ok(Self::at_offset(path, metadata.len()))
+    }
+
+    /// A tail that is never run, standing in for a source read by other
+    /// means. The path doesn't have to exist.
+    pub fn detached(path: impl AsRef<Path>) -> Self {
+        Self::at_offset(path.as_ref().to_path_buf(), 0)
     }
 
     /// Create a file tail that starts from the beginning
@@ -42,17 +864,14 @@ impl FileTail {
             anyhow::bail!("File does not exist: {}", path.display());
         }
 
-        Ok(Self {
-            path,
-            offset: 0,
-            buffer_size: 64 * 1024,
-        })
+        Ok(Self::at_offset(path, 0))
     }
 
     /// Create a file tail that starts from last N bytes
     /// This will adjust the offset to start at a valid UTF-8 character boundary
     /// and preferably at a line boundary to avoid truncating log lines.
-    pub fn with_tail_bytes(path: impl AsRef<Path>, tail_bytes: u64) -> Result<Self> {
+    /// Lines end in `delimiter`.
+    pub fn with_tail_bytes(path: impl AsRef<Path>, tail_bytes: u64, delimiter: u8) -> Result<Self> {
         let path = path.as_ref().to_path_buf();
 
         let metadata = std::fs::metadata(&path).context("Failed to get file metadata")?;
@@ -62,19 +881,152 @@ impl FileTail {
 
         // If we're not starting from the beginning, find a valid line boundary
         if offset > 0 {
-            offset = Self::find_line_boundary(&path, offset)?;
+            offset = Self::find_line_boundary(&path, offset, delimiter)?;
         }
 
-        Ok(Self {
-            path,
-            offset,
-            buffer_size: 64 * 1024,
-        })
+        Ok(Self::at_offset(path, offset))
+    }
+
+    /// Create a file tail that starts from an exact byte offset.
+    /// The offset must lie within the file; if it points into the middle of a
+    /// line it is moved forward to the start of the next line, lines ending
+    /// in `delimiter`.
+    pub fn from_offset(path: impl AsRef<Path>, offset: u64, delimiter: u8) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let metadata = std::fs::metadata(&path).context("Failed to get file metadata")?;
+        let file_size = metadata.len();
+
+        if offset > file_size {
+            anyhow::bail!(
+                "Start offset {} exceeds file size {} of {}",
+                offset,
+                file_size,
+                path.display()
+            );
+        }
+
+        let offset = Self::snap_to_line_start(&path, offset, delimiter)?;
+
+        Ok(Self::at_offset(path, offset))
+    }
+
+    /// Create a file tail that starts at the given 1-based line number,
+    /// lines ending in `delimiter`
+    pub fn from_line(path: impl AsRef<Path>, line: u64, delimiter: u8) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let file = File::open(&path).context("Failed to open file")?;
+        let mut reader = std::io::BufReader::new(file);
+        let mut offset = 0u64;
+        let mut current = 1u64;
+        let mut buf = Vec::new();
+
+        while current < line {
+            buf.clear();
+            let read = reader.read_until(delimiter, &mut buf)?;
+            if read == 0 {
+                anyhow::bail!(
+                    "Start line {} exceeds the {} lines of {}",
+                    line,
+                    current - 1,
+                    path.display()
+                );
+            }
+            offset += read as u64;
+            current += 1;
+        }
+
+        Ok(Self::at_offset(path, offset))
+    }
+
+    /// Create a file tail that only reads `start..end` (to the end of the
+    /// file without `end`). Both offsets must lie within the file; with
+    /// [`RangeSnap::Lines`] each is moved forward to the next line start,
+    /// lines ending in `delimiter`.
+    pub fn byte_range(
+        path: impl AsRef<Path>,
+        start: u64,
+        end: Option<u64>,
+        snap: RangeSnap,
+        delimiter: u8,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file_size = std::fs::metadata(&path)
+            .context("Failed to get file metadata")?
+            .len();
+
+        let end = end.unwrap_or(file_size);
+        if start > file_size || end > file_size {
+            anyhow::bail!(
+                "Range {}-{} is outside {} ({} bytes)",
+                start,
+                end,
+                path.display(),
+                file_size
+            );
+        }
+        let (start, end) = match snap {
+            RangeSnap::Lines => (
+                Self::snap_to_line_start(&path, start, delimiter)?,
+                Self::snap_to_line_start(&path, end, delimiter)?,
+            ),
+            RangeSnap::Bytes => (start, end),
+        };
+        if start >= end {
+            anyhow::bail!(
+                "Range holds no complete line of {} once snapped to lines",
+                path.display()
+            );
+        }
+
+        let mut tail = Self::at_offset(path, start);
+        tail.end = Some(end);
+        Ok(tail)
+    }
+
+    /// Cap the backlog sent on startup to at most `max_bytes`.
+    /// The start is moved forward to a line boundary; returns the number of
+    /// backlog bytes skipped.
+    pub fn limit_backfill(&mut self, max_bytes: u64, delimiter: u8) -> Result<u64> {
+        let file_size = std::fs::metadata(&self.path)
+            .context("Failed to get file metadata")?
+            .len();
+
+        if file_size.saturating_sub(self.offset) <= max_bytes {
+            return Ok(0);
+        }
+
+        let new_offset = Self::snap_to_line_start(&self.path, file_size - max_bytes, delimiter)?;
+
+        let skipped = new_offset - self.offset;
+        self.offset = new_offset;
+        self.position.skip_to(new_offset);
+        Ok(skipped)
+    }
+
+    /// Keep `offset` if a line starts there, otherwise move it forward to
+    /// the start of the next line
+    fn snap_to_line_start(path: &Path, offset: u64, delimiter: u8) -> Result<u64> {
+        if offset == 0 {
+            return Ok(0);
+        }
+
+        let mut file = File::open(path).context("Failed to open file")?;
+        file.seek(SeekFrom::Start(offset - 1))?;
+        let mut byte = [0u8; 1];
+        file.read_exact(&mut byte)?;
+
+        if byte[0] == delimiter {
+            Ok(offset)
+        } else {
+            Self::find_line_boundary(path, offset, delimiter)
+        }
     }
 
-    /// Find the nearest line boundary (newline character) at or after the given offset.
+    /// Find the nearest line boundary (just past `delimiter`) at or after the given offset.
     /// This ensures we don't start reading in the middle of a line or UTF-8 character.
-    fn find_line_boundary(path: &Path, offset: u64) -> Result<u64> {
+    fn find_line_boundary(path: &Path, offset: u64, delimiter: u8) -> Result<u64> {
         let mut file = File::open(path).context("Failed to open file")?;
         let file_size = file.metadata()?.len();
 
@@ -85,7 +1037,7 @@ impl FileTail {
 
         file.seek(SeekFrom::Start(offset))?;
 
-        // Read a small buffer to find the next newline
+        // Read a small buffer to find the next delimiter
         let search_size = 4096.min((file_size - offset) as usize);
         let mut buffer = vec![0u8; search_size];
         let bytes_read = file.read(&mut buffer)?;
@@ -96,12 +1048,12 @@ impl FileTail {
 
         buffer.truncate(bytes_read);
 
-        // Find the first newline in the buffer
-        if let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
-            // Return the position after the newline
+        // Find the first delimiter in the buffer
+        if let Some(pos) = buffer.iter().position(|&b| b == delimiter) {
+            // Return the position after the delimiter
             Ok(offset + pos as u64 + 1)
         } else {
-            // No newline found in the search window, just use the offset
+            // No delimiter found in the search window, just use the offset
             // but make sure we're at a valid UTF-8 boundary
             Ok(Self::find_utf8_boundary(&buffer, 0) + offset)
         }
@@ -127,23 +1079,269 @@ impl FileTail {
 
     /// Read new content from the file
     pub fn read_new_content(&mut self) -> Result<Option<Vec<u8>>> {
-        let mut file = File::open(&self.path).context("Failed to open file")?;
-
-        let metadata = file.metadata()?;
-        let current_size = metadata.len();
+        let data = loop {
+            let Some(data) = self.read_next()? else {
+                break None;
+            };
+            if let Some(metrics) = &self.metrics {
+                metrics
+                    .bytes_read
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            // The start of a byte order mark, wait for the rest
+            let Some(data) = self.apply_bom(data)? else {
+                break None;
+            };
+            match self.utf8.as_mut() {
+                Some(_) if data.is_empty() => {}
+                Some(utf8) => {
+                    let decoded = utf8.decode(&data);
+                    // Nothing but the start of a character, read on
+                    if !decoded.is_empty() {
+                        break Some(decoded);
+                    }
+                }
+                None if data.is_empty() => {}
+                None => break Some(data),
+            }
+        };
 
-        // Handle file truncation (log rotation)
-        if current_size < self.offset {
-            tracing::info!("File truncated, resetting offset");
-            self.offset = 0;
+        // Held back bytes haven't been sent yet
+        let pending = self.utf8.as_ref().map_or(0, LossyUtf8::pending)
+            + self.utf16.as_ref().map_or(0, Utf16Decoder::pending);
+        let pending = pending as u64;
+        self.position
+            .offset
+            .store(self.offset.saturating_sub(pending), Ordering::Relaxed);
+        if let Some((_, ino)) = self.file_id {
+            self.position.inode.store(ino, Ordering::Relaxed);
         }
 
-        // No new content
-        if current_size == self.offset {
-            return Ok(None);
-        }
+        Ok(data)
+    }
 
-        // Seek to last position
+    /// Strip a byte order mark from data read at the start of a file and
+    /// transcode UTF-16 if asked to. Returns `None` if the data could be
+    /// the start of a mark; the offset is then rewound to read it again.
+    fn apply_bom(&mut self, mut data: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if self.bom == BomPolicy::Keep {
+            return Ok(Some(data));
+        }
+        if self.offset == data.len() as u64 {
+            if Bom::incomplete(&data) {
+                self.offset = 0;
+                return Ok(None);
+            }
+            self.utf16 = None;
+            self.bom_checked = true;
+            if let Some(bom) = Bom::detect(&data) {
+                data.drain(..bom.bytes().len());
+                self.start_decoding(bom);
+            }
+        } else if !self.bom_checked {
+            // Resumed mid-file, look at how the file starts
+            self.bom_checked = true;
+            if self.bom == BomPolicy::Transcode {
+                if let Some(bom) = self.sniff_bom()? {
+                    self.start_decoding(bom);
+                }
+            }
+        }
+        Ok(Some(match self.utf16.as_mut() {
+            Some(decoder) => decoder.decode(&data),
+            None => data,
+        }))
+    }
+
+    fn start_decoding(&mut self, bom: Bom) {
+        let big_endian = match bom {
+            Bom::Utf8 => return,
+            Bom::Utf16Le => false,
+            Bom::Utf16Be => true,
+        };
+        if self.bom == BomPolicy::Transcode {
+            tracing::info!("{} is UTF-16, converting it to UTF-8", self.path.display());
+            self.utf16 = Some(Utf16Decoder::new(big_endian));
+        } else {
+            tracing::warn!(
+                "{} is UTF-16 and is sent as is, use --bom transcode to convert it",
+                self.path.display()
+            );
+        }
+    }
+
+    /// Byte order mark at the start of the open file, if any
+    fn sniff_bom(&mut self) -> Result<Option<Bom>> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(None);
+        };
+        let mut start = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.take(3).read_to_end(&mut start)?;
+        Ok(Bom::detect(&start))
+    }
+
+    /// Flush characters held back by the decoders, once no more data follows
+    fn finish_decoding(&mut self) -> Option<Vec<u8>> {
+        let utf16 = self.utf16.as_mut().and_then(Utf16Decoder::finish);
+        let utf8 = self.utf8.as_mut().and_then(LossyUtf8::finish);
+        match (utf16, utf8) {
+            (None, None) => None,
+            (utf16, utf8) => Some([utf8.unwrap_or_default(), utf16.unwrap_or_default()].concat()),
+        }
+    }
+
+    fn read_next(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.file.is_none() {
+            match self.guard_link.clone() {
+                // The file seen at startup, even if `path` changed since
+                Some(link) => self.open(&link)?,
+                None => self.open_path()?,
+            }
+        }
+
+        // The path now points at a different file (e.g. rotation by
+        // rename-over). Drain the old file to EOF, then start the new one
+        // from the beginning.
+        if self.path_replaced()? {
+            if let Some(data) = self.read_from_handle()? {
+                return Ok(Some(data));
+            }
+            self.unlink_guard();
+            self.queue_missed_rotations();
+            if let Some((file, id, path)) = self.backlog.pop_front() {
+                tracing::info!(
+                    "Draining {}, it was rotated away before it could be read",
+                    path.display()
+                );
+                self.file = Some(file);
+                self.file_id = Some(id);
+            } else {
+                match std::fs::read_link(&self.path) {
+                    Ok(target) => tracing::info!(
+                        "Symlink {} now points to {}, switching to it",
+                        self.path.display(),
+                        target.display()
+                    ),
+                    Err(_) => tracing::info!("File replaced, switching to new file"),
+                }
+                self.open_path()?;
+                self.link_guard();
+            }
+            self.offset = 0;
+            self.backfill_end = None;
+            self.rotated = true;
+            self.new_file = true;
+        }
+
+        self.read_from_handle()
+    }
+
+    /// Open the file currently at `path`
+    fn open_path(&mut self) -> Result<()> {
+        let path = self.path.clone();
+        self.open(&path)
+    }
+
+    fn open(&mut self, path: &Path) -> Result<()> {
+        let file = File::open(path).context("Failed to open file")?;
+        self.file_id = file_id(&file.metadata()?);
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// Drop the open handle and open `path` afresh, e.g. after the handle
+    /// went stale on a network filesystem. The offset is kept if the file
+    /// still reaches it.
+    fn reopen(&mut self) -> Result<()> {
+        let previous = self.file_id;
+        let size = std::fs::metadata(&self.path)
+            .context("Failed to get file metadata")?
+            .len();
+        // Keep the old handle until the new one is open, so a file that
+        // was replaced meanwhile is still recognized as a new file
+        self.open_path()?;
+        if self.file_id != previous {
+            tracing::info!(
+                "{} is a different file after reopening, starting over",
+                self.path.display()
+            );
+            self.offset = 0;
+            self.rotated = true;
+            self.new_file = true;
+        } else if size < self.offset {
+            tracing::warn!(
+                "{} is shorter than offset {} after reopening, starting over",
+                self.path.display(),
+                self.offset
+            );
+            self.offset = 0;
+            self.rotated = true;
+            self.new_file = true;
+        }
+        tracing::info!("Reopened {} at offset {}", self.path.display(), self.offset);
+        Ok(())
+    }
+
+    /// Check whether `path` now refers to a different file than the open handle
+    fn path_replaced(&self) -> Result<bool> {
+        match std::fs::metadata(&self.path) {
+            Ok(metadata) => Ok(file_id(&metadata) != self.file_id),
+            // Removed without replacement (yet), keep reading the old file
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).context("Failed to get file metadata"),
+        }
+    }
+
+    /// Read from the open handle starting at the current offset
+    fn read_from_handle(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(None);
+        };
+
+        let metadata = file.metadata()?;
+        let current_size = metadata.len();
+
+        // Handle file truncation (log rotation)
+        if current_size < self.offset {
+            match self.on_truncate {
+                TruncatePolicy::Reset => {
+                    tracing::info!("File truncated, resetting offset (--on-truncate reset)");
+                    self.offset = 0;
+                    self.rotated = true;
+                    self.new_file = true;
+                }
+                TruncatePolicy::Continue => {
+                    if !self.truncated {
+                        tracing::warn!(
+                            "File truncated to {} bytes, waiting for it to grow past offset {} (--on-truncate continue)",
+                            current_size,
+                            self.offset
+                        );
+                        self.truncated = true;
+                    }
+                    return Ok(None);
+                }
+                TruncatePolicy::ResyncFromEnd => {
+                    tracing::info!(
+                        "File truncated, skipping to its new end at {} (--on-truncate resync-from-end)",
+                        current_size
+                    );
+                    self.offset = current_size;
+                    self.rotated = true;
+                    self.new_file = true;
+                }
+            }
+        }
+        self.truncated = false;
+        let current_size = self.end.map_or(current_size, |end| current_size.min(end));
+
+        // No new content
+        if current_size == self.offset {
+            return Ok(None);
+        }
+
+        // Seek to last position
         file.seek(SeekFrom::Start(self.offset))?;
 
         // Read new content
@@ -161,67 +1359,281 @@ impl FileTail {
         Ok(Some(buffer))
     }
 
-    /// Start watching the file and stream changes
-    pub async fn watch(mut self, tx: tokio_mpsc::Sender<Vec<u8>>) -> Result<()> {
-        let (notify_tx, notify_rx) = channel();
+    /// Wrap data that was just read, flagging startup backlog
+    fn chunk(&mut self, data: Vec<u8>) -> LogChunk {
+        let mut chunk = LogChunk::new(data);
+        chunk.new_file = std::mem::take(&mut self.new_file);
+        // The published offset leaves out bytes a decoder holds back
+        chunk.end = Some(SourceEnd {
+            position: self.position.clone(),
+            inode: self.position.inode.load(Ordering::Relaxed),
+            offset: self.position.offset.load(Ordering::Relaxed),
+        });
+        chunk.charge = self
+            .memory
+            .as_ref()
+            .map(|gauge| gauge.charge(chunk.data.len()));
+        if let Some(end) = self.backfill_end {
+            chunk.backfill = true;
+            if self.offset >= end {
+                self.backfill_end = None;
+            }
+        }
+        chunk
+    }
 
-        // Create file watcher
-        let mut watcher = RecommendedWatcher::new(
-            move |res: Result<Event, notify::Error>| {
-                if let Ok(event) = res {
-                    let _ = notify_tx.send(event);
-                }
-            },
-            Config::default().with_poll_interval(Duration::from_millis(100)),
-        )
-        .context("Failed to create file watcher")?;
+    /// Send everything up to the end of the file, then stop
+    pub async fn read_once(mut self, tx: tokio_mpsc::Sender<LogChunk>) -> Result<()> {
+        tracing::info!("Reading {} to the end", self.path.display());
+        let metadata = std::fs::metadata(&self.path).context("Failed to get file metadata")?;
+        self.backfill_end = Some(metadata.len()).filter(|&end| end > self.offset);
+
+        loop {
+            self.wait_for_memory().await;
+            let Some(data) = self.read_new_content()? else {
+                break;
+            };
+            let chunk = self.chunk(data);
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+        // The file ended in the middle of a character
+        if let Some(rest) = self.finish_decoding() {
+            let _ = tx.send(LogChunk::new(rest)).await;
+        }
+        tracing::info!("Reached the end of {}", self.path.display());
+        Ok(())
+    }
 
-        // Watch the parent directory
-        let parent = self.path.parent().unwrap_or_else(|| Path::new("."));
+    /// Send a metadata snapshot when one is due or the file was rotated.
+    /// Nothing is sent while the file is missing.
+    fn publish_meta(&mut self, last_sent: &mut Instant) {
+        let Some((tx, interval)) = &self.meta else {
+            return;
+        };
+        if !self.rotated && last_sent.elapsed() < *interval {
+            return;
+        }
+        self.rotated = false;
+        *last_sent = Instant::now();
 
-        watcher
-            .watch(parent, RecursiveMode::NonRecursive)
-            .context("Failed to watch directory")?;
+        let Ok(metadata) = std::fs::metadata(&self.path) else {
+            return;
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let payload = FileMetaPayload {
+            path: self.path.to_string_lossy().into_owned(),
+            size: metadata.len(),
+            mtime,
+            offset: self.offset,
+        };
+        // Best effort, tailing never waits on the sender for metadata
+        let _ = tx.try_send(payload);
+    }
 
-        tracing::info!("Started watching: {}", self.path.display());
+    /// Whether the path has been missing for the whole grace period.
+    /// Called when a read found nothing more to send.
+    fn source_gone(&mut self) -> bool {
+        let Some((grace, _)) = &self.source_close else {
+            return false;
+        };
+        if self.path.exists() || !self.backlog.is_empty() {
+            self.missing_since = None;
+            return false;
+        }
+        let since = *self.missing_since.get_or_insert_with(Instant::now);
+        since.elapsed() >= *grace
+    }
+
+    /// Report the source as closed after sending anything held back
+    async fn close_source(&mut self, tx: &tokio_mpsc::Sender<LogChunk>) {
+        tracing::info!(
+            "{} was removed and did not come back, closing the source",
+            self.path.display()
+        );
+        if let Some(rest) = self.finish_decoding() {
+            let _ = tx.send(LogChunk::new(rest)).await;
+        }
+        if let Some((_, closed_tx)) = &self.source_close {
+            let _ = closed_tx.try_send(SourceClosedPayload {
+                path: self.path.to_string_lossy().into_owned(),
+                reason: "removed".to_string(),
+                offset: self.offset,
+            });
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.source_closed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Start watching the file and stream changes
+    #[tracing::instrument(name = "tail", skip_all, fields(file = %self.path.display()))]
+    pub async fn watch(mut self, tx: tokio_mpsc::Sender<LogChunk>) -> Result<()> {
+        let (notify_tx, mut notify_rx) = tokio_mpsc::unbounded_channel();
 
-        // Initial read - always send existing content from current offset to end
-        if let Some(data) = self.read_new_content()? {
-            tracing::info!("Sending initial {} bytes", data.len());
-            if tx.send(data).await.is_err() {
-                return Ok(());
+        // File events only shortcut the poll interval, so the tail keeps
+        // working by polling alone if the watcher can't be used
+        let mut watcher = match Self::start_watcher(&self.path, notify_tx) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                tracing::warn!("File watcher unavailable ({:#}), polling only", e);
+                None
             }
+        };
+
+        tracing::info!("Started watching: {}", self.path.display());
+
+        // Content appended since the tail was created is backlog too
+        if !self.initial_send {
+            let end = std::fs::metadata(&self.path)
+                .context("Failed to get file metadata")?
+                .len();
+            self.offset = end;
+            self.position.skip_to(end);
         }
 
-        // Watch loop - use tokio interval for async-friendly polling
-        let mut interval = tokio::time::interval(Duration::from_millis(200));
+        // Everything up to the current size is backlog
+        if let Ok(metadata) = std::fs::metadata(&self.path) {
+            self.backfill_end = Some(metadata.len()).filter(|&end| end > self.offset);
+        }
 
+        let mut initial = self.initial_send;
+        let mut failing = false;
+        let mut read_errors = 0u32;
+        let mut last_meta = Instant::now();
         loop {
-            // Use tokio select to handle both file events and polling
-            tokio::select! {
-                _ = interval.tick() => {
-                    // Drain all pending file events (non-blocking)
-                    while let Ok(event) = notify_rx.try_recv() {
-                        if Self::is_relevant_event(&event, &self.path) {
-                            tracing::debug!("File event detected: {:?}", event.kind);
-                        }
+            // Poll with backoff while idle; a file event wakes it early
+            if !initial {
+                let wake_at = tokio::time::Instant::now() + self.poll.current();
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(wake_at) => break,
+                        Some(res) = notify_rx.recv() => match res {
+                            Ok(event) if Self::is_relevant_event(&event, &self.path) => {
+                                tracing::debug!("File event detected: {:?}", event.kind);
+                                self.poll.reset();
+                                break;
+                            }
+                            Ok(_) => {}
+                            Err(e) => {
+                                if watcher.take().is_some() {
+                                    tracing::warn!(
+                                        "File watcher failed ({}), falling back to polling",
+                                        e
+                                    );
+                                }
+                            }
+                        },
                     }
+                }
+            }
 
-                    // Check for new content
-                    if let Some(data) = self.read_new_content()? {
-                        tracing::info!("Sending {} bytes", data.len());
-                        if tx.send(data).await.is_err() {
-                            tracing::info!("Channel closed, stopping file watcher");
-                            break;
+            // Check for new content. Read errors are retried with backoff,
+            // e.g. while the file is briefly missing during rotation.
+            self.wait_for_memory().await;
+            match self.read_new_content() {
+                Ok(data) => {
+                    read_errors = 0;
+                    if failing {
+                        tracing::info!("Reading {} recovered", self.path.display());
+                        failing = false;
+                    }
+                    match data {
+                        Some(data) => {
+                            self.poll.reset();
+                            if initial {
+                                tracing::info!("Sending initial {} bytes", data.len());
+                            } else {
+                                tracing::info!("Sending {} bytes", data.len());
+                            }
+                            let chunk = self.chunk(data);
+                            if tx.send(chunk).await.is_err() {
+                                tracing::info!("Channel closed, stopping file watcher");
+                                break;
+                            }
+                        }
+                        None => {
+                            if self.source_gone() {
+                                self.close_source(&tx).await;
+                                break;
+                            }
+                            self.poll.idle();
                         }
                     }
                 }
+                Err(e) => {
+                    let denied = is_permission_denied(&e);
+                    if denied && self.fail_fast {
+                        return Err(e.context(PermissionDenied(self.path.clone())));
+                    }
+                    if failing {
+                        tracing::debug!("Read still failing: {:#}", e);
+                    } else if denied {
+                        tracing::error!(
+                            "Permission denied reading {}: the agent's user can't open it, e.g. \
+                             after a deploy changed its owner or mode. Retrying until access is \
+                             restored (--fail-fast exits instead)",
+                            self.path.display()
+                        );
+                        failing = true;
+                    } else {
+                        tracing::warn!("Failed to read {}, retrying: {:#}", self.path.display(), e);
+                        failing = true;
+                    }
+                    read_errors += 1;
+                    // A stale handle never recovers by itself
+                    if self.reopen_on_error
+                        && self.file.is_some()
+                        && (is_stale_handle(&e) || read_errors >= REOPEN_AFTER_ERRORS)
+                    {
+                        read_errors = 0;
+                        if let Err(e) = self.reopen() {
+                            tracing::debug!("Reopen failed: {:#}", e);
+                        }
+                    }
+                    if self.source_gone() {
+                        self.close_source(&tx).await;
+                        break;
+                    }
+                    self.poll.idle();
+                }
             }
+            self.publish_meta(&mut last_meta);
+            initial = false;
         }
 
         Ok(())
     }
 
+    /// Watch the parent directory of `path`, forwarding events and errors
+    fn start_watcher(
+        path: &Path,
+        events: tokio_mpsc::UnboundedSender<notify::Result<Event>>,
+    ) -> Result<RecommendedWatcher> {
+        let mut watcher = RecommendedWatcher::new(
+            move |res: notify::Result<Event>| {
+                let _ = events.send(res);
+            },
+            Config::default().with_poll_interval(Duration::from_millis(100)),
+        )
+        .context("Failed to create file watcher")?;
+
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        watcher
+            .watch(parent, RecursiveMode::NonRecursive)
+            .context("Failed to watch directory")?;
+        Ok(watcher)
+    }
+
     /// Check if event is relevant to our file
     fn is_relevant_event(event: &Event, path: &Path) -> bool {
         match event.kind {
@@ -230,3 +1642,915 @@ impl FileTail {
         }
     }
 }
+
+/// Run `tail`, restarting the watch with backoff if it fails.
+///
+/// `rebuild` creates the replacement tail from the last published
+/// position. After `max_restarts` consecutive failed restarts the last
+/// error is returned. A restarted tail that delivers data resets the count.
+pub async fn supervise<F>(
+    tail: FileTail,
+    mut rebuild: F,
+    tx: tokio_mpsc::Sender<LogChunk>,
+    max_restarts: u32,
+    restart_delay: Duration,
+) -> Result<()>
+where
+    F: FnMut(Arc<TailPosition>) -> Result<FileTail>,
+{
+    let mut backoff = Backoff::new(
+        BackoffCurve::Exponential,
+        restart_delay,
+        Duration::from_secs(30),
+    );
+    let mut restarts = 0u32;
+    let mut tail = Some(tail);
+    let position = tail.as_ref().map(|t| t.position()).unwrap_or_default();
+
+    loop {
+        let error = match tail.take() {
+            Some(tail) => {
+                let read_before = position.offset.load(Ordering::Relaxed);
+                match tail.watch(tx.clone()).await {
+                    // The receiving side closed or the source ended,
+                    // nothing left to do
+                    Ok(()) => return Ok(()),
+                    // Restarting won't fix permissions
+                    Err(e) if e.is::<PermissionDenied>() => return Err(e),
+                    Err(e) => {
+                        if position.offset.load(Ordering::Relaxed) != read_before {
+                            restarts = 0;
+                            backoff.reset();
+                        }
+                        e
+                    }
+                }
+            }
+            None => match rebuild(position.clone()) {
+                Ok(rebuilt) => {
+                    tail = Some(rebuilt);
+                    continue;
+                }
+                Err(e) => e,
+            },
+        };
+
+        if restarts >= max_restarts {
+            return Err(error.context(format!(
+                "File watcher failed, gave up after {} restarts",
+                restarts
+            )));
+        }
+        restarts += 1;
+        let delay = backoff.next();
+        tracing::warn!(
+            "File watcher failed: {:#}. Restarting in {:?} ({}/{})",
+            error,
+            delay,
+            restarts,
+            max_restarts
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+impl Drop for FileTail {
+    fn drop(&mut self) {
+        self.unlink_guard();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn watch_events_carry_file_span_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"hello\n").unwrap();
+
+        let (tx, mut rx) = tokio_mpsc::channel(4);
+        let watcher = tokio::spawn(FileTail::from_start(&path).unwrap().watch(tx));
+        rx.recv().await.unwrap();
+        watcher.abort();
+
+        let field = format!("tail{{file={}}}", path.display());
+        assert!(logs_contain(&format!(
+            "{}: logline_agent::tail: Started watching",
+            field
+        )));
+    }
+
+    #[tokio::test]
+    async fn stops_reading_while_memory_limit_is_reached() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.log");
+        let content = vec![b'x'; 512 * 1024];
+        std::fs::write(&path, &content).unwrap();
+
+        let gauge = MemoryGauge::new(128 * 1024);
+        let tail = FileTail::from_start(&path)
+            .unwrap()
+            .with_memory_limit(gauge.clone());
+        let position = tail.position();
+        let (tx, mut rx) = tokio_mpsc::channel(100);
+        let watcher = tokio::spawn(tail.watch(tx));
+
+        // Two 64KB chunks fill the limit, nothing more is read
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(gauge.used(), 128 * 1024);
+        assert_eq!(position.offset.load(Ordering::Relaxed), 128 * 1024);
+
+        // Consuming the chunks frees the memory and reading resumes
+        let mut received = 0;
+        while received < content.len() {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            received += chunk.data.len();
+        }
+        assert_eq!(received, content.len());
+        watcher.abort();
+    }
+
+    #[test]
+    fn classifies_permission_errors() {
+        let denied =
+            anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+                .context("Failed to open file");
+        assert!(is_permission_denied(&denied));
+        let missing = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(!is_permission_denied(&missing));
+    }
+
+    /// Replace `path` with an unreadable file. Returns false, reporting
+    /// the skipped test, when the permissions don't apply to this user
+    /// (e.g. root).
+    #[cfg(unix)]
+    fn replace_unreadable(path: &Path, content: &[u8]) -> bool {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::write(path, content).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o000)).unwrap();
+        if File::open(path).is_ok() {
+            eprintln!("file permissions don't apply to this user, skipping");
+            return false;
+        }
+        true
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn waits_for_permissions_to_be_restored() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"first\n").unwrap();
+        let tail = FileTail::from_start(&path)
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(10), Duration::from_millis(50));
+        let (tx, mut rx) = tokio_mpsc::channel(4);
+        let watcher = tokio::spawn(tail.watch(tx));
+        assert_eq!(rx.recv().await.unwrap().data, b"first\n");
+
+        if !replace_unreadable(&path, b"second\n") {
+            watcher.abort();
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!watcher.is_finished());
+        assert!(rx.try_recv().is_err());
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let chunk = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(chunk.data, b"second\n");
+        watcher.abort();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn fail_fast_stops_on_permission_denied() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"first\n").unwrap();
+        let tail = FileTail::from_start(&path)
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(10), Duration::from_millis(50))
+            .with_fail_fast();
+        let (tx, mut rx) = tokio_mpsc::channel(4);
+        let watcher = tokio::spawn(tail.watch(tx));
+        assert_eq!(rx.recv().await.unwrap().data, b"first\n");
+
+        if !replace_unreadable(&path, b"second\n") {
+            watcher.abort();
+            return;
+        }
+        let err = tokio::time::timeout(Duration::from_secs(5), watcher)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert!(err.is::<PermissionDenied>(), "{:#}", err);
+    }
+
+    #[tokio::test]
+    async fn watch_retries_after_read_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("late.log");
+
+        // The file doesn't exist yet, so the first reads fail
+        let tail = FileTail::at_offset(path.clone(), 0)
+            .with_poll_interval(Duration::from_millis(10), Duration::from_millis(50));
+        let (tx, mut rx) = tokio_mpsc::channel(4);
+        let watcher = tokio::spawn(tail.watch(tx));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!watcher.is_finished());
+        std::fs::write(&path, b"hello\n").unwrap();
+
+        let chunk = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(chunk.data, b"hello\n");
+        watcher.abort();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn classifies_stale_handles() {
+        let stale =
+            anyhow::Error::from(std::io::Error::from_raw_os_error(116)).context("Failed to read");
+        assert!(is_stale_handle(&stale));
+        let other = anyhow::Error::from(std::io::Error::from_raw_os_error(5));
+        assert!(!is_stale_handle(&other));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reopens_file_after_repeated_read_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"hello\n").unwrap();
+
+        let mut tail = FileTail::from_start(&path)
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(10), Duration::from_millis(20));
+        // A handle every read fails on (EISDIR), standing in for a stale one
+        tail.file = Some(File::open(dir.path()).unwrap());
+        tail.file_id = file_id(&std::fs::metadata(&path).unwrap());
+        let (tx, mut rx) = tokio_mpsc::channel(4);
+        let watcher = tokio::spawn(tail.watch(tx));
+
+        let chunk = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(chunk.data, b"hello\n");
+        watcher.abort();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reopen_starts_over_in_a_replaced_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"old line\n").unwrap();
+        let mut tail = FileTail::from_start(&path).unwrap();
+        tail.open_path().unwrap();
+        tail.offset = 9;
+
+        // The replacement already reaches past the old offset
+        std::fs::rename(&path, dir.path().join("app.log.1")).unwrap();
+        std::fs::write(&path, b"a longer line in the new file\n").unwrap();
+        tail.reopen().unwrap();
+        assert_eq!(tail.offset, 0);
+    }
+
+    #[tokio::test]
+    async fn without_initial_send_skips_existing_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"old\n").unwrap();
+
+        let tail = FileTail::new(&path)
+            .unwrap()
+            .without_initial_send()
+            .with_poll_interval(Duration::from_millis(10), Duration::from_millis(50));
+        // Grows between creating the tail and the watch starting
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"racy\n")
+            .unwrap();
+        let (tx, mut rx) = tokio_mpsc::channel(4);
+        let watcher = tokio::spawn(tail.watch(tx));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(rx.try_recv().is_err());
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"new\n")
+            .unwrap();
+        let chunk = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(chunk.data, b"new\n");
+        assert!(!chunk.backfill);
+        watcher.abort();
+    }
+
+    #[tokio::test]
+    async fn supervise_restarts_a_failed_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+
+        // Fails right away: the file to find the end of doesn't exist
+        let tail = FileTail::at_offset(path.clone(), 0).without_initial_send();
+        let rebuild_path = path.clone();
+        let rebuild = move |position: Arc<TailPosition>| {
+            let offset = position.offset.load(Ordering::Relaxed);
+            let inode = position.inode.load(Ordering::Relaxed);
+            FileTail::resume(&rebuild_path, offset, inode, &[])
+                .map(|tail| tail.with_position(position))
+        };
+        let (tx, mut rx) = tokio_mpsc::channel(4);
+        let supervisor = tokio::spawn(supervise(tail, rebuild, tx, 5, Duration::from_millis(50)));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        std::fs::write(&path, b"recovered\n").unwrap();
+
+        let chunk = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(chunk.data, b"recovered\n");
+        assert!(!supervisor.is_finished());
+        supervisor.abort();
+    }
+
+    #[tokio::test]
+    async fn supervise_resumes_where_a_watcher_that_never_read_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        let moved = dir.path().join("app.log.moved");
+        std::fs::write(&path, b"old\n").unwrap();
+
+        // Starts at the end, then fails before its first read because the
+        // file is briefly gone
+        let tail = FileTail::new(&path).unwrap().without_initial_send();
+        std::fs::rename(&path, &moved).unwrap();
+        let rebuild_path = path.clone();
+        let rebuild = move |position: Arc<TailPosition>| {
+            let offset = position.offset.load(Ordering::Relaxed);
+            let inode = position.inode.load(Ordering::Relaxed);
+            FileTail::resume(&rebuild_path, offset, inode, &[])
+                .map(|tail| tail.with_position(position))
+        };
+        let (tx, mut rx) = tokio_mpsc::channel(4);
+        let supervisor = tokio::spawn(supervise(tail, rebuild, tx, 5, Duration::from_millis(50)));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        std::fs::rename(&moved, &path).unwrap();
+        append(&path, b"new\n");
+
+        let chunk = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(chunk.data, b"new\n");
+        supervisor.abort();
+    }
+
+    #[tokio::test]
+    async fn supervise_gives_up_after_max_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.log");
+
+        let tail = FileTail::at_offset(path.clone(), 0).without_initial_send();
+        let rebuild = |_: Arc<TailPosition>| FileTail::resume(&path, 0, 0, &[]);
+        let (tx, _rx) = tokio_mpsc::channel(4);
+
+        let error = supervise(tail, rebuild, tx, 2, Duration::from_millis(1))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("gave up after 2 restarts"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hardlink_guard_drains_unlinked_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard_dir = tempfile::tempdir_in(dir.path()).unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"old 1\n").unwrap();
+
+        let mut tail = FileTail::from_start(&path)
+            .unwrap()
+            .with_hardlink_guard(guard_dir.path());
+        let link = tail.guard_link.clone().unwrap();
+
+        // Rotated by unlink-and-recreate before the tail ever opened the file
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"old 2\n")
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, b"new\n").unwrap();
+
+        let mut data = Vec::new();
+        while let Some(chunk) = tail.read_new_content().unwrap() {
+            data.extend_from_slice(&chunk);
+        }
+        assert_eq!(data, b"old 1\nold 2\nnew\n");
+        assert!(!link.exists());
+
+        // The new file is guarded in turn until the tail goes away
+        let link = tail.guard_link.clone().unwrap();
+        assert!(link.exists());
+        drop(tail);
+        assert!(!link.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rename_over_drains_the_old_file_and_reads_the_new_one_from_the_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"old 1\n").unwrap();
+
+        let mut tail = FileTail::from_start(&path).unwrap();
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"old 1\n");
+
+        // Written to the old file, then replaced atomically by a longer one
+        append(&path, b"old 2\n");
+        let staged = dir.path().join("app.log.tmp");
+        std::fs::write(&staged, b"new 1\nnew 2\nnew 3\n").unwrap();
+        std::fs::rename(&staged, &path).unwrap();
+
+        let mut data = Vec::new();
+        while let Some(chunk) = tail.read_new_content().unwrap() {
+            data.extend_from_slice(&chunk);
+        }
+        assert_eq!(data, b"old 2\nnew 1\nnew 2\nnew 3\n");
+        assert_eq!(tail.position().offset.load(Ordering::Relaxed), 18);
+    }
+
+    #[test]
+    fn lossy_utf8_joins_characters_split_across_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        let euro = "€".as_bytes();
+        std::fs::write(&path, [b"price: ".as_slice(), &euro[..2]].concat()).unwrap();
+
+        let mut tail = FileTail::from_start(&path).unwrap().with_lossy_utf8();
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"price: ");
+        // The partial character isn't counted as sent yet
+        assert_eq!(tail.position().offset.load(Ordering::Relaxed), 7);
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[&euro[2..], b"5 \xff\n".as_slice()].concat())
+            .unwrap();
+        assert_eq!(
+            tail.read_new_content().unwrap().unwrap(),
+            "€5 \u{FFFD}\n".as_bytes()
+        );
+        assert_eq!(tail.position().offset.load(Ordering::Relaxed), 14);
+    }
+
+    fn bom_tail(content: &[u8], policy: BomPolicy) -> (tempfile::TempDir, FileTail) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, content).unwrap();
+        let tail = FileTail::from_start(&path).unwrap().with_bom_policy(policy);
+        (dir, tail)
+    }
+
+    fn utf16(text: &str, big_endian: bool) -> Vec<u8> {
+        text.encode_utf16()
+            .flat_map(|unit| {
+                if big_endian {
+                    unit.to_be_bytes()
+                } else {
+                    unit.to_le_bytes()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn strips_or_keeps_utf8_bom() {
+        let (_dir, mut tail) = bom_tail(b"\xEF\xBB\xBFfirst\n", BomPolicy::Keep);
+        assert_eq!(
+            tail.read_new_content().unwrap().unwrap(),
+            b"\xEF\xBB\xBFfirst\n"
+        );
+
+        let (_dir, mut tail) = bom_tail(b"\xEF\xBB\xBFfirst\n", BomPolicy::Strip);
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"first\n");
+        assert_eq!(tail.position().offset.load(Ordering::Relaxed), 9);
+
+        // Only a mark at the very start is removed
+        let (_dir, mut tail) = bom_tail(b"a\xEF\xBB\xBF\n", BomPolicy::Strip);
+        assert_eq!(
+            tail.read_new_content().unwrap().unwrap(),
+            b"a\xEF\xBB\xBF\n"
+        );
+    }
+
+    #[test]
+    fn strip_leaves_utf16_encoded() {
+        let content = [b"\xFF\xFE".as_slice(), &utf16("hi\n", false)].concat();
+        let (_dir, mut tail) = bom_tail(&content, BomPolicy::Strip);
+        assert_eq!(
+            tail.read_new_content().unwrap().unwrap(),
+            utf16("hi\n", false)
+        );
+    }
+
+    #[test]
+    fn transcodes_utf16le_and_utf16be() {
+        for (bom, big_endian) in [(b"\xFF\xFE", false), (b"\xFE\xFF", true)] {
+            let text = "naïve 😀 log\n";
+            let content = [bom.as_slice(), &utf16(text, big_endian)].concat();
+            let (_dir, mut tail) = bom_tail(&content, BomPolicy::Transcode);
+            assert_eq!(tail.read_new_content().unwrap().unwrap(), text.as_bytes());
+            assert_eq!(
+                tail.position().offset.load(Ordering::Relaxed),
+                content.len() as u64
+            );
+        }
+    }
+
+    #[test]
+    fn transcodes_utf16_split_across_reads() {
+        let content = [b"\xFE".as_slice()].concat();
+        let (dir, mut tail) = bom_tail(&content, BomPolicy::Transcode);
+        // Could be the start of a mark, nothing is sent yet
+        assert!(tail.read_new_content().unwrap().is_none());
+        assert_eq!(tail.position().offset.load(Ordering::Relaxed), 0);
+
+        let path = dir.path().join("app.log");
+        let rest = [b"\xFF".as_slice(), &utf16("😀!", true)].concat();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        // Half of the surrogate pair
+        file.write_all(&rest[..4]).unwrap();
+        assert!(tail.read_new_content().unwrap().is_none());
+        assert_eq!(tail.position().offset.load(Ordering::Relaxed), 2);
+
+        file.write_all(&rest[4..]).unwrap();
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), "😀!".as_bytes());
+    }
+
+    #[test]
+    fn transcodes_utf16_when_resuming_mid_file() {
+        let head = [b"\xFF\xFE".as_slice(), &utf16("old\n", false)].concat();
+        let content = [head.clone(), utf16("new\n", false)].concat();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, &content).unwrap();
+
+        // As when resuming from a checkpoint
+        let mut tail =
+            FileTail::at_offset(path, head.len() as u64).with_bom_policy(BomPolicy::Transcode);
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"new\n");
+    }
+
+    #[test]
+    fn lossy_utf8_replaces_invalid_and_unfinished_sequences() {
+        let mut utf8 = LossyUtf8::default();
+        assert_eq!(utf8.decode(b"a\xe2\x82"), b"a");
+        assert_eq!(utf8.pending(), 2);
+        // The sequence is broken by an ASCII byte
+        assert_eq!(utf8.decode(b"b"), "\u{FFFD}b".as_bytes());
+        assert_eq!(utf8.decode(b"\xf0\x9f"), b"");
+        assert_eq!(utf8.finish(), Some("\u{FFFD}".as_bytes().to_vec()));
+        assert_eq!(utf8.finish(), None);
+    }
+
+    #[tokio::test]
+    async fn publishes_file_meta_on_interval_and_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"hello\n").unwrap();
+
+        let (meta_tx, mut meta_rx) = tokio_mpsc::channel(4);
+        let tail = FileTail::from_start(&path)
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(10), Duration::from_millis(50))
+            .with_file_meta(meta_tx, Duration::from_secs(3600));
+        let (tx, mut rx) = tokio_mpsc::channel(4);
+        let watcher = tokio::spawn(tail.watch(tx));
+        rx.recv().await.unwrap();
+
+        // Nothing is due yet, a truncation forces a snapshot
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        let meta = tokio::time::timeout(Duration::from_secs(5), meta_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta.path, path.to_string_lossy());
+        assert_eq!(meta.size, 0);
+        assert_eq!(meta.offset, 0);
+        assert!(meta.mtime > 0);
+        watcher.abort();
+    }
+
+    async fn read_range(content: &[u8], range: &str, snap: RangeSnap) -> Result<Vec<u8>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, content).unwrap();
+        let (start, end) = parse_range(range).map_err(anyhow::Error::msg)?;
+        let tail = FileTail::byte_range(&path, start, end, snap, b'\n')?;
+
+        let (tx, mut rx) = tokio_mpsc::channel(16);
+        tail.read_once(tx).await?;
+        let mut data = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            data.extend(chunk.data);
+        }
+        Ok(data)
+    }
+
+    #[tokio::test]
+    async fn streams_a_byte_range_inside_the_file() {
+        let content = b"one\ntwo\nthree\nfour\n";
+        // 5 falls inside "two", 10 inside "three"
+        assert_eq!(
+            read_range(content, "5-10", RangeSnap::Lines).await.unwrap(),
+            b"three\n"
+        );
+        assert_eq!(
+            read_range(content, "4-8", RangeSnap::Lines).await.unwrap(),
+            b"two\n"
+        );
+        assert_eq!(
+            read_range(content, "5-10", RangeSnap::Bytes).await.unwrap(),
+            b"wo\nth"
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_a_byte_range_to_the_end() {
+        let content = b"one\ntwo\nthree";
+        assert_eq!(
+            read_range(content, "2-", RangeSnap::Lines).await.unwrap(),
+            b"two\nthree"
+        );
+        assert_eq!(
+            read_range(content, "8-13", RangeSnap::Lines).await.unwrap(),
+            b"three"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_ranges_outside_the_file() {
+        let content = b"one\ntwo\n";
+        let err = read_range(content, "4-100", RangeSnap::Lines)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("outside"), "{}", err);
+        assert!(read_range(content, "100-", RangeSnap::Bytes).await.is_err());
+        // Inside the last line, nothing whole is left
+        assert!(read_range(content, "5-7", RangeSnap::Lines).await.is_err());
+        assert!(parse_range("7-5").is_err());
+        assert!(parse_range("7").is_err());
+    }
+
+    #[test]
+    fn seeks_to_line_starts_with_a_custom_delimiter() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"one\ntwo\0three\0four\0").unwrap();
+
+        // Newlines inside a record are not boundaries
+        let tail = FileTail::from_offset(&path, 2, 0).unwrap();
+        assert_eq!(tail.offset, 8);
+        let tail = FileTail::from_line(&path, 3, 0).unwrap();
+        assert_eq!(tail.offset, 14);
+        let mut tail = FileTail::with_tail_bytes(&path, 7, 0).unwrap();
+        assert_eq!(tail.offset, 14);
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"four\0");
+    }
+
+    fn truncated_tail(policy: TruncatePolicy) -> (tempfile::TempDir, PathBuf, FileTail) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"old line 1\nold line 2\n").unwrap();
+
+        let mut tail = FileTail::from_start(&path)
+            .unwrap()
+            .with_truncate_policy(policy);
+        assert_eq!(
+            tail.read_new_content().unwrap().unwrap(),
+            b"old line 1\nold line 2\n"
+        );
+        // Truncated in place, then partly rewritten
+        std::fs::write(&path, b"new 1\n").unwrap();
+        (dir, path, tail)
+    }
+
+    fn append(path: &Path, data: &[u8]) {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(data)
+            .unwrap();
+    }
+
+    #[test]
+    fn truncate_reset_rereads_from_start() {
+        let (_dir, _path, mut tail) = truncated_tail(TruncatePolicy::Reset);
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"new 1\n");
+        assert_eq!(tail.position().offset.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn truncate_continue_keeps_offset() {
+        let (_dir, path, mut tail) = truncated_tail(TruncatePolicy::Continue);
+        assert_eq!(tail.read_new_content().unwrap(), None);
+        assert_eq!(tail.position().offset.load(Ordering::Relaxed), 22);
+
+        // Only what lies past the old offset is sent
+        append(&path, b"new 2\nnew 3\nnew 4\n");
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"4\n");
+        assert_eq!(tail.position().offset.load(Ordering::Relaxed), 24);
+    }
+
+    #[test]
+    fn truncate_resync_skips_to_new_end() {
+        let (_dir, path, mut tail) = truncated_tail(TruncatePolicy::ResyncFromEnd);
+        assert_eq!(tail.read_new_content().unwrap(), None);
+        assert_eq!(tail.position().offset.load(Ordering::Relaxed), 6);
+
+        append(&path, b"new 2\n");
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"new 2\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_a_repointed_symlink_after_draining_the_old_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("current.log");
+        let v1 = dir.path().join("app-1.log");
+        let v2 = dir.path().join("app-2.log");
+        std::fs::write(&v1, b"v1 a\n").unwrap();
+        std::os::unix::fs::symlink(&v1, &link).unwrap();
+
+        let mut tail = FileTail::from_start(&link).unwrap();
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"v1 a\n");
+
+        // Deploy: the old version logs once more, then the link flips
+        append(&v1, b"v1 b\n");
+        std::fs::write(&v2, b"v2 a\n").unwrap();
+        let staged = dir.path().join("current.log.tmp");
+        std::os::unix::fs::symlink(&v2, &staged).unwrap();
+        std::fs::rename(&staged, &link).unwrap();
+
+        let mut data = Vec::new();
+        while let Some(chunk) = tail.read_new_content().unwrap() {
+            data.extend_from_slice(&chunk);
+        }
+        assert_eq!(data, b"v1 b\nv2 a\n");
+
+        append(&v2, b"v2 b\n");
+        append(&v1, b"v1 late\n");
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"v2 b\n");
+        assert_eq!(tail.read_new_content().unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rotation_loses_no_bytes_written_before_the_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        let rotated = dir.path().join("app.log.1");
+        std::fs::write(&path, b"old 1\n").unwrap();
+
+        let mut tail = FileTail::from_start(&path).unwrap();
+        assert_eq!(tail.read_new_content().unwrap().unwrap(), b"old 1\n");
+        let inode = inode_of(&path).unwrap();
+
+        append(&path, b"old 2\n");
+        std::fs::rename(&path, &rotated).unwrap();
+        std::fs::write(&path, b"").unwrap();
+        append(&path, b"new 1\n");
+
+        let mut data = Vec::new();
+        while let Some(chunk) = tail.read_new_content().unwrap() {
+            data.extend_from_slice(&chunk);
+        }
+        assert_eq!(data, b"old 2\nnew 1\n");
+
+        // Resuming from a checkpoint taken before the rotation drains the
+        // rotated copy first
+        let suffixes = vec![".1".to_string()];
+        let mut tail = FileTail::resume(&path, 6, inode, &suffixes).unwrap();
+        let mut data = Vec::new();
+        while let Some(chunk) = tail.read_new_content().unwrap() {
+            data.extend_from_slice(&chunk);
+        }
+        assert_eq!(data, b"old 2\nnew 1\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn drains_files_rotated_faster_than_polling() {
+        const ROTATIONS: usize = 40;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, b"").unwrap();
+        // An old rotated file that was shipped before the agent started
+        std::fs::write(dir.path().join("app.log.0"), b"old\n").unwrap();
+
+        let suffixes: Vec<String> = (0..=ROTATIONS).map(|n| format!(".{}", n)).collect();
+        let mut tail = FileTail::from_start(&path)
+            .unwrap()
+            .with_rotation_backfill(&suffixes, 4);
+        assert_eq!(tail.read_new_content().unwrap(), None);
+
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let mut written = 0;
+                for n in 1..=ROTATIONS {
+                    for i in 0..20 {
+                        let line = format!("file {} line {}\n", n, i);
+                        append(&path, line.as_bytes());
+                        written += line.len();
+                    }
+                    std::fs::rename(&path, format!("{}.{}", path.display(), n)).unwrap();
+                    std::fs::write(&path, b"").unwrap();
+                }
+                written
+            })
+        };
+
+        let mut data = Vec::new();
+        let mut idle = 0;
+        while !writer.is_finished() || idle < 3 {
+            match tail.read_new_content().unwrap() {
+                Some(chunk) => {
+                    data.extend_from_slice(&chunk);
+                    idle = 0;
+                }
+                None => {
+                    idle += 1;
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            }
+        }
+        let written = writer.join().unwrap();
+
+        assert_eq!(data.len(), written);
+        let mut lines: Vec<&[u8]> = data.split_inclusive(|&b| b == b'\n').collect();
+        lines.sort();
+        lines.dedup();
+        assert_eq!(lines.len(), ROTATIONS * 20);
+    }
+
+    #[test]
+    fn poll_interval_backs_off_and_resets() {
+        let mut poll = PollInterval::new(Duration::from_millis(100), Duration::from_millis(700));
+        assert_eq!(poll.current(), Duration::from_millis(100));
+
+        poll.idle();
+        assert_eq!(poll.current(), Duration::from_millis(200));
+        poll.idle();
+        poll.idle();
+        assert_eq!(poll.current(), Duration::from_millis(700));
+        poll.idle();
+        assert_eq!(poll.current(), Duration::from_millis(700));
+
+        poll.reset();
+        assert_eq!(poll.current(), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,82 @@
+//! Placeholder templates
+//!
+//! Expands `{name}` placeholders in user-supplied templates such as
+//! `--line-prefix`. Use `{{` and `}}` for literal braces.
+
+/// Expand every `{name}` in `template` from `vars`.
+/// Unknown placeholders and unbalanced braces are errors.
+pub fn expand(template: &str, vars: &[(&str, &str)]) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(format!("unclosed '{{' in template '{}'", template)),
+                    }
+                }
+                let value = vars
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| *value)
+                    .ok_or_else(|| {
+                        let known: Vec<String> =
+                            vars.iter().map(|(key, _)| format!("{{{}}}", key)).collect();
+                        format!(
+                            "unknown placeholder '{{{}}}' in template '{}' (supported: {})",
+                            name,
+                            template,
+                            known.join(", ")
+                        )
+                    })?;
+                out.push_str(value);
+            }
+            '}' => return Err(format!("unmatched '}}' in template '{}'", template)),
+            c => out.push(c),
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitutes_placeholders_and_escaped_braces() {
+        let vars = [("device", "web-1"), ("agent_id", "a1b2")];
+        assert_eq!(
+            expand("[{device}/{agent_id}] ", &vars).unwrap(),
+            "[web-1/a1b2] "
+        );
+        assert_eq!(
+            expand("{{device}} {device}", &vars).unwrap(),
+            "{device} web-1"
+        );
+        assert_eq!(expand("", &vars).unwrap(), "");
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders_and_unbalanced_braces() {
+        let vars = [("device", "web-1")];
+        let error = expand("{host}", &vars).unwrap_err();
+        assert!(error.contains("unknown placeholder '{host}'"), "{}", error);
+        assert!(error.contains("supported: {device}"), "{}", error);
+        assert!(expand("{device", &vars).unwrap_err().contains("unclosed"));
+        assert!(expand("device}", &vars).unwrap_err().contains("unmatched"));
+    }
+}
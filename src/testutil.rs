@@ -0,0 +1,144 @@
+//! Test helpers
+//!
+//! An in-process Logline server stub that accepts connections, decodes
+//! frames with [`Frame::read_from`] and records them for assertions. A
+//! stalled variant accepts connections but never reads from them, and a
+//! [`BlackHole`] never even completes the TCP handshake.
+
+use crate::protocol::{Frame, HandshakePayload, MessageType};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Minimal server recording every frame it receives
+pub struct ServerStub {
+    addr: SocketAddr,
+    frames: Arc<Mutex<Vec<Frame>>>,
+    accepts: Arc<AtomicUsize>,
+}
+
+impl ServerStub {
+    /// Start listening on an ephemeral loopback port
+    pub fn start() -> Self {
+        Self::spawn(false)
+    }
+
+    /// Start a server that accepts connections but never reads from them
+    pub fn start_stalled() -> Self {
+        Self::spawn(true)
+    }
+
+    fn spawn(stalled: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind server stub");
+        let addr = listener.local_addr().expect("server stub address");
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let accepts = Arc::new(AtomicUsize::new(0));
+
+        let recorded = frames.clone();
+        let accepted = accepts.clone();
+        thread::spawn(move || {
+            let mut held = Vec::new();
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                accepted.fetch_add(1, Ordering::SeqCst);
+                if stalled {
+                    held.push(stream);
+                    continue;
+                }
+                let recorded = recorded.clone();
+                thread::spawn(move || {
+                    while let Ok(frame) = Frame::read_from(&mut stream) {
+                        recorded.lock().unwrap().push(frame);
+                    }
+                });
+            }
+        });
+
+        Self {
+            addr,
+            frames,
+            accepts,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Number of connections accepted so far
+    pub fn accepts(&self) -> usize {
+        self.accepts.load(Ordering::SeqCst)
+    }
+
+    /// Frames received so far, in order
+    pub fn frames(&self) -> Vec<Frame> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    /// Decoded handshakes received so far
+    pub fn handshakes(&self) -> Vec<HandshakePayload> {
+        self.frames()
+            .iter()
+            .filter(|f| f.message_type == MessageType::Handshake)
+            .map(|f| serde_json::from_slice(&f.payload).expect("valid handshake"))
+            .collect()
+    }
+
+    /// Concatenated `LogData` payloads received so far
+    pub fn log_data(&self) -> Vec<u8> {
+        self.frames()
+            .iter()
+            .filter(|f| f.message_type == MessageType::LogData)
+            .flat_map(|f| f.payload.iter().copied())
+            .collect()
+    }
+
+    /// Wait until the received log data satisfies `done`
+    pub async fn wait_for_data(&self, timeout: Duration, done: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let data = self.log_data();
+            if done(&data) || Instant::now() > deadline {
+                return data;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+}
+
+/// Address whose connection attempts hang until they time out, like a
+/// host that silently drops packets. The listener's accept queue is
+/// filled and never drained, so the kernel ignores further SYNs.
+pub struct BlackHole {
+    addr: SocketAddr,
+    _listener: socket2::Socket,
+    _queued: TcpStream,
+}
+
+impl BlackHole {
+    pub fn start() -> Self {
+        use socket2::{Domain, Socket, Type};
+
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).expect("black hole socket");
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        listener.bind(&local.into()).expect("bind black hole");
+        listener.listen(0).expect("listen on black hole");
+        let addr = listener
+            .local_addr()
+            .ok()
+            .and_then(|a| a.as_socket())
+            .expect("black hole address");
+        let queued = TcpStream::connect(addr).expect("fill black hole queue");
+        Self {
+            addr,
+            _listener: listener,
+            _queued: queued,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
@@ -0,0 +1,2361 @@
+//! Line-mode transform stage
+//!
+//! Sits between `FileTail` and the connection. Raw chunks are split into
+//! complete lines, each line runs through the configured stages, and the
+//! surviving lines are forwarded as a single chunk.
+
+use crate::config::SharedFilters;
+use crate::memory::MemoryCharge;
+use crate::metrics::Metrics;
+use crate::tail::{LogChunk, SourceEnd};
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Appended to a fragment that was cut off at the line length limit
+pub const TRUNCATION_MARKER: &[u8] = b" [truncated]\n";
+
+/// Default limit for a single line in line mode (1MB)
+pub const DEFAULT_MAX_LINE_BYTES: usize = 1024 * 1024;
+
+/// Byte that ends a line unless `--line-delimiter` says otherwise
+pub const DEFAULT_LINE_DELIMITER: u8 = b'\n';
+
+/// Parse a `--line-delimiter`: `newline`, `nul` or `byte:0xNN`
+pub fn parse_line_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "newline" => Ok(b'\n'),
+        "nul" => Ok(0),
+        _ => s
+            .strip_prefix("byte:0x")
+            .filter(|hex| hex.len() == 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| {
+                format!(
+                    "invalid line delimiter '{}', expected newline, nul or byte:0xNN",
+                    s
+                )
+            }),
+    }
+}
+
+/// Splits a byte stream into delimiter-terminated lines
+#[derive(Debug)]
+pub struct LineSplitter {
+    pending: Vec<u8>,
+    max_line_bytes: usize,
+    delimiter: u8,
+    truncations: u64,
+}
+
+impl LineSplitter {
+    pub fn new(max_line_bytes: usize) -> Self {
+        Self {
+            pending: Vec::new(),
+            max_line_bytes: max_line_bytes.max(1),
+            delimiter: DEFAULT_LINE_DELIMITER,
+            truncations: 0,
+        }
+    }
+
+    /// End lines at `delimiter` instead of a newline
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Feed a chunk and return every line it completes (delimiter included).
+    /// A trailing partial line is kept until a later chunk finishes it.
+    ///
+    /// Lines longer than the limit are never buffered whole: every time the
+    /// limit is reached the accumulated bytes are emitted as a fragment
+    /// ending in `TRUNCATION_MARKER` (with the delimiter in place of its
+    /// newline), and splitting resumes at the next delimiter.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        let mut rest = chunk;
+
+        loop {
+            let (segment, complete) = match rest.iter().position(|&b| b == self.delimiter) {
+                Some(pos) => (&rest[..=pos], true),
+                None => (rest, false),
+            };
+            self.pending.extend_from_slice(segment);
+            rest = &rest[segment.len()..];
+
+            self.split_oversized(&mut lines, complete);
+
+            if !complete {
+                break;
+            }
+            lines.push(std::mem::take(&mut self.pending));
+        }
+
+        lines
+    }
+
+    /// Cut fragments off the pending line while its content exceeds the limit
+    fn split_oversized(&mut self, lines: &mut Vec<Vec<u8>>, complete: bool) {
+        let content_len = |pending: &Vec<u8>| pending.len() - complete as usize;
+
+        while content_len(&self.pending) > self.max_line_bytes {
+            let mut fragment: Vec<u8> = self.pending.drain(..self.max_line_bytes).collect();
+            fragment.extend_from_slice(&TRUNCATION_MARKER[..TRUNCATION_MARKER.len() - 1]);
+            fragment.push(self.delimiter);
+            lines.push(fragment);
+            self.truncations += 1;
+        }
+    }
+
+    /// Number of forced fragments emitted since the last call
+    pub fn take_truncations(&mut self) -> u64 {
+        std::mem::take(&mut self.truncations)
+    }
+
+    /// Whether part of a line is buffered
+    pub fn has_partial(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Take whatever partial line is buffered
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.pending))
+        }
+    }
+}
+
+/// How records are sampled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleMode {
+    /// Keep every Nth record, deterministically
+    EveryNth(u64),
+    /// Keep each record with the given probability
+    Rate(f64),
+}
+
+/// Parse a `1/N` sampling spec
+pub fn parse_sample(s: &str) -> Result<u64, String> {
+    let n = s
+        .strip_prefix("1/")
+        .ok_or_else(|| format!("invalid sample '{}', expected 1/N", s))?;
+    match n.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!(
+            "invalid sample '{}', N must be a positive integer",
+            s
+        )),
+    }
+}
+
+/// Parse a sampling probability in `(0, 1]`
+pub fn parse_sample_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate <= 1.0 => Ok(rate),
+        _ => Err(format!("invalid sample rate '{}', expected (0, 1]", s)),
+    }
+}
+
+/// Record sampler.
+///
+/// Sampling is lossy: dropped records are never sent to the server.
+/// Records are sampled as a whole, so a record is either shipped
+/// complete or not at all.
+pub struct Sampler {
+    mode: SampleMode,
+    seen: u64,
+    rng: StdRng,
+    metrics: Arc<Metrics>,
+}
+
+impl Sampler {
+    pub fn new(mode: SampleMode, metrics: Arc<Metrics>) -> Self {
+        Self {
+            mode,
+            seen: 0,
+            rng: StdRng::from_entropy(),
+            metrics,
+        }
+    }
+
+    /// Decide whether the next record is kept
+    pub fn keep(&mut self) -> bool {
+        self.seen += 1;
+        let keep = match self.mode {
+            SampleMode::EveryNth(n) => self.seen.is_multiple_of(n),
+            SampleMode::Rate(rate) => self.rng.gen_bool(rate),
+        };
+
+        let counter = if keep {
+            &self.metrics.lines_sampled_kept
+        } else {
+            &self.metrics.lines_sampled_dropped
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        keep
+    }
+}
+
+/// What to do with lines that are not valid JSON in NDJSON mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MalformedPolicy {
+    /// Drop the line
+    Drop,
+    /// Forward it as `{"_malformed": "<line>"}`
+    Wrap,
+}
+
+/// Validates that each line is a JSON document
+pub struct NdjsonValidator {
+    policy: MalformedPolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl NdjsonValidator {
+    pub fn new(policy: MalformedPolicy, metrics: Arc<Metrics>) -> Self {
+        Self { policy, metrics }
+    }
+
+    /// Check a line. Blank lines are dropped without counting as malformed.
+    pub fn check(&self, line: Vec<u8>) -> Option<Vec<u8>> {
+        let content = line.trim_ascii_end();
+        if content.trim_ascii_start().is_empty() {
+            return None;
+        }
+
+        if serde_json::from_slice::<serde_json::Value>(content).is_ok() {
+            return Some(line);
+        }
+
+        self.metrics
+            .ndjson_malformed
+            .fetch_add(1, Ordering::Relaxed);
+        match self.policy {
+            MalformedPolicy::Drop => None,
+            MalformedPolicy::Wrap => {
+                let wrapped = serde_json::json!({
+                    "_malformed": String::from_utf8_lossy(content),
+                });
+                let mut out = wrapped.to_string().into_bytes();
+                out.push(b'\n');
+                Some(out)
+            }
+        }
+    }
+}
+
+/// Log severity, ordered from least to most severe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    /// Map an extracted level token, ignoring case
+    pub fn from_token(token: &[u8]) -> Option<Self> {
+        let token = std::str::from_utf8(token).ok()?.trim().to_ascii_lowercase();
+        match token.as_str() {
+            "trace" | "trc" => Some(Level::Trace),
+            "debug" | "dbg" => Some(Level::Debug),
+            "info" | "inf" | "notice" => Some(Level::Info),
+            "warn" | "warning" | "wrn" => Some(Level::Warn),
+            "error" | "err" | "fatal" | "critical" | "crit" | "panic" => Some(Level::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Default `--level-regex`: the first well-known level word in the line
+pub const DEFAULT_LEVEL_REGEX: &str =
+    r"(?i)\b(trace|debug|info|notice|warn|warning|error|err|fatal|critical|crit|panic)\b";
+
+/// What to do with lines the level regex finds no level in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnleveledPolicy {
+    /// Forward the line
+    Keep,
+    /// Drop the line
+    Drop,
+}
+
+/// Drops lines below a minimum severity
+pub struct LevelFilter {
+    min: Level,
+    regex: regex::bytes::Regex,
+    unleveled: UnleveledPolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl LevelFilter {
+    /// `regex` extracts the level from its first capture group, or the
+    /// whole match if it has none
+    pub fn new(
+        min: Level,
+        regex: &str,
+        unleveled: UnleveledPolicy,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        Ok(Self {
+            min,
+            regex: regex::bytes::Regex::new(regex)?,
+            unleveled,
+            metrics,
+        })
+    }
+
+    /// Level found in a line, if any
+    pub fn level(&self, line: &[u8]) -> Option<Level> {
+        let captures = self.regex.captures(line)?;
+        let token = captures.get(1).or_else(|| captures.get(0))?;
+        Level::from_token(token.as_bytes())
+    }
+
+    /// Decide whether a line is kept
+    pub fn keep(&self, line: &[u8]) -> bool {
+        let keep = match self.level(line) {
+            Some(level) => level >= self.min,
+            None => self.unleveled == UnleveledPolicy::Keep,
+        };
+        if !keep {
+            self.metrics
+                .lines_dropped_by_level
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        keep
+    }
+}
+
+/// Where the ANSI stripper is within an escape sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    Ground,
+    /// After ESC
+    Escape,
+    /// `ESC` followed by intermediate bytes, waiting for the final byte
+    EscapeIntermediate,
+    /// `ESC [` parameters and intermediates, up to the final byte
+    Csi,
+    /// OSC/DCS/PM/APC string, ended by BEL or `ESC \`
+    String,
+    /// ESC inside a string, possibly the start of `ESC \`
+    StringEscape,
+}
+
+/// Removes ANSI escape sequences (SGR colors, other CSI controls, OSC
+/// titles and links). State carries over between calls, so a sequence
+/// split across chunks or line fragments is still removed whole.
+#[derive(Debug)]
+pub struct AnsiStripper {
+    state: AnsiState,
+    metrics: Arc<Metrics>,
+}
+
+impl AnsiStripper {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            state: AnsiState::Ground,
+            metrics,
+        }
+    }
+
+    /// `data` without escape sequences
+    pub fn strip(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        let mut stripped = 0u64;
+        for &b in data {
+            // A newline never belongs to a sequence, drop the broken one
+            if b == b'\n' {
+                self.state = AnsiState::Ground;
+                out.push(b);
+                continue;
+            }
+            self.state = match (self.state, b) {
+                (AnsiState::Ground, 0x1b) => AnsiState::Escape,
+                (AnsiState::Ground, _) => {
+                    out.push(b);
+                    AnsiState::Ground
+                }
+                (AnsiState::Escape, b'[') => AnsiState::Csi,
+                (AnsiState::Escape, b']' | b'P' | b'X' | b'^' | b'_') => AnsiState::String,
+                (AnsiState::Escape | AnsiState::EscapeIntermediate, 0x20..=0x2f) => {
+                    AnsiState::EscapeIntermediate
+                }
+                (AnsiState::Csi, 0x20..=0x3f) => AnsiState::Csi,
+                (AnsiState::String, 0x1b) => AnsiState::StringEscape,
+                (AnsiState::String, 0x07) => {
+                    stripped += 1;
+                    AnsiState::Ground
+                }
+                (AnsiState::String, _) => AnsiState::String,
+                (AnsiState::StringEscape, b'\\') => {
+                    stripped += 1;
+                    AnsiState::Ground
+                }
+                (AnsiState::StringEscape, 0x1b) => AnsiState::StringEscape,
+                (AnsiState::StringEscape, _) => AnsiState::String,
+                // Final byte, or anything that can't continue the sequence
+                (AnsiState::Escape | AnsiState::EscapeIntermediate | AnsiState::Csi, _) => {
+                    stripped += 1;
+                    AnsiState::Ground
+                }
+            };
+        }
+        if stripped > 0 {
+            self.metrics
+                .ansi_stripped
+                .fetch_add(stripped, Ordering::Relaxed);
+        }
+        out
+    }
+}
+
+/// Collapses runs of identical consecutive lines.
+///
+/// The first line of a run is forwarded right away; repeats are counted and
+/// reported as one `last message repeated N times` record when a different
+/// line arrives, or once the window has passed since the first repeat.
+pub struct Deduper {
+    window: Duration,
+    last: Option<Vec<u8>>,
+    repeats: u64,
+    /// When the pending repeats must be reported
+    deadline: Option<Instant>,
+    /// Report repeats as `{"_repeated": N}` to keep NDJSON output valid
+    json: bool,
+    /// Ends the report, like the lines around it
+    delimiter: u8,
+    /// Report for the run ended by the last line, as a stage
+    report: Option<Vec<u8>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Deduper {
+    pub fn new(window: Duration, json: bool, metrics: Arc<Metrics>) -> Self {
+        Self {
+            window,
+            last: None,
+            repeats: 0,
+            deadline: None,
+            json,
+            delimiter: DEFAULT_LINE_DELIMITER,
+            report: None,
+            metrics,
+        }
+    }
+
+    /// End reports with `delimiter` instead of a newline
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Feed a line. Returns the repeat report for the previous run, if it
+    /// ended, and the line itself unless it is a repeat.
+    pub fn push(&mut self, line: Vec<u8>) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+        if self.last.as_ref() == Some(&line) {
+            self.repeats += 1;
+            self.deadline
+                .get_or_insert_with(|| Instant::now() + self.window);
+            self.metrics.lines_deduped.fetch_add(1, Ordering::Relaxed);
+            return (None, None);
+        }
+
+        let report = self.flush();
+        self.last = Some(line.clone());
+        (report, Some(line))
+    }
+
+    /// Report repeats counted so far
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        self.deadline = None;
+        let repeats = std::mem::take(&mut self.repeats);
+        if repeats == 0 {
+            return None;
+        }
+
+        let mut report = if self.json {
+            serde_json::json!({ "_repeated": repeats }).to_string()
+        } else {
+            let plural = if repeats == 1 { "" } else { "s" };
+            format!("last message repeated {} time{}", repeats, plural)
+        }
+        .into_bytes();
+        report.push(self.delimiter);
+        Some(report)
+    }
+
+    /// When pending repeats are due to be reported
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
+
+/// Names of the line stages, for `--transforms`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum TransformKind {
+    /// `--strip-ansi`
+    StripAnsi,
+    /// Include/exclude patterns from `--config`
+    Filter,
+    /// `--min-level`
+    Level,
+    /// Redaction patterns from `--config`
+    Redact,
+    /// `--ndjson`
+    Ndjson,
+    /// `--dedupe`
+    Dedupe,
+    /// `--sample` / `--sample-rate`
+    Sample,
+    /// `--line-prefix`
+    Prefix,
+    /// `--multiline-join`
+    Join,
+}
+
+impl std::fmt::Display for TransformKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = clap::ValueEnum::to_possible_value(self).expect("no skipped variants");
+        f.write_str(value.get_name())
+    }
+}
+
+/// Default `--transforms`: clean up and filter first, so redaction and
+/// dedupe see the final text, only prefix what is actually sent, and
+/// flatten records last, right before they are framed
+pub const DEFAULT_TRANSFORMS: &str =
+    "strip-ansi,filter,level,redact,ndjson,dedupe,sample,prefix,join";
+
+/// One line-mode stage. Lines are newline-terminated records.
+pub trait Transform: Send {
+    /// Transform a line, or drop it by returning `None`
+    fn apply(&mut self, line: &[u8]) -> Option<Vec<u8>>;
+
+    /// Handle a record generated by an earlier stage, such as a dedupe
+    /// repeat count, rather than read from the file
+    fn report(&mut self, record: &[u8]) -> Option<Vec<u8>> {
+        self.apply(record)
+    }
+
+    /// A record this stage generated while applying the last line, to be
+    /// sent ahead of it
+    fn take_report(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// A record held back until `deadline` or the end of input
+    fn flush(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// When `flush` is due
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+}
+
+impl Transform for AnsiStripper {
+    fn apply(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        Some(self.strip(line))
+    }
+}
+
+/// Include/exclude patterns of the (reloadable) line filters
+pub struct IncludeExclude(pub SharedFilters);
+
+impl Transform for IncludeExclude {
+    fn apply(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        let filters = self.0.read().unwrap_or_else(|e| e.into_inner());
+        filters.matches(line).then(|| line.to_vec())
+    }
+}
+
+/// Redaction patterns of the (reloadable) line filters
+pub struct Redact(pub SharedFilters);
+
+impl Transform for Redact {
+    fn apply(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        let filters = self.0.read().unwrap_or_else(|e| e.into_inner());
+        Some(filters.redact(line.to_vec()))
+    }
+}
+
+impl Transform for LevelFilter {
+    fn apply(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        self.keep(line).then(|| line.to_vec())
+    }
+}
+
+impl Transform for NdjsonValidator {
+    fn apply(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        self.check(line.to_vec())
+    }
+}
+
+impl Transform for Deduper {
+    fn apply(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        let (report, line) = self.push(line.to_vec());
+        self.report = report;
+        line
+    }
+
+    fn take_report(&mut self) -> Option<Vec<u8>> {
+        self.report.take()
+    }
+
+    fn flush(&mut self) -> Option<Vec<u8>> {
+        Deduper::flush(self)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        Deduper::deadline(self)
+    }
+}
+
+impl Transform for Sampler {
+    fn apply(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        self.keep().then(|| line.to_vec())
+    }
+
+    /// Reports aren't sampled, they stand for lines already counted
+    fn report(&mut self, record: &[u8]) -> Option<Vec<u8>> {
+        Some(record.to_vec())
+    }
+}
+
+/// Prepends an already expanded prefix to every line
+pub struct Prefix(pub Vec<u8>);
+
+impl Transform for Prefix {
+    fn apply(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        Some([self.0.as_slice(), line].concat())
+    }
+}
+
+/// Parse a `--multiline-join` separator, expanding `\n`, `\r`, `\t`, `\0`,
+/// `\\` and `\xNN`
+pub fn parse_separator(s: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        let escaped = match bytes.next() {
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(b'0') => 0,
+            Some(b'\\') => b'\\',
+            Some(b'x') => {
+                let hex = [bytes.next(), bytes.next()];
+                let hex = match hex {
+                    [Some(hi), Some(lo)] => [hi, lo],
+                    _ => {
+                        return Err(format!(
+                            "invalid separator '{}', \\x needs two hex digits",
+                            s
+                        ))
+                    }
+                };
+                std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("invalid separator '{}', \\x needs two hex digits", s))?
+            }
+            Some(other) => {
+                return Err(format!(
+                    "invalid separator '{}', unknown escape \\{}",
+                    s, other as char
+                ))
+            }
+            None => return Err(format!("invalid separator '{}', trailing backslash", s)),
+        };
+        out.push(escaped);
+    }
+    Ok(out)
+}
+
+/// Flattens a record that spans several lines into one, replacing each
+/// line break inside it (`\n` or `\r\n`) with a separator. The delimiter
+/// ending the record is kept.
+pub struct Join {
+    separator: Vec<u8>,
+    delimiter: u8,
+}
+
+impl Join {
+    pub fn new(separator: Vec<u8>, delimiter: u8) -> Self {
+        Self {
+            separator,
+            delimiter,
+        }
+    }
+}
+
+impl Transform for Join {
+    fn apply(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        let (content, end) = match line.split_last() {
+            Some((&last, content)) if last == self.delimiter => (content, Some(last)),
+            _ => (line, None),
+        };
+        let mut out = Vec::with_capacity(line.len());
+        let mut rest = content;
+        while let Some(pos) = rest.iter().position(|&b| b == b'\n') {
+            let text = &rest[..pos];
+            out.extend_from_slice(text.strip_suffix(b"\r").unwrap_or(text));
+            out.extend_from_slice(&self.separator);
+            rest = &rest[pos + 1..];
+        }
+        out.extend_from_slice(rest);
+        out.extend(end);
+        Some(out)
+    }
+}
+
+/// Cut a line whose content is longer than `limit` into parts of at most
+/// `limit` bytes, marker included, each starting with a `[part i/n] `
+/// marker and ending in `delimiter`. Stripping the markers and the
+/// delimiters between the parts gives back the original line; cuts fall on
+/// byte boundaries.
+pub fn split_line(line: &[u8], limit: usize, delimiter: u8) -> Option<Vec<Vec<u8>>> {
+    let limit = limit.max(1);
+    let (content, newline) = match line.strip_suffix(&[delimiter]) {
+        Some(content) => (content, true),
+        None => (line, false),
+    };
+    if content.len() <= limit {
+        return None;
+    }
+
+    // The marker grows with the number of parts, which depends on the room
+    // the marker leaves, so settle on a count that fits the widest marker
+    let mut total = 1;
+    let room = loop {
+        let widest = format!("[part {}/{}] ", total, total).len();
+        let room = limit.saturating_sub(widest).max(1);
+        let needed = content.len().div_ceil(room);
+        if needed <= total {
+            break room;
+        }
+        total = needed;
+    };
+    let total = content.len().div_ceil(room);
+    let parts = content
+        .chunks(room)
+        .enumerate()
+        .map(|(i, piece)| {
+            let mut part = format!("[part {}/{}] ", i + 1, total).into_bytes();
+            part.extend_from_slice(piece);
+            // An unterminated line stays unterminated
+            if newline || i + 1 < total {
+                part.push(delimiter);
+            }
+            part
+        })
+        .collect();
+    Some(parts)
+}
+
+/// What `--line-numbers` does when the file is rotated or truncated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LineNumberRotation {
+    /// Count the new file from 1
+    #[default]
+    Reset,
+    /// Keep counting across files
+    Continue,
+}
+
+/// Whether the line matching `--skip-header-until` is part of the header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HeaderMatch {
+    /// Send the matching line, it is the first line of data
+    #[default]
+    Keep,
+    /// Drop the matching line, it is the last line of the header
+    Drop,
+}
+
+/// Most lines `--skip-header-until` drops from one file. Without a match
+/// by then the file has no header and the rest of it is sent.
+pub const MAX_HEADER_LINES: u64 = 1000;
+
+/// Drops the lines at the start of a file up to the first one matching
+/// a regex (`--skip-header-until`)
+#[derive(Debug)]
+pub struct HeaderSkip {
+    until: regex::bytes::Regex,
+    matched: HeaderMatch,
+    /// Skip the header of every file after a rotation or truncation too
+    on_rotation: bool,
+    /// Still inside a header
+    active: bool,
+    /// Lines dropped from the current header
+    skipped: u64,
+}
+
+impl HeaderSkip {
+    /// `at_start` is false when reading doesn't begin at the start of the
+    /// file, so there is no header until the next file
+    pub fn new(
+        until: regex::bytes::Regex,
+        matched: HeaderMatch,
+        on_rotation: bool,
+        at_start: bool,
+    ) -> Self {
+        Self {
+            until,
+            matched,
+            on_rotation,
+            active: at_start,
+            skipped: 0,
+        }
+    }
+
+    /// Whether `line`, ending in `delimiter`, belongs to the header. The
+    /// regex sees the line without its delimiter or a trailing `\r`.
+    fn skip(&mut self, line: &[u8], delimiter: u8) -> bool {
+        if !self.active {
+            return false;
+        }
+        let content = line.strip_suffix(&[delimiter]).unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        if !self.until.is_match(content) {
+            if self.skipped == MAX_HEADER_LINES {
+                self.active = false;
+                tracing::warn!(
+                    "No line matched --skip-header-until within {} lines, sending the rest of the file",
+                    MAX_HEADER_LINES
+                );
+                return false;
+            }
+            self.skipped += 1;
+            return true;
+        }
+        self.active = false;
+        let drop = self.matched == HeaderMatch::Drop;
+        if drop {
+            self.skipped += 1;
+        }
+        tracing::info!("Skipped a {} line header", self.skipped);
+        drop
+    }
+
+    fn start_file(&mut self) {
+        if self.on_rotation {
+            self.active = true;
+            self.skipped = 0;
+        }
+    }
+}
+
+/// Line-mode pipeline: splits the stream into lines and runs each one
+/// through the stages in order
+pub struct LinePipeline {
+    splitter: LineSplitter,
+    stages: Vec<Box<dyn Transform>>,
+    split_large_lines: Option<usize>,
+    /// Byte that ends a line
+    delimiter: u8,
+    /// Number source lines, and what to do on rotation
+    line_numbers: Option<LineNumberRotation>,
+    /// Complete source lines seen so far
+    lines_seen: u64,
+    /// Number of the line going through the stages
+    current_line: u64,
+    /// Numbers of the emitted lines not yet forwarded
+    emitted_numbers: Vec<u64>,
+    /// Header block dropped before the stages run
+    header: Option<HeaderSkip>,
+    metrics: Arc<Metrics>,
+}
+
+impl LinePipeline {
+    pub fn new(max_line_bytes: usize, metrics: Arc<Metrics>) -> Self {
+        Self {
+            splitter: LineSplitter::new(max_line_bytes),
+            stages: Vec::new(),
+            split_large_lines: None,
+            delimiter: DEFAULT_LINE_DELIMITER,
+            line_numbers: None,
+            lines_seen: 0,
+            current_line: 0,
+            emitted_numbers: Vec::new(),
+            header: None,
+            metrics,
+        }
+    }
+
+    /// Split lines at `delimiter` instead of a newline
+    pub fn with_line_delimiter(mut self, delimiter: u8) -> Self {
+        self.splitter = self.splitter.with_delimiter(delimiter);
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Tag every forwarded line with its source line number, counting
+    /// complete lines from where the tail started
+    pub fn with_line_numbers(mut self, on_rotation: LineNumberRotation) -> Self {
+        self.line_numbers = Some(on_rotation);
+        self
+    }
+
+    /// Drop header lines before any stage sees them. They still count
+    /// towards `--line-numbers`.
+    pub fn with_header_skip(mut self, header: HeaderSkip) -> Self {
+        self.header = Some(header);
+        self
+    }
+
+    /// The input moved on to a new file
+    fn start_file(&mut self) {
+        if self.line_numbers == Some(LineNumberRotation::Reset) {
+            self.lines_seen = 0;
+        }
+        if let Some(header) = &mut self.header {
+            header.start_file();
+        }
+    }
+
+    /// Wrap output, attaching the numbers of the lines it holds
+    fn output(&mut self, mut chunk: LogChunk) -> LogChunk {
+        chunk.line_numbers = std::mem::take(&mut self.emitted_numbers);
+        chunk
+    }
+
+    /// Append a stage, run after the ones added before it
+    pub fn with_stage(mut self, stage: Box<dyn Transform>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    /// Send lines longer than `limit` as marked parts (see [`split_line`]),
+    /// once every stage has run
+    pub fn with_split_large_lines(mut self, limit: usize) -> Self {
+        self.split_large_lines = Some(limit);
+        self
+    }
+
+    /// Append a line that made it through every stage
+    fn emit(&mut self, line: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        let parts = self
+            .split_large_lines
+            .and_then(|limit| split_line(line, limit, self.delimiter));
+        match parts {
+            Some(parts) => {
+                self.metrics.lines_split.fetch_add(1, Ordering::Relaxed);
+                for part in parts {
+                    out.extend_from_slice(&part);
+                }
+            }
+            None => out.extend_from_slice(line),
+        }
+        if self.line_numbers.is_some() {
+            // Parts of a split line share its number
+            let complete = out[start..]
+                .iter()
+                .filter(|&&b| b == self.delimiter)
+                .count();
+            self.emitted_numbers
+                .extend(std::iter::repeat_n(self.current_line, complete));
+        }
+    }
+
+    /// Run a line through the stages from `first` on, appending what
+    /// comes out. `report` marks records generated by an earlier stage.
+    fn run_from(&mut self, first: usize, mut line: Vec<u8>, report: bool, out: &mut Vec<u8>) {
+        for i in first..self.stages.len() {
+            let stage = &mut self.stages[i];
+            let next = if report {
+                stage.report(&line)
+            } else {
+                stage.apply(&line)
+            };
+            if let Some(generated) = stage.take_report() {
+                self.run_from(i + 1, generated, true, out);
+            }
+            match next {
+                Some(next) => line = next,
+                None => return,
+            }
+        }
+        self.emit(&line, out);
+    }
+
+    /// Run a single record through the stages
+    fn apply(&mut self, line: Vec<u8>) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        self.run_from(0, line, false, &mut out);
+        (!out.is_empty()).then_some(out)
+    }
+
+    /// Flush held back records, those due by `now` or all of them
+    fn flush_stages(&mut self, now: Option<Instant>) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        for i in 0..self.stages.len() {
+            let due = match now {
+                Some(now) => self.stages[i].deadline().is_some_and(|d| d <= now),
+                None => true,
+            };
+            if !due {
+                continue;
+            }
+            if let Some(record) = self.stages[i].flush() {
+                self.run_from(i + 1, record, true, &mut out);
+            }
+        }
+        (!out.is_empty()).then_some(out)
+    }
+
+    /// Earliest time a stage wants to flush
+    fn deadline(&self) -> Option<Instant> {
+        self.stages
+            .iter()
+            .filter_map(|stage| stage.deadline())
+            .min()
+    }
+
+    /// Process a raw chunk, returning the bytes to forward (if any)
+    pub fn process(&mut self, chunk: &[u8]) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        for line in self.splitter.push(chunk) {
+            // Fragments of a line cut at --max-line-bytes share its number
+            self.current_line = self.lines_seen + 1;
+            if line.last() == Some(&self.delimiter) {
+                self.lines_seen += 1;
+            }
+            if self
+                .header
+                .as_mut()
+                .is_some_and(|header| header.skip(&line, self.delimiter))
+            {
+                self.metrics
+                    .header_lines_skipped
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if let Some(line) = self.apply(line) {
+                out.extend_from_slice(&line);
+            }
+        }
+
+        let truncations = self.splitter.take_truncations();
+        if truncations > 0 {
+            tracing::warn!(
+                "Line exceeded the length limit, sent {} truncated fragment(s)",
+                truncations
+            );
+            self.metrics
+                .lines_truncated
+                .fetch_add(truncations, Ordering::Relaxed);
+        }
+
+        (!out.is_empty()).then_some(out)
+    }
+
+    /// Process the trailing partial line once the input has ended
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        self.current_line = self.lines_seen + 1;
+        let mut out = match self.splitter.flush() {
+            Some(line) => self.apply(line).unwrap_or_default(),
+            None => Vec::new(),
+        };
+        if let Some(held) = self.flush_stages(None) {
+            out.extend_from_slice(&held);
+        }
+        (!out.is_empty()).then_some(out)
+    }
+
+    /// Run the pipeline between the file watcher and the connection
+    pub async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<LogChunk>,
+        tx: mpsc::Sender<LogChunk>,
+    ) -> Result<()> {
+        // End of the last input, and of the last input that left no
+        // partial line behind: output only covers the source up to there
+        let mut input_end: Option<SourceEnd> = None;
+        let mut line_end: Option<SourceEnd> = None;
+        loop {
+            // Flush held back records, e.g. repeats once the dedupe
+            // window passes without new lines
+            let received = match self.deadline() {
+                Some(deadline) => match tokio::time::timeout_at(deadline, rx.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        if let Some(data) = self.flush_stages(Some(Instant::now())) {
+                            let chunk = self.output(LogChunk::new(data));
+                            if tx.send(chunk).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        continue;
+                    }
+                },
+                None => rx.recv().await,
+            };
+            let Some(chunk) = received else { break };
+
+            if chunk.new_file {
+                self.start_file();
+            }
+            let processed = self.process(&chunk.data);
+            if chunk.end.is_some() {
+                input_end = chunk.end.clone();
+            }
+            if !self.splitter.has_partial() {
+                line_end = input_end.clone();
+            }
+            if let Some(data) = processed {
+                let mut chunk = self.output(chunk.derive(data));
+                chunk.end = line_end.clone();
+                if tx.send(chunk).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Some(data) = self.finish() {
+            let mut chunk = self.output(LogChunk::new(data));
+            chunk.end = input_end;
+            let _ = tx.send(chunk).await;
+        }
+
+        Ok(())
+    }
+}
+
+/// Default byte cap for a frame built by [`FrameBatcher`]
+pub const DEFAULT_FRAME_BYTES: usize = 64 * 1024;
+
+/// Default lower bound of the adaptive batch target (4KB)
+pub const DEFAULT_BATCH_MIN_BYTES: usize = 4 * 1024;
+
+/// Default upper bound of the adaptive batch target (1MB)
+pub const DEFAULT_BATCH_MAX_BYTES: usize = 1024 * 1024;
+
+/// Consecutive backlogged receives before the adaptive target grows
+const GROW_AFTER_BACKLOGGED: u32 = 4;
+
+/// Regroups line-mode output so every chunk, and so every `LogData` frame,
+/// holds at most `max_lines` complete lines. A batch is sent once it is
+/// full, would grow past `max_bytes`, or has waited `linger`.
+///
+/// With an adaptive range, `max_bytes` becomes a moving target: it doubles
+/// while the input channel stays backlogged and halves whenever a batch
+/// has to be sent by the linger timer because traffic is sparse.
+#[derive(Debug)]
+pub struct FrameBatcher {
+    max_lines: usize,
+    max_bytes: usize,
+    linger: Duration,
+    /// Bounds of the adaptive byte target, if enabled
+    adaptive: Option<(usize, usize)>,
+    /// Consecutive receives that found more chunks already queued
+    backlogged: u32,
+    metrics: Option<Arc<Metrics>>,
+    batch: Vec<u8>,
+    lines: usize,
+    /// Byte that ends a line
+    delimiter: u8,
+    /// Source line numbers of the batched lines, if the input has them
+    line_numbers: Vec<u64>,
+    /// Empty chunk derived from the first line's chunk, the batch is
+    /// derived from it in turn
+    origin: Option<LogChunk>,
+    /// When the current batch is sent even if not full
+    deadline: Option<Instant>,
+    /// Unterminated end of the input, sent on shutdown
+    partial: Vec<u8>,
+    /// End of the last input, and of the last input whose lines are all
+    /// in the batch or sent
+    input_end: Option<SourceEnd>,
+    line_end: Option<SourceEnd>,
+}
+
+impl FrameBatcher {
+    pub fn new(max_lines: usize, max_bytes: usize, linger: Duration) -> Self {
+        Self {
+            max_lines: max_lines.max(1),
+            max_bytes,
+            linger,
+            adaptive: None,
+            backlogged: 0,
+            metrics: None,
+            batch: Vec::new(),
+            lines: 0,
+            delimiter: DEFAULT_LINE_DELIMITER,
+            line_numbers: Vec::new(),
+            origin: None,
+            deadline: None,
+            partial: Vec::new(),
+            input_end: None,
+            line_end: None,
+        }
+    }
+
+    /// Adapt the byte target between `min` and `max`, starting at `min`.
+    /// The current target is published in `metrics`.
+    pub fn with_adaptive_bytes(mut self, min: usize, max: usize, metrics: Arc<Metrics>) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        self.adaptive = Some((min, max));
+        self.max_bytes = min;
+        metrics
+            .batch_target_bytes
+            .store(min as u64, Ordering::Relaxed);
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Count lines ending in `delimiter` instead of a newline
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Feed back the input channel depth seen after a receive
+    fn observe_depth(&mut self, depth: usize) {
+        if depth == 0 {
+            self.backlogged = 0;
+            return;
+        }
+        self.backlogged += 1;
+        if self.backlogged >= GROW_AFTER_BACKLOGGED {
+            self.backlogged = 0;
+            self.set_target(self.max_bytes.saturating_mul(2));
+        }
+    }
+
+    /// A batch went out on the linger timer: traffic is sparse
+    fn observe_linger(&mut self) {
+        self.backlogged = 0;
+        self.set_target(self.max_bytes / 2);
+    }
+
+    fn set_target(&mut self, target: usize) {
+        let Some((min, max)) = self.adaptive else {
+            return;
+        };
+        let target = target.clamp(min, max);
+        if target != self.max_bytes {
+            tracing::debug!("Batch target {} -> {} bytes", self.max_bytes, target);
+            self.max_bytes = target;
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics
+                .batch_target_bytes
+                .store(target as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Add a chunk, returning the batches it completed
+    pub fn push(&mut self, chunk: &LogChunk) -> Vec<LogChunk> {
+        let mut out = Vec::new();
+        let mut line_numbers = chunk.line_numbers.iter();
+        let delimiter = self.delimiter;
+        for line in chunk.data.split_inclusive(|&b| b == delimiter) {
+            self.partial.extend_from_slice(line);
+            if line.last() != Some(&delimiter) {
+                break;
+            }
+            let line = std::mem::take(&mut self.partial);
+
+            if !self.batch.is_empty() && self.batch.len() + line.len() > self.max_bytes {
+                out.extend(self.take());
+            }
+            if self.batch.is_empty() {
+                self.origin = Some(chunk.derive(Vec::new()));
+                self.deadline = Some(Instant::now() + self.linger);
+            }
+            self.batch.extend_from_slice(&line);
+            self.line_numbers.extend(line_numbers.next());
+            self.lines += 1;
+            if self.lines >= self.max_lines || self.batch.len() >= self.max_bytes {
+                out.extend(self.take());
+            }
+        }
+        if chunk.end.is_some() {
+            self.input_end = chunk.end.clone();
+        }
+        if self.partial.is_empty() {
+            self.line_end = self.input_end.clone();
+        }
+        out
+    }
+
+    /// The current batch, however full
+    pub fn take(&mut self) -> Option<LogChunk> {
+        self.deadline = None;
+        self.lines = 0;
+        let origin = self.origin.take()?;
+        let mut batch = origin.derive(std::mem::take(&mut self.batch));
+        batch.line_numbers = std::mem::take(&mut self.line_numbers);
+        batch.end = self.line_end.clone();
+        Some(batch)
+    }
+
+    /// Everything left once the input has ended, including an unterminated line
+    pub fn finish(&mut self) -> Vec<LogChunk> {
+        let mut out: Vec<LogChunk> = self.take().into_iter().collect();
+        if !self.partial.is_empty() {
+            let mut partial = LogChunk::new(std::mem::take(&mut self.partial));
+            partial.end = self.input_end.clone();
+            out.push(partial);
+        }
+        out
+    }
+
+    /// Batch chunks from `rx` into `tx`
+    pub async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<LogChunk>,
+        tx: mpsc::Sender<LogChunk>,
+    ) -> Result<()> {
+        loop {
+            let received = match self.deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, rx.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        self.observe_linger();
+                        if let Some(batch) = self.take() {
+                            if tx.send(batch).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        continue;
+                    }
+                },
+                None => rx.recv().await,
+            };
+            let Some(chunk) = received else { break };
+            self.observe_depth(rx.len());
+
+            for batch in self.push(&chunk) {
+                if tx.send(batch).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+
+        for batch in self.finish() {
+            if tx.send(batch).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Emits at most once per interval, concatenating everything that arrives
+/// in between into one chunk. Unlike batching this is a floor on the time
+/// between emissions rather than a ceiling on their size. Pending data
+/// stays charged to `--max-memory-bytes`, so a burst within one interval
+/// pauses the tail instead of growing without bound.
+#[derive(Debug)]
+pub struct Coalescer {
+    interval: Duration,
+    last_emit: Option<Instant>,
+    /// Empty chunk derived from the first pending chunk
+    origin: Option<LogChunk>,
+    pending: Vec<u8>,
+    line_numbers: Vec<u64>,
+    /// Charges of the chunks in `pending`, held until it is emitted
+    charges: Vec<MemoryCharge>,
+    /// Source end of the last pending chunk
+    end: Option<SourceEnd>,
+}
+
+impl Coalescer {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_emit: None,
+            origin: None,
+            pending: Vec::new(),
+            line_numbers: Vec::new(),
+            charges: Vec::new(),
+            end: None,
+        }
+    }
+
+    fn push(&mut self, mut chunk: LogChunk) {
+        if self.origin.is_none() {
+            self.origin = Some(chunk.derive(Vec::new()));
+        }
+        self.pending.extend_from_slice(&chunk.data);
+        self.line_numbers.extend_from_slice(&chunk.line_numbers);
+        self.charges.extend(chunk.charge.take());
+        if chunk.end.is_some() {
+            self.end = chunk.end;
+        }
+    }
+
+    fn due(&self) -> bool {
+        self.last_emit
+            .is_none_or(|last| last.elapsed() >= self.interval)
+    }
+
+    fn take(&mut self) -> Option<LogChunk> {
+        let origin = self.origin.take()?;
+        self.last_emit = Some(Instant::now());
+        let mut chunk = origin.derive(std::mem::take(&mut self.pending));
+        chunk.line_numbers = std::mem::take(&mut self.line_numbers);
+        chunk.end = self.end.take();
+        self.charges.clear();
+        Some(chunk)
+    }
+
+    /// Coalesce chunks from `rx` into `tx`; whatever is pending when the
+    /// input ends is sent right away
+    pub async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<LogChunk>,
+        tx: mpsc::Sender<LogChunk>,
+    ) -> Result<()> {
+        loop {
+            let next_emit = self
+                .origin
+                .as_ref()
+                .and(self.last_emit)
+                .map(|last| last + self.interval);
+            let received = match next_emit {
+                Some(at) => match tokio::time::timeout_at(at, rx.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        if let Some(out) = self.take() {
+                            if tx.send(out).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        continue;
+                    }
+                },
+                None => rx.recv().await,
+            };
+            let Some(chunk) = received else { break };
+            self.push(chunk);
+            if self.due() {
+                if let Some(out) = self.take() {
+                    if tx.send(out).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        if let Some(out) = self.take() {
+            let _ = tx.send(out).await;
+        }
+        Ok(())
+    }
+}
+
+/// Stops the stream after a number of lines and/or bytes, whichever comes first
+#[derive(Debug, Clone, Default)]
+pub struct ShipLimit {
+    max_lines: Option<u64>,
+    max_bytes: Option<u64>,
+    /// Byte that ends a line
+    delimiter: u8,
+    lines: u64,
+    bytes: u64,
+}
+
+impl ShipLimit {
+    pub fn new(max_lines: Option<u64>, max_bytes: Option<u64>) -> Self {
+        Self {
+            max_lines,
+            max_bytes,
+            delimiter: DEFAULT_LINE_DELIMITER,
+            ..Self::default()
+        }
+    }
+
+    /// Count lines ending in `delimiter` instead of a newline
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Cut `data` at whichever limit is reached first, returning the part to
+    /// ship and the flag naming the limit once one is hit
+    pub fn take(&mut self, mut data: Vec<u8>) -> (Vec<u8>, Option<&'static str>) {
+        let mut end = data.len();
+        let mut hit = None;
+
+        if let Some(max) = self.max_lines {
+            let mut lines = self.lines;
+            for (i, _) in data
+                .iter()
+                .enumerate()
+                .filter(|(_, &b)| b == self.delimiter)
+            {
+                lines += 1;
+                if lines >= max {
+                    end = i + 1;
+                    hit = Some("--max-lines");
+                    break;
+                }
+            }
+        }
+        if let Some(max) = self.max_bytes {
+            let room = max.saturating_sub(self.bytes);
+            if end as u64 >= room && (hit.is_none() || room < end as u64) {
+                end = room as usize;
+                hit = Some("--max-bytes");
+            }
+        }
+
+        data.truncate(end);
+        self.lines += data.iter().filter(|&&b| b == self.delimiter).count() as u64;
+        self.bytes += data.len() as u64;
+        (data, hit)
+    }
+
+    /// Forward chunks until a limit is reached, then close the stream so the
+    /// sender drains what was forwarded and stops. Returns whether a limit was hit.
+    pub async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<LogChunk>,
+        tx: mpsc::Sender<LogChunk>,
+    ) -> Result<bool> {
+        while let Some(mut chunk) = rx.recv().await {
+            let len = chunk.data.len();
+            let (data, hit) = self.take(std::mem::take(&mut chunk.data));
+            let mut line_numbers = std::mem::take(&mut chunk.line_numbers);
+            line_numbers.truncate(data.iter().filter(|&&b| b == self.delimiter).count());
+            let mut chunk = chunk.derive(data);
+            chunk.line_numbers = line_numbers;
+            // Where a cut chunk ends in the source isn't known
+            if chunk.data.len() < len {
+                chunk.end = None;
+            }
+            if !chunk.data.is_empty() && tx.send(chunk).await.is_err() {
+                return Ok(false);
+            }
+            if let Some(flag) = hit {
+                tracing::info!(
+                    "Reached {} limit ({} lines, {} bytes shipped), stopping",
+                    flag,
+                    self.lines,
+                    self.bytes
+                );
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level_filter(unleveled: UnleveledPolicy) -> LevelFilter {
+        LevelFilter::new(
+            Level::Warn,
+            DEFAULT_LEVEL_REGEX,
+            unleveled,
+            Arc::new(Metrics::new()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn level_filter_matches_case_insensitively() {
+        let filter = level_filter(UnleveledPolicy::Keep);
+        assert_eq!(filter.level(b"2024-01-01 WARN disk low"), Some(Level::Warn));
+        assert_eq!(filter.level(b"[error] boom"), Some(Level::Error));
+        assert_eq!(filter.level(b"Warning: retrying"), Some(Level::Warn));
+        assert_eq!(filter.level(b"level=Info started"), Some(Level::Info));
+
+        assert!(filter.keep(b"ERROR failed\n"));
+        assert!(filter.keep(b"FATAL out of memory\n"));
+        assert!(!filter.keep(b"info: started\n"));
+        assert!(!filter.keep(b"DEBUG x=1\n"));
+        assert_eq!(
+            filter
+                .metrics
+                .lines_dropped_by_level
+                .load(Ordering::Relaxed),
+            2
+        );
+    }
+
+    #[test]
+    fn level_filter_handles_lines_without_a_level() {
+        // "information" and "errors" aren't level tokens
+        let line = b"no information about errors here\n";
+        assert!(level_filter(UnleveledPolicy::Keep).keep(line));
+        assert!(!level_filter(UnleveledPolicy::Drop).keep(line));
+    }
+
+    #[test]
+    fn level_regex_capture_group_selects_the_token() {
+        let filter = LevelFilter::new(
+            Level::Info,
+            r#""severity":"(\w+)""#,
+            UnleveledPolicy::Keep,
+            Arc::new(Metrics::new()),
+        )
+        .unwrap();
+        assert_eq!(
+            filter.level(br#"{"msg":"debug mode on","severity":"INFO"}"#),
+            Some(Level::Info)
+        );
+        assert_eq!(filter.level(br#"{"severity":"verbose"}"#), None);
+    }
+
+    #[test]
+    fn strips_colors_and_other_escapes() {
+        let metrics = Arc::new(Metrics::new());
+        let mut stripper = AnsiStripper::new(metrics.clone());
+        assert_eq!(
+            stripper.strip(b"\x1b[1;31mERROR\x1b[0m disk \x1b[38;5;208mfull\x1b[m\n"),
+            b"ERROR disk full\n"
+        );
+        assert_eq!(
+            stripper.strip(b"\x1b]0;title\x07\x1b]8;;http://x\x1b\\link\x1b(B\x1b[2K done\n"),
+            b"link done\n"
+        );
+        assert_eq!(stripper.strip(b"plain [text]\n"), b"plain [text]\n");
+        assert_eq!(metrics.ansi_stripped.load(Ordering::Relaxed), 8);
+    }
+
+    #[test]
+    fn strips_escapes_split_across_chunks() {
+        let metrics = Arc::new(Metrics::new());
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone())
+            .with_stage(Box::new(AnsiStripper::new(metrics.clone())));
+        assert_eq!(pipeline.process(b"a \x1b"), None);
+        assert_eq!(pipeline.process(b"[32"), None);
+        assert_eq!(pipeline.process(b"mgreen\x1b[0"), None);
+        assert_eq!(pipeline.process(b"m\nb\n"), Some(b"a green\nb\n".to_vec()));
+
+        // Split between line fragments cut at the length limit
+        let mut stripper = AnsiStripper::new(metrics.clone());
+        assert_eq!(stripper.strip(b"x\x1b[3"), b"x");
+        assert_eq!(stripper.strip(b"1my"), b"y");
+        assert_eq!(metrics.ansi_stripped.load(Ordering::Relaxed), 3);
+    }
+
+    fn redacting_filters() -> SharedFilters {
+        let config = crate::config::FileConfig {
+            redact: vec![r"user=\w+".to_string()],
+            ..Default::default()
+        };
+        Arc::new(std::sync::RwLock::new(config.filters().unwrap()))
+    }
+
+    #[test]
+    fn redacting_before_dedupe_collapses_lines_that_differ_only_in_secrets() {
+        let input = b"login user=alice\nlogin user=bob\nlogin user=carol\n";
+
+        let metrics = Arc::new(Metrics::new());
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone())
+            .with_stage(Box::new(Redact(redacting_filters())))
+            .with_stage(Box::new(Deduper::new(
+                Duration::from_secs(5),
+                false,
+                metrics,
+            )));
+        assert_eq!(
+            pipeline.process(input),
+            Some(b"login [REDACTED]\n".to_vec())
+        );
+        assert_eq!(
+            pipeline.finish(),
+            Some(b"last message repeated 2 times\n".to_vec())
+        );
+
+        let metrics = Arc::new(Metrics::new());
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone())
+            .with_stage(Box::new(Deduper::new(
+                Duration::from_secs(5),
+                false,
+                metrics,
+            )))
+            .with_stage(Box::new(Redact(redacting_filters())));
+        assert_eq!(
+            pipeline.process(input),
+            Some(b"login [REDACTED]\n".repeat(3))
+        );
+        assert_eq!(pipeline.finish(), None);
+    }
+
+    #[test]
+    fn level_filter_sees_prefix_only_when_it_runs_after_it() {
+        let input = b"info: started\nERROR failed\n";
+
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, Arc::new(Metrics::new()))
+            .with_stage(Box::new(level_filter(UnleveledPolicy::Keep)))
+            .with_stage(Box::new(Prefix(b"[error-stream] ".to_vec())));
+        assert_eq!(
+            pipeline.process(input),
+            Some(b"[error-stream] ERROR failed\n".to_vec())
+        );
+
+        // The prefix's level token now decides for every line
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, Arc::new(Metrics::new()))
+            .with_stage(Box::new(Prefix(b"[error-stream] ".to_vec())))
+            .with_stage(Box::new(level_filter(UnleveledPolicy::Keep)));
+        assert_eq!(
+            pipeline.process(input),
+            Some(b"[error-stream] info: started\n[error-stream] ERROR failed\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn reports_pass_later_stages_but_are_not_sampled() {
+        let metrics = Arc::new(Metrics::new());
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone())
+            .with_stage(Box::new(Deduper::new(
+                Duration::from_secs(5),
+                false,
+                metrics.clone(),
+            )))
+            .with_stage(Box::new(Sampler::new(SampleMode::EveryNth(2), metrics)))
+            .with_stage(Box::new(Prefix(b"> ".to_vec())));
+        // a is sampled out, the report for its repeats and b are kept
+        assert_eq!(
+            pipeline.process(b"a\na\nb\n"),
+            Some(b"> last message repeated 1 time\n> b\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn ndjson_keeps_valid_lines_and_drops_or_wraps_malformed_ones() {
+        let metrics = Arc::new(Metrics::new());
+        let input = b"{\"a\":1}\nnot json\n\n  \n[1, 2]\r\n{\"b\":\n";
+
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone()).with_stage(
+            Box::new(NdjsonValidator::new(MalformedPolicy::Drop, metrics.clone())),
+        );
+        assert_eq!(
+            pipeline.process(input),
+            Some(b"{\"a\":1}\n[1, 2]\r\n".to_vec())
+        );
+        // Blank lines are dropped but not counted
+        assert_eq!(metrics.ndjson_malformed.load(Ordering::Relaxed), 2);
+
+        let validator = NdjsonValidator::new(MalformedPolicy::Wrap, metrics.clone());
+        assert_eq!(
+            validator.check(b"not \"json\"\n".to_vec()),
+            Some(b"{\"_malformed\":\"not \\\"json\\\"\"}\n".to_vec())
+        );
+        assert_eq!(validator.check(b"\n".to_vec()), None);
+        assert_eq!(metrics.ndjson_malformed.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn prefixes_empty_lines_and_skips_filtered_ones() {
+        let config = crate::config::FileConfig {
+            exclude: vec!["healthcheck".to_string()],
+            ..Default::default()
+        };
+        let filters = Arc::new(std::sync::RwLock::new(config.filters().unwrap()));
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, Arc::new(Metrics::new()))
+            .with_stage(Box::new(IncludeExclude(filters)))
+            .with_stage(Box::new(Prefix(b"[web-1] ".to_vec())));
+        assert_eq!(
+            pipeline.process(b"a\n\nGET /healthcheck\nb\n"),
+            Some(b"[web-1] a\n[web-1] \n[web-1] b\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn splits_long_lines_into_marked_parts() {
+        // Each part is 16 bytes at most, marker included
+        let parts = split_line(b"abcdefghijklmnopq\n", 16, b'\n').unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                b"[part 1/4] abcde\n".to_vec(),
+                b"[part 2/4] fghij\n".to_vec(),
+                b"[part 3/4] klmno\n".to_vec(),
+                b"[part 4/4] pq\n".to_vec(),
+            ]
+        );
+        // The newline doesn't count against the limit
+        assert_eq!(split_line(b"abcdefghijklmnop\n", 16, b'\n'), None);
+        assert_eq!(
+            split_line(b"abcdefghijklmnopq", 16, b'\n').unwrap()[3],
+            b"[part 4/4] pq"
+        );
+        // Ten parts need a wider marker, which leaves less room in each
+        let parts = split_line(&[b'x'; 60], 16, b'\n').unwrap();
+        assert_eq!(parts.len(), 20);
+        assert_eq!(parts[19], b"[part 20/20] xxx");
+        assert!(parts.iter().all(|part| part.len() <= 16 + 1));
+    }
+
+    #[test]
+    fn split_parts_reassemble_to_the_original_line() {
+        let line = [b"x".repeat(2500), b"\n".to_vec()].concat();
+        let metrics = Arc::new(Metrics::new());
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone())
+            .with_stage(Box::new(Prefix(b"> ".to_vec())))
+            .with_split_large_lines(1000);
+
+        let out = pipeline
+            .process(&[b"short\n".as_slice(), &line].concat())
+            .unwrap();
+        let records: Vec<&[u8]> = out.split_inclusive(|&b| b == b'\n').collect();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0], b"> short\n");
+
+        let mut reassembled = Vec::new();
+        for (i, part) in records[1..].iter().enumerate() {
+            assert!(part.len() <= 1000 + 1);
+            let marker = format!("[part {}/3] ", i + 1);
+            let piece = part.strip_prefix(marker.as_bytes()).unwrap();
+            reassembled.extend_from_slice(piece.strip_suffix(b"\n").unwrap());
+        }
+        reassembled.push(b'\n');
+        assert_eq!(reassembled, [b"> ".as_slice(), &line].concat());
+        assert_eq!(metrics.lines_split.load(Ordering::Relaxed), 1);
+    }
+
+    fn dedupe_pipeline(window: Duration) -> LinePipeline {
+        let metrics = Arc::new(Metrics::new());
+        LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone())
+            .with_stage(Box::new(Deduper::new(window, false, metrics)))
+    }
+
+    #[test]
+    fn dedupe_passes_single_lines_through() {
+        let mut pipeline = dedupe_pipeline(Duration::from_secs(5));
+        assert_eq!(pipeline.process(b"a\nb\na\n"), Some(b"a\nb\na\n".to_vec()));
+        assert_eq!(pipeline.finish(), None);
+    }
+
+    #[test]
+    fn dedupe_reports_repeats_when_the_line_changes() {
+        let mut pipeline = dedupe_pipeline(Duration::from_secs(5));
+        assert_eq!(
+            pipeline.process(b"a\na\nb\n"),
+            Some(b"a\nlast message repeated 1 time\nb\n".to_vec())
+        );
+
+        assert_eq!(pipeline.process(b"b\n".repeat(100).as_slice()), None);
+        assert_eq!(
+            pipeline.process(b"c\n"),
+            Some(b"last message repeated 100 times\nc\n".to_vec())
+        );
+        let metrics = &pipeline.metrics;
+        assert_eq!(metrics.lines_deduped.load(Ordering::Relaxed), 101);
+    }
+
+    #[test]
+    fn dedupe_flushes_repeats_at_end_of_input() {
+        let metrics = Arc::new(Metrics::new());
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone())
+            .with_stage(Box::new(NdjsonValidator::new(
+                MalformedPolicy::Drop,
+                metrics.clone(),
+            )))
+            .with_stage(Box::new(Deduper::new(
+                Duration::from_secs(5),
+                true,
+                metrics,
+            )));
+        assert_eq!(pipeline.process(b"{}\n{}\n{}\n"), Some(b"{}\n".to_vec()));
+        assert_eq!(pipeline.finish(), Some(b"{\"_repeated\":2}\n".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn dedupe_reports_repeats_after_idle_window() {
+        let pipeline = dedupe_pipeline(Duration::from_millis(50));
+        let (in_tx, in_rx) = mpsc::channel(4);
+        let (out_tx, mut out_rx) = mpsc::channel(4);
+        let handle = tokio::spawn(pipeline.run(in_rx, out_tx));
+
+        in_tx
+            .send(LogChunk::new(b"x\nx\nx\n".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(out_rx.recv().await.unwrap().data, b"x\n");
+        // Reported while the input is still open
+        let report = tokio::time::timeout(Duration::from_secs(5), out_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.data, b"last message repeated 2 times\n");
+
+        drop(in_tx);
+        handle.await.unwrap().unwrap();
+        assert!(out_rx.recv().await.is_none());
+    }
+
+    async fn numbered(on_rotation: LineNumberRotation) -> Vec<Vec<u64>> {
+        let pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, Arc::new(Metrics::new()))
+            .with_stage(Box::new(Sampler::new(
+                SampleMode::EveryNth(2),
+                Arc::new(Metrics::new()),
+            )))
+            .with_line_numbers(on_rotation);
+        let (in_tx, in_rx) = mpsc::channel(4);
+        let (out_tx, mut out_rx) = mpsc::channel(4);
+        let handle = tokio::spawn(pipeline.run(in_rx, out_tx));
+
+        in_tx
+            .send(LogChunk::new(b"a\nb\nc".to_vec()))
+            .await
+            .unwrap();
+        in_tx.send(LogChunk::new(b"\nd\n".to_vec())).await.unwrap();
+        let mut rotated = LogChunk::new(b"e\nf\ng\n".to_vec());
+        rotated.new_file = true;
+        in_tx.send(rotated).await.unwrap();
+        drop(in_tx);
+        handle.await.unwrap().unwrap();
+
+        let mut numbers = Vec::new();
+        while let Some(chunk) = out_rx.recv().await {
+            assert_eq!(
+                chunk.line_numbers.len(),
+                chunk.data.iter().filter(|&&b| b == b'\n').count()
+            );
+            numbers.push(chunk.line_numbers);
+        }
+        numbers
+    }
+
+    #[tokio::test]
+    async fn numbers_source_lines_across_rotation() {
+        // Sampling drops lines but not their numbers
+        assert_eq!(
+            numbered(LineNumberRotation::Reset).await,
+            vec![vec![2], vec![4], vec![2]]
+        );
+        assert_eq!(
+            numbered(LineNumberRotation::Continue).await,
+            vec![vec![2], vec![4], vec![6]]
+        );
+    }
+
+    async fn without_header(matched: HeaderMatch, on_rotation: bool) -> Vec<u8> {
+        let until = regex::bytes::Regex::new(r"^\d{4}-\d{2}-\d{2} ").unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone())
+            .with_header_skip(HeaderSkip::new(until, matched, on_rotation, true));
+        let (in_tx, in_rx) = mpsc::channel(4);
+        let (out_tx, mut out_rx) = mpsc::channel(4);
+        let handle = tokio::spawn(pipeline.run(in_rx, out_tx));
+
+        in_tx
+            .send(LogChunk::new(
+                b"== billing export ==\ndate level message\n2024-05-01 INFO".to_vec(),
+            ))
+            .await
+            .unwrap();
+        in_tx
+            .send(LogChunk::new(
+                b" started\n2024-05-01 INFO ready\nnot a header\n".to_vec(),
+            ))
+            .await
+            .unwrap();
+        let mut rotated = LogChunk::new(b"== billing export ==\n2024-05-02 INFO again\n".to_vec());
+        rotated.new_file = true;
+        in_tx.send(rotated).await.unwrap();
+        drop(in_tx);
+        handle.await.unwrap().unwrap();
+
+        let mut out = Vec::new();
+        while let Some(chunk) = out_rx.recv().await {
+            out.extend_from_slice(&chunk.data);
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn skips_the_header_block_at_the_start() {
+        assert_eq!(
+            without_header(HeaderMatch::Keep, false).await,
+            b"2024-05-01 INFO started\n2024-05-01 INFO ready\nnot a header\n\
+              == billing export ==\n2024-05-02 INFO again\n"
+        );
+        assert_eq!(
+            without_header(HeaderMatch::Drop, false).await,
+            b"2024-05-01 INFO ready\nnot a header\n\
+              == billing export ==\n2024-05-02 INFO again\n"
+        );
+        assert_eq!(
+            without_header(HeaderMatch::Keep, true).await,
+            b"2024-05-01 INFO started\n2024-05-01 INFO ready\nnot a header\n\
+              2024-05-02 INFO again\n"
+        );
+    }
+
+    #[test]
+    fn header_regex_sees_the_line_without_its_ending() {
+        let skip = |input: &[u8]| {
+            let until = regex::bytes::Regex::new("^---$").unwrap();
+            let metrics = Arc::new(Metrics::new());
+            let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics)
+                .with_header_skip(HeaderSkip::new(until, HeaderMatch::Drop, false, true));
+            pipeline.process(input)
+        };
+
+        assert_eq!(
+            skip(b"HEADER\n---\nreal 1\nreal 2\n"),
+            Some(b"real 1\nreal 2\n".to_vec())
+        );
+        assert_eq!(
+            skip(b"HEADER\r\n---\r\nreal 1\r\n"),
+            Some(b"real 1\r\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn ships_a_file_without_a_header_match_after_the_cap() {
+        let until = regex::bytes::Regex::new("^---$").unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone())
+            .with_header_skip(HeaderSkip::new(until, HeaderMatch::Drop, false, true));
+
+        let mut input = b"line\n".repeat(MAX_HEADER_LINES as usize);
+        input.extend_from_slice(b"after the cap\n---\n");
+        assert_eq!(
+            pipeline.process(&input),
+            Some(b"after the cap\n---\n".to_vec())
+        );
+        assert_eq!(
+            metrics.header_lines_skipped.load(Ordering::Relaxed),
+            MAX_HEADER_LINES
+        );
+    }
+
+    #[test]
+    fn parses_line_delimiters() {
+        assert_eq!(parse_line_delimiter("newline"), Ok(b'\n'));
+        assert_eq!(parse_line_delimiter("nul"), Ok(0));
+        assert_eq!(parse_line_delimiter("byte:0x1e"), Ok(0x1e));
+        assert!(parse_line_delimiter("byte:0x1").is_err());
+        assert!(parse_line_delimiter("byte:1e").is_err());
+        assert!(parse_line_delimiter("tab").is_err());
+    }
+
+    #[test]
+    fn splits_nul_delimited_records_across_reads() {
+        let metrics = Arc::new(Metrics::new());
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone())
+            .with_line_delimiter(0)
+            .with_stage(Box::new(
+                Deduper::new(Duration::from_secs(5), false, metrics).with_delimiter(0),
+            ))
+            .with_stage(Box::new(Prefix(b"> ".to_vec())));
+
+        // Newlines are part of the record, the delimiter arrives a read later
+        assert_eq!(
+            pipeline.process(b"panic:\n  at main\0sec"),
+            Some(b"> panic:\n  at main\0".to_vec())
+        );
+        assert_eq!(pipeline.process(b"ond"), None);
+        assert_eq!(
+            pipeline.process(b"\0second\0"),
+            Some(b"> second\0".to_vec())
+        );
+        assert_eq!(
+            pipeline.finish(),
+            Some(b"> last message repeated 1 time\0".to_vec())
+        );
+    }
+
+    #[test]
+    fn cuts_a_runaway_line_at_the_limit() {
+        let metrics = Arc::new(Metrics::new());
+        let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, metrics.clone());
+        let chunk = vec![b'x'; 2 * 1024 * 1024];
+
+        let mut expected = vec![b'x'; DEFAULT_MAX_LINE_BYTES];
+        expected.extend_from_slice(TRUNCATION_MARKER);
+        // The second megabyte reaches the limit only once more data arrives
+        assert_eq!(pipeline.process(&chunk), Some(expected.clone()));
+        assert_eq!(pipeline.splitter.pending.len(), DEFAULT_MAX_LINE_BYTES);
+        assert_eq!(metrics.lines_truncated.load(Ordering::Relaxed), 1);
+
+        let mut tail = expected;
+        tail.extend_from_slice(b"xy\n");
+        assert_eq!(pipeline.process(b"xy\n"), Some(tail));
+        assert_eq!(metrics.lines_truncated.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn cuts_and_counts_lines_at_a_custom_byte() {
+        let metrics = Arc::new(Metrics::new());
+        let mut pipeline = LinePipeline::new(4, metrics).with_line_delimiter(0x1e);
+        assert_eq!(
+            pipeline.process(b"abcdef\x1egh\ni"),
+            Some(b"abcd [truncated]\x1eef\x1e".to_vec())
+        );
+        assert_eq!(pipeline.process(b"\x1e"), Some(b"gh\ni\x1e".to_vec()));
+
+        let mut batcher =
+            FrameBatcher::new(2, DEFAULT_FRAME_BYTES, Duration::from_secs(5)).with_delimiter(0x1e);
+        let out = batches(&mut batcher, &[b"a\nb\x1ec", b"\x1ed\x1e"]);
+        assert_eq!(out, vec![b"a\nb\x1ec\x1e".to_vec()]);
+        assert_eq!(batcher.take().unwrap().data, b"d\x1e");
+
+        let mut limit = ShipLimit::new(Some(1), None).with_delimiter(0x1e);
+        assert_eq!(
+            limit.take(b"a\nb\x1ec\x1e".to_vec()),
+            (b"a\nb\x1e".to_vec(), Some("--max-lines"))
+        );
+    }
+
+    #[test]
+    fn parses_separator_escapes() {
+        assert_eq!(parse_separator(" | ").unwrap(), b" | ");
+        assert_eq!(parse_separator("\\t").unwrap(), b"\t");
+        assert_eq!(parse_separator("\\\\n").unwrap(), b"\\n");
+        assert_eq!(parse_separator("\\x1f\\0").unwrap(), b"\x1f\0");
+        assert!(parse_separator("\\q").is_err());
+        assert!(parse_separator("\\x4").is_err());
+        assert!(parse_separator("end\\").is_err());
+    }
+
+    #[test]
+    fn joins_a_stack_trace_into_one_line() {
+        let trace = b"panic: boom\n  at main.rs:3\r\n  at lib.rs:9\0";
+        let joined = |separator: &str| {
+            let mut pipeline = LinePipeline::new(DEFAULT_MAX_LINE_BYTES, Arc::new(Metrics::new()))
+                .with_line_delimiter(0)
+                .with_stage(Box::new(Join::new(parse_separator(separator).unwrap(), 0)));
+            pipeline.process(trace).unwrap()
+        };
+
+        assert_eq!(
+            joined(" | "),
+            b"panic: boom |   at main.rs:3 |   at lib.rs:9\0"
+        );
+        assert_eq!(
+            joined("\\t"),
+            b"panic: boom\t  at main.rs:3\t  at lib.rs:9\0"
+        );
+        assert_eq!(
+            joined("\\\\n"),
+            b"panic: boom\\n  at main.rs:3\\n  at lib.rs:9\0"
+        );
+        // A newline that ends the record is the delimiter, not a break
+        let mut join = Join::new(b" ".to_vec(), b'\n');
+        assert_eq!(join.apply(b"single\n").unwrap(), b"single\n");
+    }
+
+    fn batches(batcher: &mut FrameBatcher, data: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        for data in data {
+            let chunk = LogChunk::new(data.to_vec());
+            out.extend(batcher.push(&chunk).into_iter().map(|c| c.data));
+        }
+        out
+    }
+
+    #[test]
+    fn frame_batcher_splits_on_line_count() {
+        let mut batcher = FrameBatcher::new(2, DEFAULT_FRAME_BYTES, Duration::from_secs(5));
+        let out = batches(&mut batcher, &[b"a\nb\nc", b"\nd\ne\n"]);
+        assert_eq!(out, vec![b"a\nb\n".to_vec(), b"c\nd\n".to_vec()]);
+        assert_eq!(batcher.take().unwrap().data, b"e\n");
+        assert!(batcher.take().is_none());
+    }
+
+    #[tokio::test]
+    async fn adaptive_target_grows_under_backlog() {
+        let metrics = Arc::new(Metrics::new());
+        let batcher = FrameBatcher::new(usize::MAX, 0, Duration::from_secs(5)).with_adaptive_bytes(
+            1024,
+            16 * 1024,
+            metrics.clone(),
+        );
+        let (in_tx, in_rx) = mpsc::channel(256);
+        let (out_tx, mut out_rx) = mpsc::channel(256);
+
+        // Queue everything up front so the batcher always finds a backlog
+        for i in 0..200 {
+            in_tx
+                .send(LogChunk::new(format!("line {:0>100}\n", i).into_bytes()))
+                .await
+                .unwrap();
+        }
+        drop(in_tx);
+        let handle = tokio::spawn(batcher.run(in_rx, out_tx));
+
+        let mut sizes = Vec::new();
+        while let Some(chunk) = out_rx.recv().await {
+            sizes.push(chunk.data.len());
+        }
+        handle.await.unwrap().unwrap();
+
+        assert_eq!(
+            metrics.batch_target_bytes.load(Ordering::Relaxed),
+            16 * 1024
+        );
+        assert!(sizes.iter().any(|&size| size > 8 * 1024), "{:?}", sizes);
+    }
+
+    #[tokio::test]
+    async fn coalescer_spaces_emits_by_the_interval() {
+        let interval = Duration::from_millis(100);
+        let (in_tx, in_rx) = mpsc::channel(64);
+        let (out_tx, mut out_rx) = mpsc::channel(64);
+        let coalescer = tokio::spawn(Coalescer::new(interval).run(in_rx, out_tx));
+
+        let producer = tokio::spawn(async move {
+            for i in 0..40u8 {
+                in_tx.send(LogChunk::new(vec![i])).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            // Dropping the sender must flush without waiting out the interval
+            Instant::now()
+        });
+
+        let mut emits = Vec::new();
+        let mut data = Vec::new();
+        while let Some(chunk) = out_rx.recv().await {
+            emits.push(Instant::now());
+            data.extend(chunk.data);
+        }
+        let closed = producer.await.unwrap();
+        coalescer.await.unwrap().unwrap();
+
+        assert_eq!(data, (0..40u8).collect::<Vec<_>>());
+        assert!(emits.len() >= 3, "{} emits", emits.len());
+        for pair in emits.windows(2).take(emits.len() - 2) {
+            // Allow for the receiver being scheduled late
+            assert!(pair[1] - pair[0] >= Duration::from_millis(90));
+        }
+        assert!(emits.last().unwrap().duration_since(closed) < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn coalescer_keeps_pending_data_charged() {
+        let gauge = crate::memory::MemoryGauge::new(1024);
+        let mut coalescer = Coalescer::new(Duration::from_secs(60));
+        for data in [b"one\n", b"two\n"] {
+            let mut chunk = LogChunk::new(data.to_vec());
+            chunk.charge = Some(gauge.charge(chunk.data.len()));
+            coalescer.push(chunk);
+        }
+        assert_eq!(gauge.used(), 8);
+
+        let out = coalescer.take().unwrap();
+        assert_eq!(out.data, b"one\ntwo\n");
+        assert_eq!(gauge.used(), 8);
+        drop(out);
+        assert_eq!(gauge.used(), 0);
+    }
+
+    #[test]
+    fn adaptive_target_shrinks_when_sparse() {
+        let metrics = Arc::new(Metrics::new());
+        let mut batcher = FrameBatcher::new(usize::MAX, 0, Duration::from_millis(1))
+            .with_adaptive_bytes(1024, 8192, metrics.clone());
+        for _ in 0..GROW_AFTER_BACKLOGGED * 3 {
+            batcher.observe_depth(10);
+        }
+        assert_eq!(batcher.max_bytes, 8192);
+
+        batcher.observe_linger();
+        assert_eq!(batcher.max_bytes, 4096);
+        for _ in 0..5 {
+            batcher.observe_linger();
+        }
+        assert_eq!(batcher.max_bytes, 1024);
+        assert_eq!(metrics.batch_target_bytes.load(Ordering::Relaxed), 1024);
+    }
+
+    #[test]
+    fn frame_batcher_respects_byte_cap() {
+        let mut batcher = FrameBatcher::new(100, 8, Duration::from_secs(5));
+        let out = batches(&mut batcher, &[b"1234\n5678\nlong line\nx\n"]);
+        // A line longer than the cap still goes out whole, on its own
+        assert_eq!(
+            out,
+            vec![
+                b"1234\n".to_vec(),
+                b"5678\n".to_vec(),
+                b"long line\n".to_vec()
+            ]
+        );
+        for batch in &out {
+            assert!(batch.ends_with(b"\n"));
+        }
+        assert_eq!(batcher.finish().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn frame_batcher_flushes_partial_batch_when_idle_and_at_shutdown() {
+        let batcher = FrameBatcher::new(10, DEFAULT_FRAME_BYTES, Duration::from_millis(50));
+        let (in_tx, in_rx) = mpsc::channel(4);
+        let (out_tx, mut out_rx) = mpsc::channel(4);
+        let handle = tokio::spawn(batcher.run(in_rx, out_tx));
+
+        in_tx.send(LogChunk::new(b"a\nb\n".to_vec())).await.unwrap();
+        let batch = tokio::time::timeout(Duration::from_secs(5), out_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.data, b"a\nb\n");
+
+        in_tx
+            .send(LogChunk::new(b"c\nunterminated".to_vec()))
+            .await
+            .unwrap();
+        drop(in_tx);
+        handle.await.unwrap().unwrap();
+        assert_eq!(out_rx.recv().await.unwrap().data, b"c\n");
+        assert_eq!(out_rx.recv().await.unwrap().data, b"unterminated");
+        assert!(out_rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn max_lines_cuts_after_the_last_line() {
+        let mut limit = ShipLimit::new(Some(3), None);
+        assert_eq!(limit.take(b"a\nb\n".to_vec()), (b"a\nb\n".to_vec(), None));
+        assert_eq!(
+            limit.take(b"c\nd\n".to_vec()),
+            (b"c\n".to_vec(), Some("--max-lines"))
+        );
+    }
+
+    #[test]
+    fn max_bytes_cuts_at_the_byte_count() {
+        let mut limit = ShipLimit::new(None, Some(5));
+        assert_eq!(limit.take(b"abc".to_vec()), (b"abc".to_vec(), None));
+        assert_eq!(
+            limit.take(b"defg".to_vec()),
+            (b"de".to_vec(), Some("--max-bytes"))
+        );
+    }
+
+    #[test]
+    fn first_limit_reached_wins() {
+        let mut limit = ShipLimit::new(Some(2), Some(100));
+        assert_eq!(
+            limit.take(b"one\ntwo\nthree\n".to_vec()),
+            (b"one\ntwo\n".to_vec(), Some("--max-lines"))
+        );
+
+        let mut limit = ShipLimit::new(Some(2), Some(4));
+        assert_eq!(
+            limit.take(b"one\ntwo\n".to_vec()),
+            (b"one\n".to_vec(), Some("--max-bytes"))
+        );
+    }
+}